@@ -19,13 +19,28 @@ EXAMPLES=(
     [4]="pio_ping"
     [5]="pio_compound"
     [6]="protocol"
-    [7]="rgb_leds"
+    [7]="protocol_loopback"
+    [8]="rgb_leds"
 )
 declare -A MODELS
 MODELS=(
-    [0]="dilemma"
-    [1]="cnano"
+    [0]="dilemma,rp2040"
+    [1]="cnano,rp2040"
+    [2]="skeletyl,rp2040"
 )
+# The models whose keymaps are only described in JSON
+declare -A JSON_MODELS
+JSON_MODELS=(
+    [0]="keymap_json,dilemma_max,rp2040"
+    [1]="keymap_json,scylla,rp2040"
+    [2]="keymap_json,tbkmini,rp2040"
+)
+# The Charybdis Nano with a PMW3320 sensor. The PMW3389 needs its SROM,
+# which is not part of the tree.
+PMW3320_MODEL="keymap_borisfaure,cnano,pmw3320,rp2040"
+# Target of the RP2350 controllers, checked with one model
+RP2350_TARGET="thumbv8m.main-none-eabihf"
+RP2350_MODEL="dilemma,rp2350"
 
 
 run_doc() {
@@ -42,6 +57,10 @@ run_doc() {
             cargo doc --no-default-features --features "${KEYMAP},${MODEL}"
         done
     done
+    for MODEL in "${JSON_MODELS[@]}"
+    do
+        cargo doc --no-default-features --features "${MODEL}"
+    done
 }
 
 run_fmt() {
@@ -64,6 +83,13 @@ run_clippy() {
             cargo clippy --no-default-features --features "${KEYMAP},${MODEL}" -- -D warnings
         done
     done
+    for MODEL in "${JSON_MODELS[@]}"
+    do
+        cargo clippy --no-default-features --features "${MODEL},defmt" -- -D warnings
+        cargo clippy --no-default-features --features "${MODEL}" -- -D warnings
+    done
+    cargo clippy --no-default-features --features "${PMW3320_MODEL},defmt" -- -D warnings
+    cargo clippy --no-default-features --features "${PMW3320_MODEL}" -- -D warnings
 }
 
 run_check() {
@@ -80,10 +106,22 @@ run_check() {
             cargo check --no-default-features --features "${KEYMAP},${MODEL}"
         done
     done
+    for MODEL in "${JSON_MODELS[@]}"
+    do
+        cargo check --no-default-features --features "${MODEL},defmt"
+        cargo check --no-default-features --features "${MODEL}"
+    done
+    cargo check --no-default-features --features "${PMW3320_MODEL},defmt"
+    cargo check --no-default-features --features "${PMW3320_MODEL}"
+    for KEYMAP in "${KEYMAPS[@]}"
+    do
+        cargo check --target "$RP2350_TARGET" --no-default-features --features "${KEYMAP},${RP2350_MODEL}"
+    done
 }
 
 run_test() {
     cargo test -p utils --target "x86_64-unknown-linux-gnu"
+    cargo bench -p utils --target "x86_64-unknown-linux-gnu" --no-run
 }
 
 run_build() {
@@ -101,6 +139,11 @@ run_build() {
             cargo build --no-default-features --features "${KEYMAP},${MODEL}"
         done
     done
+    for MODEL in "${JSON_MODELS[@]}"
+    do
+        cargo build --no-default-features --features "${MODEL},defmt"
+        cargo build --no-default-features --features "${MODEL}"
+    done
 }
 
 run_build_release() {
@@ -118,6 +161,11 @@ run_build_release() {
             cargo build --release --no-default-features --features "${KEYMAP},${MODEL}"
         done
     done
+    for MODEL in "${JSON_MODELS[@]}"
+    do
+        cargo build --release --no-default-features --features "${MODEL},defmt"
+        cargo build --release --no-default-features --features "${MODEL}"
+    done
 }
 
 case $1 in
@@ -1,81 +1,183 @@
-use embassy_rp::{
-    gpio::Output,
-    peripherals::SPI0,
-    spi::{Async, Error as SpiError, Instance as SpiInstance, Mode, Spi},
-};
 use embassy_time::{with_timeout, Duration, Timer};
-use embedded_hal::spi::SpiBus;
+use embedded_hal_async::spi::SpiDevice;
+
+use crate::{
+    glide::{GlideConfig, GlideContext},
+    regs::{self, Register},
+};
+
+/// How the sensor reports the position of the finger
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PositionMode {
+    /// Absolute coordinates, from which the moves are computed.
+    /// Supports glide and reports the pressure.
+    Absolute,
+    /// Relative moves, computed by the sensor
+    Relative,
+}
+
+/// Overlay on top of the sensor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Overlay {
+    /// Curved overlay: the sensitivity of the edges is tuned
+    Curved,
+    /// Flat overlay
+    Other,
+}
 
-pub mod glide;
-pub mod regs;
+/// Rotation applied to the moves, depending on how the sensor is mounted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TransformMode {
+    /// No rotation
+    Normal,
+    /// Rotate by 90° clockwise
+    Rotate90,
+    /// Rotate by 180°
+    Rotate180,
+    /// Rotate by 270° clockwise
+    Rotate270,
+}
 
-use glide::GlideContext;
-use regs::Register;
+impl TransformMode {
+    fn transform(&self, x: i8, y: i8) -> (i8, i8) {
+        match self {
+            TransformMode::Normal => (x, y),
+            TransformMode::Rotate90 => (y, x.saturating_neg()),
+            TransformMode::Rotate180 => (x.saturating_neg(), y.saturating_neg()),
+            TransformMode::Rotate270 => (y.saturating_neg(), x),
+        }
+    }
 
-/// Diameter of the sensor, in mm
-const DIAMETER: u32 = 35;
-/// Sensor refresh rate, in ms
-const REFRESH_RATE_MS: u64 = 10;
+    fn transform_position(&self, x: u8, y: u8) -> (u8, u8) {
+        match self {
+            TransformMode::Normal => (x, y),
+            TransformMode::Rotate90 => (y, u8::MAX - x),
+            TransformMode::Rotate180 => (u8::MAX - x, u8::MAX - y),
+            TransformMode::Rotate270 => (u8::MAX - y, x),
+        }
+    }
+}
 
-#[derive(Debug, defmt::Format)]
-pub enum TrackpadError {
-    Spi(SpiError),
+/// Configuration of the trackpad
+pub struct Config {
+    /// How the sensor reports the position of the finger
+    pub position_mode: PositionMode,
+    /// Overlay on top of the sensor
+    pub overlay: Overlay,
+    /// Rotation applied to the moves
+    pub transform: TransformMode,
+    /// Glide after the finger is lifted, in absolute mode only
+    pub glide: Option<GlideConfig>,
 }
-impl From<SpiError> for TrackpadError {
-    fn from(e: SpiError) -> Self {
-        TrackpadError::Spi(e)
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            position_mode: PositionMode::Absolute,
+            overlay: Overlay::Curved,
+            transform: TransformMode::Normal,
+            glide: None,
+        }
     }
 }
 
-type TrackpadSpi = Spi<'static, SPI0, Async>;
-pub struct Trackpad<'a, T: SpiInstance, M: Mode> {
-    /// The SPI bus
-    spi: Spi<'a, T, M>,
-    /// The CS pin
-    cs: Output<'a>,
+/// Cirque Pinnacle trackpad, of diameter `DIAMETER` in mm, behind a SPI
+/// device
+pub struct Trackpad<SPI, const DIAMETER: u32> {
+    spi: SPI,
     position_mode: PositionMode,
     overlay: Overlay,
     transform: TransformMode,
-    relative_remainder: (i16, i16),
     glide: Option<GlideContext>,
     last_pos: Option<(u16, u16)>,
+    relative_remainder: (i16, i16),
     scale: u16,
     last_scale: u16,
 }
-pub type TrackpadDev = Trackpad<'static, SPI0, Async>;
 
-impl<'a, I: SpiInstance + SpiDevice, M: Mode> Trackpad<'a, I, M> {
-    /// Create a new Trackpad driver
-    pub fn new(spi: Spi<'a, I, M>, cs: Output<'a>) -> Self {
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum Reading {
+    Absolute {
+        x: u16,
+        y: u16,
+        z: u16,
+        buttons: u8,
+        touch_down: bool,
+    },
+    Relative {
+        dx: i16,
+        dy: i16,
+        wheel_count: i8,
+        buttons: u8,
+    },
+}
+
+impl Reading {
+    const ABS_X_MIN: u16 = 127;
+    const ABS_X_MAX: u16 = 1919;
+    const ABS_X_RANGE: u16 = Self::ABS_X_MAX - Self::ABS_X_MIN;
+
+    const ABS_Y_MIN: u16 = 63;
+    const ABS_Y_MAX: u16 = 1471;
+    const ABS_Y_RANGE: u16 = Self::ABS_Y_MAX - Self::ABS_Y_MIN;
+
+    const REL_X_RANGE: u16 = 256;
+    const REL_Y_RANGE: u16 = 256;
+
+    fn resolve_abs(x: u16, y: u16) -> (u16, u16) {
+        let x = x.clamp(Self::ABS_X_MIN, Self::ABS_X_MAX) - Self::ABS_X_MIN;
+        let y = y.clamp(Self::ABS_Y_MIN, Self::ABS_Y_MAX) - Self::ABS_Y_MIN;
+
+        (x, y)
+    }
+}
+
+const WRITE_MASK: u8 = 0x80;
+const READ_MASK: u8 = 0xA0;
+const FILLER_BYTE: u8 = 0xFC;
+
+fn saturating_i16_to_i8(v: i16) -> i8 {
+    v.clamp(i8::MIN as i16, i8::MAX as i16) as i8
+}
+
+/// Scale for a resolution of `cpi` counts per inch
+fn cpi_to_scale(cpi: u16, diameter: u32) -> u16 {
+    ((cpi as u32 * diameter * 10) / 254) as u16
+}
+
+impl<SPI: SpiDevice, const DIAMETER: u32> Trackpad<SPI, DIAMETER> {
+    /// Create the driver. The sensor is set up by `init()`.
+    pub fn new(spi: SPI, config: Config) -> Self {
         Self {
             spi,
-            cs,
-            position_mode: PositionMode::Absolute,
-            overlay: Overlay::Curved,
-            transform: TransformMode::Rotate90,
-            glide: None,
-            relative_remainder: (0, 0),
+            position_mode: config.position_mode,
+            overlay: config.overlay,
+            transform: config.transform,
+            glide: config.glide.map(GlideContext::new),
             last_pos: None,
-            scale: ((800 * DIAMETER * 10) / 254) as u16,
+            relative_remainder: (0, 0),
+            scale: cpi_to_scale(800, DIAMETER),
             last_scale: 0,
         }
     }
 
-    /// Run the sensor
-    pub async fn run(&mut self) {
-        loop {}
+    /// Set the resolution, in counts per inch
+    pub fn set_scale(&mut self, cpi: u16) {
+        self.scale = cpi_to_scale(cpi, DIAMETER);
     }
 
-    /// Start the sensor
-    pub async fn start(&mut self) {
-        loop {}
+    /// Check whether a Pinnacle answers on the bus, by reading its chip ID
+    pub async fn probe(&mut self) -> Result<bool, SPI::Error> {
+        let id = self.rap_read_reg::<regs::ChipId>().await?;
+        Ok(id.0 == regs::FIRMWARE_ID)
     }
 
-    pub fn set_scale(&mut self, cpi: u16) {
-        self.scale = ((cpi as u32 * DIAMETER * 10) / 254) as u16;
-    }
-
-    pub async fn init(&mut self) -> Result<(), TrackpadError> {
+    /// Reset and set up the sensor
+    pub async fn init(&mut self) -> Result<(), SPI::Error> {
         self.rap_write_reg(regs::SystemConfig::def().with_reset(true))
             .await?;
 
@@ -109,10 +211,6 @@ impl<'a, I: SpiInstance + SpiDevice, M: Mode> Trackpad<'a, I, M> {
 
         self.rap_write_reg(regs::SampleRate::from_byte(regs::SampleRate::SPS_100))
             .await?;
-        // self.era_write_reg(regs::TrackTimerReload::from_byte(
-        //     regs::TrackTimerReload::SPS_200,
-        // ))
-        // .await?;
 
         let should_calibrate = match self.overlay {
             Overlay::Curved => {
@@ -132,9 +230,24 @@ impl<'a, I: SpiInstance + SpiDevice, M: Mode> Trackpad<'a, I, M> {
         Ok(())
     }
 
-    pub async fn get_report(&mut self) -> Result<Option<(i8, i8)>, TrackpadError> {
+    /// Position `(x, y)` of the finger touching the sensor, as of the last
+    /// report, each from 0 to 255 across the sensor, rotated like the
+    /// moves. `None` without touch, and always in relative mode.
+    pub fn touch_position(&self) -> Option<(u8, u8)> {
+        let (x, y) = self.last_pos?;
+        let scale = self.scale.max(1) as u32;
+        let normalize = |v: u16| (v as u32 * u8::MAX as u32 / scale).min(u8::MAX as u32) as u8;
+        Some(
+            self.transform
+                .transform_position(normalize(x), normalize(y)),
+        )
+    }
+
+    /// Read the sensor and return the move `(dx, dy)` since the previous
+    /// report along with the pressure, or `None` if there is no new data.
+    /// The pressure is always 0 in relative mode.
+    pub async fn get_report(&mut self) -> Result<Option<(i8, i8, u8)>, SPI::Error> {
         let reading = self.read_data().await?;
-        // crate::log::info!("raw reading: {:?}", reading);
 
         let glide_report = self.glide.as_mut().and_then(|g| g.check());
 
@@ -144,7 +257,7 @@ impl<'a, I: SpiInstance + SpiDevice, M: Mode> Trackpad<'a, I, M> {
 
         let reading = self.scale_reading(reading);
 
-        let (mut report_x, mut report_y) = (0, 0);
+        let (mut report_x, mut report_y, mut pressure) = (0, 0, 0);
 
         match reading {
             Reading::Absolute {
@@ -154,12 +267,12 @@ impl<'a, I: SpiInstance + SpiDevice, M: Mode> Trackpad<'a, I, M> {
                 buttons: _,
                 touch_down,
             } => {
+                pressure = (z & 0x3f) as u8;
+
                 if !touch_down {
                     self.last_pos = None;
                 }
 
-                // crate::log::info!("handling report: {:?} last: {:?}", reading, self.last_pos);
-
                 if self.last_scale != 0 && self.last_scale == self.scale && x != 0 && y != 0 {
                     if let Some((last_x, last_y)) = self.last_pos {
                         report_x = saturating_i16_to_i8(x as i16 - last_x as i16);
@@ -184,6 +297,13 @@ impl<'a, I: SpiInstance + SpiDevice, M: Mode> Trackpad<'a, I, M> {
                         }
                     }
                 }
+
+                // Filter out small movements below threshold (reduce noise/shadow movements)
+                const MOVEMENT_THRESHOLD: i8 = 3;
+                if report_x.abs() < MOVEMENT_THRESHOLD && report_y.abs() < MOVEMENT_THRESHOLD {
+                    report_x = 0;
+                    report_y = 0;
+                }
             }
             Reading::Relative {
                 dx,
@@ -196,39 +316,35 @@ impl<'a, I: SpiInstance + SpiDevice, M: Mode> Trackpad<'a, I, M> {
             }
         }
 
-        Ok(Some(self.transform.transform(report_x, report_y)))
+        let (report_x, report_y) = self.transform.transform(report_x, report_y);
+        Ok(Some((report_x, report_y, pressure)))
     }
 
-    async fn read_data(&mut self) -> Result<Option<Reading>, TrackpadError> {
+    async fn read_data(&mut self) -> Result<Option<Reading>, SPI::Error> {
         let status = self.rap_read_reg::<regs::Status>().await?;
         if !status.data_ready() {
             return Ok(None);
         }
 
-        // crate::log::info!("status: {:?}", status);
-
         let mut data = [0u8; 6];
         self.rap_read(regs::Packet0::REG, &mut data).await?;
         self.clear_flags().await?;
 
-        // crate::log::info!("read raw bytes: {:?}", data);
-
         match self.position_mode {
             PositionMode::Absolute => {
                 let buttons = data[0] & 0x3f;
-                let x = (data[2] as u16) | ((data[4] & 0x0F) as u16) << 8;
-                let y = (data[3] as u16) | ((data[4] & 0xF0) as u16) << 4;
+                let x = (data[2] as u16) | (((data[4] & 0x0F) as u16) << 8);
+                let y = (data[3] as u16) | (((data[4] & 0xF0) as u16) << 4);
                 let z = (data[5] & 0x3f) as u16;
                 let touch_down = x != 0 || y != 0;
 
-                let reading = Reading::Absolute {
+                Ok(Some(Reading::Absolute {
                     x,
                     y,
                     z,
                     buttons,
                     touch_down,
-                };
-                Ok(Some(reading))
+                }))
             }
             PositionMode::Relative => {
                 let buttons = data[0] & 0x07;
@@ -245,7 +361,7 @@ impl<'a, I: SpiInstance + SpiDevice, M: Mode> Trackpad<'a, I, M> {
                     -(data[2] as i16)
                 };
 
-                let wheel_count = i8::from_be_bytes([data[2]]);
+                let wheel_count = i8::from_be_bytes([data[3]]);
 
                 Ok(Some(Reading::Relative {
                     dx,
@@ -305,14 +421,18 @@ impl<'a, I: SpiInstance + SpiDevice, M: Mode> Trackpad<'a, I, M> {
             }
         }
     }
-    async fn set_feed_enable(&mut self, enabled: bool) -> Result<(), TrackpadError> {
+}
+
+/// utility stuff
+impl<SPI: SpiDevice, const DIAMETER: u32> Trackpad<SPI, DIAMETER> {
+    async fn set_feed_enable(&mut self, enabled: bool) -> Result<(), SPI::Error> {
         let mut feed_config = self.rap_read_reg::<regs::FeedConfig1>().await?;
         feed_config.set_feed_enable(enabled);
         self.rap_write_reg(feed_config).await?;
         Ok(())
     }
 
-    async fn clear_flags(&mut self) -> Result<(), TrackpadError> {
+    async fn clear_flags(&mut self) -> Result<(), SPI::Error> {
         self.rap_write_reg(
             regs::Status::def()
                 .with_command_complete(false)
@@ -326,7 +446,7 @@ impl<'a, I: SpiInstance + SpiDevice, M: Mode> Trackpad<'a, I, M> {
     async fn set_adc_attenuation(
         &mut self,
         gain: regs::AdcAttenuation,
-    ) -> Result<bool, TrackpadError> {
+    ) -> Result<bool, SPI::Error> {
         let mut cfg = self.era_read_reg::<regs::TrackAdcConfig>().await?;
 
         if gain == cfg.attenuate() {
@@ -340,7 +460,7 @@ impl<'a, I: SpiInstance + SpiDevice, M: Mode> Trackpad<'a, I, M> {
         Ok(true)
     }
 
-    async fn tune_edge_sensivity(&mut self) -> Result<(), TrackpadError> {
+    async fn tune_edge_sensivity(&mut self) -> Result<(), SPI::Error> {
         self.era_read_reg::<regs::XAxisWideZMin>().await?;
         self.era_write_reg(regs::XAxisWideZMin(0x04)).await?;
         self.era_read_reg::<regs::XAxisWideZMin>().await?;
@@ -352,7 +472,7 @@ impl<'a, I: SpiInstance + SpiDevice, M: Mode> Trackpad<'a, I, M> {
         Ok(())
     }
 
-    async fn calibrate(&mut self) -> Result<(), TrackpadError> {
+    async fn calibrate(&mut self) -> Result<(), SPI::Error> {
         let cfg = self.rap_read_reg::<regs::CalConfig>().await?;
         self.rap_write_reg(cfg.with_calibrate(true)).await?;
 
@@ -374,14 +494,14 @@ impl<'a, I: SpiInstance + SpiDevice, M: Mode> Trackpad<'a, I, M> {
     }
 
     #[allow(unused)]
-    async fn set_cursor_smoothing(&mut self, enabled: bool) -> Result<(), TrackpadError> {
+    async fn set_cursor_smoothing(&mut self, enabled: bool) -> Result<(), SPI::Error> {
         let cfg = self.rap_read_reg::<regs::FeedConfig3>().await?;
         self.rap_write_reg(cfg.with_disable_cross_rate_smoothing(!enabled))
             .await
     }
 
     #[allow(unused)]
-    async fn set_noise_comp(&mut self, enabled: bool) -> Result<(), TrackpadError> {
+    async fn set_noise_comp(&mut self, enabled: bool) -> Result<(), SPI::Error> {
         let cfg = self.rap_read_reg::<regs::FeedConfig3>().await?;
         self.rap_write_reg(
             cfg.with_disable_cross_rate_smoothing(!enabled)
@@ -389,20 +509,21 @@ impl<'a, I: SpiInstance + SpiDevice, M: Mode> Trackpad<'a, I, M> {
         )
         .await
     }
-    async fn era_read_reg<R: regs::Register<u16>>(&mut self) -> Result<R, TrackpadError> {
+}
+
+/// era reading
+impl<SPI: SpiDevice, const DIAMETER: u32> Trackpad<SPI, DIAMETER> {
+    async fn era_read_reg<R: regs::Register<u16>>(&mut self) -> Result<R, SPI::Error> {
         let mut b: u8 = 0u8;
         self.era_read(R::REG, core::slice::from_mut(&mut b)).await?;
         Ok(R::from_byte(b))
     }
 
-    async fn era_write_reg<R: regs::Register<u16>>(
-        &mut self,
-        value: R,
-    ) -> Result<(), TrackpadError> {
+    async fn era_write_reg<R: regs::Register<u16>>(&mut self, value: R) -> Result<(), SPI::Error> {
         self.era_write(R::REG, value.to_byte()).await
     }
 
-    async fn era_read(&mut self, address: u16, buf: &mut [u8]) -> Result<(), TrackpadError> {
+    async fn era_read(&mut self, address: u16, buf: &mut [u8]) -> Result<(), SPI::Error> {
         self.set_feed_enable(false).await?;
 
         let [upper, lower] = address.to_be_bytes();
@@ -437,7 +558,7 @@ impl<'a, I: SpiInstance + SpiDevice, M: Mode> Trackpad<'a, I, M> {
         Ok(())
     }
 
-    async fn era_write(&mut self, address: u16, data: u8) -> Result<(), TrackpadError> {
+    async fn era_write(&mut self, address: u16, data: u8) -> Result<(), SPI::Error> {
         self.set_feed_enable(false).await?;
 
         self.rap_write_reg(regs::AXSValue(data)).await?;
@@ -465,33 +586,32 @@ impl<'a, I: SpiInstance + SpiDevice, M: Mode> Trackpad<'a, I, M> {
 
         Ok(())
     }
-    async fn rap_read_reg<R: regs::Register<u8>>(&mut self) -> Result<R, TrackpadError> {
+}
+
+/// rap reading
+impl<SPI: SpiDevice, const DIAMETER: u32> Trackpad<SPI, DIAMETER> {
+    async fn rap_read_reg<R: regs::Register<u8>>(&mut self) -> Result<R, SPI::Error> {
         let mut b: u8 = 0u8;
         self.rap_read(R::REG, core::slice::from_mut(&mut b)).await?;
         Ok(R::from_byte(b))
     }
 
-    async fn rap_write_reg<R: regs::Register<u8>>(
-        &mut self,
-        value: R,
-    ) -> Result<(), TrackpadError> {
+    async fn rap_write_reg<R: regs::Register<u8>>(&mut self, value: R) -> Result<(), SPI::Error> {
         self.rap_write(R::REG, &[value.to_byte()]).await
     }
 
-    // async fn rap_read_byte(&mut self, address: u8) -> Result<u8, TrackpadError> {
+    // async fn rap_read_byte(&mut self, address: u8) -> Result<u8, SPI::Error> {
     //     let mut b: u8 = 0u8;
     //     self.rap_read(address, core::slice::from_mut(&mut b))
     //         .await?;
     //     Ok(b)
     // }
 
-    // async fn rap_write_byte(&mut self, address: u8, value: u8) -> Result<(), TrackpadError> {
+    // async fn rap_write_byte(&mut self, address: u8, value: u8) -> Result<(), SPI::Error> {
     //     self.rap_write(address, &[value]).await
     // }
 
-    async fn rap_read(&mut self, address: u8, buf: &mut [u8]) -> Result<(), TrackpadError> {
-        const READ_MASK: u8 = 0xA0;
-        const FILLER_BYTE: u8 = 0xFC;
+    async fn rap_read(&mut self, address: u8, buf: &mut [u8]) -> Result<(), SPI::Error> {
         let cmd = address | READ_MASK;
         let mut bin = [0u8; 3];
         self.spi
@@ -505,8 +625,7 @@ impl<'a, I: SpiInstance + SpiDevice, M: Mode> Trackpad<'a, I, M> {
         Ok(())
     }
 
-    async fn rap_write(&mut self, address: u8, buf: &[u8]) -> Result<(), TrackpadError> {
-        const WRITE_MASK: u8 = 0x80;
+    async fn rap_write(&mut self, address: u8, buf: &[u8]) -> Result<(), SPI::Error> {
         let cmd = address | WRITE_MASK;
         self.spi
             .transaction(&mut [
@@ -516,81 +635,3 @@ impl<'a, I: SpiInstance + SpiDevice, M: Mode> Trackpad<'a, I, M> {
             .await
     }
 }
-
-#[embassy_executor::task]
-pub async fn run(mut pad: TrackpadDev) {
-    let res = pad.start().await;
-    if let Err(e) = res {
-        defmt::error!("Error: {:?}", defmt::Debug2Format(&e));
-    }
-    pad.run().await;
-}
-
-pub enum TransformMode {
-    Normal,
-    Rotate90,
-    Rotate180,
-    Rotate270,
-}
-
-impl TransformMode {
-    fn transform(&self, x: i8, y: i8) -> (i8, i8) {
-        match self {
-            TransformMode::Normal => (x, y),
-            TransformMode::Rotate90 => (y, -x),
-            TransformMode::Rotate180 => (-x, -y),
-            TransformMode::Rotate270 => (-y, x),
-        }
-    }
-}
-
-pub enum Overlay {
-    Curved,
-    Other,
-}
-
-pub enum PositionMode {
-    Absolute,
-    Relative,
-}
-
-#[derive(Debug, defmt::Format)]
-pub enum Reading {
-    Absolute {
-        x: u16,
-        y: u16,
-        z: u16,
-        buttons: u8,
-        touch_down: bool,
-    },
-    Relative {
-        dx: i16,
-        dy: i16,
-        wheel_count: i8,
-        buttons: u8,
-    },
-}
-
-impl Reading {
-    const ABS_X_MIN: u16 = 127;
-    const ABS_X_MAX: u16 = 1919;
-    const ABS_X_RANGE: u16 = Self::ABS_X_MAX - Self::ABS_X_MIN;
-
-    const ABS_Y_MIN: u16 = 63;
-    const ABS_Y_MAX: u16 = 1471;
-    const ABS_Y_RANGE: u16 = Self::ABS_Y_MAX - Self::ABS_Y_MIN;
-
-    const REL_X_RANGE: u16 = 256;
-    const REL_Y_RANGE: u16 = 256;
-
-    fn resolve_abs(x: u16, y: u16) -> (u16, u16) {
-        let x = x.clamp(Self::ABS_X_MIN, Self::ABS_X_MAX) - Self::ABS_X_MIN;
-        let y = y.clamp(Self::ABS_Y_MIN, Self::ABS_Y_MAX) - Self::ABS_Y_MIN;
-
-        (x, y)
-    }
-}
-
-fn saturating_i16_to_i8(v: i16) -> i8 {
-    v.clamp(i8::MIN as i16, i8::MAX as i16) as i8
-}
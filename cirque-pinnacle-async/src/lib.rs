@@ -0,0 +1,10 @@
+#![no_std]
+#![doc = include_str!("../README.md")]
+
+mod driver;
+/// Glide after the finger is lifted
+pub mod glide;
+/// Registers of the sensor
+pub mod regs;
+
+pub use driver::*;
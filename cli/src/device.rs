@@ -0,0 +1,114 @@
+//! Raw HID transport of the configuration protocol
+
+use hidapi::{DeviceInfo, HidApi, HidDevice, HidError};
+use std::fmt;
+use utils::raw_hid::{report, Command, Report, REPORT_SIZE, STATUS_OK, USAGE, USAGE_PAGE};
+
+/// Time to wait for the answer to a command, in ms
+const TIMEOUT_MS: i32 = 1000;
+
+/// Errors talking to the keyboard
+#[derive(Debug)]
+pub enum Error {
+    /// Error of the HID library
+    Hid(HidError),
+    /// No keyboard found
+    NoDevice,
+    /// Several keyboards found, and none selected
+    SeveralDevices,
+    /// No answer to the command in time
+    Timeout(Command),
+    /// Command unknown to the firmware
+    Unsupported(Command),
+    /// Answer to another command
+    UnexpectedAnswer(Command, u8),
+    /// The firmware failed to run the command
+    Failed(Command),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Hid(e) => write!(f, "HID error: {}", e),
+            Error::NoDevice => write!(f, "no keyboard found"),
+            Error::SeveralDevices => {
+                write!(f, "several keyboards found, select one with --device")
+            }
+            Error::Timeout(cmd) => write!(f, "no answer to {:?}", cmd),
+            Error::Unsupported(cmd) => write!(f, "{:?} is not supported by the firmware", cmd),
+            Error::UnexpectedAnswer(cmd, id) => {
+                write!(f, "unexpected answer 0x{:02x} to {:?}", id, cmd)
+            }
+            Error::Failed(cmd) => write!(f, "{:?} failed", cmd),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<HidError> for Error {
+    fn from(e: HidError) -> Self {
+        Error::Hid(e)
+    }
+}
+
+/// Raw HID interfaces of the keyboards found
+pub fn list(api: &HidApi) -> impl Iterator<Item = &DeviceInfo> {
+    api.device_list()
+        .filter(|d| d.usage_page() == USAGE_PAGE && d.usage() == USAGE as u16)
+}
+
+/// Keyboard reached through its raw HID interface
+pub struct Device {
+    /// Raw HID interface
+    hid: HidDevice,
+}
+
+impl Device {
+    /// Open the keyboard whose raw HID interface is at `path`, or the only
+    /// keyboard found when `path` is `None`
+    pub fn open(api: &HidApi, path: Option<&str>) -> Result<Self, Error> {
+        let mut found = list(api).filter(|d| match path {
+            Some(p) => d.path().to_bytes() == p.as_bytes(),
+            None => true,
+        });
+        let info = found.next().ok_or(Error::NoDevice)?;
+        if found.next().is_some() {
+            return Err(Error::SeveralDevices);
+        }
+        Ok(Self {
+            hid: info.open_device(api)?,
+        })
+    }
+
+    /// Send the command `cmd` with the arguments `args`, and return the data
+    /// of its answer
+    pub fn command(&self, cmd: Command, args: &[u8]) -> Result<Report, Error> {
+        // Output reports are prefixed with their report ID, none here
+        let mut out = [0u8; REPORT_SIZE + 1];
+        out[1..].copy_from_slice(&report(cmd, args));
+        self.hid.write(&out)?;
+        let mut answer = [0u8; REPORT_SIZE];
+        if self.hid.read_timeout(&mut answer, TIMEOUT_MS)? == 0 {
+            return Err(Error::Timeout(cmd));
+        }
+        match answer[0] {
+            id if id == cmd as u8 => {
+                // Drop the command id
+                answer.copy_within(1.., 0);
+                answer[REPORT_SIZE - 1] = 0;
+                Ok(answer)
+            }
+            id if id == Command::Unhandled as u8 => Err(Error::Unsupported(cmd)),
+            id => Err(Error::UnexpectedAnswer(cmd, id)),
+        }
+    }
+
+    /// Send the command `cmd`, answered with a status
+    pub fn command_status(&self, cmd: Command, args: &[u8]) -> Result<(), Error> {
+        match self.command(cmd, args)?[0] {
+            STATUS_OK => Ok(()),
+            _ => Err(Error::Failed(cmd)),
+        }
+    }
+}
@@ -0,0 +1,498 @@
+//! Host companion tool of the firmware.
+//!
+//! It speaks the raw HID configuration protocol of `utils::raw_hid` to
+//! list the keyboards plugged, read and change their CPI, RGB animation and
+//! brightness, scroll speed, hold-tap keys, auto-mouse, idle timeout and keymap, dump the statistics of the link between the
+//! halves and of the key presses, and reboot them into the bootloader.
+
+mod device;
+
+use clap::{Parser, Subcommand};
+use device::{Device, Error};
+use hidapi::HidApi;
+use std::process::ExitCode;
+use utils::keymap::{Keycode, NOT_EDITED};
+use utils::raw_hid::{Command, LinkStats, SensorQuality, PROTOCOL_VERSION, REPORT_SIZE};
+use utils::rgb_anims::RgbAnimType;
+use utils::settings::{
+    Settings, TapHoldMode, MAX_AUTO_MOUSE_TIMEOUT_MS, MAX_CPI, MAX_IDLE_TIMEOUT_S,
+    MAX_RGB_BRIGHTNESS, MAX_SCROLL_DIVISOR, MAX_TAP_HOLD_TIMEOUT_MS, MIN_CPI, MIN_IDLE_TIMEOUT_S,
+    MIN_SCROLL_DIVISOR, MIN_TAP_HOLD_TIMEOUT_MS, SCROLL_DIVISOR_STEP, SETTINGS_SIZE,
+};
+
+/// Configure the keyboard over raw HID
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Path of the raw HID interface of the keyboard, as given by `list`.
+    /// Needed when several keyboards are plugged.
+    #[arg(short, long, global = true)]
+    device: Option<String>,
+    #[command(subcommand)]
+    command: Action,
+}
+
+/// Actions
+#[derive(Subcommand)]
+enum Action {
+    /// List the keyboards plugged
+    List,
+    /// Show the version of the protocol of the firmware
+    Version,
+    /// Show the CPI of the pointer, or set it
+    Cpi {
+        /// New CPI
+        #[arg(value_parser = clap::value_parser!(u16).range(MIN_CPI as i64..=MAX_CPI as i64))]
+        cpi: Option<u16>,
+    },
+    /// Show the RGB animation, or set it
+    Rgb {
+        /// New animation: off, wheel, pulse, solid:<color> or
+        /// pulse:<color>, the color being an index of the palette
+        #[arg(value_parser = parse_anim)]
+        anim: Option<RgbAnimType>,
+    },
+    /// Show the brightness of the RGB LEDs, or set it
+    Brightness {
+        /// New brightness, in percent
+        #[arg(value_parser = clap::value_parser!(u8).range(0..=MAX_RGB_BRIGHTNESS as i64))]
+        percent: Option<u8>,
+    },
+    /// Show the pointer counts per scroll step, or set them
+    Scroll {
+        /// New counts per scroll step, a multiple of 4 up to 60
+        #[arg(value_parser = parse_scroll_divisor)]
+        divisor: Option<u8>,
+    },
+    /// Show how the hold-tap keys are resolved, or change it
+    TapHold {
+        /// Time after which a key is held, in ms
+        #[arg(long, value_parser = clap::value_parser!(u16)
+            .range(MIN_TAP_HOLD_TIMEOUT_MS as i64..=MAX_TAP_HOLD_TIMEOUT_MS as i64))]
+        timeout: Option<u16>,
+        /// Resolution when pressed along other keys: tap-preferred,
+        /// permissive-hold or hold-on-other-key-press
+        #[arg(long, value_parser = parse_tap_hold_mode)]
+        mode: Option<TapHoldMode>,
+    },
+    /// Show the auto-mouse configuration, or change it
+    AutoMouse {
+        /// Whether moving the pointer activates the mouse layer
+        #[arg(long)]
+        enabled: Option<bool>,
+        /// Time without pointer activity after which the mouse layer is
+        /// left, in ms
+        #[arg(long, value_parser = clap::value_parser!(u16)
+            .range(0..=MAX_AUTO_MOUSE_TIMEOUT_MS as i64))]
+        timeout: Option<u16>,
+        /// Time the mouse layer is kept after a mouse click, in ms
+        #[arg(long, value_parser = clap::value_parser!(u16)
+            .range(0..=MAX_AUTO_MOUSE_TIMEOUT_MS as i64))]
+        click_delay: Option<u16>,
+    },
+    /// Show the time without any activity before the system clock of the
+    /// board is lowered, or set it
+    IdleTimeout {
+        /// New timeout, in seconds
+        #[arg(value_parser = clap::value_parser!(u8)
+            .range(MIN_IDLE_TIMEOUT_S as i64..=MAX_IDLE_TIMEOUT_S as i64))]
+        seconds: Option<u8>,
+    },
+    /// Show the keycode of a key, or assign it another one
+    Key {
+        /// Layer of the key
+        layer: u8,
+        /// Row of the key
+        row: u8,
+        /// Column of the key
+        col: u8,
+        /// New keycode: no, trans, key:<usage>, layer:<layer>,
+        /// default-layer:<layer>, or builtin to restore the one of the
+        /// firmware
+        #[arg(value_parser = parse_keycode)]
+        keycode: Option<Option<Keycode>>,
+    },
+    /// Restore the keymap built in the firmware
+    ResetKeymap,
+    /// Show the statistics of the link between the halves
+    LinkStats,
+    /// Show the number of presses of each key
+    KeyStats {
+        /// Forget the key presses counted so far
+        #[arg(long)]
+        reset: bool,
+    },
+    /// Show the typing speed
+    Wpm,
+    /// Show the tracking quality of the trackball sensor
+    SensorQuality,
+    /// Reboot the keyboard into its bootloader
+    Bootloader,
+}
+
+/// Parse an RGB animation
+fn parse_anim(s: &str) -> Result<RgbAnimType, String> {
+    let color = |c: &str| match c.parse::<u8>() {
+        Ok(c) if c < 32 => Ok(c),
+        _ => Err(format!("invalid color index {}: must be below 32", c)),
+    };
+    match s.split_once(':') {
+        None if s == "off" => Ok(RgbAnimType::Off),
+        None if s == "wheel" => Ok(RgbAnimType::Wheel),
+        None if s == "pulse" => Ok(RgbAnimType::Pulse),
+        Some(("solid", c)) => Ok(RgbAnimType::SolidColor(color(c)?)),
+        Some(("pulse", c)) => Ok(RgbAnimType::PulseSolid(color(c)?)),
+        _ => Err(format!("unknown animation {}", s)),
+    }
+}
+
+/// Name of an RGB animation, as parsed by `parse_anim`
+fn anim_name(anim: RgbAnimType) -> String {
+    match anim {
+        RgbAnimType::Off => "off".into(),
+        RgbAnimType::SolidColor(c) => format!("solid:{}", c),
+        RgbAnimType::Wheel => "wheel".into(),
+        RgbAnimType::Pulse => "pulse".into(),
+        RgbAnimType::PulseSolid(c) => format!("pulse:{}", c),
+    }
+}
+
+/// Parse the pointer counts per scroll step
+fn parse_scroll_divisor(s: &str) -> Result<u8, String> {
+    match s.parse::<u8>() {
+        Ok(d)
+            if (MIN_SCROLL_DIVISOR..=MAX_SCROLL_DIVISOR).contains(&d)
+                && d % SCROLL_DIVISOR_STEP == 0 =>
+        {
+            Ok(d)
+        }
+        _ => Err(format!(
+            "invalid scroll divisor {}: must be a multiple of {} from {} to {}",
+            s, SCROLL_DIVISOR_STEP, MIN_SCROLL_DIVISOR, MAX_SCROLL_DIVISOR
+        )),
+    }
+}
+
+/// Parse a hold-tap mode
+fn parse_tap_hold_mode(s: &str) -> Result<TapHoldMode, String> {
+    match s {
+        "tap-preferred" => Ok(TapHoldMode::TapPreferred),
+        "permissive-hold" => Ok(TapHoldMode::PermissiveHold),
+        "hold-on-other-key-press" => Ok(TapHoldMode::HoldOnOtherKeyPress),
+        _ => Err(format!("unknown hold-tap mode {}", s)),
+    }
+}
+
+/// Name of a hold-tap mode, as parsed by `parse_tap_hold_mode`
+fn tap_hold_mode_name(mode: TapHoldMode) -> &'static str {
+    match mode {
+        TapHoldMode::TapPreferred => "tap-preferred",
+        TapHoldMode::PermissiveHold => "permissive-hold",
+        TapHoldMode::HoldOnOtherKeyPress => "hold-on-other-key-press",
+    }
+}
+
+/// Parse a keycode, `None` for the built-in one
+fn parse_keycode(s: &str) -> Result<Option<Keycode>, String> {
+    let number = |n: &str| {
+        let n = match n.strip_prefix("0x") {
+            Some(hex) => u8::from_str_radix(hex, 16),
+            None => n.parse::<u8>(),
+        };
+        n.map_err(|_| format!("invalid keycode {}", s))
+    };
+    let keycode = match s.split_once(':') {
+        None if s == "builtin" => return Ok(None),
+        None if s == "no" => Keycode::No,
+        None if s == "trans" => Keycode::Transparent,
+        Some(("key", usage)) => Keycode::Key(number(usage)?),
+        Some(("layer", layer)) => Keycode::Layer(number(layer)?),
+        Some(("default-layer", layer)) => Keycode::DefaultLayer(number(layer)?),
+        _ => return Err(format!("unknown keycode {}", s)),
+    };
+    // Reject the keycodes the firmware does not support
+    Keycode::from_u16(keycode.to_u16())
+        .filter(|k| *k == keycode)
+        .map(Some)
+        .ok_or_else(|| format!("unsupported keycode {}", s))
+}
+
+/// Name of a keycode, as parsed by `parse_keycode`
+fn keycode_name(keycode: Option<Keycode>) -> String {
+    match keycode {
+        None => "builtin".into(),
+        Some(Keycode::No) => "no".into(),
+        Some(Keycode::Transparent) => "trans".into(),
+        Some(Keycode::Key(usage)) => format!("key:0x{:02x}", usage),
+        Some(Keycode::Layer(layer)) => format!("layer:{}", layer),
+        Some(Keycode::DefaultLayer(layer)) => format!("default-layer:{}", layer),
+    }
+}
+
+/// Read the settings of the active profile
+fn get_settings(dev: &Device) -> Result<Settings, Error> {
+    let data = dev.command(Command::GetSettings, &[])?;
+    let mut bytes = [0u8; SETTINGS_SIZE];
+    bytes.copy_from_slice(&data[..SETTINGS_SIZE]);
+    Settings::from_bytes(&bytes).map_err(|_| Error::Failed(Command::GetSettings))
+}
+
+/// Change the settings of the active profile with `f`
+fn update_settings(dev: &Device, f: impl FnOnce(&mut Settings)) -> Result<(), Error> {
+    let mut settings = get_settings(dev)?;
+    f(&mut settings);
+    let bytes = settings
+        .to_bytes()
+        .map_err(|_| Error::Failed(Command::SetSettings))?;
+    dev.command_status(Command::SetSettings, &bytes)
+}
+
+/// Print the number of presses of each key, row by row
+fn print_key_stats(dev: &Device) -> Result<(), Error> {
+    // Answer: rows, columns, index of the first key, then the counts
+    const HEADER: usize = 3;
+    let mut counts = Vec::new();
+    let cols = loop {
+        let data = dev.command(Command::GetKeyStats, &[counts.len() as u8])?;
+        let (rows, cols) = (data[0] as usize, data[1] as usize);
+        let remaining = (rows * cols).saturating_sub(counts.len());
+        if remaining == 0 {
+            break cols;
+        }
+        counts.extend(
+            data[HEADER..REPORT_SIZE - 1]
+                .chunks_exact(4)
+                .take(remaining)
+                .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]])),
+        );
+    };
+    for row in counts.chunks(cols.max(1)) {
+        let row: Vec<String> = row.iter().map(|c| format!("{:>8}", c)).collect();
+        println!("{}", row.join(""));
+    }
+    Ok(())
+}
+
+/// Run `action` on the keyboard
+fn run(api: &HidApi, device: Option<&str>, action: Action) -> Result<(), Error> {
+    if let Action::List = action {
+        for info in device::list(api) {
+            println!(
+                "{} {:04x}:{:04x} {} {}",
+                info.path().to_string_lossy(),
+                info.vendor_id(),
+                info.product_id(),
+                info.manufacturer_string().unwrap_or(""),
+                info.product_string().unwrap_or(""),
+            );
+        }
+        return Ok(());
+    }
+    let dev = Device::open(api, device)?;
+    match action {
+        Action::List => unreachable!(),
+        Action::Version => {
+            let data = dev.command(Command::GetProtocolVersion, &[])?;
+            let version = u16::from_be_bytes([data[0], data[1]]);
+            println!("Protocol version {} (tool: {})", version, PROTOCOL_VERSION);
+        }
+        Action::Cpi { cpi: None } => println!("{}", get_settings(&dev)?.cpi),
+        Action::Cpi { cpi: Some(cpi) } => update_settings(&dev, |s| s.cpi = cpi)?,
+        Action::Rgb { anim: None } => println!("{}", anim_name(get_settings(&dev)?.rgb_anim)),
+        Action::Rgb { anim: Some(anim) } => update_settings(&dev, |s| s.rgb_anim = anim)?,
+        Action::Brightness { percent: None } => {
+            println!("{}%", get_settings(&dev)?.rgb_brightness)
+        }
+        Action::Brightness {
+            percent: Some(percent),
+        } => update_settings(&dev, |s| s.rgb_brightness = percent)?,
+        Action::Scroll { divisor: None } => {
+            println!("{}", get_settings(&dev)?.pointer.scroll_divisor)
+        }
+        Action::Scroll {
+            divisor: Some(divisor),
+        } => update_settings(&dev, |s| s.pointer.scroll_divisor = divisor)?,
+        Action::TapHold {
+            timeout: None,
+            mode: None,
+        } => {
+            let tap_hold = get_settings(&dev)?.tap_hold;
+            println!(
+                "{}ms {}",
+                tap_hold.timeout_ms,
+                tap_hold_mode_name(tap_hold.mode)
+            );
+        }
+        Action::TapHold { timeout, mode } => update_settings(&dev, |s| {
+            if let Some(timeout) = timeout {
+                s.tap_hold.timeout_ms = timeout;
+            }
+            if let Some(mode) = mode {
+                s.tap_hold.mode = mode;
+            }
+        })?,
+        Action::AutoMouse {
+            enabled: None,
+            timeout: None,
+            click_delay: None,
+        } => {
+            let auto_mouse = get_settings(&dev)?.auto_mouse;
+            println!(
+                "{} timeout: {}ms click delay: {}ms",
+                if auto_mouse.enabled {
+                    "enabled"
+                } else {
+                    "disabled"
+                },
+                auto_mouse.timeout_ms,
+                auto_mouse.click_delay_ms
+            );
+        }
+        Action::AutoMouse {
+            enabled,
+            timeout,
+            click_delay,
+        } => update_settings(&dev, |s| {
+            if let Some(enabled) = enabled {
+                s.auto_mouse.enabled = enabled;
+            }
+            if let Some(timeout) = timeout {
+                s.auto_mouse.timeout_ms = timeout;
+            }
+            if let Some(click_delay) = click_delay {
+                s.auto_mouse.click_delay_ms = click_delay;
+            }
+        })?,
+        Action::IdleTimeout { seconds: None } => {
+            let data = dev.command(Command::GetIdleTimeout, &[])?;
+            println!("{}s", data[0]);
+        }
+        Action::IdleTimeout {
+            seconds: Some(seconds),
+        } => dev.command_status(Command::SetIdleTimeout, &[seconds])?,
+        Action::Key {
+            layer,
+            row,
+            col,
+            keycode: None,
+        } => {
+            let data = dev.command(Command::GetKeycode, &[layer, row, col])?;
+            let code = u16::from_be_bytes([data[3], data[4]]);
+            println!("{}", keycode_name(Keycode::from_u16(code)));
+        }
+        Action::Key {
+            layer,
+            row,
+            col,
+            keycode: Some(keycode),
+        } => {
+            let code = keycode.map_or(NOT_EDITED, Keycode::to_u16).to_be_bytes();
+            dev.command_status(Command::SetKeycode, &[layer, row, col, code[0], code[1]])?;
+        }
+        Action::ResetKeymap => dev.command_status(Command::ResetKeymap, &[])?,
+        Action::LinkStats => {
+            let data = dev.command(Command::GetLinkStats, &[])?;
+            let stats = LinkStats::from_bytes(&data).ok_or(Error::Failed(Command::GetLinkStats))?;
+            println!(
+                "Link:            {}",
+                if stats.link_up { "up" } else { "down" }
+            );
+            println!("Errors:          {}", stats.errors);
+            println!("Events sent:     {}", stats.events_sent);
+            println!("Events received: {}", stats.events_received);
+            println!("Clock drift:     {} ppm", stats.drift_ppm);
+        }
+        Action::KeyStats { reset: false } => print_key_stats(&dev)?,
+        Action::KeyStats { reset: true } => dev.command_status(Command::ResetKeyStats, &[])?,
+        Action::Wpm => {
+            let data = dev.command(Command::GetWpm, &[])?;
+            println!("{} WPM", u16::from_le_bytes([data[0], data[1]]));
+        }
+        Action::SensorQuality => {
+            let data = dev.command(Command::GetSensorQuality, &[])?;
+            let quality =
+                SensorQuality::from_bytes(&data).ok_or(Error::Failed(Command::GetSensorQuality))?;
+            println!("Lowest SQUAL:    {}", quality.squal);
+            println!("Raw data sum:    {}", quality.raw_data_sum);
+            println!("Highest shutter: {}", quality.shutter);
+            println!("Samples:         {}", quality.samples);
+        }
+        Action::Bootloader => {
+            dev.command(Command::BootloaderJump, &[])?;
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let res = HidApi::new()
+        .map_err(Error::from)
+        .and_then(|api| run(&api, cli.device.as_deref(), cli.command));
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anims() {
+        for anim in [
+            RgbAnimType::Off,
+            RgbAnimType::SolidColor(3),
+            RgbAnimType::Wheel,
+            RgbAnimType::Pulse,
+            RgbAnimType::PulseSolid(31),
+        ] {
+            assert_eq!(parse_anim(&anim_name(anim)), Ok(anim));
+        }
+        assert!(parse_anim("solid:32").is_err());
+        assert!(parse_anim("rainbow").is_err());
+    }
+
+    #[test]
+    fn test_scroll_divisor() {
+        assert_eq!(parse_scroll_divisor("4"), Ok(4));
+        assert_eq!(parse_scroll_divisor("60"), Ok(60));
+        assert!(parse_scroll_divisor("0").is_err());
+        assert!(parse_scroll_divisor("10").is_err());
+        assert!(parse_scroll_divisor("64").is_err());
+    }
+
+    #[test]
+    fn test_tap_hold_modes() {
+        for mode in [
+            TapHoldMode::TapPreferred,
+            TapHoldMode::PermissiveHold,
+            TapHoldMode::HoldOnOtherKeyPress,
+        ] {
+            assert_eq!(parse_tap_hold_mode(tap_hold_mode_name(mode)), Ok(mode));
+        }
+        assert!(parse_tap_hold_mode("tap").is_err());
+    }
+
+    #[test]
+    fn test_keycodes() {
+        for keycode in [
+            None,
+            Some(Keycode::No),
+            Some(Keycode::Transparent),
+            Some(Keycode::Key(0x04)),
+            Some(Keycode::Layer(2)),
+            Some(Keycode::DefaultLayer(0)),
+        ] {
+            assert_eq!(parse_keycode(&keycode_name(keycode)), Ok(keycode));
+        }
+        assert_eq!(parse_keycode("key:41"), Ok(Some(Keycode::Key(41))));
+        assert!(parse_keycode("key:0xb0").is_err());
+        assert!(parse_keycode("layer:32").is_err());
+        assert!(parse_keycode("shift").is_err());
+    }
+}
@@ -1,5 +1,462 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+#[cfg(not(any(feature = "rp2040", feature = "rp2350")))]
+compile_error!("Either feature \"rp2040\" or \"rp2350\" must be enabled.");
+#[cfg(all(feature = "rp2040", feature = "rp2350"))]
+compile_error!("Only one of \"rp2040\" or \"rp2350\" can be enabled at a time.");
+
 fn main() {
+    // Memory layout of the chip, found by `link.x` as `memory.x`
+    #[cfg(feature = "rp2040")]
+    let memory: &[u8] = include_bytes!("memory-rp2040.x");
+    #[cfg(feature = "rp2350")]
+    let memory: &[u8] = include_bytes!("memory-rp2350.x");
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(out.join("memory.x"), memory).unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=memory-rp2040.x");
+    println!("cargo:rerun-if-changed=memory-rp2350.x");
+    // The second stage bootloader is only needed by the RP2040
+    #[cfg(feature = "rp2040")]
+    println!("cargo:rustc-link-arg=-Tlink-rp.x");
+
     // Only add the defmt linker script when the defmt feature is enabled
     #[cfg(feature = "defmt")]
     println!("cargo:rustc-link-arg=-Tdefmt.x");
+
+    // Generate the layers of the keymap described in JSON
+    #[cfg(feature = "keymap_json")]
+    keymap_json::generate();
+
+    // The SROM of the PMW3389 is not part of the tree
+    #[cfg(feature = "pmw3389")]
+    pmw3389_srom(&out);
+}
+
+/// Copy the SROM of the PMW3389 from the file at `PMW3389_SROM`, relative to
+/// the crate, to `out` where the driver includes it
+#[cfg(feature = "pmw3389")]
+fn pmw3389_srom(out: &std::path::Path) {
+    println!("cargo:rerun-if-env-changed=PMW3389_SROM");
+    let Ok(srom) = env::var("PMW3389_SROM") else {
+        panic!(
+            "The pmw3389 feature needs the SROM of the PMW3389, which is not part of the tree: \
+             set PMW3389_SROM to its file, relative to the firmware directory"
+        );
+    };
+    let path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join(srom);
+    println!("cargo:rerun-if-changed={}", path.display());
+    let srom =
+        fs::read(&path).unwrap_or_else(|e| panic!("Failed to read {}: {}", path.display(), e));
+    // The second byte is the version of the SROM
+    if srom.len() < 2 {
+        panic!("Invalid SROM {}: too short", path.display());
+    }
+    fs::write(out.join("pmw3389_srom.bin"), srom).unwrap();
+}
+
+/// Generation of the `keymap_json` keymap from a JSON description
+///
+/// The description follows QMK's `keymap.json`: a `layers` array with, for
+/// each layer, the QMK names of its keys, row by row of the matrix and from
+/// the left half to the right one. An optional `layer_colors` array gives the
+/// color index of the RGB LEDs on each layer, and an optional
+/// `auto_mouse_layer` the layer activated by the auto-mouse mode.
+#[cfg(feature = "keymap_json")]
+mod keymap_json {
+    use serde_json::Value;
+    use std::env;
+    use std::fmt::Write;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Description used when `KEYMAP_JSON` is not set, relative to the crate
+    #[cfg(not(any(feature = "dilemma_max", feature = "scylla", feature = "tbkmini")))]
+    const DEFAULT_KEYMAP: &str = "keymaps/default.json";
+    #[cfg(feature = "dilemma_max")]
+    const DEFAULT_KEYMAP: &str = "keymaps/dilemma_max.json";
+    #[cfg(feature = "scylla")]
+    const DEFAULT_KEYMAP: &str = "keymaps/scylla.json";
+    #[cfg(feature = "tbkmini")]
+    const DEFAULT_KEYMAP: &str = "keymaps/tbkmini.json";
+
+    /// Keys of each layer: the 5x12 matrix of the Dilemma Max and the
+    /// Scylla, the 4x12 one of the TBK Mini, the 4x10 one of the other models
+    const KEYS_PER_LAYER: usize = if cfg!(any(feature = "dilemma_max", feature = "scylla")) {
+        60
+    } else if cfg!(feature = "tbkmini") {
+        48
+    } else {
+        40
+    };
+
+    /// Keys of the keyboard usage page: QMK names, without their `KC_`
+    /// prefix, and keyberon ones. The letters, digits, function keys and
+    /// keypad digits are handled apart.
+    const KEYS: &[(&[&str], &str)] = &[
+        (&["ENT", "ENTER"], "Enter"),
+        (&["ESC", "ESCAPE"], "Escape"),
+        (&["BSPC", "BACKSPACE"], "BSpace"),
+        (&["TAB"], "Tab"),
+        (&["SPC", "SPACE"], "Space"),
+        (&["MINS", "MINUS"], "Minus"),
+        (&["EQL", "EQUAL"], "Equal"),
+        (&["LBRC", "LEFT_BRACKET"], "LBracket"),
+        (&["RBRC", "RIGHT_BRACKET"], "RBracket"),
+        (&["BSLS", "BACKSLASH"], "Bslash"),
+        (&["NUHS", "NONUS_HASH"], "NonUsHash"),
+        (&["SCLN", "SEMICOLON"], "SColon"),
+        (&["QUOT", "QUOTE"], "Quote"),
+        (&["GRV", "GRAVE"], "Grave"),
+        (&["COMM", "COMMA"], "Comma"),
+        (&["DOT"], "Dot"),
+        (&["SLSH", "SLASH"], "Slash"),
+        (&["CAPS", "CAPS_LOCK"], "CapsLock"),
+        (&["PSCR", "PRINT_SCREEN"], "PScreen"),
+        (&["SCRL", "SCROLL_LOCK"], "ScrollLock"),
+        (&["PAUS", "PAUSE"], "Pause"),
+        (&["INS", "INSERT"], "Insert"),
+        (&["HOME"], "Home"),
+        (&["PGUP", "PAGE_UP"], "PgUp"),
+        (&["DEL", "DELETE"], "Delete"),
+        (&["END"], "End"),
+        (&["PGDN", "PAGE_DOWN"], "PgDown"),
+        (&["RGHT", "RIGHT"], "Right"),
+        (&["LEFT"], "Left"),
+        (&["DOWN"], "Down"),
+        (&["UP"], "Up"),
+        (&["NUM", "NUM_LOCK"], "NumLock"),
+        (&["PSLS", "KP_SLASH"], "KpSlash"),
+        (&["PAST", "KP_ASTERISK"], "KpAsterisk"),
+        (&["PMNS", "KP_MINUS"], "KpMinus"),
+        (&["PPLS", "KP_PLUS"], "KpPlus"),
+        (&["PENT", "KP_ENTER"], "KpEnter"),
+        (&["PDOT", "KP_DOT"], "KpDot"),
+        (&["PEQL", "KP_EQUAL"], "KpEqual"),
+        (&["NUBS", "NONUS_BACKSLASH"], "NonUsBslash"),
+        (&["APP", "APPLICATION"], "Application"),
+        (&["MENU"], "Menu"),
+        (&["STOP"], "Stop"),
+        (&["MUTE", "AUDIO_MUTE"], "Mute"),
+        (&["VOLU", "AUDIO_VOL_UP"], "VolUp"),
+        (&["VOLD", "AUDIO_VOL_DOWN"], "VolDown"),
+        (&["MPLY", "MEDIA_PLAY_PAUSE"], "MediaPlayPause"),
+        (&["MNXT", "MEDIA_NEXT_TRACK"], "MediaNextSong"),
+        (&["MPRV", "MEDIA_PREV_TRACK"], "MediaPreviousSong"),
+        (&["LCTL", "LEFT_CTRL"], "LCtrl"),
+        (&["LSFT", "LEFT_SHIFT"], "LShift"),
+        (&["LALT", "LEFT_ALT", "LOPT"], "LAlt"),
+        (&["LGUI", "LEFT_GUI", "LCMD"], "LGui"),
+        (&["RCTL", "RIGHT_CTRL"], "RCtrl"),
+        (&["RSFT", "RIGHT_SHIFT"], "RShift"),
+        (&["RALT", "RIGHT_ALT", "ROPT", "ALGR"], "RAlt"),
+        (&["RGUI", "RIGHT_GUI", "RCMD"], "RGui"),
+    ];
+
+    /// Shifted keys: QMK names, without their `KC_` prefix, and keyberon
+    /// names of the keys to shift
+    const SHIFTED_KEYS: &[(&[&str], &str)] = &[
+        (&["EXLM", "EXCLAIM"], "Kb1"),
+        (&["AT"], "Kb2"),
+        (&["HASH"], "Kb3"),
+        (&["DLR", "DOLLAR"], "Kb4"),
+        (&["PERC", "PERCENT"], "Kb5"),
+        (&["CIRC", "CIRCUMFLEX"], "Kb6"),
+        (&["AMPR", "AMPERSAND"], "Kb7"),
+        (&["ASTR", "ASTERISK"], "Kb8"),
+        (&["LPRN", "LEFT_PAREN"], "Kb9"),
+        (&["RPRN", "RIGHT_PAREN"], "Kb0"),
+        (&["UNDS", "UNDERSCORE"], "Minus"),
+        (&["PLUS"], "Equal"),
+        (&["LCBR", "LEFT_CURLY_BRACE"], "LBracket"),
+        (&["RCBR", "RIGHT_CURLY_BRACE"], "RBracket"),
+        (&["PIPE"], "Bslash"),
+        (&["COLN", "COLON"], "SColon"),
+        (&["DQUO", "DQT", "DOUBLE_QUOTE"], "Quote"),
+        (&["TILD", "TILDE"], "Grave"),
+        (&["LABK", "LT", "LEFT_ANGLE_BRACKET"], "Comma"),
+        (&["RABK", "GT", "RIGHT_ANGLE_BRACKET"], "Dot"),
+        (&["QUES", "QUESTION"], "Slash"),
+    ];
+
+    /// Modifier wrappers, such as `LSFT(KC_A)`, and keyberon names of their
+    /// modifiers
+    const MODIFIERS: &[(&[&str], &str)] = &[
+        (&["LCTL", "C"], "LCtrl"),
+        (&["LSFT", "S"], "LShift"),
+        (&["LALT", "A", "LOPT"], "LAlt"),
+        (&["LGUI", "G", "LCMD"], "LGui"),
+        (&["RCTL"], "RCtrl"),
+        (&["RSFT"], "RShift"),
+        (&["RALT", "ROPT", "ALGR"], "RAlt"),
+        (&["RGUI", "RCMD"], "RGui"),
+    ];
+
+    /// Custom events: QMK names, or Bastard Keyboards' ones for the pointer,
+    /// and events
+    const CUSTOM_EVENTS: &[(&[&str], &str)] = &[
+        (&["MS_BTN1", "KC_BTN1", "KC_MS_BTN1"], "MouseLeftClick"),
+        (&["MS_BTN2", "KC_BTN2", "KC_MS_BTN2"], "MouseRightClick"),
+        (&["MS_BTN3", "KC_BTN3", "KC_MS_BTN3"], "MouseWheelClick"),
+        (&["MS_UP", "KC_MS_U", "KC_MS_UP"], "MouseUp"),
+        (&["MS_DOWN", "KC_MS_D", "KC_MS_DOWN"], "MouseDown"),
+        (&["MS_LEFT", "KC_MS_L", "KC_MS_LEFT"], "MouseLeft"),
+        (&["MS_RGHT", "KC_MS_R", "KC_MS_RIGHT"], "MouseRight"),
+        (&["DRGSCRL", "DRAGSCROLL_MODE"], "BallIsWheel"),
+        (&["SNIPING", "SNIPING_MODE"], "SniperMode(4)"),
+        (&["DRG_TOG", "DRAGSCROLL_MODE_TOGGLE"], "ToggleDragScroll"),
+        (&["MS_WHLL", "KC_WH_L", "KC_MS_WH_LEFT"], "PanLeft"),
+        (&["MS_WHLR", "KC_WH_R", "KC_MS_WH_RIGHT"], "PanRight"),
+        (&["RGB_MOD", "RM_NEXT", "UG_NEXT"], "NextLedAnimation"),
+        (&["QK_BOOT", "QK_BOOTLOADER"], "ResetToUsbMassStorage"),
+        (&["EE_CLR", "QK_CLEAR_EEPROM"], "FactoryReset"),
+        (&["KC_PWR", "KC_SYSTEM_POWER"], "SystemPowerDown"),
+        (&["KC_SLEP", "KC_SYSTEM_SLEEP"], "SystemSleep"),
+        (&["KC_WAKE", "KC_SYSTEM_WAKE"], "SystemWakeUp"),
+        (&["QK_LLCK", "QK_LAYER_LOCK"], "LayerLock"),
+        (&["QK_REP", "QK_REPEAT_KEY"], "Repeat"),
+        (&["QK_GESC", "QK_GRAVE_ESCAPE"], "GraveEscape"),
+        (
+            &["EH_LEFT", "MAGIC_EE_HANDS_LEFT"],
+            "SetHandedness(utils::settings::Handedness::Left)",
+        ),
+        (
+            &["EH_RGHT", "MAGIC_EE_HANDS_RIGHT"],
+            "SetHandedness(utils::settings::Handedness::Right)",
+        ),
+    ];
+
+    /// Custom events of the Charybdis Nano only, no action on the Dilemma
+    const CNANO_EVENTS: &[(&[&str], &str)] = &[
+        (
+            &["DPI_MOD", "POINTER_DEFAULT_DPI_FORWARD"],
+            "IncreaseCpi(100)",
+        ),
+        (
+            &["DPI_RMOD", "POINTER_DEFAULT_DPI_REVERSE"],
+            "DecreaseCpi(100)",
+        ),
+    ];
+
+    /// Custom events of the Dilemma only, no action on the Charybdis Nano
+    const DILEMMA_EVENTS: &[(&[&str], &str)] = &[
+        (&["MS_WHLU", "KC_WH_U", "KC_MS_WH_UP"], "WheelUp"),
+        (&["MS_WHLD", "KC_WH_D", "KC_MS_WH_DOWN"], "WheelDown"),
+    ];
+
+    /// Actions defined in `actions.rs`
+    const ACTIONS: &[(&[&str], &str)] = &[
+        (&["SC_LSPO"], "crate::actions::LSPO"),
+        (&["SC_RSPC"], "crate::actions::RSPC"),
+    ];
+
+    /// Generate `keymap_json.rs` in `OUT_DIR` from the description in the
+    /// file at `KEYMAP_JSON`, `DEFAULT_KEYMAP` if not set
+    pub fn generate() {
+        println!("cargo:rerun-if-env-changed=KEYMAP_JSON");
+        let path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap())
+            .join(env::var("KEYMAP_JSON").unwrap_or_else(|_| DEFAULT_KEYMAP.into()));
+        println!("cargo:rerun-if-changed={}", path.display());
+        let json = fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Failed to read {}: {}", path.display(), e));
+        let code = serde_json::from_str(&json)
+            .map_err(|e| e.to_string())
+            .and_then(|keymap| layers(&keymap))
+            .unwrap_or_else(|e| panic!("Invalid keymap {}: {}", path.display(), e));
+        let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("keymap_json.rs");
+        fs::write(&out, code)
+            .unwrap_or_else(|e| panic!("Failed to write {}: {}", out.display(), e));
+    }
+
+    /// Code of `NB_LAYERS`, `LAYER_COLORS`, `AUTO_MOUSE_LAYER` and `KEYS` for
+    /// the description
+    fn layers(keymap: &Value) -> Result<String, String> {
+        let layers = keymap
+            .get("layers")
+            .and_then(Value::as_array)
+            .filter(|l| !l.is_empty())
+            .ok_or("no \"layers\" array")?;
+        let colors = match keymap.get("layer_colors") {
+            None => vec![0; layers.len()],
+            Some(colors) => colors
+                .as_array()
+                .filter(|c| c.len() == layers.len())
+                .ok_or("\"layer_colors\" must have a color index per layer")?
+                .iter()
+                .map(|c| {
+                    c.as_u64()
+                        .and_then(|c| u8::try_from(c).ok())
+                        .ok_or_else(|| format!("invalid layer color {}", c))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        };
+        let auto_mouse = match keymap.get("auto_mouse_layer") {
+            None => "Action::NoOp".into(),
+            Some(layer) => layer
+                .as_u64()
+                .filter(|l| *l < layers.len() as u64)
+                .map(|l| format!("Action::Layer({})", l))
+                .ok_or_else(|| format!("invalid auto-mouse layer {}", layer))?,
+        };
+
+        let mut code = String::new();
+        writeln!(code, "/// Number of layers").unwrap();
+        writeln!(code, "pub const NB_LAYERS: usize = {};", layers.len()).unwrap();
+        writeln!(code).unwrap();
+        writeln!(
+            code,
+            "/// Color index of the RGB LEDs on each layer, 0 to run the RGB animation"
+        )
+        .unwrap();
+        writeln!(
+            code,
+            "pub const LAYER_COLORS: [u8; NB_LAYERS] = {:?};",
+            colors
+        )
+        .unwrap();
+        writeln!(code).unwrap();
+        writeln!(
+            code,
+            "/// Action of the virtual key pressed by the auto-mouse mode"
+        )
+        .unwrap();
+        writeln!(
+            code,
+            "const AUTO_MOUSE_LAYER: Action<CustomEvent> = {};",
+            auto_mouse
+        )
+        .unwrap();
+        writeln!(code).unwrap();
+        writeln!(code, "/// Keys of each layer, row by row").unwrap();
+        writeln!(
+            code,
+            "const KEYS: [[Action<CustomEvent>; ROWS * FULL_COLS]; NB_LAYERS] = ["
+        )
+        .unwrap();
+        for (l, layer) in layers.iter().enumerate() {
+            let keys = layer
+                .as_array()
+                .ok_or_else(|| format!("layer {} is not an array", l))?;
+            if keys.len() != KEYS_PER_LAYER {
+                return Err(format!(
+                    "layer {} has {} keys instead of {}",
+                    l,
+                    keys.len(),
+                    KEYS_PER_LAYER
+                ));
+            }
+            writeln!(code, "    // Layer {}", l).unwrap();
+            writeln!(code, "    [").unwrap();
+            for (k, key) in keys.iter().enumerate() {
+                let action = key
+                    .as_str()
+                    .ok_or_else(|| format!("key {} is not a string", key))
+                    .and_then(|name| action(name, layers.len()))
+                    .map_err(|e| format!("layer {}, key {}: {}", l, k, e))?;
+                writeln!(code, "        {},", action).unwrap();
+            }
+            writeln!(code, "    ],").unwrap();
+        }
+        writeln!(code, "];").unwrap();
+        Ok(code)
+    }
+
+    /// Action of the key named `name`, on a keymap of `layers` layers
+    fn action(name: &str, layers: usize) -> Result<String, String> {
+        let name = name.trim();
+        match name {
+            "KC_NO" | "XXXXXXX" => return Ok("Action::NoOp".into()),
+            "KC_TRNS" | "KC_TRANSPARENT" | "_______" => return Ok("Action::Trans".into()),
+            _ => {}
+        }
+        for (function, variant) in [("MO", "Layer"), ("DF", "DefaultLayer")] {
+            if let Some(arg) = call(name, function) {
+                return match arg.parse::<usize>() {
+                    Ok(layer) if layer < layers => Ok(format!("Action::{}({})", variant, layer)),
+                    _ => Err(format!("invalid layer in {}", name)),
+                };
+            }
+        }
+        if let Some(event) = find(CUSTOM_EVENTS, name) {
+            return Ok(format!("Action::Custom(CustomEvent::{})", event));
+        }
+        if let Some(event) = find(CNANO_EVENTS, name) {
+            return Ok(match cfg!(feature = "cnano") {
+                true => format!("Action::Custom(CustomEvent::{})", event),
+                false => "Action::NoOp".into(),
+            });
+        }
+        if let Some(event) = find(DILEMMA_EVENTS, name) {
+            return Ok(match cfg!(feature = "dilemma") {
+                true => format!("Action::Custom(CustomEvent::{})", event),
+                false => "Action::NoOp".into(),
+            });
+        }
+        if let Some(action) = find(ACTIONS, name) {
+            return Ok(action.into());
+        }
+        let key_codes = key_codes(name)?
+            .iter()
+            .map(|k| format!("KeyCode::{}", k))
+            .collect::<Vec<_>>();
+        Ok(match key_codes.as_slice() {
+            [key_code] => format!("Action::KeyCode({})", key_code),
+            _ => format!(
+                "Action::MultipleKeyCodes(&[{}].as_slice())",
+                key_codes.join(", ")
+            ),
+        })
+    }
+
+    /// Keyberon names of the keys pressed by the key named `name`: a key,
+    /// a shifted key or a key wrapped in modifiers
+    fn key_codes(name: &str) -> Result<Vec<String>, String> {
+        for (wrappers, modifier) in MODIFIERS {
+            if let Some(arg) = wrappers.iter().find_map(|w| call(name, w)) {
+                let mut keys = vec![modifier.to_string()];
+                keys.extend(key_codes(arg)?);
+                return Ok(keys);
+            }
+        }
+        let key = name
+            .strip_prefix("KC_")
+            .ok_or_else(|| format!("unknown key {}", name))?;
+        if let Some(shifted) = find(SHIFTED_KEYS, key) {
+            return Ok(vec!["LShift".into(), shifted.into()]);
+        }
+        let number = |prefix: &str, max: u8| {
+            key.strip_prefix(prefix)
+                .and_then(|n| n.parse::<u8>().ok())
+                .filter(|n| *n <= max)
+        };
+        let key_code = match key.as_bytes() {
+            [b'A'..=b'Z'] => key.to_string(),
+            [b'0'..=b'9'] => format!("Kb{}", key),
+            _ if number("F", 24).is_some_and(|n| n > 0) => key.to_string(),
+            _ if number("P", 9).is_some() && key.len() == 2 => format!("Kp{}", &key[1..]),
+            _ => find(KEYS, key)
+                .ok_or_else(|| format!("unknown key {}", name))?
+                .to_string(),
+        };
+        Ok(vec![key_code])
+    }
+
+    /// Argument of `name` if it is a call of `function`, such as `MO(1)`
+    fn call<'a>(name: &'a str, function: &str) -> Option<&'a str> {
+        name.strip_prefix(function)?
+            .trim_start()
+            .strip_prefix('(')?
+            .strip_suffix(')')
+            .map(str::trim)
+    }
+
+    /// Value of `name` in `table`
+    fn find(table: &[(&[&str], &'static str)], name: &str) -> Option<&'static str> {
+        table
+            .iter()
+            .find(|(names, _)| names.contains(&name))
+            .map(|(_, value)| *value)
+    }
 }
@@ -0,0 +1,365 @@
+//! Hardware-in-loop regression test of the link between the halves.
+//! As for `pio_comms`, the TRRS jack is plugged at the end so that what is
+//! sent is received back.
+//!
+//! Two instances of the real `SideProtocol`, A and B, talk to each other
+//! over the looped back wire: on every slot, the message of A then the one
+//! of B are sent. The wire keeps their order, so the first word received is
+//! for B and the second one for A.  Bits are flipped at random on the wire
+//! to exercise the retransmissions.
+//!
+//! Each side sends a known sequence of events and checks the one it
+//! receives. The counters are reported every `REPORT_PERIOD_S` seconds and
+//! the status LED is switched off on the first failure.
+
+#![no_std]
+#![no_main]
+
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
+use embassy_rp::{
+    bind_interrupts,
+    clocks::clk_sys_freq,
+    gpio::{Level, Output},
+    peripherals::{PIN_1, PIN_29, PIO1},
+    pio::{
+        self, program::pio_file, Direction, FifoJoin, InterruptHandler as PioInterruptHandler, Pio,
+        ShiftDirection, StateMachine,
+    },
+    Peri,
+};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
+use embassy_time::{Duration, Ticker};
+use fixed::{traits::ToFixed, types::U56F8};
+use futures::future;
+#[cfg(not(feature = "defmt"))]
+use panic_halt as _;
+use portable_atomic::{AtomicU32, Ordering};
+use utils::log::{error, info};
+use utils::prng::Pcg32;
+use utils::protocol::{Hardware, SideProtocol};
+use utils::serde::Event;
+#[cfg(feature = "defmt")]
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct PioIrq1 {
+    PIO1_IRQ_0 => PioInterruptHandler<PIO1>;
+});
+const USART_SPEED: u64 = 57600;
+const TX: usize = 0;
+const RX: usize = 1;
+type SmTx<'a> = StateMachine<'a, PIO1, { TX }>;
+type SmRx<'a> = StateMachine<'a, PIO1, { RX }>;
+type PioCommon<'a> = pio::Common<'a, PIO1>;
+type PioPin<'a> = pio::Pin<'a, PIO1>;
+
+/// Duration of a slot, in ms: long enough to send two words at
+/// `USART_SPEED`
+const SLOT_MS: u64 = 2;
+/// Period between two events sent by a side, in ms
+const EVENT_PERIOD_MS: u64 = 5;
+/// One word out of `CORRUPTION_RATE` gets a bit flipped
+const CORRUPTION_RATE: u32 = 50;
+/// Period of the report of the counters, in seconds
+const REPORT_PERIOD_S: u64 = 5;
+/// Number of events in the sequence sent by each side
+const NB_TEST_EVENTS: u32 = 80;
+
+/// Hardware queue size for TX/RX messages
+const HW_QUEUE_SIZE: usize = 16;
+/// Messages sent by A
+static A_TX_QUEUE: Channel<ThreadModeRawMutex, u32, HW_QUEUE_SIZE> = Channel::new();
+/// Messages received by A
+static A_RX_QUEUE: Channel<ThreadModeRawMutex, u32, HW_QUEUE_SIZE> = Channel::new();
+/// Messages sent by B
+static B_TX_QUEUE: Channel<ThreadModeRawMutex, u32, HW_QUEUE_SIZE> = Channel::new();
+/// Messages received by B
+static B_RX_QUEUE: Channel<ThreadModeRawMutex, u32, HW_QUEUE_SIZE> = Channel::new();
+
+/// Number of words whose bits were flipped
+static CORRUPTED: AtomicU32 = AtomicU32::new(0);
+/// Number of messages dropped because a RX queue was full
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Counters of a side
+struct Counters {
+    /// Events sent
+    sent: AtomicU32,
+    /// Events received in the expected order
+    passed: AtomicU32,
+    /// Events received out of order: lost or duplicated events
+    failed: AtomicU32,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            sent: AtomicU32::new(0),
+            passed: AtomicU32::new(0),
+            failed: AtomicU32::new(0),
+        }
+    }
+}
+
+/// Counters of A
+static A_COUNTERS: Counters = Counters::new();
+/// Counters of B
+static B_COUNTERS: Counters = Counters::new();
+
+/// Event number `n` of the sequence: presses and releases of all the keys
+fn test_event(n: u32) -> Event {
+    let key = (n / 2) % (NB_TEST_EVENTS / 2);
+    let (r, c) = ((key / 10) as u8, (key % 10) as u8);
+    if n % 2 == 0 {
+        Event::Press(r, c)
+    } else {
+        Event::Release(r, c)
+    }
+}
+
+/// Position of `event` in the sequence, if part of it
+fn test_event_index(event: Event) -> Option<u32> {
+    let (r, c, release) = match event {
+        Event::Press(r, c) => (r, c, 0),
+        Event::Release(r, c) => (r, c, 1),
+        _ => return None,
+    };
+    Some((r as u32 * 10 + c as u32) * 2 + release)
+}
+
+/// Hardware of one side, whose messages go through the shared wire
+struct LoopbackHw {
+    /// Queue of the messages to send
+    tx: &'static Channel<ThreadModeRawMutex, u32, HW_QUEUE_SIZE>,
+    /// Queue of the messages received
+    rx: &'static Channel<ThreadModeRawMutex, u32, HW_QUEUE_SIZE>,
+}
+
+impl Hardware for LoopbackHw {
+    async fn queue_send(&mut self, msg: u32) {
+        self.tx.send(msg).await;
+    }
+
+    async fn receive(&mut self) -> u32 {
+        self.rx.receive().await
+    }
+
+    async fn set_error_state(&mut self, _error: bool) {}
+}
+
+/// Flip a random bit of `msg`, once in a while
+fn maybe_corrupt(rng: &mut Pcg32, msg: u32) -> u32 {
+    if rng.random() % CORRUPTION_RATE == 0 {
+        CORRUPTED.fetch_add(1, Ordering::Relaxed);
+        msg ^ (1 << (rng.random() % 32))
+    } else {
+        msg
+    }
+}
+
+/// Queue a received message for a side, filtering out the keepalives
+fn deliver(rx: &Channel<ThreadModeRawMutex, u32, HW_QUEUE_SIZE>, msg: u32) {
+    if msg != 0 && rx.try_send(msg).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Send the messages of A and B on every slot, and hand over what is
+/// received back to the other side
+async fn wire_loop(mut tx_sm: SmTx<'_>, mut rx_sm: SmRx<'_>) {
+    let mut rng = Pcg32::new(0x1234_5678);
+    let mut ticker = Ticker::every(Duration::from_millis(SLOT_MS));
+    loop {
+        ticker.next().await;
+        let from_a = A_TX_QUEUE.try_receive().unwrap_or_default();
+        let from_b = B_TX_QUEUE.try_receive().unwrap_or_default();
+        tx_sm.tx().wait_push(maybe_corrupt(&mut rng, from_a)).await;
+        tx_sm.tx().wait_push(maybe_corrupt(&mut rng, from_b)).await;
+        let to_b = rx_sm.rx().wait_pull().await;
+        let to_a = rx_sm.rx().wait_pull().await;
+        deliver(&B_RX_QUEUE, to_b);
+        deliver(&A_RX_QUEUE, to_a);
+    }
+}
+
+/// Send the sequence of events to the other side and check the one received
+async fn side_loop(mut protocol: SideProtocol<LoopbackHw>, counters: &Counters) {
+    let mut ticker = Ticker::every(Duration::from_millis(EVENT_PERIOD_MS));
+    let mut next_tx = 0;
+    let mut next_rx = 0;
+    loop {
+        match select(ticker.next(), protocol.receive()).await {
+            Either::First(_) => {
+                protocol.queue_event(test_event(next_tx)).await;
+                next_tx = (next_tx + 1) % NB_TEST_EVENTS;
+                counters.sent.fetch_add(1, Ordering::Relaxed);
+            }
+            Either::Second(event) => match test_event_index(event) {
+                Some(n) if n == next_rx => {
+                    counters.passed.fetch_add(1, Ordering::Relaxed);
+                    next_rx = (n + 1) % NB_TEST_EVENTS;
+                }
+                Some(n) => {
+                    error!("Expected event #{}, got #{}", next_rx, n);
+                    counters.failed.fetch_add(1, Ordering::Relaxed);
+                    next_rx = (n + 1) % NB_TEST_EVENTS;
+                }
+                None => {
+                    error!("Unexpected event: {:?}", event);
+                    counters.failed.fetch_add(1, Ordering::Relaxed);
+                }
+            },
+        }
+    }
+}
+
+/// Report the counters periodically
+async fn report_loop(status_led: &mut Output<'_>) {
+    let mut ticker = Ticker::every(Duration::from_secs(REPORT_PERIOD_S));
+    let mut last_passed = 0;
+    loop {
+        ticker.next().await;
+        let mut failed = 0;
+        let mut passed = 0;
+        for (name, counters) in [("A", &A_COUNTERS), ("B", &B_COUNTERS)] {
+            let sent = counters.sent.load(Ordering::Relaxed);
+            let side_passed = counters.passed.load(Ordering::Relaxed);
+            let side_failed = counters.failed.load(Ordering::Relaxed);
+            info!(
+                "[{}] sent: {} passed: {} failed: {}",
+                name, sent, side_passed, side_failed
+            );
+            passed += side_passed;
+            failed += side_failed;
+        }
+        info!(
+            "corrupted: {} dropped: {}",
+            CORRUPTED.load(Ordering::Relaxed),
+            DROPPED.load(Ordering::Relaxed)
+        );
+        if passed == last_passed {
+            error!("FAIL: the link is stalled");
+            failed += 1;
+        }
+        last_passed = passed;
+        if failed == 0 {
+            info!("PASS");
+            status_led.toggle();
+        } else {
+            error!("FAIL: {} failures", failed);
+            status_led.set_low();
+        }
+    }
+}
+
+fn pio_freq() -> fixed::FixedU32<fixed::types::extra::U8> {
+    (U56F8::from_num(clk_sys_freq()) / (8 * USART_SPEED)).to_fixed()
+}
+
+fn task_tx<'a>(
+    common: &mut PioCommon<'a>,
+    mut sm_tx: SmTx<'a>,
+    tx_pin: &mut PioPin<'a>,
+) -> SmTx<'a> {
+    let tx_prog = pio_file!("src/tx.pio");
+    sm_tx.set_pins(Level::High, &[tx_pin]);
+    sm_tx.set_pin_dirs(Direction::Out, &[tx_pin]);
+
+    let mut cfg = embassy_rp::pio::Config::default();
+    cfg.set_out_pins(&[tx_pin]);
+    cfg.set_set_pins(&[tx_pin]);
+    cfg.use_program(&common.load_program(&tx_prog.program), &[]);
+    cfg.shift_out.auto_fill = false;
+    cfg.shift_out.direction = ShiftDirection::Right;
+    cfg.shift_out.threshold = 32;
+    cfg.fifo_join = FifoJoin::TxOnly;
+    cfg.clock_divider = pio_freq();
+    sm_tx.set_config(&cfg);
+    sm_tx.set_enable(true);
+
+    sm_tx
+}
+
+fn task_rx<'a>(
+    common: &mut PioCommon<'a>,
+    mut sm_rx: SmRx<'a>,
+    rx_pin: &mut PioPin<'a>,
+) -> SmRx<'a> {
+    let rx_prog = pio_file!("src/rx.pio");
+    sm_rx.set_pins(Level::High, &[rx_pin]);
+    sm_rx.set_pin_dirs(Direction::In, &[rx_pin]);
+
+    let mut cfg = embassy_rp::pio::Config::default();
+    cfg.set_in_pins(&[rx_pin]);
+    cfg.set_jmp_pin(rx_pin);
+    cfg.use_program(&common.load_program(&rx_prog.program), &[]);
+    cfg.shift_in.auto_fill = false;
+    cfg.shift_in.direction = ShiftDirection::Right;
+    cfg.shift_in.threshold = 32;
+    cfg.fifo_join = FifoJoin::RxOnly;
+    cfg.clock_divider = pio_freq();
+    sm_rx.set_config(&cfg);
+    sm_rx.set_enable(true);
+
+    sm_rx
+}
+
+async fn loopback_test<'a>(
+    mut pio_common: PioCommon<'a>,
+    sm0: SmTx<'a>,
+    sm1: SmRx<'a>,
+    gpio_pin_1: Peri<'static, PIN_1>,
+    gpio_pin_29: Peri<'static, PIN_29>,
+    status_led: &mut Output<'a>,
+) {
+    let mut pin_tx = pio_common.make_pio_pin(gpio_pin_29);
+    let mut pin_rx = pio_common.make_pio_pin(gpio_pin_1);
+
+    let tx_sm = task_tx(&mut pio_common, sm0, &mut pin_tx);
+    let rx_sm = task_rx(&mut pio_common, sm1, &mut pin_rx);
+
+    let side_a = SideProtocol::new(
+        LoopbackHw {
+            tx: &A_TX_QUEUE,
+            rx: &A_RX_QUEUE,
+        },
+        #[cfg(feature = "defmt")]
+        "A",
+    );
+    let side_b = SideProtocol::new(
+        LoopbackHw {
+            tx: &B_TX_QUEUE,
+            rx: &B_RX_QUEUE,
+        },
+        #[cfg(feature = "defmt")]
+        "B",
+    );
+
+    future::join4(
+        wire_loop(tx_sm, rx_sm),
+        side_loop(side_a, &A_COUNTERS),
+        side_loop(side_b, &B_COUNTERS),
+        report_loop(status_led),
+    )
+    .await;
+}
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("=== Protocol Loopback Test Starting ===");
+
+    let p = embassy_rp::init(Default::default());
+    let pio1 = Pio::new(p.PIO1, PioIrq1);
+    let mut status_led = Output::new(p.PIN_24, Level::Low);
+    status_led.set_high();
+
+    loopback_test(
+        pio1.common,
+        pio1.sm0,
+        pio1.sm1,
+        p.PIN_1,
+        p.PIN_29,
+        &mut status_led,
+    )
+    .await;
+}
@@ -1,6 +1,8 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    /* The last 16K are reserved for the settings, the 4K before for the
+     * key statistics and the 4K before for the keymap edits */
+    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 24K
 
     /* Pick one of the two options for RAM layout     */
 
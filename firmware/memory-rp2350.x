@@ -0,0 +1,44 @@
+MEMORY {
+    /* The last 16K are reserved for the settings, the 4K before for the
+     * key statistics and the 4K before for the keymap edits */
+    FLASH : ORIGIN = 0x10000000, LENGTH = 4096K - 24K
+
+    /* Striped SRAM0 to SRAM7, then the two banks meant for the stacks */
+    RAM   : ORIGIN = 0x20000000, LENGTH = 512K
+    SRAM8 : ORIGIN = 0x20080000, LENGTH = 4K
+    SRAM9 : ORIGIN = 0x20081000, LENGTH = 4K
+}
+
+/* The boot ROM looks for the image definition in the first 4K of the flash */
+SECTIONS {
+    .start_block : ALIGN(4)
+    {
+        __start_block_addr = .;
+        KEEP(*(.start_block));
+        KEEP(*(.boot_info));
+    } > FLASH
+} INSERT AFTER .vector_table;
+
+/* Move .text to start after the boot info */
+_stext = ADDR(.start_block) + SIZEOF(.start_block);
+
+SECTIONS {
+    .bi_entries : ALIGN(4)
+    {
+        __bi_entries_start = .;
+        KEEP(*(.bi_entries));
+        . = ALIGN(4);
+        __bi_entries_end = .;
+    } > FLASH
+} INSERT AFTER .text;
+
+SECTIONS {
+    .end_block : ALIGN(4)
+    {
+        __end_block_addr = .;
+        KEEP(*(.end_block));
+    } > FLASH
+} INSERT AFTER .uninit;
+
+PROVIDE(start_to_end = __end_block_addr - __start_block_addr);
+PROVIDE(end_to_start = __start_block_addr - __end_block_addr);
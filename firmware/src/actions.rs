@@ -0,0 +1,52 @@
+use crate::core::CustomEvent;
+use crate::tap_hold;
+use keyberon::action::{k, m, Action, HoldTapAction, HoldTapConfig};
+use keyberon::key_code::KeyCode::{self, *};
+
+/// Space Cadet shift: `$shift` when held, `$shift` with `$key` when tapped
+macro_rules! space_cadet {
+    ($shift:expr, $key:expr) => {
+        Action::HoldTap(&HoldTapAction {
+            timeout: tap_hold::TIMEOUT,
+            tap_hold_interval: 0,
+            config: HoldTapConfig::Custom(tap_hold::resolve),
+            hold: k($shift),
+            tap: m(&[$shift, $key].as_slice()),
+        })
+    };
+}
+
+/// Grave Escape: Escape, or Grave when pressed with Shift or GUI
+pub const GESC: Action<CustomEvent> = Action::Custom(CustomEvent::GraveEscape);
+/// Left Space Cadet shift: Left Shift when held, `(` when tapped
+pub const LSPO: Action<CustomEvent> = space_cadet!(LShift, Kb9);
+/// Right Space Cadet shift: Right Shift when held, `)` when tapped
+pub const RSPC: Action<CustomEvent> = space_cadet!(RShift, Kb0);
+
+/// Key pressed by the Grave Escape key, if held
+#[derive(Debug, Default)]
+pub struct GraveEscape(Option<KeyCode>);
+
+impl GraveEscape {
+    /// Grave Escape key, released
+    pub const fn new() -> Self {
+        Self(None)
+    }
+
+    /// Press or release the Grave Escape key, given the keys pressed
+    /// through the layout. The key it presses is decided on the press.
+    pub fn on_event(&mut self, is_pressed: bool, mut pressed: impl Iterator<Item = KeyCode>) {
+        self.0 = is_pressed.then(|| {
+            if pressed.any(|kc| matches!(kc, LShift | RShift | LGui | RGui)) {
+                Grave
+            } else {
+                Escape
+            }
+        });
+    }
+
+    /// Key pressed, if any
+    pub fn key(&self) -> Option<KeyCode> {
+        self.0
+    }
+}
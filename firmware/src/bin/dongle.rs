@@ -0,0 +1,703 @@
+//! USB dongle bridging both halves of the keyboard to the computer.
+//!
+//! The dongle is an RP2040 board plugged to the computer. It runs the keymap
+//! and presents the keyboard, mouse, consumer and system control HID
+//! interfaces, while each half, powered through its link, runs the regular
+//! firmware without USB and sends its key events over the split protocol.
+//!
+//! The left half being the slave of the link, the dongle is the master of
+//! the link to the left half, on GP0. The right half being the master, the
+//! dongle is the slave of the link to the right half, on GP1.
+//!
+//! The pointer moves are not carried by the split protocol yet: only the
+//! mouse clicks of the keymap are reported.
+
+#![no_std]
+#![no_main]
+
+use cortex_m::singleton;
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
+use embassy_rp::{
+    bind_interrupts, clocks,
+    gpio::{Level, Pull},
+    peripherals::{PIO0, PIO1, USB},
+    pio::{
+        self, program::pio_asm, Common, Direction, Instance,
+        InterruptHandler as PioInterruptHandler, Pio, ShiftDirection, StateMachine,
+    },
+    usb::{Driver, InterruptHandler as USBInterruptHandler},
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Ticker};
+use embassy_usb::class::hid::{
+    Config as HidConfig, HidBootProtocol, HidSubclass, HidWriter, State,
+};
+use embassy_usb::{Builder, Config as USBConfig};
+use fixed::{traits::ToFixed, types::U56F8};
+use keyberon::key_code::KeyCode;
+use keyberon::layout::{CustomEvent as KbCustomEvent, Event as KBEvent, Layout};
+#[cfg(not(feature = "defmt"))]
+use panic_halt as _;
+use utils::hid::{
+    ConsumerReport, KeyOverride, KeyboardReport, MouseReport, SystemReport, Usage,
+    CONSUMER_REPORT_DESCRIPTOR, KB_REPORT_DESCRIPTOR, MOUSE_REPORT_DESCRIPTOR,
+    SYSTEM_REPORT_DESCRIPTOR,
+};
+use utils::log::{error, info, warn};
+use utils::pipeline::{CustomEvent, Io, KeyEvent, Keymap, Pipeline};
+use utils::protocol::{Hardware, SideProtocol};
+use utils::serde::Event;
+use utils::settings::{Settings, TapHold};
+#[cfg(feature = "defmt")]
+use {defmt_rtt as _, panic_probe as _};
+
+/// Custom events, as expected by the keymaps
+mod core {
+    pub use utils::pipeline::CustomEvent;
+}
+
+/// Pin assignments and matrices of the supported keyboards, only the
+/// matrices being used
+#[allow(dead_code, unused_imports, unused_macros)]
+#[path = "../board/mod.rs"]
+mod board;
+
+/// Matrix dimensions, as expected by the keymaps
+mod keys {
+    pub use crate::board::ROWS;
+    /// Full number of columns of both halves
+    pub const FULL_COLS: usize = 2 * crate::board::COLS;
+}
+
+/// Reusable keymap actions, not all of them used by every keymap
+#[allow(dead_code)]
+#[path = "../actions.rs"]
+mod actions;
+use actions::GraveEscape;
+
+/// Parts specific to the RP2040 or to the RP2350
+#[path = "../chip.rs"]
+mod chip;
+
+/// Hold-tap keys resolved following the settings
+#[path = "../tap_hold.rs"]
+mod tap_hold;
+
+// The keymaps also define items only used by the halves, such as the
+// pointer speeds of the layers
+
+/// Basic layout for the keyboard
+#[cfg(feature = "keymap_basic")]
+#[allow(dead_code)]
+#[path = "../keymap_basic.rs"]
+mod keymap;
+
+/// Keymap by Boris Faure
+#[cfg(feature = "keymap_borisfaure")]
+#[allow(dead_code)]
+#[path = "../keymap_borisfaure.rs"]
+mod keymap;
+
+/// Test layout for the keyboard
+#[cfg(feature = "keymap_test")]
+#[allow(dead_code)]
+#[path = "../keymap_test.rs"]
+mod keymap;
+
+/// Keymap generated from a JSON description
+#[cfg(feature = "keymap_json")]
+#[allow(dead_code)]
+#[path = "../keymap_json.rs"]
+mod keymap;
+
+#[cfg(all(feature = "dilemma_max", not(feature = "keymap_json")))]
+compile_error!(
+    "The keymaps of the Dilemma Max are only described in JSON: enable \"keymap_json\"."
+);
+#[cfg(all(
+    any(feature = "scylla", feature = "tbkmini"),
+    not(feature = "keymap_json")
+))]
+compile_error!(
+    "The keymaps of the 6-column Scylla and TBK Mini are only described in JSON: enable \"keymap_json\"."
+);
+
+use keymap::{
+    KBLayout, AUTO_MOUSE_EXCLUDED_KEYS, KEY_OVERRIDES, LAYERS, UNLOCK_CHORD, VIRTUAL_MOUSE_KEY,
+};
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => USBInterruptHandler<USB>;
+});
+bind_interrupts!(struct PioIrq0 {
+    PIO0_IRQ_0 => PioInterruptHandler<PIO0>;
+});
+bind_interrupts!(struct PioIrq1 {
+    PIO1_IRQ_0 => PioInterruptHandler<PIO1>;
+});
+
+/// USB VID based on
+/// <https://github.com/obdev/v-usb/blob/master/usbdrv/USB-IDs-for-free.txt>
+const VID: u16 = 0x16c0;
+/// USB PID
+const PID: u16 = 0x27db;
+/// USB Product
+const PRODUCT: &str = "Bastard Keyboards dongle";
+/// USB Manufacturer
+const MANUFACTURER: &str = "Bastard Keyboards & Boris Faure";
+
+/// Speed of the PIO state machines, in bps, as on the halves
+const SPEED: u64 = 460_800;
+/// Number of events waiting to be sent to a half
+const LINK_DEPTH: usize = 8;
+/// Number of messages waiting to be sent or processed on a link
+const HW_QUEUE_DEPTH: usize = 16;
+/// Number of key events waiting to be processed by the keymap
+const LAYOUT_DEPTH: usize = 16;
+
+/// Wired link with a half of the keyboard
+struct Link {
+    /// Name of the half, for the logs
+    #[cfg(feature = "defmt")]
+    name: &'static str,
+    /// Events to send to the half
+    events: Channel<CriticalSectionRawMutex, Event, LINK_DEPTH>,
+    /// Messages queued by the protocol, to be sent by the hardware task
+    hw_tx: Channel<CriticalSectionRawMutex, u32, HW_QUEUE_DEPTH>,
+    /// Messages received by the hardware task, to be processed by the
+    /// protocol
+    hw_rx: Channel<CriticalSectionRawMutex, u32, HW_QUEUE_DEPTH>,
+}
+
+impl Link {
+    /// Create a new link
+    const fn new(#[cfg(feature = "defmt")] name: &'static str) -> Self {
+        Self {
+            #[cfg(feature = "defmt")]
+            name,
+            events: Channel::new(),
+            hw_tx: Channel::new(),
+            hw_rx: Channel::new(),
+        }
+    }
+
+    /// Send `event` to the half
+    async fn send(&self, event: Event) {
+        if self.events.is_full() {
+            error!("Link channel is full");
+        }
+        self.events.send(event).await;
+    }
+}
+
+/// Link with the left half
+static LEFT: Link = Link::new(
+    #[cfg(feature = "defmt")]
+    "Left",
+);
+/// Link with the right half
+static RIGHT: Link = Link::new(
+    #[cfg(feature = "defmt")]
+    "Right",
+);
+
+/// Channel to send the key events of both halves to the keymap
+static LAYOUT_CHANNEL: Channel<CriticalSectionRawMutex, KeyEvent, LAYOUT_DEPTH> = Channel::new();
+
+/// Protocol layer Hardware implementation - interfaces with the queues of a
+/// link
+struct LinkHardware {
+    /// Link with the half
+    link: &'static Link,
+    /// Whether the link is on error
+    on_error: bool,
+}
+
+impl Hardware for LinkHardware {
+    async fn queue_send(&mut self, msg: u32) {
+        if self.link.hw_tx.is_full() {
+            error!("HW TX queue is full");
+        }
+        self.link.hw_tx.send(msg).await;
+    }
+
+    async fn receive(&mut self) -> u32 {
+        self.link.hw_rx.receive().await
+    }
+
+    async fn set_error_state(&mut self, error: bool) {
+        if error != self.on_error {
+            self.on_error = error;
+            if error {
+                warn!("Link with the {} half on error", self.link.name);
+            } else {
+                info!("Link with the {} half restored", self.link.name);
+            }
+        }
+    }
+}
+
+/// Exchange a message every ms with a half, as its hardware task does
+async fn exchange<P: Instance, const SM: usize>(mut sm: StateMachine<'static, P, SM>, link: &Link) {
+    let mut ticker = Ticker::every(Duration::from_millis(1));
+    loop {
+        ticker.next().await;
+        // ALWAYS send something to maintain 1ms timing
+        let msg_to_send = link.hw_tx.try_receive().unwrap_or_default();
+        sm.tx().wait_push(msg_to_send).await;
+
+        if sm.rx().level() > 0 {
+            let received_msg = sm.rx().wait_pull().await;
+            // Filter out keepalive messages (0x00000000)
+            if received_msg != 0x00000000 {
+                let _ = link.hw_rx.try_send(received_msg);
+            }
+        }
+    }
+}
+
+/// Hardware task of the link with the left half
+#[embassy_executor::task]
+async fn left_hardware_task(sm: StateMachine<'static, PIO0, 0>) {
+    exchange(sm, &LEFT).await;
+}
+
+/// Hardware task of the link with the right half
+#[embassy_executor::task]
+async fn right_hardware_task(sm: StateMachine<'static, PIO1, 0>) {
+    exchange(sm, &RIGHT).await;
+}
+
+/// Protocol task of a link: sends the events queued for the half and
+/// forwards the key events of the half to the keymap
+#[embassy_executor::task(pool_size = 2)]
+async fn link_task(link: &'static Link) {
+    let mut protocol = SideProtocol::new(
+        LinkHardware {
+            link,
+            on_error: false,
+        },
+        #[cfg(feature = "defmt")]
+        link.name,
+    );
+    loop {
+        match select(link.events.receive(), protocol.receive()).await {
+            Either::First(event) => protocol.queue_event(event).await,
+            Either::Second(event) => {
+                let event = match event {
+                    Event::Press(i, j) => KeyEvent::Press(i, j),
+                    Event::Release(i, j) => KeyEvent::Release(i, j),
+                    _ => continue,
+                };
+                if LAYOUT_CHANNEL.is_full() {
+                    error!("Layout channel is full");
+                }
+                LAYOUT_CHANNEL.send(event).await;
+            }
+        }
+    }
+}
+
+/// Clock divider of the PIO state machines
+fn pio_freq() -> fixed::FixedU32<fixed::types::extra::U8> {
+    (clocks::clk_sys_freq() as u64 / (8 * SPEED))
+        .to_fixed::<U56F8>()
+        .to_fixed()
+}
+
+/// Configure `sm` to run `program` on `pin`
+fn configure<P: Instance, const SM: usize>(
+    common: &mut Common<'static, P>,
+    sm: &mut StateMachine<'static, P, SM>,
+    pin: &pio::Pin<'static, P>,
+    program: &pio::program::Program<32>,
+) {
+    let mut cfg = pio::Config::default();
+    cfg.use_program(&common.load_program(program), &[]);
+    cfg.set_set_pins(&[pin]);
+    cfg.set_out_pins(&[pin]);
+    cfg.set_in_pins(&[pin]);
+    cfg.clock_divider = pio_freq();
+    cfg.shift_out.auto_fill = false;
+    cfg.shift_out.direction = ShiftDirection::Right;
+    cfg.shift_out.threshold = 32;
+    cfg.shift_in.auto_fill = false;
+    cfg.shift_in.direction = ShiftDirection::Right;
+    cfg.shift_in.threshold = 32;
+    // Don't join FIFOs - need both TX and RX
+    sm.set_config(&cfg);
+    sm.set_enable(true);
+}
+
+/// Master: Transmit first, then receive, as the right half does
+fn setup_master_compound<P: Instance, const SM: usize>(
+    common: &mut Common<'static, P>,
+    sm: &mut StateMachine<'static, P, SM>,
+    pin: &mut pio::Pin<'static, P>,
+) {
+    sm.set_pins(Level::High, &[pin]);
+    sm.set_pin_dirs(Direction::Out, &[pin]);
+    pin.set_slew_rate(embassy_rp::gpio::SlewRate::Fast);
+    pin.set_schmitt(true);
+
+    let prog = pio_asm!(
+        ".wrap_target",
+        // === TX Phase ===
+        "pull block",      // Get data to transmit
+        "set pindirs, 1",  // Pin as output
+        "set pins, 1 [3]", // Idle high
+        "set x, 31",       // Counter for 32 bits
+        "set pins, 0 [3]", // Start bit low
+        "tx_loop:",
+        "out pins, 1",          // Output data bit
+        "jmp x--, tx_loop [2]", // Loop (4 cycles per bit)
+        "set pins, 1 [7]",      // Return to idle, delay for slave
+        // === Switch to RX ===
+        "set pindirs, 0", // Pin as input
+        // === RX Phase ===
+        "wait 0 pin, 0", // Wait for slave's start bit
+        "nop [2]",       // Align to middle of first bit
+        "set x, 31",     // Counter for 32 bits
+        "rx_loop:",
+        "in pins, 1",           // Sample bit
+        "jmp x--, rx_loop [2]", // Loop (4 cycles per bit)
+        "push block",           // Push received data
+        "wait 1 pin, 0",        // Wait for idle
+        ".wrap"
+    );
+    configure(common, sm, pin, &prog.program);
+}
+
+/// Slave: Receive first, then transmit, as the left half does
+fn setup_slave_compound<P: Instance, const SM: usize>(
+    common: &mut Common<'static, P>,
+    sm: &mut StateMachine<'static, P, SM>,
+    pin: &pio::Pin<'static, P>,
+) {
+    let prog = pio_asm!(
+        ".wrap_target",
+        // === RX Phase (slave receives first) ===
+        "set pindirs, 0", // Pin as input
+        "wait 0 pin, 0",  // Wait for master's start bit
+        "nop [2]",        // Align to middle of first bit
+        "set x, 31",      // Counter for 32 bits
+        "rx_loop:",
+        "in pins, 1",           // Sample bit
+        "jmp x--, rx_loop [2]", // Loop (4 cycles per bit)
+        "push block",           // Push received data
+        "wait 1 pin, 0",        // Wait for idle
+        // === Switch to TX ===
+        "pull block",     // Get response data (wait for CPU to provide it)
+        "set pindirs, 1", // Pin as output
+        // === TX Phase ===
+        "set pins, 1 [3]", // Idle high
+        "set x, 31",       // Counter for 32 bits
+        "set pins, 0 [3]", // Start bit low
+        "tx_loop:",
+        "out pins, 1",          // Output data bit
+        "jmp x--, tx_loop [2]", // Loop (4 cycles per bit)
+        "set pins, 1 [7]",      // Return to idle with delay
+        ".wrap"
+    );
+    configure(common, sm, pin, &prog.program);
+}
+
+/// Keyberon layout, as the keymap of the pipeline, with the Grave Escape
+/// key
+struct Keyberon(KBLayout, GraveEscape);
+
+impl Keymap for Keyberon {
+    fn event(&mut self, event: KeyEvent) {
+        self.0.event(match event {
+            KeyEvent::Press(i, j) => KBEvent::Press(i, j),
+            KeyEvent::Release(i, j) => KBEvent::Release(i, j),
+        });
+    }
+
+    fn tick(&mut self) -> Option<(CustomEvent, bool)> {
+        let event = match self.0.tick() {
+            KbCustomEvent::Press(event) => Some((*event, true)),
+            KbCustomEvent::Release(event) => Some((*event, false)),
+            KbCustomEvent::NoEvent => None,
+        };
+        tap_hold::tick();
+        if let Some((CustomEvent::GraveEscape, is_pressed)) = event {
+            self.1.on_event(is_pressed, self.0.keycodes());
+        }
+        event
+    }
+
+    fn current_layer(&self) -> usize {
+        self.0.current_layer()
+    }
+
+    fn set_default_layer(&mut self, layer: usize) {
+        self.0.set_default_layer(layer);
+    }
+
+    fn set_tap_hold(&mut self, config: TapHold) {
+        tap_hold::set_config(config);
+    }
+
+    fn reload(&mut self) {
+        // The keymap of the dongle cannot be edited
+    }
+
+    fn usages(&self) -> impl Iterator<Item = Usage> + '_ {
+        self.0.keycodes().chain(self.1.key()).filter_map(usage)
+    }
+
+    fn key_overrides(&self) -> &'static [KeyOverride] {
+        &KEY_OVERRIDES
+    }
+}
+
+/// What a keyberon keycode reports to the host
+fn usage(kc: KeyCode) -> Option<Usage> {
+    use keyberon::key_code::KeyCode::*;
+    match kc {
+        No => None,
+        ErrorRollOver | PostFail | ErrorUndefined => Some(Usage::Error(kc as u8)),
+        kc if kc.is_modifier() => Some(Usage::Modifier(kc.as_modifier_bit())),
+        // Consumer control keys
+        MediaNextSong => Some(Usage::Consumer(0x00B5)),
+        MediaPreviousSong => Some(Usage::Consumer(0x00B6)),
+        MediaPlayPause => Some(Usage::Consumer(0x00CD)),
+        Mute => Some(Usage::Consumer(0x00E2)),
+        VolUp => Some(Usage::Consumer(0x00E9)),
+        VolDown => Some(Usage::Consumer(0x00EA)),
+        // Regular keyboard keys
+        _ => Some(Usage::Key(kc as u8)),
+    }
+}
+
+/// HID writers of the dongle
+struct DongleIo {
+    /// HID keyboard writer
+    hid_kb: HidWriter<'static, Driver<'static, USB>, 8>,
+    /// HID mouse writer
+    hid_mouse: HidWriter<'static, Driver<'static, USB>, 7>,
+    /// HID consumer control writer
+    hid_consumer: HidWriter<'static, Driver<'static, USB>, 2>,
+    /// HID system control writer
+    hid_system: HidWriter<'static, Driver<'static, USB>, 1>,
+    /// Mouse buttons pressed through the keymap
+    buttons: u8,
+    /// Whether the mouse buttons changed since the last mouse report
+    buttons_changed: bool,
+}
+
+impl DongleIo {
+    /// Press or release the mouse button `button`
+    fn on_click(&mut self, button: u8, is_pressed: bool) {
+        if is_pressed {
+            self.buttons |= button;
+        } else {
+            self.buttons &= !button;
+        }
+        self.buttons_changed = true;
+    }
+}
+
+impl Io for DongleIo {
+    fn key_event(&mut self) -> Option<KeyEvent> {
+        LAYOUT_CHANNEL.try_receive().ok()
+    }
+
+    async fn mouse_report(&mut self) -> Option<(MouseReport, bool)> {
+        if !self.buttons_changed {
+            return None;
+        }
+        self.buttons_changed = false;
+        let report = MouseReport {
+            buttons: self.buttons,
+            ..Default::default()
+        };
+        Some((report, false))
+    }
+
+    async fn send_mouse_report(&mut self, report: MouseReport) {
+        if let Err(_e) = self.hid_mouse.write(&report.serialize()).await {
+            warn!("Failed to send mouse report: {:?}", _e);
+        }
+    }
+
+    async fn send_keyboard_report(&mut self, report: KeyboardReport) {
+        if let Err(_e) = self.hid_kb.write(&report.serialize()).await {
+            warn!("Failed to send report: {:?}", _e);
+        }
+    }
+
+    async fn send_consumer_report(&mut self, report: ConsumerReport) {
+        if let Err(_e) = self.hid_consumer.write(&report.serialize()).await {
+            warn!("Failed to send consumer report: {:?}", _e);
+        }
+    }
+
+    async fn send_system_report(&mut self, report: SystemReport) {
+        if let Err(_e) = self.hid_system.write(&report.serialize()).await {
+            warn!("Failed to send system report: {:?}", _e);
+        }
+    }
+
+    async fn set_color_layer(&mut self, layer: u8) {
+        LEFT.send(Event::RgbAnimChangeLayer(layer)).await;
+        RIGHT.send(Event::RgbAnimChangeLayer(layer)).await;
+    }
+
+    async fn custom_event(&mut self, event: CustomEvent, is_pressed: bool) {
+        match (event, is_pressed) {
+            (CustomEvent::MouseLeftClick, _) => self.on_click(1 << 0, is_pressed),
+            (CustomEvent::MouseRightClick, _) => self.on_click(1 << 1, is_pressed),
+            (CustomEvent::MouseWheelClick, _) => self.on_click(1 << 2, is_pressed),
+            (CustomEvent::ResetToUsbMassStorage, true) => {
+                chip::reset_to_usb_boot();
+            }
+            _ => (),
+        }
+    }
+
+    fn notify_activity(&mut self) {}
+
+    fn notify_auto_mouse(&mut self) {}
+
+    fn notify_input_lock(&mut self, _locked: bool) {}
+
+    // The dongle has no persistent settings yet
+    fn factory_reset(&mut self) {}
+}
+
+/// USB task
+#[embassy_executor::task]
+async fn usb_task(builder: Builder<'static, Driver<'static, USB>>) {
+    builder.build().run().await;
+}
+
+/// Create a HID writer with the report descriptor `report_descriptor`
+fn hid_writer<const N: usize>(
+    builder: &mut Builder<'static, Driver<'static, USB>>,
+    report_descriptor: &'static [u8],
+    poll_ms: u8,
+    hid_subclass: HidSubclass,
+    hid_boot_protocol: HidBootProtocol,
+    state: &'static mut State<'static>,
+) -> HidWriter<'static, Driver<'static, USB>, N> {
+    let config = HidConfig {
+        report_descriptor,
+        request_handler: None,
+        poll_ms,
+        max_packet_size: N as u16,
+        hid_subclass,
+        hid_boot_protocol,
+    };
+    HidWriter::new(builder, state, config)
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let p = embassy_rp::init(Default::default());
+    info!("Hello from the dongle!");
+
+    let driver = Driver::new(p.USB, Irqs);
+    let mut usb_config = USBConfig::new(VID, PID);
+    usb_config.manufacturer = Some(MANUFACTURER);
+    usb_config.product = Some(PRODUCT);
+    usb_config.serial_number = Some(env!("CARGO_PKG_VERSION"));
+    usb_config.max_power = 500;
+    usb_config.max_packet_size_0 = 64;
+
+    let config_descriptor = singleton!(: [u8; 256] = [0; 256]).unwrap();
+    let bos_descriptor = singleton!(: [u8; 256] =[0; 256]).unwrap();
+    let msos_descriptor = singleton!(: [u8; 256] = [0; 256]).unwrap();
+    let control_buf = singleton!(: [u8; 256] = [0; 256]).unwrap();
+    let mut builder = Builder::new(
+        driver,
+        usb_config,
+        config_descriptor,
+        bos_descriptor,
+        msos_descriptor,
+        control_buf,
+    );
+
+    let hid_kb = hid_writer(
+        &mut builder,
+        KB_REPORT_DESCRIPTOR,
+        utils::hid::KB_POLL_MS,
+        HidSubclass::Boot,
+        HidBootProtocol::Keyboard,
+        singleton!(: State = State::new()).unwrap(),
+    );
+    let hid_mouse = hid_writer(
+        &mut builder,
+        MOUSE_REPORT_DESCRIPTOR,
+        10,
+        HidSubclass::Boot,
+        HidBootProtocol::Mouse,
+        singleton!(: State = State::new()).unwrap(),
+    );
+    let hid_consumer = hid_writer(
+        &mut builder,
+        CONSUMER_REPORT_DESCRIPTOR,
+        60,
+        HidSubclass::No,
+        HidBootProtocol::None,
+        singleton!(: State = State::new()).unwrap(),
+    );
+    let hid_system = hid_writer(
+        &mut builder,
+        SYSTEM_REPORT_DESCRIPTOR,
+        60,
+        HidSubclass::No,
+        HidBootProtocol::None,
+        singleton!(: State = State::new()).unwrap(),
+    );
+    spawner.spawn(usb_task(builder).unwrap());
+
+    let Pio {
+        common: mut left_common,
+        sm0: mut left_sm,
+        ..
+    } = Pio::new(p.PIO0, PioIrq0);
+    let mut left_pin = left_common.make_pio_pin(p.PIN_0);
+    left_pin.set_pull(Pull::Up);
+    setup_master_compound(&mut left_common, &mut left_sm, &mut left_pin);
+    spawner.spawn(left_hardware_task(left_sm).unwrap());
+
+    let Pio {
+        common: mut right_common,
+        sm0: mut right_sm,
+        ..
+    } = Pio::new(p.PIO1, PioIrq1);
+    let mut right_pin = right_common.make_pio_pin(p.PIN_1);
+    right_pin.set_pull(Pull::Up);
+    setup_slave_compound(&mut right_common, &mut right_sm, &right_pin);
+    spawner.spawn(right_hardware_task(right_sm).unwrap());
+
+    spawner.spawn(link_task(&LEFT).unwrap());
+    spawner.spawn(link_task(&RIGHT).unwrap());
+
+    let io = DongleIo {
+        hid_kb,
+        hid_mouse,
+        hid_consumer,
+        hid_system,
+        buttons: 0,
+        buttons_changed: false,
+    };
+    let mut pipeline = Pipeline::new(
+        Keyberon(Layout::new(&LAYERS), GraveEscape::new()),
+        io,
+        VIRTUAL_MOUSE_KEY,
+        &UNLOCK_CHORD,
+        &AUTO_MOUSE_EXCLUDED_KEYS,
+        &Settings::default(),
+    );
+
+    info!("let's go!");
+    let mut ticker = Ticker::every(Duration::from_millis(1));
+    loop {
+        ticker.next().await;
+        pipeline.tick().await;
+    }
+}
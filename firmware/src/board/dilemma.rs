@@ -0,0 +1,78 @@
+use super::SensorPins;
+use embassy_rp::gpio::{AnyPin, Level};
+use embassy_rp::peripherals::{PIN_1, PIN_10, PIN_29};
+use embassy_rp::Peri;
+
+/// Keyboard matrix rows, the last one holding the thumb keys
+pub const ROWS: usize = 4;
+/// Keyboard matrix columns, on each half
+pub const COLS: usize = 5;
+/// Columns in the layout of the thumb keys of each half, left then right,
+/// by column in the matrix of the half
+pub const THUMBS: [[Option<u8>; COLS]; 2] = [
+    [Some(3), Some(4), Some(2), None, None],
+    [Some(6), Some(5), Some(7), None, None],
+];
+/// Keys pressed by turning the encoder of each half, left then right, when
+/// its pins are equal then when they differ
+pub const ENCODER_KEYS: [Option<[(u8, u8); 2]>; 2] = [None, Some([(3, 8), (3, 9)])];
+
+/// Pin of the wire between the halves
+pub type SideLinkPin = PIN_1;
+
+/// Pins of the Dilemma
+pub struct BoardConfig {
+    /// Pulled to the ground on the left half, to VCC on the right one
+    pub side_detect: Peri<'static, PIN_29>,
+    /// Matrix rows, read with a pull-up
+    pub rows: [Peri<'static, AnyPin>; ROWS],
+    /// Matrix columns, driven low one at a time
+    pub cols: [Peri<'static, AnyPin>; COLS],
+    /// Status LED
+    pub status_led: Peri<'static, AnyPin>,
+    /// Level turning the status LED off: it is active high
+    pub status_led_off: Level,
+    /// Link to the other half
+    pub side_link: Peri<'static, SideLinkPin>,
+    /// Data line of the RGB LEDs
+    pub rgb_leds: Peri<'static, PIN_10>,
+    /// Rotary encoder, A and B
+    pub encoder: Option<[Peri<'static, AnyPin>; 2]>,
+    /// SPI bus of the pointing device, a trackpad on the stock board, on the
+    /// right half
+    pub sensor: SensorPins,
+}
+
+/// Take the pins of the Dilemma out of the peripherals
+macro_rules! board_config {
+    ($p:ident) => {
+        $crate::board::BoardConfig {
+            side_detect: $p.PIN_29,
+            rows: [
+                $p.PIN_4.into(),  // R2
+                $p.PIN_5.into(),  // R3
+                $p.PIN_27.into(), // R4
+                $p.PIN_26.into(), // R5
+            ],
+            cols: [
+                $p.PIN_8.into(),  // C2
+                $p.PIN_9.into(),  // C3
+                $p.PIN_7.into(),  // C4
+                $p.PIN_6.into(),  // C5
+                $p.PIN_28.into(), // C6
+            ],
+            status_led: $p.PIN_17.into(),
+            status_led_off: embassy_rp::gpio::Level::Low,
+            side_link: $p.PIN_1,
+            rgb_leds: $p.PIN_10,
+            encoder: Some([$p.PIN_24.into(), $p.PIN_25.into()]),
+            sensor: $crate::board::SensorPins {
+                sclk: $p.PIN_22,      // B1
+                mosi: $p.PIN_23,      // B2
+                miso: $p.PIN_20,      // B3
+                cs: $p.PIN_21.into(), // B4
+            },
+        }
+    };
+}
+pub(crate) use board_config;
@@ -0,0 +1,47 @@
+// One file per model, the one matching the enabled feature is re-exported
+
+#[cfg(feature = "pointing_device")]
+use embassy_rp::{
+    gpio::AnyPin,
+    peripherals::{PIN_20, PIN_22, PIN_23},
+    Peri,
+};
+
+/// SPI pins of the pointing device, only the CS pin depends on the board
+#[cfg(feature = "pointing_device")]
+pub struct SensorPins {
+    pub sclk: Peri<'static, PIN_22>,
+    pub mosi: Peri<'static, PIN_23>,
+    pub miso: Peri<'static, PIN_20>,
+    pub cs: Peri<'static, AnyPin>,
+}
+
+#[cfg(feature = "cnano")]
+mod cnano;
+#[cfg(feature = "cnano")]
+pub(crate) use cnano::*;
+
+#[cfg(all(feature = "dilemma", not(feature = "dilemma_max")))]
+mod dilemma;
+#[cfg(all(feature = "dilemma", not(feature = "dilemma_max")))]
+pub(crate) use dilemma::*;
+
+#[cfg(feature = "dilemma_max")]
+mod dilemma_max;
+#[cfg(feature = "dilemma_max")]
+pub(crate) use dilemma_max::*;
+
+#[cfg(feature = "scylla")]
+mod scylla;
+#[cfg(feature = "scylla")]
+pub(crate) use scylla::*;
+
+#[cfg(feature = "skeletyl")]
+mod skeletyl;
+#[cfg(feature = "skeletyl")]
+pub(crate) use skeletyl::*;
+
+#[cfg(feature = "tbkmini")]
+mod tbkmini;
+#[cfg(feature = "tbkmini")]
+pub(crate) use tbkmini::*;
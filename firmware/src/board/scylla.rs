@@ -0,0 +1,72 @@
+use embassy_rp::gpio::{AnyPin, Level};
+use embassy_rp::peripherals::{PIN_0, PIN_1, PIN_15};
+use embassy_rp::Peri;
+
+/// Keyboard matrix rows, the last one holding the thumb keys
+pub const ROWS: usize = 5;
+/// Keyboard matrix columns, on each half
+pub const COLS: usize = 6;
+/// Columns in the layout of the thumb keys of each half, left then right,
+/// by column in the matrix of the half
+pub const THUMBS: [[Option<u8>; COLS]; 2] = [
+    [None, Some(1), Some(2), Some(3), Some(4), Some(5)],
+    [None, Some(10), Some(9), Some(8), Some(7), Some(6)],
+];
+
+/// Pin of the wire between the halves
+pub type SideLinkPin = PIN_1;
+
+/// Pins of the Scylla, on the Elite-C holder with the resistor detecting
+/// the side of the Skeletyl. Its top row uses R1, on GP29: the halves are
+/// linked by the serial line of the jack, on GP1 (D2), instead of the wire
+/// added to GP29 on the other holders. The outer column, C1, is taken as
+/// GP27 (F6): check both against the revision of the holder before
+/// flashing.
+pub struct BoardConfig {
+    /// Pulled to the ground on the left half, to VCC on the right one
+    pub side_detect: Peri<'static, PIN_15>,
+    /// Matrix rows, read with a pull-up
+    pub rows: [Peri<'static, AnyPin>; ROWS],
+    /// Matrix columns, driven low one at a time
+    pub cols: [Peri<'static, AnyPin>; COLS],
+    /// Status LED
+    pub status_led: Peri<'static, AnyPin>,
+    /// Level turning the status LED off: it is active low
+    pub status_led_off: Level,
+    /// Link to the other half
+    pub side_link: Peri<'static, SideLinkPin>,
+    /// Data line of the RGB LEDs
+    pub rgb_leds: Peri<'static, PIN_0>,
+    /// Rotary encoder: none
+    pub encoder: Option<[Peri<'static, AnyPin>; 2]>,
+}
+
+/// Take the pins of the Scylla out of the peripherals
+macro_rules! board_config {
+    ($p:ident) => {
+        $crate::board::BoardConfig {
+            side_detect: $p.PIN_15,
+            rows: [
+                $p.PIN_29.into(), // R1
+                $p.PIN_26.into(), // R2
+                $p.PIN_5.into(),  // R3
+                $p.PIN_4.into(),  // R4
+                $p.PIN_9.into(),  // R5
+            ],
+            cols: [
+                $p.PIN_27.into(), // C1
+                $p.PIN_28.into(), // C2
+                $p.PIN_21.into(), // C3
+                $p.PIN_6.into(),  // C4
+                $p.PIN_7.into(),  // C5
+                $p.PIN_8.into(),  // C6
+            ],
+            status_led: $p.PIN_24.into(),
+            status_led_off: embassy_rp::gpio::Level::High,
+            side_link: $p.PIN_1,
+            rgb_leds: $p.PIN_0,
+            encoder: None,
+        }
+    };
+}
+pub(crate) use board_config;
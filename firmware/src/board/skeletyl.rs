@@ -0,0 +1,65 @@
+use embassy_rp::gpio::{AnyPin, Level};
+use embassy_rp::peripherals::{PIN_0, PIN_15, PIN_29};
+use embassy_rp::Peri;
+
+/// Keyboard matrix rows, the last one holding the thumb keys
+pub const ROWS: usize = 4;
+/// Keyboard matrix columns, on each half
+pub const COLS: usize = 5;
+/// Columns in the layout of the thumb keys of each half, left then right,
+/// by column in the matrix of the half
+pub const THUMBS: [[Option<u8>; COLS]; 2] = [
+    [Some(4), None, Some(2), Some(3), None],
+    [Some(5), None, Some(6), Some(7), None],
+];
+
+/// Pin of the wire between the halves
+pub type SideLinkPin = PIN_29;
+
+/// Pins of the Skeletyl, on the same Elite-C holder as the Charybdis Nano
+pub struct BoardConfig {
+    /// Pulled to the ground on the left half, to VCC on the right one
+    pub side_detect: Peri<'static, PIN_15>,
+    /// Matrix rows, read with a pull-up
+    pub rows: [Peri<'static, AnyPin>; ROWS],
+    /// Matrix columns, driven low one at a time
+    pub cols: [Peri<'static, AnyPin>; COLS],
+    /// Status LED
+    pub status_led: Peri<'static, AnyPin>,
+    /// Level turning the status LED off: it is active low
+    pub status_led_off: Level,
+    /// Link to the other half
+    pub side_link: Peri<'static, SideLinkPin>,
+    /// Data line of the RGB LEDs
+    pub rgb_leds: Peri<'static, PIN_0>,
+    /// Rotary encoder: none
+    pub encoder: Option<[Peri<'static, AnyPin>; 2]>,
+}
+
+/// Take the pins of the Skeletyl out of the peripherals
+macro_rules! board_config {
+    ($p:ident) => {
+        $crate::board::BoardConfig {
+            side_detect: $p.PIN_15,
+            rows: [
+                $p.PIN_26.into(), // R2
+                $p.PIN_5.into(),  // R3
+                $p.PIN_4.into(),  // R4
+                $p.PIN_9.into(),  // R5
+            ],
+            cols: [
+                $p.PIN_28.into(), // C2
+                $p.PIN_21.into(), // C3
+                $p.PIN_6.into(),  // C4
+                $p.PIN_7.into(),  // C5
+                $p.PIN_8.into(),  // C6
+            ],
+            status_led: $p.PIN_24.into(),
+            status_led_off: embassy_rp::gpio::Level::High,
+            side_link: $p.PIN_29,
+            rgb_leds: $p.PIN_0,
+            encoder: None,
+        }
+    };
+}
+pub(crate) use board_config;
@@ -0,0 +1,81 @@
+use crate::channels::{self, Queue, BUZZER_DEPTH};
+use crate::settings;
+use crate::sysclk;
+use embassy_rp::{
+    peripherals::{PIN_18, PWM_SLICE1},
+    pwm::{Config, Pwm},
+    Peri,
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::Timer;
+use fixed::traits::ToFixed;
+use utils::settings::MAX_BUZZER_VOLUME;
+
+/// Integer divider of the PWM clock. With the system clock down to 62.5MHz
+/// when idle, it keeps the counter top in range for tones from 200Hz.
+const PWM_DIVIDER: u32 = 64;
+
+/// Sounds played by the buzzer
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Sound {
+    /// Layer change
+    Layer,
+    /// Lock indicator turned on
+    LockOn,
+    /// Lock indicator turned off
+    LockOff,
+    /// Link with the other half on error
+    LinkError,
+}
+
+impl Sound {
+    /// Tones of the sound: frequency in Hz and duration in ms
+    fn tones(self) -> &'static [(u32, u64)] {
+        match self {
+            Sound::Layer => &[(2000, 30)],
+            Sound::LockOn => &[(1500, 40), (2500, 40)],
+            Sound::LockOff => &[(2500, 40), (1500, 40)],
+            Sound::LinkError => &[(400, 200)],
+        }
+    }
+}
+
+/// Channel to request sounds
+static BUZZER_CHANNEL: Channel<CriticalSectionRawMutex, Sound, BUZZER_DEPTH> = Channel::new();
+
+/// Request `sound` to be played. Dropped when the buzzer cannot keep up.
+pub fn play(sound: Sound) {
+    let _ = BUZZER_CHANNEL.try_send(sound);
+}
+
+/// PWM configuration of a tone of `freq` Hz at `volume` percent, the loudest
+/// being a square wave
+fn tone_config(freq: u32, volume: u8) -> Config {
+    let top = (sysclk::sys_freq() / (PWM_DIVIDER * freq)).clamp(2, u16::MAX as u32 + 1) - 1;
+    let mut config = Config::default();
+    config.divider = PWM_DIVIDER.to_fixed();
+    config.top = top as u16;
+    config.compare_a = ((top + 1) * volume.min(MAX_BUZZER_VOLUME) as u32 / 200) as u16;
+    config
+}
+
+/// Buzzer task, playing the requested sounds one after the other
+#[embassy_executor::task]
+pub async fn run(slice: Peri<'static, PWM_SLICE1>, pin: Peri<'static, PIN_18>) {
+    let silence = Config::default();
+    let mut pwm = Pwm::new_output_a(slice, pin, silence.clone());
+    loop {
+        let sound = BUZZER_CHANNEL.receive().await;
+        channels::record(Queue::Buzzer, BUZZER_CHANNEL.len());
+        let buzzer = settings::get().buzzer;
+        if !buzzer.enabled {
+            continue;
+        }
+        for &(freq, duration_ms) in sound.tones() {
+            pwm.set_config(&tone_config(freq, buzzer.volume));
+            Timer::after_millis(duration_ms).await;
+        }
+        pwm.set_config(&silence);
+    }
+}
@@ -0,0 +1,162 @@
+#[cfg(feature = "defmt")]
+use {
+    embassy_time::{Duration, Ticker},
+    portable_atomic::{AtomicUsize, Ordering},
+    utils::log::{info, warn},
+};
+
+// Depths of the channels between the tasks.
+//
+// Unless stated otherwise, a sender logs an error when the channel is full
+// and then waits for some room: no event is lost, but the sending task
+// stalls, and so may the tasks feeding it. The depths are chosen so that
+// this only happens when the receiving task is stuck, which the watchdog
+// then catches. Build with the `defmt` feature to get the high-water marks
+// of the channels reported, to validate them.
+
+/// Depth of `core::LAYOUT_CHANNEL`: key events from the matrix scanners of
+/// both halves, the encoder and the lock LEDs. The core drains the whole
+/// channel on every tick, so it only needs to hold the events of a few
+/// scans of all the keys.
+pub const LAYOUT_DEPTH: usize = 32;
+/// Depth of `rgb_leds::ANIM_CHANNEL`: animation commands, mostly layer
+/// changes. One command is handled per animation frame.
+pub const ANIM_DEPTH: usize = 16;
+/// Depth of `side::SIDE_CHANNEL`: events to send to the other half. The
+/// settings mirroring paces its events to avoid filling it.
+pub const SIDE_DEPTH: usize = 16;
+/// Depth of the side link hardware TX queue: messages waiting for their
+/// 1ms slot on the link, including the acknowledgements.
+pub const SIDE_HW_TX_DEPTH: usize = 64;
+/// Depth of the side link hardware RX queue: messages received from the
+/// other half. Messages received while it is full are dropped, and
+/// retransmitted by the protocol.
+pub const SIDE_HW_RX_DEPTH: usize = 32;
+/// Depth of `hid::HID_KB_CHANNEL`, `hid::HID_CONSUMER_CHANNEL` and
+/// `hid::HID_SYSTEM_CHANNEL`: reports waiting for the host to poll the
+/// endpoint. At most one report is queued per core tick, and only when it
+/// changed. The keyboard reports are not
+/// waited for: once the channel is full, they are coalesced into the latest
+/// one.
+pub const HID_REPORTS_DEPTH: usize = 32;
+/// Depth of `mouse::MOUSE_MOVE_CHANNEL`: pointer moves, produced at most
+/// once per ms and drained on every core tick.
+pub const MOUSE_MOVE_DEPTH: usize = 32;
+/// Depth of `mouse::MOUSE_BUTTON_CHANNEL`: button changes from the trackpad
+/// taps, at most two per touch, one of them being drained per core tick.
+pub const MOUSE_BUTTON_DEPTH: usize = 8;
+/// Depth of `haptic::HAPTIC_CHANNEL`: haptic feedback requests. They are
+/// dropped when it is full, as a late feedback is useless, and it stays
+/// full when there is no haptic driver.
+pub const HAPTIC_DEPTH: usize = 4;
+/// Depth of `buzzer::BUZZER_CHANNEL`: sounds to play, built with the
+/// `buzzer` feature. They are dropped when it is full.
+pub const BUZZER_DEPTH: usize = 4;
+/// Depth of `steno::STENO_CHANNEL`: chords to send to the host, built with
+/// the `steno` feature. A chord takes at least the time to press and
+/// release its keys. They are dropped when it is full.
+#[cfg(feature = "steno")]
+pub const STENO_DEPTH: usize = 8;
+/// Depth of `trackball::SENSOR_CMD_CHANNEL`: CPI changes, triggered by
+/// keys.
+#[cfg(feature = "pointing_device")]
+pub const SENSOR_CMD_DEPTH: usize = 4;
+
+/// Channels whose high-water mark is tracked
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Queue {
+    /// `core::LAYOUT_CHANNEL`
+    Layout = 0,
+    /// `rgb_leds::ANIM_CHANNEL`
+    Anim = 1,
+    /// `side::SIDE_CHANNEL`
+    Side = 2,
+    /// Side link hardware TX queue
+    SideHwTx = 3,
+    /// Side link hardware RX queue
+    SideHwRx = 4,
+    /// `hid::HID_KB_CHANNEL`
+    HidKb = 5,
+    /// `hid::HID_CONSUMER_CHANNEL`
+    HidConsumer = 6,
+    /// `mouse::MOUSE_MOVE_CHANNEL`
+    MouseMove = 7,
+    /// `haptic::HAPTIC_CHANNEL`
+    Haptic = 8,
+    /// `buzzer::BUZZER_CHANNEL`
+    Buzzer = 9,
+    /// `hid::HID_SYSTEM_CHANNEL`
+    HidSystem = 10,
+    /// `mouse::MOUSE_BUTTON_CHANNEL`
+    MouseButton = 11,
+    /// `trackball::SENSOR_CMD_CHANNEL`
+    #[cfg(feature = "pointing_device")]
+    SensorCmd = 12,
+}
+
+/// Number of tracked channels
+#[cfg(feature = "defmt")]
+const NB_QUEUES: usize = 12 + cfg!(feature = "pointing_device") as usize;
+
+/// All the tracked channels, in the order of their index, with their depth
+#[cfg(feature = "defmt")]
+const QUEUES: [(Queue, usize); NB_QUEUES] = [
+    (Queue::Layout, LAYOUT_DEPTH),
+    (Queue::Anim, ANIM_DEPTH),
+    (Queue::Side, SIDE_DEPTH),
+    (Queue::SideHwTx, SIDE_HW_TX_DEPTH),
+    (Queue::SideHwRx, SIDE_HW_RX_DEPTH),
+    (Queue::HidKb, HID_REPORTS_DEPTH),
+    (Queue::HidConsumer, HID_REPORTS_DEPTH),
+    (Queue::MouseMove, MOUSE_MOVE_DEPTH),
+    (Queue::Haptic, HAPTIC_DEPTH),
+    (Queue::Buzzer, BUZZER_DEPTH),
+    (Queue::HidSystem, HID_REPORTS_DEPTH),
+    (Queue::MouseButton, MOUSE_BUTTON_DEPTH),
+    #[cfg(feature = "pointing_device")]
+    (Queue::SensorCmd, SENSOR_CMD_DEPTH),
+];
+
+/// Period of the high-water marks report, in seconds
+#[cfg(feature = "defmt")]
+const REPORT_PERIOD_S: u64 = 10;
+
+/// Highest number of messages seen in each channel
+#[cfg(feature = "defmt")]
+static HIGH_WATER_MARKS: [AtomicUsize; NB_QUEUES] = [const { AtomicUsize::new(0) }; NB_QUEUES];
+
+/// Record the depth of `queue` when a message is received from it, given
+/// the number of messages left in it.
+/// Its depth is the highest right before a message is received, so this is
+/// enough to track its high-water mark. Does nothing without `defmt`.
+pub fn record(queue: Queue, len: usize) {
+    #[cfg(feature = "defmt")]
+    HIGH_WATER_MARKS[queue as usize].fetch_max(len + 1, Ordering::Relaxed);
+    #[cfg(not(feature = "defmt"))]
+    let _ = (queue, len);
+}
+
+/// Log the high-water marks of the channels
+#[cfg(feature = "defmt")]
+fn dump() {
+    for ((queue, depth), hwm) in QUEUES.iter().zip(HIGH_WATER_MARKS.iter()) {
+        let hwm = hwm.load(Ordering::Relaxed);
+        if hwm >= *depth {
+            warn!("[CHANNELS] {:?}: full, depth {}", queue, depth);
+        } else {
+            info!("[CHANNELS] {:?}: {}/{}", queue, hwm, depth);
+        }
+    }
+}
+
+/// Periodically report the high-water marks of the channels
+#[cfg(feature = "defmt")]
+#[embassy_executor::task]
+pub async fn report() {
+    let mut ticker = Ticker::every(Duration::from_secs(REPORT_PERIOD_S));
+    loop {
+        ticker.next().await;
+        dump();
+    }
+}
@@ -0,0 +1,24 @@
+#[cfg(feature = "rp2350")]
+use embassy_rp::block::ImageDef;
+
+/// Image definition, looked for by the boot ROM of the RP2350 to start the
+/// firmware
+#[cfg(feature = "rp2350")]
+#[link_section = ".start_block"]
+#[used]
+pub static IMAGE_DEF: ImageDef = ImageDef::secure_exe();
+
+/// Reboot type of the RP2350 boot ROM entering the USB bootloader
+#[cfg(feature = "rp2350")]
+const REBOOT_TYPE_BOOTSEL: u32 = 0x0002;
+/// Time before the RP2350 reboots, in ms
+#[cfg(feature = "rp2350")]
+const REBOOT_DELAY_MS: u32 = 10;
+
+/// Reboot into the USB mass storage bootloader of the boot ROM
+pub fn reset_to_usb_boot() {
+    #[cfg(feature = "rp2040")]
+    embassy_rp::rom_data::reset_to_usb_boot(0, 0);
+    #[cfg(feature = "rp2350")]
+    embassy_rp::rom_data::reboot(REBOOT_TYPE_BOOTSEL, REBOOT_DELAY_MS, 0, 0);
+}
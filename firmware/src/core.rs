@@ -1,367 +1,774 @@
-use crate::hid::{ConsumerReport, KeyboardReport, HID_CONSUMER_CHANNEL, HID_KB_CHANNEL};
-use crate::mouse::MouseHandler;
+use crate::actions::GraveEscape;
+#[cfg(feature = "buzzer")]
+use crate::buzzer::{self, Sound};
+use crate::channels::{self, Queue, LAYOUT_DEPTH};
+use crate::chip;
+use crate::display;
+use crate::haptic::{self, HapticEvent};
+use crate::hid::{self, HID_CONSUMER_CHANNEL, HID_SYSTEM_CHANNEL};
+use crate::key_stats;
+use crate::keys::{FULL_COLS, ROWS};
+#[cfg(feature = "timing_logs")]
+use crate::metrics::{self, Metric};
+use crate::mouse::{MouseHandler, MouseMove, MOUSE_BUTTON_CHANNEL, MOUSE_MOVE_CHANNEL};
 use crate::rgb_leds::{AnimCommand, ANIM_CHANNEL};
+use crate::settings::{self, SettingsReceiver, SETTINGS_WATCH};
 use crate::side::SIDE_CHANNEL;
+#[cfg(feature = "steno")]
+use crate::steno::{self, Steno, STENO_LAYOUT};
+use crate::storage::{EditableLayout, KEYMAP_SIGNAL};
+use crate::sysclk;
+use crate::tap_hold;
+#[cfg(feature = "tracing")]
+use crate::trace::{self, Kind};
 #[cfg(feature = "cnano")]
 use crate::trackball::{SensorCommand, SENSOR_CMD_CHANNEL};
-#[cfg(feature = "defmt")]
-use defmt::Debug2Format;
-use embassy_futures::select::{select, Either};
+use crate::watchdog::{self, Task, HEARTBEAT_PERIOD_MS};
+use embassy_futures::select::{select, select4, Either, Either4};
 use embassy_rp::peripherals::USB;
 use embassy_rp::usb::Driver;
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
-use embassy_time::{Duration, Ticker};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, watch::Watch};
+use embassy_time::{Duration, Instant, Ticker, Timer};
 use embassy_usb::class::hid::HidWriter;
 use keyberon::key_code::KeyCode;
-use keyberon::layout::{CustomEvent as KbCustomEvent, Event as KBEvent, Layout};
+use keyberon::layout::{CustomEvent as KbCustomEvent, Event as KBEvent};
+use portable_atomic::{AtomicU8, Ordering};
+use utils::combos::Combos;
+use utils::dynamic_macro::{DynamicMacros, PLAYBACK_PERIOD_MS};
+use utils::hid::{ConsumerReport, KeyOverride, KeyboardReport, MouseReport, SystemReport, Usage};
 use utils::log::{error, info};
+use utils::mouse_keys::{Direction, MouseKeys};
+use utils::pipeline::{Io, KeyEvent, Keymap, Pipeline};
 use utils::serde::Event;
+#[cfg(feature = "cnano")]
+use utils::settings::MAX_LIFT_CONFIG;
+use utils::settings::{TapHold, NB_PROFILES};
+#[cfg(feature = "steno")]
+use utils::steno::Outcome;
+use utils::swap_hands::SwapHands;
+use utils::tap_dance::TapDances;
+use utils::wpm::SmoothedWpm;
+
+pub use utils::pipeline::CustomEvent;
 
 /// Basic layout for the keyboard
 #[cfg(feature = "keymap_basic")]
-use crate::keymap_basic::{KBLayout, LAYERS, VIRTUAL_MOUSE_KEY};
+use crate::keymap_basic::{
+    AUTO_MOUSE_EXCLUDED_KEYS, COMBOS, KEY_OVERRIDES, POINTER_LAYERS, TAP_DANCES, UNLOCK_CHORD,
+    VIRTUAL_MOUSE_KEY,
+};
 
 /// Keymap by Boris Faure
 #[cfg(feature = "keymap_borisfaure")]
-use crate::keymap_borisfaure::{KBLayout, LAYERS, VIRTUAL_MOUSE_KEY};
+use crate::keymap_borisfaure::{
+    AUTO_MOUSE_EXCLUDED_KEYS, COMBOS, KEY_OVERRIDES, POINTER_LAYERS, TAP_DANCES, UNLOCK_CHORD,
+    VIRTUAL_MOUSE_KEY,
+};
 
 /// Test layout for the keyboard
 #[cfg(feature = "keymap_test")]
-use crate::keymap_test::{KBLayout, LAYERS, VIRTUAL_MOUSE_KEY};
+use crate::keymap_test::{
+    AUTO_MOUSE_EXCLUDED_KEYS, COMBOS, KEY_OVERRIDES, POINTER_LAYERS, TAP_DANCES, UNLOCK_CHORD,
+    VIRTUAL_MOUSE_KEY,
+};
+
+/// Keymap generated from a JSON description
+#[cfg(feature = "keymap_json")]
+use crate::keymap_json::{
+    AUTO_MOUSE_EXCLUDED_KEYS, COMBOS, KEY_OVERRIDES, POINTER_LAYERS, TAP_DANCES, UNLOCK_CHORD,
+    VIRTUAL_MOUSE_KEY,
+};
 
 /// Layout refresh rate, in ms
 const REFRESH_RATE_MS: u64 = 1;
-/// Number of events in the layout channel
-const NB_EVENTS: usize = 128;
+/// Active layer, as known by the pipeline
+static CURRENT_LAYER: AtomicU8 = AtomicU8::new(0);
+/// Number of tasks that can listen to the typing speed changes
+const NB_WPM_RECEIVERS: usize = 1;
+/// Typing speed changes, in words per minute: smoothed by the core on the
+/// host half, as forwarded by the host on the other one
+pub static WPM_WATCH: Watch<CriticalSectionRawMutex, u16, NB_WPM_RECEIVERS> = Watch::new();
 /// Channel to send `keyberon::layout::event` events to the layout handler
-pub static LAYOUT_CHANNEL: Channel<ThreadModeRawMutex, KBEvent, NB_EVENTS> = Channel::new();
-
-/// Custom events for the layout, mostly mouse events
-//#[allow(clippy::enum_variant_names)]
-#[derive(Debug, PartialEq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub enum CustomEvent {
-    /// Mouse left click
-    MouseLeftClick,
-    /// Mouse right click
-    MouseRightClick,
-    /// Mouse Wheel click
-    MouseWheelClick,
-    /// Ball is wheel
-    BallIsWheel,
-    /// Increase sensor CPI
-    #[cfg(feature = "cnano")]
-    IncreaseCpi,
-    /// Decrease sensor CPI
-    #[cfg(feature = "cnano")]
-    DecreaseCpi,
-    /// Next Animation of the RGB LEDs
-    NextLedAnimation,
-    /// Reset to usb mass storage
-    ResetToUsbMassStorage,
-    /// Wheel up
-    #[cfg(feature = "dilemma")]
-    WheelUp,
-    /// Wheel down
-    #[cfg(feature = "dilemma")]
-    WheelDown,
-    /// Stop the automouse feature
-    NoMouseAction,
+pub static LAYOUT_CHANNEL: Channel<CriticalSectionRawMutex, KBEvent, LAYOUT_DEPTH> = Channel::new();
+
+/// Key auto-repeated while its turbo key is held
+struct Turbo {
+    /// HID keycode of the repeated key
+    keycode: u8,
+    /// Period of the repetition, in ms
+    period_ms: u16,
+    /// Time elapsed in the current period, in ms
+    elapsed_ms: u16,
 }
 
-/// Timeout for the automouse feature: when the mouse is not used for this
-/// amount of time, it will be considered inactive.
-#[cfg(feature = "dilemma")]
-const AUTO_MOUSE_TIMEOUT: usize = 150;
-#[cfg(feature = "cnano")]
-const AUTO_MOUSE_TIMEOUT: usize = 10;
+impl Turbo {
+    /// Whether the key is pressed: during the first half of each period
+    fn is_down(&self) -> bool {
+        self.elapsed_ms < self.period_ms / 2
+    }
 
-/// Core keyboard/mouse handler
-pub struct Core<'a> {
-    /// Keyboard layout
-    layout: KBLayout,
-    /// Current layer
-    current_layer: usize,
-    /// Keyboard HID report
-    kb_report: KeyboardReport,
-    /// Consumer Control HID report
-    consumer_report: ConsumerReport,
-    /// Mouse handler
-    mouse: MouseHandler,
-    /// HID mouse writer
-    hid_mouse_writer: HidWriter<'a, Driver<'a, USB>, 7>,
-    /// Timeout for the automouse feature. When this is non-zero, the mouse
-    /// will be considered active. Goes down to 0 every tick.
-    auto_mouse_timeout: usize,
-    /// Current color layer
-    color_layer: u8,
-    /// Is mouse active
-    mouse_active: bool,
+    /// Advance by one tick of `REFRESH_RATE_MS`
+    fn tick(&mut self) {
+        self.elapsed_ms = (self.elapsed_ms + REFRESH_RATE_MS as u16) % self.period_ms;
+    }
 }
 
-impl<'a> Core<'a> {
-    /// Create a new core
-    pub fn new(hid_mouse_writer: HidWriter<'a, Driver<'a, USB>, 7>) -> Self {
-        Self {
-            layout: Layout::new(&LAYERS),
-            current_layer: 0,
-            kb_report: KeyboardReport::default(),
-            consumer_report: ConsumerReport::default(),
-            mouse: MouseHandler::new(),
-            hid_mouse_writer,
-            auto_mouse_timeout: 0,
-            color_layer: 0,
-            mouse_active: false,
+/// State of a latch layer key
+#[derive(Debug, Clone, Copy)]
+enum Latch {
+    /// Held, activating `layer`, and whether another key got pressed
+    /// meanwhile
+    Held { layer: usize, used: bool },
+    /// Tapped: `layer` is active for the next key press
+    Latched(usize),
+}
+
+/// Keyberon layout, as the keymap of the pipeline, with the combos holding
+/// back its key events, the turbo key being held, if any, the tap dances,
+/// the layers locked or latched and the Grave Escape key
+struct Keyberon {
+    /// Layout, on the keymap edited at runtime
+    layout: EditableLayout,
+    /// Default layer, from the settings
+    default_layer: usize,
+    /// Layer locked by the layer lock key, acting as the default layer
+    /// until unlocked, if any
+    locked_layer: Option<usize>,
+    /// State of the latch layer key in use, if any, its layer acting as the
+    /// default layer meanwhile
+    latch: Option<Latch>,
+    /// Combos of the keymap
+    combos: Combos<KeyCode>,
+    /// Turbo key being held, if any
+    turbo: Option<Turbo>,
+    /// Tap dances of the keymap
+    tap_dances: TapDances<KeyCode>,
+    /// Grave Escape key
+    grave_escape: GraveEscape,
+    /// Whether a key was pressed since the last tick, resolving the pending
+    /// tap dance unless that key is a tap dance key itself
+    pressed: bool,
+}
+
+impl Keymap for Keyberon {
+    fn event(&mut self, event: KeyEvent) {
+        if let KeyEvent::Press(_, _) = event {
+            self.pressed = true;
         }
+        self.combos
+            .on_event(event, |event| self.layout.event(kb_event(event)));
     }
 
-    /// Set the color layer of the RGB LEDs
-    async fn set_color_layer(&mut self, layer: u8) {
-        if self.color_layer != layer {
-            info!("Setting color layer to {}", layer);
-            self.color_layer = layer;
-            if SIDE_CHANNEL.is_full() {
-                error!("Side channel is full");
+    fn tick(&mut self) -> Option<(CustomEvent, bool)> {
+        if let Some(turbo) = &mut self.turbo {
+            turbo.tick();
+        }
+        self.tap_dances.tick();
+        self.combos.tick(|event| self.layout.event(kb_event(event)));
+        let event = match self.layout.tick() {
+            KbCustomEvent::Press(event) => Some((*event, true)),
+            KbCustomEvent::Release(event) => Some((*event, false)),
+            KbCustomEvent::NoEvent => None,
+        };
+        tap_hold::tick();
+        let pressed = core::mem::take(&mut self.pressed);
+        match event {
+            Some((CustomEvent::LayerLock, true)) => self.toggle_layer_lock(),
+            Some((CustomEvent::GraveEscape, is_pressed)) => self
+                .grave_escape
+                .on_event(is_pressed, self.layout.keycodes()),
+            _ => (),
+        }
+        match event {
+            Some((CustomEvent::LatchLayer(layer), is_pressed)) => {
+                self.on_latch_key(layer as usize, is_pressed)
             }
-            SIDE_CHANNEL.send(Event::RgbAnimChangeLayer(layer)).await;
-            if ANIM_CHANNEL.is_full() {
-                error!("Anim channel is full");
+            _ if pressed => self.on_key_press(),
+            _ => (),
+        }
+        match event {
+            Some((CustomEvent::Turbo { keycode, period_ms }, true)) => {
+                self.turbo = Some(Turbo {
+                    keycode,
+                    period_ms: period_ms.max(2 * REFRESH_RATE_MS as u16),
+                    elapsed_ms: 0,
+                });
+            }
+            Some((CustomEvent::Turbo { keycode, .. }, false))
+                if self.turbo.as_ref().is_some_and(|t| t.keycode == keycode) =>
+            {
+                self.turbo = None;
             }
-            ANIM_CHANNEL.send(AnimCommand::ChangeLayer(layer)).await;
+            Some((CustomEvent::TapDance(index), is_pressed)) => {
+                self.tap_dances.on_event(index, is_pressed);
+            }
+            _ if pressed => self.tap_dances.interrupt(),
+            _ => (),
         }
+        event
     }
 
-    /// (Re)Set mouse active timeout
-    /// Also set the leds to the mouse active color
-    async fn on_mouse_active(&mut self) {
-        if !self.mouse_active {
-            self.mouse_active = true;
-            info!("Set Mouse Active");
-            self.layout
-                .event(KBEvent::Press(VIRTUAL_MOUSE_KEY.0, VIRTUAL_MOUSE_KEY.1));
-            self.auto_mouse_timeout = AUTO_MOUSE_TIMEOUT;
-        }
+    fn current_layer(&self) -> usize {
+        self.layout.current_layer()
     }
 
-    /// When the mouse becomes inactive, reset the leds to the current layer
-    /// color
-    async fn on_mouse_inactive(&mut self) {
-        info!("On Mouse Inactive");
-        if self.mouse_active {
-            self.mouse_active = false;
-            info!("Set Mouse Inactive");
-            self.layout
-                .event(KBEvent::Release(VIRTUAL_MOUSE_KEY.0, VIRTUAL_MOUSE_KEY.1));
+    fn set_default_layer(&mut self, layer: usize) {
+        self.default_layer = layer;
+        if self.locked_layer.is_none() && self.latch.is_none() {
+            self.layout.set_default_layer(layer);
         }
     }
 
-    /// Process a key event
-    async fn on_key_event(&mut self, event: KBEvent) {
-        self.layout.event(event);
+    fn set_tap_hold(&mut self, config: TapHold) {
+        tap_hold::set_config(config);
     }
 
-    /// Process the state of the keyboard and mouse
-    async fn tick(&mut self) {
-        // Process all mouse events first since they are time sensitive
-        while let Some((mouse_report, has_pressure)) = self.mouse.tick().await {
-            let pending_mouse_clicks = mouse_report.buttons != 0;
-            // Don't consider wheel movement as mouse activity since it may
-            // just be scrolling and not actual mouse movement
-            let mouse_moved = mouse_report.x != 0 || mouse_report.y != 0;
-            let raw = mouse_report.serialize();
-            #[cfg(feature = "defmt")]
-            if let Err(e) = self.hid_mouse_writer.write(&raw).await {
-                error!("Failed to send mouse report: {:?}", e);
-            }
-            let _ = self.hid_mouse_writer.write(&raw).await;
-            if mouse_moved || pending_mouse_clicks || has_pressure {
-                self.auto_mouse_timeout = AUTO_MOUSE_TIMEOUT;
-                self.on_mouse_active().await;
-            }
-        }
-        if self.auto_mouse_timeout > 0 {
-            self.auto_mouse_timeout -= 1;
-            if self.auto_mouse_timeout == 0 {
-                self.on_mouse_inactive().await;
-            }
-        }
+    fn reload(&mut self) {
+        self.layout.reload();
+        self.locked_layer = None;
+        self.latch = None;
+        self.layout.set_default_layer(self.default_layer);
+    }
 
-        // Process all events in the layout channel if any
-        // This is where the keymap is processed
-        while let Ok(event) = LAYOUT_CHANNEL.try_receive() {
-            self.on_key_event(event).await;
-        }
-        let custom_event = self.layout.tick();
-        let new_layer = self.layout.current_layer();
-        self.process_custom_event(custom_event).await;
-        let (new_kb_report, new_consumer_report) = generate_hid_reports(&mut self.layout);
-        if new_kb_report != self.kb_report {
-            self.kb_report = new_kb_report;
-            if HID_KB_CHANNEL.is_full() {
-                error!("HID KB channel is full");
+    fn usages(&self) -> impl Iterator<Item = Usage> + '_ {
+        let turbo = self
+            .turbo
+            .as_ref()
+            .filter(|t| t.is_down())
+            .map(|t| Usage::Key(t.keycode));
+        self.layout
+            .keycodes()
+            .chain(self.combos.key())
+            .chain(self.tap_dances.key())
+            .chain(self.grave_escape.key())
+            .filter_map(usage)
+            .chain(turbo)
+    }
+
+    fn key_overrides(&self) -> &'static [KeyOverride] {
+        &KEY_OVERRIDES
+    }
+}
+
+impl Keyberon {
+    /// Lock the layer currently held, keeping it once its layer key is
+    /// released, or unlock the layer locked
+    fn toggle_layer_lock(&mut self) {
+        match self.locked_layer.take() {
+            Some(layer) => {
+                info!("Layer {} unlocked", layer);
+                self.restore_default_layer();
             }
-            HID_KB_CHANNEL.send(new_kb_report).await;
-        }
-        if new_consumer_report != self.consumer_report {
-            self.consumer_report = new_consumer_report;
-            if HID_CONSUMER_CHANNEL.is_full() {
-                error!("HID Consumer channel is full");
+            None => {
+                let layer = self.layout.current_layer();
+                if layer != self.default_layer {
+                    info!("Layer {} locked", layer);
+                    self.locked_layer = Some(layer);
+                    self.layout.set_default_layer(layer);
+                }
             }
-            HID_CONSUMER_CHANNEL.send(new_consumer_report).await;
         }
-        if new_layer != self.current_layer {
-            info!("Layer: {}", new_layer);
-            self.current_layer = new_layer;
-            self.set_color_layer(new_layer as u8).await;
+    }
+
+    /// Go back to the locked layer, if any, or to the default layer,
+    /// unless a latched layer is still active
+    fn restore_default_layer(&mut self) {
+        if self.latch.is_none() {
+            let layer = self.locked_layer.unwrap_or(self.default_layer);
+            self.layout.set_default_layer(layer);
         }
     }
 
-    /// Process a custom event from the layout
-    async fn process_custom_event(&mut self, event: KbCustomEvent<CustomEvent>) {
-        match event {
-            KbCustomEvent::Press(CustomEvent::MouseLeftClick) => {
-                self.mouse.on_left_click(true);
-            }
-            KbCustomEvent::Release(CustomEvent::MouseLeftClick) => {
-                self.mouse.on_left_click(false);
-            }
-            KbCustomEvent::Press(CustomEvent::MouseRightClick) => {
-                self.mouse.on_right_click(true);
+    /// Press or release the latch key of the layer `layer`
+    fn on_latch_key(&mut self, layer: usize, is_pressed: bool) {
+        match (self.latch, is_pressed) {
+            (_, true) => {
+                self.latch = Some(Latch::Held { layer, used: false });
+                self.layout.set_default_layer(layer);
             }
-            KbCustomEvent::Release(CustomEvent::MouseRightClick) => {
-                self.mouse.on_right_click(false);
+            (Some(Latch::Held { layer, used: false }), false) => {
+                info!("Layer {} latched", layer);
+                self.latch = Some(Latch::Latched(layer));
             }
-            KbCustomEvent::Press(CustomEvent::MouseWheelClick) => {
-                self.mouse.on_middle_click(true);
+            (Some(Latch::Held { used: true, .. }), false) => {
+                self.latch = None;
+                self.restore_default_layer();
             }
-            KbCustomEvent::Release(CustomEvent::MouseWheelClick) => {
-                self.mouse.on_middle_click(false);
+            _ => (),
+        }
+    }
+
+    /// Account for another key press, once processed by the layout: it
+    /// uses up the latched layer, if any
+    fn on_key_press(&mut self) {
+        match &mut self.latch {
+            Some(Latch::Held { used, .. }) => *used = true,
+            Some(Latch::Latched(_)) => {
+                self.latch = None;
+                self.restore_default_layer();
             }
-            KbCustomEvent::Press(CustomEvent::BallIsWheel) => {
-                self.mouse.on_ball_is_wheel(true);
+            None => (),
+        }
+    }
+}
+
+/// Keyberon event of a key event
+fn kb_event(event: KeyEvent) -> KBEvent {
+    match event {
+        KeyEvent::Press(i, j) => KBEvent::Press(i, j),
+        KeyEvent::Release(i, j) => KBEvent::Release(i, j),
+    }
+}
+
+/// What a keyberon keycode reports to the host
+fn usage(kc: KeyCode) -> Option<Usage> {
+    use keyberon::key_code::KeyCode::*;
+    match kc {
+        No => None,
+        ErrorRollOver | PostFail | ErrorUndefined => Some(Usage::Error(kc as u8)),
+        kc if kc.is_modifier() => Some(Usage::Modifier(kc.as_modifier_bit())),
+        // Consumer control keys
+        // Map them to consumer usage codes
+        MediaNextSong => Some(Usage::Consumer(0x00B5)),
+        MediaPreviousSong => Some(Usage::Consumer(0x00B6)),
+        MediaPlayPause => Some(Usage::Consumer(0x00CD)),
+        Mute => Some(Usage::Consumer(0x00E2)),
+        VolUp => Some(Usage::Consumer(0x00E9)),
+        VolDown => Some(Usage::Consumer(0x00EA)),
+        // Regular keyboard keys
+        _ => Some(Usage::Key(kc as u8)),
+    }
+}
+
+/// Channels, USB and peripherals used by the pipeline
+struct CoreIo<'a> {
+    /// Mouse handler
+    mouse: MouseHandler,
+    /// HID mouse writer
+    hid_mouse_writer: HidWriter<'a, Driver<'a, USB>, 7>,
+    /// Last keyboard report sent by the pipeline
+    kb_report: KeyboardReport,
+    /// Dynamic macros, recorded from the keyboard reports sent
+    macros: DynamicMacros,
+    /// Mirror of the key events while the swap hands key is held
+    swap_hands: SwapHands<ROWS>,
+    /// Pointer moves of the mouse keys held
+    mouse_keys: MouseKeys,
+    /// Chord capture of the steno mode
+    #[cfg(feature = "steno")]
+    steno: Steno,
+}
+
+impl CoreIo<'_> {
+    /// Mirror the key events while the hands are swapped, and divert the
+    /// events of the steno keys to the steno mode, when active.
+    /// Returns the event left for the keymap, if any.
+    fn filter_key_event(&mut self, event: KeyEvent) -> Option<KeyEvent> {
+        let event = self.swap_hands.on_event(event);
+        #[cfg(feature = "steno")]
+        let event = match self.steno.on_key_event(event) {
+            Outcome::Forward(event) => event,
+            Outcome::Captured => return None,
+            Outcome::Chord(chord) => {
+                steno::send(chord);
+                return None;
             }
-            KbCustomEvent::Release(CustomEvent::BallIsWheel) => {
-                self.mouse.on_ball_is_wheel(false);
+            Outcome::Exit => {
+                info!("Leaving the steno mode");
+                return None;
             }
-            #[cfg(feature = "dilemma")]
-            KbCustomEvent::Press(CustomEvent::WheelUp) => {
-                self.mouse.on_wheel(true);
+        };
+        Some(event)
+    }
+
+    /// Play the dynamic macro of the slot `slot`, then restore the keys
+    /// pressed through the keymap
+    async fn play_macro(&mut self, slot: u8) {
+        for report in self.macros.playback(slot) {
+            hid::send_kb_report(report);
+            Timer::after(Duration::from_millis(PLAYBACK_PERIOD_MS)).await;
+            watchdog::heartbeat(Task::Core);
+        }
+        hid::send_kb_report(self.kb_report);
+    }
+}
+
+impl Io for CoreIo<'_> {
+    fn key_event(&mut self) -> Option<KeyEvent> {
+        while let Ok(event) = LAYOUT_CHANNEL.try_receive() {
+            if let Some(event) = self.filter_key_event(received(event)) {
+                return Some(event);
             }
+        }
+        None
+    }
+
+    async fn mouse_report(&mut self) -> Option<(MouseReport, bool)> {
+        self.mouse.tick().await
+    }
+
+    async fn send_mouse_report(&mut self, report: MouseReport) {
+        let raw = report.serialize();
+        #[cfg(feature = "defmt")]
+        if let Err(e) = self.hid_mouse_writer.write(&raw).await {
+            error!("Failed to send mouse report: {:?}", e);
+        }
+        let _ = self.hid_mouse_writer.write(&raw).await;
+    }
+
+    async fn send_keyboard_report(&mut self, report: KeyboardReport) {
+        #[cfg(feature = "tracing")]
+        trace::record(trace::Task::Core, Kind::KeyboardReport(report));
+        self.kb_report = report;
+        self.macros.record(report);
+        hid::send_kb_report(report);
+    }
+
+    async fn send_consumer_report(&mut self, report: ConsumerReport) {
+        if HID_CONSUMER_CHANNEL.is_full() {
+            error!("HID Consumer channel is full");
+        }
+        HID_CONSUMER_CHANNEL.send(report).await;
+    }
+
+    async fn send_system_report(&mut self, report: SystemReport) {
+        if HID_SYSTEM_CHANNEL.is_full() {
+            error!("HID System channel is full");
+        }
+        HID_SYSTEM_CHANNEL.send(report).await;
+    }
+
+    async fn set_color_layer(&mut self, layer: u8) {
+        CURRENT_LAYER.store(layer, Ordering::Relaxed);
+        self.mouse.set_layer(
+            POINTER_LAYERS
+                .get(layer as usize)
+                .copied()
+                .unwrap_or_default(),
+        );
+        haptic::trigger(HapticEvent::Layer);
+        #[cfg(feature = "buzzer")]
+        buzzer::play(Sound::Layer);
+        display::set_layer(layer);
+        if SIDE_CHANNEL.is_full() {
+            error!("Side channel is full");
+        }
+        SIDE_CHANNEL.send(Event::RgbAnimChangeLayer(layer)).await;
+        if ANIM_CHANNEL.is_full() {
+            error!("Anim channel is full");
+        }
+        ANIM_CHANNEL.send(AnimCommand::ChangeLayer(layer)).await;
+    }
+
+    async fn custom_event(&mut self, event: CustomEvent, is_pressed: bool) {
+        match (event, is_pressed) {
+            (CustomEvent::MouseLeftClick, _) => self.mouse.on_left_click(is_pressed),
+            (CustomEvent::MouseRightClick, _) => self.mouse.on_right_click(is_pressed),
+            (CustomEvent::MouseWheelClick, _) => self.mouse.on_middle_click(is_pressed),
+            (CustomEvent::MouseUp, _) => self.mouse_keys.on_event(Direction::Up, is_pressed),
+            (CustomEvent::MouseDown, _) => self.mouse_keys.on_event(Direction::Down, is_pressed),
+            (CustomEvent::MouseLeft, _) => self.mouse_keys.on_event(Direction::Left, is_pressed),
+            (CustomEvent::MouseRight, _) => self.mouse_keys.on_event(Direction::Right, is_pressed),
+            (CustomEvent::BallIsWheel, _) => self.mouse.on_ball_is_wheel(is_pressed),
+            (CustomEvent::SniperMode(divisor), true) => self.mouse.on_sniper_mode(divisor),
+            (CustomEvent::SniperMode(_), false) => self.mouse.on_sniper_mode(1),
+            (CustomEvent::ToggleDragScroll, true) => self.mouse.toggle_drag_scroll(),
+            (CustomEvent::SwapHands, _) => self.swap_hands.set_active(is_pressed),
             #[cfg(feature = "dilemma")]
-            KbCustomEvent::Release(CustomEvent::WheelUp) => {}
-            #[cfg(feature = "dilemma")]
-            KbCustomEvent::Press(CustomEvent::WheelDown) => {
-                self.mouse.on_wheel(false);
-            }
+            (CustomEvent::WheelUp, true) => self.mouse.on_wheel(true),
             #[cfg(feature = "dilemma")]
-            KbCustomEvent::Release(CustomEvent::WheelDown) => {}
+            (CustomEvent::WheelDown, true) => self.mouse.on_wheel(false),
+            (CustomEvent::PanLeft, true) => self.mouse.on_pan(false),
+            (CustomEvent::PanRight, true) => self.mouse.on_pan(true),
 
             #[cfg(feature = "cnano")]
-            KbCustomEvent::Press(CustomEvent::IncreaseCpi) => {
+            (CustomEvent::IncreaseCpi(step), true) => {
                 if SENSOR_CMD_CHANNEL.is_full() {
                     error!("Sensor channel is full");
                 }
-                SENSOR_CMD_CHANNEL.send(SensorCommand::IncreaseCpi).await;
+                SENSOR_CMD_CHANNEL
+                    .send(SensorCommand::IncreaseCpi(step))
+                    .await;
             }
             #[cfg(feature = "cnano")]
-            KbCustomEvent::Release(CustomEvent::IncreaseCpi) => {}
+            (CustomEvent::DecreaseCpi(step), true) => {
+                if SENSOR_CMD_CHANNEL.is_full() {
+                    error!("Sensor channel is full");
+                }
+                SENSOR_CMD_CHANNEL
+                    .send(SensorCommand::DecreaseCpi(step))
+                    .await;
+            }
             #[cfg(feature = "cnano")]
-            KbCustomEvent::Press(CustomEvent::DecreaseCpi) => {
+            (CustomEvent::SetCpi(cpi), true) => {
                 if SENSOR_CMD_CHANNEL.is_full() {
                     error!("Sensor channel is full");
                 }
-                SENSOR_CMD_CHANNEL.send(SensorCommand::DecreaseCpi).await;
+                SENSOR_CMD_CHANNEL.send(SensorCommand::SetCpi(cpi)).await;
             }
             #[cfg(feature = "cnano")]
-            KbCustomEvent::Release(CustomEvent::DecreaseCpi) => {}
+            (CustomEvent::NextLiftConfig, true) => {
+                let config = (settings::lift_config() + 1) % (MAX_LIFT_CONFIG + 1);
+                settings::set_lift_config(config);
+            }
 
-            KbCustomEvent::Press(CustomEvent::NextLedAnimation) => {
+            (CustomEvent::NextLedAnimation, true) => {
                 if ANIM_CHANNEL.is_full() {
                     error!("Anim channel is full");
                 }
                 ANIM_CHANNEL.send(AnimCommand::Next).await;
             }
-            KbCustomEvent::Release(CustomEvent::NextLedAnimation) => {}
 
-            KbCustomEvent::Press(CustomEvent::ResetToUsbMassStorage) => {
-                embassy_rp::rom_data::reset_to_usb_boot(0, 0);
+            (CustomEvent::NextProfile, true) => {
+                let profile = (settings::active_profile() + 1) % NB_PROFILES as u8;
+                settings::select_profile(profile);
+                if ANIM_CHANNEL.is_full() {
+                    error!("Anim channel is full");
+                }
+                ANIM_CHANNEL.send(AnimCommand::ShowProfile(profile)).await;
             }
-            KbCustomEvent::Release(CustomEvent::ResetToUsbMassStorage) => {}
 
-            KbCustomEvent::Press(CustomEvent::NoMouseAction) => {
-                if self.auto_mouse_timeout != 0 {
-                    self.auto_mouse_timeout = 0;
-                    self.on_mouse_inactive().await;
-                }
+            (CustomEvent::NextBallisticProfile, true) => {
+                let profile = settings::get().ballistics.next();
+                info!("Ballistic profile: {:?}", profile);
+                settings::update(|s| {
+                    s.ballistics = profile;
+                    s.cpi = profile.response().cpi;
+                });
             }
-            KbCustomEvent::Release(CustomEvent::NoMouseAction) => {}
 
-            KbCustomEvent::NoEvent => (),
-        }
-    }
-}
+            (CustomEvent::ToggleNaturalScroll, true) => {
+                let natural_scroll = !settings::get().pointer.natural_scroll;
+                info!("Natural scrolling: {}", natural_scroll);
+                settings::update(|s| s.pointer.natural_scroll = natural_scroll);
+            }
 
-#[embassy_executor::task]
-/// Keyboard layout handler
-/// Handles layout events into the keymap and sends HID reports to the HID handler
-pub async fn run(mut core: Core<'static>) {
-    let mut ticker = Ticker::every(Duration::from_millis(REFRESH_RATE_MS));
+            (CustomEvent::ToggleAutoShift, true) => {
+                let autoshift = !settings::get().autoshift.enabled;
+                info!("Autoshift: {}", autoshift);
+                settings::update(|s| s.autoshift.enabled = autoshift);
+            }
 
-    loop {
-        match select(ticker.next(), LAYOUT_CHANNEL.receive()).await {
-            Either::First(_) => {
-                core.tick().await;
+            #[cfg(feature = "steno")]
+            (CustomEvent::EnterSteno, true) => {
+                info!("Entering the steno mode");
+                self.steno.enter();
+            }
+
+            (CustomEvent::ResetToUsbMassStorage, true) => {
+                chip::reset_to_usb_boot();
             }
-            Either::Second(event) => {
-                core.on_key_event(event).await;
+
+            (CustomEvent::MacroRecordStart(slot), true) => self.macros.start_recording(slot),
+            (CustomEvent::MacroRecordStop, true) => self.macros.stop_recording(),
+            (CustomEvent::MacroPlay(slot), true) => self.play_macro(slot).await,
+
+            #[cfg(feature = "tracing")]
+            (CustomEvent::DumpTrace, true) => trace::dump(),
+
+            (CustomEvent::DumpKeyStats, true) => key_stats::dump(),
+
+            (CustomEvent::SetHandedness(handedness), true) => {
+                info!("Handedness set to {:?}, from the next boot on", handedness);
+                settings::set_handedness(handedness);
             }
+
+            _ => (),
+        }
+    }
+
+    fn notify_activity(&mut self) {
+        sysclk::notify_activity();
+    }
+
+    fn notify_auto_mouse(&mut self) {
+        haptic::trigger(HapticEvent::AutoMouse);
+    }
+
+    fn notify_input_lock(&mut self, locked: bool) {
+        let cmd = if locked {
+            AnimCommand::InputLocked
+        } else {
+            AnimCommand::InputUnlocked
         };
+        if ANIM_CHANNEL.try_send(cmd).is_err() {
+            error!("Anim channel is full");
+        }
+    }
+
+    fn factory_reset(&mut self) {
+        settings::factory_reset();
     }
 }
 
-/// Set a report as an error based on keycode `kc`
-fn keyboard_report_set_error(report: &mut KeyboardReport, kc: KeyCode) {
-    report.modifier = 0;
-    report.keycodes = [kc as u8; 6];
-    error!("Error: {:?}", Debug2Format(&kc));
+/// Account for a keyberon event received from `LAYOUT_CHANNEL` and convert
+/// it into a key event of the pipeline
+fn received(event: KBEvent) -> KeyEvent {
+    channels::record(Queue::Layout, LAYOUT_CHANNEL.len());
+    #[cfg(feature = "tracing")]
+    trace::record(trace::Task::Core, event.into());
+    match event {
+        KBEvent::Press(i, j) => KeyEvent::Press(i, j),
+        KBEvent::Release(i, j) => KeyEvent::Release(i, j),
+    }
 }
 
-/// Generate HID reports (keyboard and consumer) from the current layout
-fn generate_hid_reports(layout: &mut KBLayout) -> (KeyboardReport, ConsumerReport) {
-    let mut kb_report = KeyboardReport::default();
-    let mut consumer_report = ConsumerReport::default();
-
-    for kc in layout.keycodes() {
-        use keyberon::key_code::KeyCode::*;
-        match kc {
-            No => (),
-            ErrorRollOver | PostFail | ErrorUndefined => {
-                keyboard_report_set_error(&mut kb_report, kc)
-            }
-            kc if kc.is_modifier() => kb_report.modifier |= kc.as_modifier_bit(),
-            // Consumer control keys (>= 0xE8)
-            // Map them to consumer usage codes
-            MediaNextSong => consumer_report.usage = 0x00B5,
-            MediaPreviousSong => consumer_report.usage = 0x00B6,
-            MediaPlayPause => consumer_report.usage = 0x00CD,
-            Mute => consumer_report.usage = 0x00E2,
-            VolUp => consumer_report.usage = 0x00E9,
-            VolDown => consumer_report.usage = 0x00EA,
-            // Regular keyboard keys (< 0xE8)
-            _ => {
-                // Only add to keyboard report if it's a valid keycode < 0xE8
-                let kc_value = kc as u8;
-                if kc_value < 0xE8 {
-                    kb_report.keycodes[..]
-                        .iter_mut()
-                        .find(|c| **c == 0)
-                        .map(|c| *c = kc_value)
-                        .unwrap_or_else(|| {
-                            keyboard_report_set_error(&mut kb_report, ErrorRollOver)
-                        });
+/// Active layer
+pub fn current_layer() -> u8 {
+    CURRENT_LAYER.load(Ordering::Relaxed)
+}
+
+/// Typing speed, in words per minute, see `WPM_WATCH`
+pub fn wpm() -> u16 {
+    WPM_WATCH.try_get().unwrap_or(0)
+}
+
+/// Core keyboard/mouse handler
+pub struct Core<'a> {
+    /// Keymap, auto-mouse and HID reports processing
+    pipeline: Pipeline<Keyberon, CoreIo<'a>>,
+    /// Receiver of the settings changes
+    settings_rcv: SettingsReceiver,
+    /// Typing speed
+    wpm: SmoothedWpm,
+}
+
+impl<'a> Core<'a> {
+    /// Create a new core
+    pub fn new(hid_mouse_writer: HidWriter<'a, Driver<'a, USB>, 7>) -> Self {
+        let io = CoreIo {
+            mouse: MouseHandler::new(),
+            hid_mouse_writer,
+            kb_report: KeyboardReport::default(),
+            macros: DynamicMacros::new(),
+            swap_hands: SwapHands::new(FULL_COLS as u8),
+            mouse_keys: MouseKeys::new(),
+            #[cfg(feature = "steno")]
+            steno: Steno::new(&STENO_LAYOUT),
+        };
+        Self {
+            pipeline: Pipeline::new(
+                Keyberon {
+                    layout: EditableLayout::new(),
+                    default_layer: 0,
+                    locked_layer: None,
+                    latch: None,
+                    combos: Combos::new(&COMBOS),
+                    turbo: None,
+                    tap_dances: TapDances::new(&TAP_DANCES),
+                    grave_escape: GraveEscape::new(),
+                    pressed: false,
+                },
+                io,
+                VIRTUAL_MOUSE_KEY,
+                &UNLOCK_CHORD,
+                &AUTO_MOUSE_EXCLUDED_KEYS,
+                &settings::get(),
+            ),
+            settings_rcv: SETTINGS_WATCH.receiver().unwrap(),
+            wpm: SmoothedWpm::new(),
+        }
+    }
+
+    /// Process a key event received from `LAYOUT_CHANNEL`
+    fn on_key_event(&mut self, event: KBEvent) {
+        if let KBEvent::Press(_, _) = event {
+            self.wpm.on_press();
+        }
+        if let Some(event) = self.pipeline.io().filter_key_event(received(event)) {
+            self.pipeline.on_key_event(event);
+        }
+    }
+
+    /// Wait for a key event, a pointer move or click or a settings change,
+    /// sending heartbeats to the watchdog meanwhile
+    async fn wait_event(&mut self) {
+        loop {
+            match select4(
+                LAYOUT_CHANNEL.receive(),
+                select(
+                    MOUSE_MOVE_CHANNEL.ready_to_receive(),
+                    MOUSE_BUTTON_CHANNEL.ready_to_receive(),
+                ),
+                select(self.settings_rcv.changed(), KEYMAP_SIGNAL.wait()),
+                Timer::after(Duration::from_millis(HEARTBEAT_PERIOD_MS)),
+            )
+            .await
+            {
+                Either4::First(event) => {
+                    self.on_key_event(event);
+                    return;
+                }
+                Either4::Second(_) => return,
+                Either4::Third(Either::First(settings)) => self.pipeline.apply_settings(&settings),
+                Either4::Third(Either::Second(_)) => self.pipeline.reload_keymap(),
+                Either4::Fourth(_) => {
+                    watchdog::heartbeat(Task::Core);
+                    self.update_wpm();
                 }
             }
         }
     }
-    (kb_report, consumer_report)
+
+    /// Publish the typing speed when it changes
+    fn update_wpm(&mut self) {
+        if self.wpm.update(Instant::now().as_millis()) {
+            WPM_WATCH.sender().send(self.wpm.wpm());
+        }
+    }
+
+    /// Process the state of the keyboard and mouse
+    async fn tick(&mut self) {
+        self.update_wpm();
+        if let Some(settings) = self.settings_rcv.try_changed() {
+            self.pipeline.apply_settings(&settings);
+        }
+        if KEYMAP_SIGNAL.try_take().is_some() {
+            self.pipeline.reload_keymap();
+        }
+        self.pipeline.tick().await;
+        if let Some((dx, dy)) = self.pipeline.io().mouse_keys.tick() {
+            // The core drains the channel itself, so it must not wait on it
+            if MOUSE_MOVE_CHANNEL
+                .try_send(MouseMove {
+                    dx,
+                    dy,
+                    pressure: 0,
+                    wheel: 0,
+                    pan: 0,
+                })
+                .is_err()
+            {
+                error!("Mouse move channel is full");
+            }
+        }
+    }
+}
+
+#[embassy_executor::task]
+/// Keyboard layout handler
+/// Handles layout events into the keymap and sends HID reports to the HID handler.
+/// The layout is refreshed every `REFRESH_RATE_MS` only while some state
+/// depends on time. Otherwise, the task sleeps until an event comes in and
+/// processes it at once.
+pub async fn run(mut core: Core<'static>) {
+    let mut ticker = Ticker::every(Duration::from_millis(REFRESH_RATE_MS));
+    watchdog::register(Task::Core);
+
+    loop {
+        if !core.pipeline.needs_tick() {
+            core.wait_event().await;
+            ticker.reset();
+        } else if let Either::Second(event) = select(ticker.next(), LAYOUT_CHANNEL.receive()).await
+        {
+            core.on_key_event(event);
+            continue;
+        }
+        #[cfg(feature = "timing_logs")]
+        let start = embassy_time::Instant::now();
+        core.tick().await;
+        #[cfg(feature = "timing_logs")]
+        metrics::record(Metric::CoreTick, start);
+        watchdog::heartbeat(Task::Core);
+    }
 }
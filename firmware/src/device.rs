@@ -1,20 +1,121 @@
-use core::sync::atomic::{AtomicBool, Ordering};
+use crate::hid;
+use crate::settings;
+use crate::side::SIDE_CHANNEL;
+use crate::usb::WAKEUP_SIGNAL;
 use embassy_rp::gpio::Input;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, watch::Watch};
 use embassy_usb::Handler;
-use utils::log::info;
+use portable_atomic::{AtomicBool, AtomicU8, Ordering};
+use utils::log::{error, info};
+use utils::serde::Event;
+use utils::settings::Handedness;
 
-/// Device configured flag
-static CONFIGURED: AtomicBool = AtomicBool::new(false);
+/// State of the USB device, as seen from the `Handler` callbacks.
+///
+/// The RP2040 VBUS detection is overridden by the USB driver, so the
+/// presence of a host is deduced from the bus activity instead: a bus reset
+/// is only seen when a host drives the data lines, which is not the case
+/// with a power-only cable or a hub without any upstream host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum UsbState {
+    /// No host seen on the bus: powered only
+    Detached = 0,
+    /// A host reset the bus, enumeration is ongoing
+    Attached = 1,
+    /// Enumerated and configured by the host
+    Configured = 2,
+    /// Configured, then suspended by the host
+    Suspended = 3,
+}
+
+impl UsbState {
+    /// Convert from the value stored in `USB_STATE`
+    fn from_u8(v: u8) -> Self {
+        match v {
+            1 => UsbState::Attached,
+            2 => UsbState::Configured,
+            3 => UsbState::Suspended,
+            _ => UsbState::Detached,
+        }
+    }
+}
+
+/// Current USB state
+static USB_STATE: AtomicU8 = AtomicU8::new(UsbState::Detached as u8);
+
+/// Whether the host is suspended, be it the host of this half or the one of
+/// the other half
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+/// Number of tasks that can listen to the suspend transitions
+const NB_SUSPEND_RECEIVERS: usize = 3;
+/// Suspend transitions of the host, for the tasks to react to them
+pub static SUSPEND_WATCH: Watch<CriticalSectionRawMutex, bool, NB_SUSPEND_RECEIVERS> = Watch::new();
+
+/// Get the current USB state
+pub fn usb_state() -> UsbState {
+    UsbState::from_u8(USB_STATE.load(Ordering::Relaxed))
+}
+
+/// Set the USB state
+fn set_usb_state(state: UsbState) {
+    let prev = UsbState::from_u8(USB_STATE.swap(state as u8, Ordering::Relaxed));
+    if prev != state {
+        info!("USB state: {:?} -> {:?}", prev, state);
+        let suspended = state == UsbState::Suspended;
+        if suspended != (prev == UsbState::Suspended) {
+            set_suspended(suspended);
+            if SIDE_CHANNEL
+                .try_send(Event::HostSuspended(suspended))
+                .is_err()
+            {
+                error!("Side channel is full");
+            }
+        }
+    }
+}
+
+/// Set whether the host is suspended, on this half or on the other one
+pub fn set_suspended(suspended: bool) {
+    if SUSPENDED.swap(suspended, Ordering::Relaxed) != suspended {
+        info!("Host suspended: {}", suspended);
+        SUSPEND_WATCH.sender().send(suspended);
+    }
+}
 
+/// Whether the host is suspended, on this half or on the other one
+pub fn is_suspended() -> bool {
+    SUSPENDED.load(Ordering::Relaxed)
+}
+
+/// Resume the suspended host: directly if this half is the host, otherwise
+/// through the other half
+pub fn wake_host() {
+    if usb_state() == UsbState::Suspended {
+        WAKEUP_SIGNAL.signal(());
+    } else if SIDE_CHANNEL.try_send(Event::WakeHost).is_err() {
+        error!("Side channel is full");
+    }
+}
+
+/// Whether this half is the right one: from the settings if the handedness
+/// is stored there, otherwise from the GPIO strap `pin`
 pub fn is_right(pin: Input) -> bool {
-    let is_right = pin.is_high();
+    let is_right = match settings::handedness() {
+        Handedness::Left => false,
+        Handedness::Right => true,
+        Handedness::Auto => pin.is_high(),
+    };
     info!("Side detected: is_right: {}", is_right);
     is_right
 }
 
-/// Whether the device is the host or not
+/// Whether the device is the host or not: it is enumerated on USB by a
+/// host, even if that host is currently suspended
 pub fn is_host() -> bool {
-    CONFIGURED.load(Ordering::Relaxed)
+    matches!(usb_state(), UsbState::Configured | UsbState::Suspended)
 }
 
 /// Device Handler, used to know when it's configured
@@ -29,7 +130,7 @@ impl DeviceHandler {
 
 impl Handler for DeviceHandler {
     fn enabled(&mut self, _enabled: bool) {
-        CONFIGURED.store(false, Ordering::Relaxed);
+        set_usb_state(UsbState::Detached);
         #[cfg(feature = "defmt")]
         if _enabled {
             info!("Device enabled");
@@ -39,17 +140,22 @@ impl Handler for DeviceHandler {
     }
 
     fn reset(&mut self) {
-        CONFIGURED.store(false, Ordering::Relaxed);
+        set_usb_state(UsbState::Attached);
+        hid::set_boot_protocol(false);
         info!("Bus reset, the Vbus current limit is 100mA");
     }
 
     fn addressed(&mut self, _addr: u8) {
-        CONFIGURED.store(false, Ordering::Relaxed);
+        set_usb_state(UsbState::Attached);
         info!("USB address set to: {}", _addr);
     }
 
     fn configured(&mut self, configured: bool) {
-        CONFIGURED.store(configured, Ordering::Relaxed);
+        set_usb_state(if configured {
+            UsbState::Configured
+        } else {
+            UsbState::Attached
+        });
         #[cfg(feature = "defmt")]
         if configured {
             info!(
@@ -59,4 +165,12 @@ impl Handler for DeviceHandler {
             info!("Device is no longer configured, the Vbus current limit is 100mA.");
         }
     }
+
+    fn suspended(&mut self, suspended: bool) {
+        match (usb_state(), suspended) {
+            (UsbState::Configured, true) => set_usb_state(UsbState::Suspended),
+            (UsbState::Suspended, false) => set_usb_state(UsbState::Configured),
+            _ => {}
+        }
+    }
 }
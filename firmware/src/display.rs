@@ -0,0 +1,297 @@
+use crate::device::is_host;
+use crate::i2c_bus::SharedI2c;
+use crate::settings;
+use crate::side::SIDE_CHANNEL;
+use core::cell::RefCell;
+use core::convert::Infallible;
+use core::fmt::Write;
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Ticker};
+use embedded_graphics::{
+    mono_font::{ascii::FONT_6X10, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    text::{Baseline, Text},
+};
+use embedded_hal_async::i2c::I2c;
+use heapless::String;
+use utils::log::{error, info};
+use utils::serde::{Event, MAX_DISPLAY_WPM};
+
+/// I2C address of the display
+const ADDRESS: u8 = 0x3C;
+/// Width of the display, in pixels
+const WIDTH: usize = 128;
+/// Height of the display, in pixels
+#[cfg(not(feature = "sh1106"))]
+const HEIGHT: usize = 32;
+#[cfg(feature = "sh1106")]
+const HEIGHT: usize = 64;
+/// Number of 8-pixel high pages of the display
+const NB_PAGES: usize = HEIGHT / 8;
+/// First column of the display in the controller RAM
+#[cfg(not(feature = "sh1106"))]
+const COLUMN_OFFSET: u8 = 0;
+#[cfg(feature = "sh1106")]
+const COLUMN_OFFSET: u8 = 2;
+
+/// Initialization commands of a 128x32 SSD1306
+#[cfg(not(feature = "sh1106"))]
+const INIT: &[u8] = &[
+    0xAE, // Display off
+    0xD5, 0x80, // Clock divide ratio
+    0xA8, 0x1F, // Multiplex ratio: 32 rows
+    0xD3, 0x00, // No display offset
+    0x40, // Start line 0
+    0x8D, 0x14, // Charge pump on
+    0xA1, // Segments remapped
+    0xC8, // COM scan direction remapped
+    0xDA, 0x02, // Sequential COM pins
+    0x81, 0x8F, // Contrast
+    0xD9, 0xF1, // Pre-charge period
+    0xDB, 0x40, // VCOMH deselect level
+    0xA4, // Display the RAM content
+    0xA6, // Normal, not inverted
+    0xAF, // Display on
+];
+/// Initialization commands of a 128x64 SH1106
+#[cfg(feature = "sh1106")]
+const INIT: &[u8] = &[
+    0xAE, // Display off
+    0xD5, 0x80, // Clock divide ratio
+    0xA8, 0x3F, // Multiplex ratio: 64 rows
+    0xD3, 0x00, // No display offset
+    0x40, // Start line 0
+    0xAD, 0x8B, // DC-DC converter on
+    0xA1, // Segments remapped
+    0xC8, // COM scan direction remapped
+    0xDA, 0x12, // Alternative COM pins
+    0x81, 0x80, // Contrast
+    0xD9, 0x22, // Pre-charge period
+    0xDB, 0x35, // VCOM deselect level
+    0xA4, // Display the RAM content
+    0xA6, // Normal, not inverted
+    0xAF, // Display on
+];
+
+/// Control byte before commands
+const CONTROL_COMMANDS: u8 = 0x00;
+/// Control byte before data
+const CONTROL_DATA: u8 = 0x40;
+
+/// Num Lock bit of the lock indicators, as in the HID LED report
+pub const NUM_LOCK: u8 = 1 << 0;
+/// Caps Lock bit of the lock indicators
+pub const CAPS_LOCK: u8 = 1 << 1;
+/// Scroll Lock bit of the lock indicators
+pub const SCROLL_LOCK: u8 = 1 << 2;
+
+/// What is shown on the display
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct State {
+    /// Active layer
+    layer: u8,
+    /// Typing speed, in words per minute
+    wpm: u8,
+    /// Lock indicators
+    locks: u8,
+    /// Whether the link with the other half works
+    link_ok: bool,
+}
+
+/// Current state to show
+static STATE: Mutex<CriticalSectionRawMutex, RefCell<State>> = Mutex::new(RefCell::new(State {
+    layer: 0,
+    wpm: 0,
+    locks: 0,
+    link_ok: true,
+}));
+/// Signaled when the state changed
+static REDRAW: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Update the state, redrawing the display if it changed
+fn update(f: impl FnOnce(&mut State)) {
+    let changed = STATE.lock(|s| {
+        let mut state = s.borrow_mut();
+        let prev = *state;
+        f(&mut state);
+        prev != *state
+    });
+    if changed {
+        REDRAW.signal(());
+    }
+}
+
+/// Set the active layer
+pub fn set_layer(layer: u8) {
+    update(|s| s.layer = layer);
+}
+
+/// Set the lock indicators, see `NUM_LOCK`, `CAPS_LOCK` and `SCROLL_LOCK`
+pub fn set_locks(locks: u8) {
+    update(|s| s.locks = locks);
+}
+
+/// Set the typing speed, as computed by the host half
+pub fn set_wpm(wpm: u8) {
+    update(|s| s.wpm = wpm);
+}
+
+/// Set whether the link with the other half works
+pub fn set_link_ok(link_ok: bool) {
+    update(|s| s.link_ok = link_ok);
+}
+
+/// Frame buffer, one byte per column of each page
+struct Frame([[u8; WIDTH]; NB_PAGES]);
+
+impl OriginDimensions for Frame {
+    fn size(&self) -> Size {
+        Size::new(WIDTH as u32, HEIGHT as u32)
+    }
+}
+
+impl DrawTarget for Frame {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            let (Ok(x), Ok(y)) = (usize::try_from(point.x), usize::try_from(point.y)) else {
+                continue;
+            };
+            if x >= WIDTH || y >= HEIGHT {
+                continue;
+            }
+            let bit = 1 << (y % 8);
+            if color.is_on() {
+                self.0[y / 8][x] |= bit;
+            } else {
+                self.0[y / 8][x] &= !bit;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Frame {
+    /// Render `state` with the sensor CPI `cpi`
+    fn render(state: &State, cpi: u16) -> Self {
+        let mut frame = Frame([[0; WIDTH]; NB_PAGES]);
+        let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+        let mut lines: [String<{ WIDTH / 6 }>; 3] = Default::default();
+        let _ = write!(lines[0], "Layer {:<3} CPI {}", state.layer, cpi);
+        let link = if state.link_ok { "OK" } else { "ERR" };
+        let _ = write!(lines[1], "WPM {:<5} Link {}", state.wpm, link);
+        for (bit, name) in [
+            (NUM_LOCK, "NUM "),
+            (CAPS_LOCK, "CAPS "),
+            (SCROLL_LOCK, "SCRL"),
+        ] {
+            if state.locks & bit != 0 {
+                let _ = lines[2].push_str(name);
+            }
+        }
+        for (i, line) in lines.iter().enumerate() {
+            let position = Point::new(0, 11 * i as i32);
+            let _ = Text::with_baseline(line, position, style, Baseline::Top).draw(&mut frame);
+        }
+        frame
+    }
+}
+
+/// SSD1306 or SH1106 OLED display
+struct Display<I> {
+    /// I2C bus
+    i2c: I,
+}
+
+impl<I: I2c> Display<I> {
+    /// Send commands
+    async fn commands(&mut self, commands: &[u8]) -> Result<(), I::Error> {
+        let mut buf = [CONTROL_COMMANDS; 32];
+        buf[1..=commands.len()].copy_from_slice(commands);
+        self.i2c.write(ADDRESS, &buf[..=commands.len()]).await
+    }
+
+    /// Initialize the display. Fails when there is no display.
+    async fn init(&mut self) -> Result<(), I::Error> {
+        self.commands(INIT).await
+    }
+
+    /// Show `frame`
+    async fn flush(&mut self, frame: &Frame) -> Result<(), I::Error> {
+        let mut buf = [CONTROL_DATA; WIDTH + 1];
+        for (page, columns) in frame.0.iter().enumerate() {
+            self.commands(&[
+                0xB0 | page as u8,
+                COLUMN_OFFSET & 0xF,
+                0x10 | (COLUMN_OFFSET >> 4),
+            ])
+            .await?;
+            buf[1..].copy_from_slice(columns);
+            self.i2c.write(ADDRESS, &buf).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Send `event` to the other half
+async fn send(event: Event) {
+    if SIDE_CHANNEL.is_full() {
+        error!("Side channel is full");
+    }
+    SIDE_CHANNEL.send(event).await;
+}
+
+/// Display task: shows the state whenever it changes. On the host half, it
+/// also updates the typing speed every second and forwards the typing
+/// speed and the lock indicators to the other half, even without a
+/// display.
+#[embassy_executor::task]
+pub async fn run(i2c: SharedI2c) {
+    let mut display = Display { i2c };
+    let present = display.init().await.is_ok();
+    if present {
+        info!("Display found");
+    } else {
+        info!("No display found");
+    }
+    let mut ticker = Ticker::every(Duration::from_secs(1));
+    // State last shown
+    let mut shown: Option<(State, u16)> = None;
+    // State last forwarded to the other half
+    let mut forwarded: Option<State> = None;
+    loop {
+        if let Either::Second(()) = select(REDRAW.wait(), ticker.next()).await {
+            if is_host() {
+                set_wpm(crate::core::wpm().min(MAX_DISPLAY_WPM as u16) as u8);
+            }
+        }
+        let state = STATE.lock(|s| *s.borrow());
+
+        if is_host() {
+            if forwarded.map_or(true, |f| f.wpm != state.wpm) {
+                send(Event::DisplayWpm(state.wpm)).await;
+            }
+            if forwarded.map_or(true, |f| f.locks != state.locks) {
+                send(Event::DisplayLocks(state.locks)).await;
+            }
+            forwarded = Some(state);
+        }
+
+        // The CPI may be changed at any time, so it is checked every second
+        let cpi = settings::get().cpi;
+        if present && shown != Some((state, cpi)) {
+            if display.flush(&Frame::render(&state, cpi)).await.is_err() {
+                error!("Failed to update the display");
+            }
+            shown = Some((state, cpi));
+        }
+    }
+}
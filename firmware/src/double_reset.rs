@@ -0,0 +1,53 @@
+use crate::chip;
+use crate::status_led;
+use core::mem::MaybeUninit;
+use core::ptr::{addr_of_mut, read_volatile, write_volatile};
+use embassy_rp::pac;
+use embassy_time::Timer;
+use utils::log::info;
+
+/// Magic value set in RAM while within the double reset window
+const DOUBLE_RESET_MAGIC: u32 = 0xf016_81de;
+/// Time window after boot during which a second reset enters the
+/// bootloader, in ms
+const DOUBLE_RESET_WINDOW_MS: u64 = 500;
+
+/// Double reset flag in the `.uninit` section: not initialized on boot, so
+/// it survives a reset
+#[link_section = ".uninit.DOUBLE_RESET"]
+static mut DOUBLE_RESET: MaybeUninit<u32> = MaybeUninit::uninit();
+
+/// Pointer to the double reset flag
+fn flag() -> *mut u32 {
+    // SAFETY: only the address of the flag is taken
+    unsafe { (*addr_of_mut!(DOUBLE_RESET)).as_mut_ptr() }
+}
+
+/// Enter the bootloader (BOOTSEL mode) if the keyboard was reset twice in a
+/// row within `DOUBLE_RESET_WINDOW_MS`.
+/// Otherwise, arm the double reset detection: the `disarm` task must then be
+/// spawned to clear it at the end of the window.
+/// Resets caused by the watchdog or by a panic are ignored.
+pub fn check(after_panic: bool) {
+    let reason = pac::WATCHDOG.reason().read();
+    let after_watchdog = reason.timer() || reason.force();
+    // SAFETY: the flag is only accessed from this module, from thread mode
+    unsafe {
+        if read_volatile(flag()) == DOUBLE_RESET_MAGIC && !after_watchdog && !after_panic {
+            write_volatile(flag(), 0);
+            info!("Double reset detected, entering the bootloader");
+            chip::reset_to_usb_boot();
+        }
+        write_volatile(flag(), DOUBLE_RESET_MAGIC);
+    }
+    status_led::set_bootloader_pending(true);
+}
+
+/// Clear the double reset flag at the end of the detection window
+#[embassy_executor::task]
+pub async fn disarm() {
+    Timer::after_millis(DOUBLE_RESET_WINDOW_MS).await;
+    // SAFETY: the flag is only accessed from this module, from thread mode
+    unsafe { write_volatile(flag(), 0) };
+    status_led::set_bootloader_pending(false);
+}
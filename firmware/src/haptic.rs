@@ -0,0 +1,124 @@
+use crate::channels::{self, Queue, HAPTIC_DEPTH};
+use crate::i2c_bus::SharedI2c;
+use crate::settings;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use utils::log::{error, info};
+
+/// I2C address of the DRV2605L
+const ADDRESS: u8 = 0x5A;
+
+/// Status register, holding the device ID
+const REG_STATUS: u8 = 0x00;
+/// Mode register
+const REG_MODE: u8 = 0x01;
+/// Waveform library selection register
+const REG_LIBRARY: u8 = 0x03;
+/// First waveform sequencer register
+const REG_WAVESEQ1: u8 = 0x04;
+/// Second waveform sequencer register
+const REG_WAVESEQ2: u8 = 0x05;
+/// Go register, to play the waveform sequence
+const REG_GO: u8 = 0x0C;
+/// Feedback control register
+const REG_FEEDBACK: u8 = 0x1A;
+
+/// Device ID of the DRV2605, in the status register
+const DEVICE_ID_DRV2605: u8 = 3;
+/// Device ID of the DRV2605L, in the status register
+const DEVICE_ID_DRV2605L: u8 = 7;
+/// Internal trigger mode, out of standby
+const MODE_INTERNAL_TRIGGER: u8 = 0x00;
+/// Feedback control for a Linear Resonance Actuator, as found on the
+/// common haptic boards: default values with the LRA bit set
+const FEEDBACK_LRA: u8 = 0xB6;
+/// ROM library for LRAs
+const LIBRARY_LRA: u8 = 6;
+
+/// Events giving a haptic feedback
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HapticEvent {
+    /// Tap on the trackpad
+    Tap,
+    /// Layer change
+    Layer,
+    /// Mouse layer activated by the pointer
+    AutoMouse,
+}
+
+/// Channel to request haptic feedbacks
+static HAPTIC_CHANNEL: Channel<CriticalSectionRawMutex, HapticEvent, HAPTIC_DEPTH> = Channel::new();
+
+/// Request the haptic feedback of `event`. Dropped when the feedbacks
+/// cannot keep up or when there is no haptic driver.
+pub fn trigger(event: HapticEvent) {
+    let _ = HAPTIC_CHANNEL.try_send(event);
+}
+
+/// DRV2605L haptic driver
+struct Drv2605l<I> {
+    /// I2C bus
+    i2c: I,
+}
+
+impl<I: embedded_hal_async::i2c::I2c> Drv2605l<I> {
+    /// Write `value` to the register `reg`
+    async fn write(&mut self, reg: u8, value: u8) -> Result<(), I::Error> {
+        self.i2c.write(ADDRESS, &[reg, value]).await
+    }
+
+    /// Read the register `reg`
+    async fn read(&mut self, reg: u8) -> Result<u8, I::Error> {
+        let mut value = [0u8];
+        self.i2c.write_read(ADDRESS, &[reg], &mut value).await?;
+        Ok(value[0])
+    }
+
+    /// Check that the driver is present and configure it for an LRA.
+    /// Returns whether it is present.
+    async fn init(&mut self) -> Result<bool, I::Error> {
+        let device_id = self.read(REG_STATUS).await? >> 5;
+        if device_id != DEVICE_ID_DRV2605 && device_id != DEVICE_ID_DRV2605L {
+            return Ok(false);
+        }
+        self.write(REG_MODE, MODE_INTERNAL_TRIGGER).await?;
+        self.write(REG_FEEDBACK, FEEDBACK_LRA).await?;
+        self.write(REG_LIBRARY, LIBRARY_LRA).await?;
+        Ok(true)
+    }
+
+    /// Play the effect `effect` of the ROM library
+    async fn play(&mut self, effect: u8) -> Result<(), I::Error> {
+        self.write(REG_WAVESEQ1, effect).await?;
+        // End of the sequence
+        self.write(REG_WAVESEQ2, 0).await?;
+        self.write(REG_GO, 1).await
+    }
+}
+
+/// Haptic feedback task. Returns at once when there is no DRV2605L on the
+/// I2C bus.
+#[embassy_executor::task]
+pub async fn run(i2c: SharedI2c) {
+    let mut driver = Drv2605l { i2c };
+    match driver.init().await {
+        Ok(true) => info!("Haptic driver found"),
+        Ok(false) | Err(_) => {
+            info!("No haptic driver found");
+            return;
+        }
+    }
+    loop {
+        let event = HAPTIC_CHANNEL.receive().await;
+        channels::record(Queue::Haptic, HAPTIC_CHANNEL.len());
+        let haptics = settings::get().haptics;
+        let effect = match event {
+            HapticEvent::Tap => haptics.tap,
+            HapticEvent::Layer => haptics.layer,
+            HapticEvent::AutoMouse => haptics.auto_mouse,
+        };
+        if effect != 0 && driver.play(effect).await.is_err() {
+            error!("Failed to play the haptic effect {}", effect);
+        }
+    }
+}
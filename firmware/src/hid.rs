@@ -1,247 +1,111 @@
+#[cfg(feature = "buzzer")]
+use crate::buzzer::{self, Sound};
+use crate::channels::{self, Queue, HID_REPORTS_DEPTH};
 use crate::core::LAYOUT_CHANNEL;
-use crate::device::is_host;
+use crate::device::{usb_state, UsbState};
+use crate::display::{self, CAPS_LOCK, NUM_LOCK, SCROLL_LOCK};
+#[cfg(feature = "timing_logs")]
+use crate::metrics;
+use crate::watchdog::{self, Task, HEARTBEAT_PERIOD_MS};
 use embassy_executor::Spawner;
+use embassy_futures::select::{select, select3, Either, Either3};
 use embassy_rp::peripherals::USB;
 use embassy_rp::usb::Driver;
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
-use embassy_usb::class::hid::{ReportId, RequestHandler};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal,
+};
+use embassy_time::{Duration, Timer};
+use embassy_usb::class::hid::{HidProtocolMode, ReportId, RequestHandler};
 use embassy_usb::control::OutResponse;
+use portable_atomic::{AtomicBool, Ordering};
 use utils::log::{error, info, warn};
 
-/// Only one report is sent at a time
-const NB_REPORTS: usize = 128;
-/// Channel to send HID keyboard reports to the HID writer
-pub static HID_KB_CHANNEL: Channel<ThreadModeRawMutex, KeyboardReport, NB_REPORTS> = Channel::new();
+pub use utils::hid::{
+    ConsumerReport, KeyboardReport, MouseReport, SystemReport, CONSUMER_REPORT_DESCRIPTOR,
+    KB_REPORT_DESCRIPTOR, MOUSE_REPORT_DESCRIPTOR, SYSTEM_REPORT_DESCRIPTOR,
+};
+
+/// Channel to send HID keyboard reports to the HID writer, through
+/// `send_kb_report`
+static HID_KB_CHANNEL: Channel<CriticalSectionRawMutex, KeyboardReport, HID_REPORTS_DEPTH> =
+    Channel::new();
+/// Latest keyboard report, once `HID_KB_CHANNEL` is full: the reports are
+/// coalesced into it until the HID writer catches up
+static HID_KB_PENDING: Signal<CriticalSectionRawMutex, KeyboardReport> = Signal::new();
 /// Channel to send HID consumer control reports to the HID writer
-pub static HID_CONSUMER_CHANNEL: Channel<ThreadModeRawMutex, ConsumerReport, NB_REPORTS> =
+pub static HID_CONSUMER_CHANNEL: Channel<
+    CriticalSectionRawMutex,
+    ConsumerReport,
+    HID_REPORTS_DEPTH,
+> = Channel::new();
+/// Channel to send HID system control reports to the HID writer
+pub static HID_SYSTEM_CHANNEL: Channel<CriticalSectionRawMutex, SystemReport, HID_REPORTS_DEPTH> =
     Channel::new();
 
+/// Whether the host selected the boot protocol for the keyboard, as BIOSes
+/// do. The report protocol is the default, restored on bus resets.
+static BOOT_PROTOCOL: AtomicBool = AtomicBool::new(false);
+
+/// Select the boot protocol for the keyboard, or the report protocol
+pub fn set_boot_protocol(boot: bool) {
+    if BOOT_PROTOCOL.swap(boot, Ordering::Relaxed) != boot {
+        info!(
+            "Keyboard protocol: {}",
+            if boot { "boot" } else { "report" }
+        );
+    }
+}
+
 /// HID writer type for keyboard (8 bytes)
 pub type HidWriter<'a, 'b> = embassy_usb::class::hid::HidWriter<'a, Driver<'b, USB>, 8>;
 /// HID writer type for consumer control (2 bytes)
 pub type HidConsumerWriter<'a, 'b> = embassy_usb::class::hid::HidWriter<'a, Driver<'b, USB>, 2>;
+/// HID writer type for system control (1 byte)
+pub type HidSystemWriter<'a, 'b> = embassy_usb::class::hid::HidWriter<'a, Driver<'b, USB>, 1>;
 
 #[rustfmt::skip]
-/// Keyboard HID report descriptor
-pub const KB_REPORT_DESCRIPTOR: &[u8] = &[
-    0x05, 0x01,        // Usage Page (Generic Desktop Ctrls)
-    0x09, 0x06,        // Usage (Keyboard)
+/// Raw HID report descriptor, used by the configuration protocol.
+/// Same usage page and usage as QMK's raw HID.
+pub const RAW_HID_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xFF,  // Usage Page (Vendor Defined 0xFF60)
+    0x09, 0x61,        // Usage (0x61)
     0xA1, 0x01,        // Collection (Application)
-    0x05, 0x07,        //   Usage Page (Kbrd/Keypad)
-    0x19, 0xE0,        //   Usage Minimum (0xE0)
-    0x29, 0xE7,        //   Usage Maximum (0xE7)
+    0x09, 0x62,        //   Usage (0x62)
     0x15, 0x00,        //   Logical Minimum (0)
-    0x25, 0x01,        //   Logical Maximum (1)
-    0x75, 0x01,        //   Report Size (1)
-    0x95, 0x08,        //   Report Count (8)
-    0x81, 0x02,        //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
-    0x19, 0x00,        //   Usage Minimum (0x00)
-    0x29, 0xFF,        //   Usage Maximum (0xFF)
     0x26, 0xFF, 0x00,  //   Logical Maximum (255)
+    0x95, 0x20,        //   Report Count (32)
     0x75, 0x08,        //   Report Size (8)
-    0x95, 0x01,        //   Report Count (1)
-    0x81, 0x03,        //   Input (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
-    0x05, 0x08,        //   Usage Page (LEDs)
-    0x19, 0x01,        //   Usage Minimum (Num Lock)
-    0x29, 0x05,        //   Usage Maximum (Kana)
-    0x25, 0x01,        //   Logical Maximum (1)
-    0x75, 0x01,        //   Report Size (1)
-    0x95, 0x05,        //   Report Count (5)
-    0x91, 0x02,        //   Output (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-    0x95, 0x03,        //   Report Count (3)
-    0x91, 0x03,        //   Output (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-    0x05, 0x07,        //   Usage Page (Kbrd/Keypad)
-    0x19, 0x00,        //   Usage Minimum (0x00)
-    0x29, 0xDD,        //   Usage Maximum (0xDD)
+    0x81, 0x02,        //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x09, 0x63,        //   Usage (0x63)
+    0x15, 0x00,        //   Logical Minimum (0)
     0x26, 0xFF, 0x00,  //   Logical Maximum (255)
+    0x95, 0x20,        //   Report Count (32)
     0x75, 0x08,        //   Report Size (8)
-    0x95, 0x06,        //   Report Count (6)
-    0x81, 0x00,        //   Input (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
-    0xC0,              // End Collection
-// 69 bytes
-];
-
-#[rustfmt::skip]
-/// Mouse HID report descriptor
-pub const MOUSE_REPORT_DESCRIPTOR: &[u8] = &[
-    0x05, 0x01,        // Usage Page (Generic Desktop Ctrls)
-    0x09, 0x02,        // Usage (Mouse)
-    0xA1, 0x01,        // Collection (Application)
-    0x09, 0x01,        //   Usage (Pointer)
-    0xA1, 0x00,        //   Collection (Physical)
-    0x05, 0x09,        //     Usage Page (Button)
-    0x19, 0x01,        //     Usage Minimum (0x01)
-    0x29, 0x05,        //     Usage Maximum (0x05)
-    0x15, 0x00,        //     Logical Minimum (0)
-    0x25, 0x01,        //     Logical Maximum (1)
-    0x95, 0x05,        //     Report Count (5)
-    0x75, 0x01,        //     Report Size (1)
-    0x81, 0x02,        //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
-    0x95, 0x01,        //     Report Count (1)
-    0x75, 0x03,        //     Report Size (3)
-    0x81, 0x01,        //     Input (Const,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
-    0x05, 0x01,        //     Usage Page (Generic Desktop Ctrls)
-    0x09, 0x30,        //     Usage (X)
-    0x09, 0x31,        //     Usage (Y)
-    0x16, 0x00, 0x80,  //     Logical Minimum (-32768)
-    0x26, 0xFF, 0x7F,  //     Logical Maximum (32767)
-    0x75, 0x10,        //     Report Size (16)
-    0x95, 0x02,        //     Report Count (2)
-    0x81, 0x06,        //     Input (Data,Var,Rel,No Wrap,Linear,Preferred State,No Null Position)
-    0xC0,              //   End Collection
-    0xA1, 0x00,        //   Collection (Physical)
-    0x05, 0x01,        //     Usage Page (Generic Desktop Ctrls)
-    0x09, 0x38,        //     Usage (Wheel)
-    0x15, 0x81,        //     Logical Minimum (-127)
-    0x25, 0x7F,        //     Logical Maximum (127)
-    0x75, 0x08,        //     Report Size (8)
-    0x95, 0x01,        //     Report Count (1)
-    0x81, 0x06,        //     Input (Data,Var,Rel,No Wrap,Linear,Preferred State,No Null Position)
-    0xC0,              //   End Collection
-    0xA1, 0x00,        //   Collection (Physical)
-    0x05, 0x0C,        //     Usage Page (Consumer)
-    0x0A, 0x38, 0x02,  //     Usage (AC Pan)
-    0x95, 0x01,        //     Report Count (1)
-    0x75, 0x08,        //     Report Size (8)
-    0x15, 0x81,        //     Logical Minimum (-127)
-    0x25, 0x7F,        //     Logical Maximum (127)
-    0x81, 0x06,        //     Input (Data,Var,Rel,No Wrap,Linear,Preferred State,No Null Position)
-    0xC0,              //   End Collection
-    0xC0,              // End Collection
-// 87 bytes
-];
-
-#[rustfmt::skip]
-/// Consumer Control HID report descriptor
-/// Supports media keys like Play/Pause, Volume Up/Down, Mute, etc.
-pub const CONSUMER_REPORT_DESCRIPTOR: &[u8] = &[
-    0x05, 0x0C,        // Usage Page (Consumer)
-    0x09, 0x01,        // Usage (Consumer Control)
-    0xA1, 0x01,        // Collection (Application)
-    0x19, 0x00,        //   Usage Minimum (0x00)
-    0x2A, 0x3C, 0x02,  //   Usage Maximum (0x023C)
-    0x15, 0x00,        //   Logical Minimum (0)
-    0x26, 0x3C, 0x02,  //   Logical Maximum (572)
-    0x95, 0x01,        //   Report Count (1)
-    0x75, 0x10,        //   Report Size (16)
-    0x81, 0x00,        //   Input (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x91, 0x02,        //   Output (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
     0xC0,              // End Collection
-// 21 bytes
+// 34 bytes
 ];
 
-/// Keyboard HID report
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[derive(Debug, Default, PartialEq, Clone, Copy)]
-pub struct KeyboardReport {
-    /// Modifier keys, in the following order (from least significant bit):
-    /// - Left Control
-    /// - Left Shift
-    /// - Left Alt
-    /// - Left GUI
-    /// - Right Control
-    /// - Right Shift
-    /// - Right Alt
-    /// - Right GUI
-    pub modifier: u8,
-    /// Keycodes for up to 6 simultaneously pressed keys
-    pub keycodes: [u8; 6],
-}
-
-impl KeyboardReport {
-    /// Serialize the report
-    pub fn serialize(&self) -> [u8; 8] {
-        [
-            self.modifier,
-            0u8,
-            self.keycodes[0],
-            self.keycodes[1],
-            self.keycodes[2],
-            self.keycodes[3],
-            self.keycodes[4],
-            self.keycodes[5],
-        ]
-    }
-}
-
-/// Mouse HID report
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[derive(Debug, Default, PartialEq, Clone, Copy)]
-pub struct MouseReport {
-    /// Buttons state
-    /// Button 1 to 8 where Button1 is the LSB
-    pub buttons: u8,
-    /// x movement
-    pub x: i16,
-    /// y movement
-    pub y: i16,
-    /// Scroll down (negative) or up (positive) this many units
-    pub wheel: i8,
-    /// Scroll left (negative) or right (positive) this many units
-    pub pan: i8,
-}
+/// Num lock state, shared by the request handlers
+static NUM_LOCK_ON: AtomicBool = AtomicBool::new(false);
+/// Caps lock state, shared by the request handlers
+static CAPS_LOCK_ON: AtomicBool = AtomicBool::new(false);
 
-impl MouseReport {
-    /// Serialize the report
-    pub fn serialize(&self) -> [u8; 7] {
-        let x = self.x.to_le_bytes();
-        let y = self.y.to_le_bytes();
-        [
-            self.buttons,
-            x[0],
-            x[1],
-            y[0],
-            y[1],
-            self.wheel as u8,
-            self.pan as u8,
-        ]
-    }
-}
-
-/// Consumer Control HID report
-/// Used for media keys (Play/Pause, Volume, etc.)
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[derive(Debug, Default, PartialEq, Clone, Copy)]
-pub struct ConsumerReport {
-    /// Consumer usage code (16-bit)
-    /// Common values:
-    /// - 0x0000: No key pressed (release)
-    /// - 0x00B5: Scan Next Track
-    /// - 0x00B6: Scan Previous Track
-    /// - 0x00CD: Play/Pause
-    /// - 0x00E2: Mute
-    /// - 0x00E9: Volume Up
-    /// - 0x00EA: Volume Down
-    pub usage: u16,
-}
-
-impl ConsumerReport {
-    /// Serialize the report
-    pub fn serialize(&self) -> [u8; 2] {
-        self.usage.to_le_bytes()
-    }
-}
-
-/// HID handler
-pub struct HidRequestHandler<'a> {
+/// HID handler, of the control requests of the keyboard interface and of
+/// the reports read on its OUT endpoint. The states it tracks are shared by
+/// all the handlers.
+pub struct HidRequestHandler {
     /// Spawner
-    spawner: &'a Spawner,
-    /// Num lock state
-    num_lock: bool,
-    /// Caps lock state
-    caps_lock: bool,
+    spawner: Spawner,
 }
-impl<'a> HidRequestHandler<'a> {
+impl HidRequestHandler {
     /// Create a new HID request handler
-    pub fn new(spawner: &'a Spawner) -> Self {
-        HidRequestHandler {
-            spawner,
-            num_lock: false,
-            caps_lock: false,
-        }
+    pub fn new(spawner: Spawner) -> Self {
+        HidRequestHandler { spawner }
     }
 }
 
-impl RequestHandler for HidRequestHandler<'_> {
+impl RequestHandler for HidRequestHandler {
     fn get_report(&mut self, _id: ReportId, _buf: &mut [u8]) -> Option<usize> {
         info!("Get report for {:?}", _id);
         None
@@ -252,7 +116,21 @@ impl RequestHandler for HidRequestHandler<'_> {
         if let ReportId::Out(0) = id {
             self.num_lock(data[0] & 1 != 0);
             self.caps_lock(data[0] & (1 << 1) != 0);
+            display::set_locks(data[0] & (NUM_LOCK | CAPS_LOCK | SCROLL_LOCK));
+        }
+        OutResponse::Accepted
+    }
+
+    fn get_protocol(&self) -> HidProtocolMode {
+        if BOOT_PROTOCOL.load(Ordering::Relaxed) {
+            HidProtocolMode::Boot
+        } else {
+            HidProtocolMode::Report
         }
+    }
+
+    fn set_protocol(&mut self, protocol: HidProtocolMode) -> OutResponse {
+        set_boot_protocol(protocol == HidProtocolMode::Boot);
         OutResponse::Accepted
     }
 
@@ -295,33 +173,97 @@ async fn num_lock_change() {
         .await;
 }
 
-impl HidRequestHandler<'_> {
+impl HidRequestHandler {
     /// Set the caps lock state. May not have changed.
     fn caps_lock(&mut self, caps_lock: bool) {
-        if self.caps_lock != caps_lock {
-            self.caps_lock = caps_lock;
+        if CAPS_LOCK_ON.swap(caps_lock, Ordering::Relaxed) != caps_lock {
+            #[cfg(feature = "buzzer")]
+            buzzer::play(if caps_lock {
+                Sound::LockOn
+            } else {
+                Sound::LockOff
+            });
             self.spawner.spawn(caps_lock_change().unwrap());
         }
     }
     /// Set the num lock state. May not have changed.
     fn num_lock(&mut self, num_lock: bool) {
-        if self.num_lock != num_lock {
-            self.num_lock = num_lock;
+        if NUM_LOCK_ON.swap(num_lock, Ordering::Relaxed) != num_lock {
+            #[cfg(feature = "buzzer")]
+            buzzer::play(if num_lock {
+                Sound::LockOn
+            } else {
+                Sound::LockOff
+            });
             self.spawner.spawn(num_lock_change().unwrap());
         }
     }
 }
 
+/// Whether the reports can be written: this half is the host and the host
+/// is not suspended. A write to a suspended host only completes once it
+/// resumes, stopping the heartbeat of the writer meanwhile: the reports are
+/// dropped instead.
+fn can_write() -> bool {
+    usb_state() == UsbState::Configured
+}
+
+/// Queue a keyboard report for the HID writer. When the host stops polling
+/// and the channel fills up, the following reports are coalesced into the
+/// latest one instead of queuing stale intermediate states, which would
+/// replay once the host polls again.
+pub fn send_kb_report(report: KeyboardReport) {
+    if HID_KB_PENDING.signaled() {
+        HID_KB_PENDING.signal(report);
+    } else if HID_KB_CHANNEL.try_send(report).is_err() {
+        warn!("HID KB channel is full, coalescing the reports");
+        HID_KB_PENDING.signal(report);
+    }
+}
+
+/// Next keyboard report to send: the queued ones first, then the coalesced
+/// one. `None` when none came within a heartbeat period.
+async fn next_kb_report() -> Option<KeyboardReport> {
+    if let Ok(report) = HID_KB_CHANNEL.try_receive() {
+        return Some(report);
+    }
+    match select3(
+        HID_KB_CHANNEL.receive(),
+        HID_KB_PENDING.wait(),
+        Timer::after(Duration::from_millis(HEARTBEAT_PERIOD_MS)),
+    )
+    .await
+    {
+        Either3::First(report) | Either3::Second(report) => Some(report),
+        Either3::Third(_) => None,
+    }
+}
+
 /// Loop to read HID KeyboardReport reports from the channel and send them over USB
 #[embassy_executor::task]
 pub async fn hid_kb_writer_handler(mut writer: HidWriter<'static, 'static>) {
+    watchdog::register(Task::HidKb);
     loop {
-        let hid_report = HID_KB_CHANNEL.receive().await;
-        if is_host() {
-            let raw = hid_report.serialize();
-            match writer.write(&raw).await {
-                Ok(()) => {}
-                Err(_e) => warn!("Failed to send report: {:?}", _e),
+        let Some(hid_report) = next_kb_report().await else {
+            watchdog::heartbeat(Task::HidKb);
+            continue;
+        };
+        watchdog::heartbeat(Task::HidKb);
+        channels::record(Queue::HidKb, HID_KB_CHANNEL.len());
+        if can_write() {
+            let raw = if BOOT_PROTOCOL.load(Ordering::Relaxed) {
+                hid_report.to_boot().serialize()
+            } else {
+                hid_report.serialize()
+            };
+            // The host may get suspended during the write
+            match select(writer.write(&raw), Timer::after_millis(HEARTBEAT_PERIOD_MS)).await {
+                #[cfg(feature = "timing_logs")]
+                Either::First(Ok(())) => metrics::report_written(),
+                #[cfg(not(feature = "timing_logs"))]
+                Either::First(Ok(())) => {}
+                Either::First(Err(_e)) => warn!("Failed to send report: {:?}", _e),
+                Either::Second(_) => warn!("Report dropped, the host does not poll"),
             }
         }
     }
@@ -330,13 +272,58 @@ pub async fn hid_kb_writer_handler(mut writer: HidWriter<'static, 'static>) {
 /// Loop to read HID ConsumerReport reports from the channel and send them over USB
 #[embassy_executor::task]
 pub async fn hid_consumer_writer_handler(mut writer: HidConsumerWriter<'static, 'static>) {
+    watchdog::register(Task::HidConsumer);
     loop {
-        let hid_report = HID_CONSUMER_CHANNEL.receive().await;
-        if is_host() {
+        let hid_report = match select(
+            HID_CONSUMER_CHANNEL.receive(),
+            Timer::after(Duration::from_millis(HEARTBEAT_PERIOD_MS)),
+        )
+        .await
+        {
+            Either::First(report) => report,
+            Either::Second(_) => {
+                watchdog::heartbeat(Task::HidConsumer);
+                continue;
+            }
+        };
+        watchdog::heartbeat(Task::HidConsumer);
+        channels::record(Queue::HidConsumer, HID_CONSUMER_CHANNEL.len());
+        if can_write() {
+            let raw = hid_report.serialize();
+            match select(writer.write(&raw), Timer::after_millis(HEARTBEAT_PERIOD_MS)).await {
+                Either::First(Ok(())) => {}
+                Either::First(Err(_e)) => warn!("Failed to send consumer report: {:?}", _e),
+                Either::Second(_) => warn!("Consumer report dropped, the host does not poll"),
+            }
+        }
+    }
+}
+
+/// Loop to read HID SystemReport reports from the channel and send them over USB
+#[embassy_executor::task]
+pub async fn hid_system_writer_handler(mut writer: HidSystemWriter<'static, 'static>) {
+    watchdog::register(Task::HidSystem);
+    loop {
+        let hid_report = match select(
+            HID_SYSTEM_CHANNEL.receive(),
+            Timer::after(Duration::from_millis(HEARTBEAT_PERIOD_MS)),
+        )
+        .await
+        {
+            Either::First(report) => report,
+            Either::Second(_) => {
+                watchdog::heartbeat(Task::HidSystem);
+                continue;
+            }
+        };
+        watchdog::heartbeat(Task::HidSystem);
+        channels::record(Queue::HidSystem, HID_SYSTEM_CHANNEL.len());
+        if can_write() {
             let raw = hid_report.serialize();
-            match writer.write(&raw).await {
-                Ok(()) => {}
-                Err(_e) => warn!("Failed to send consumer report: {:?}", _e),
+            match select(writer.write(&raw), Timer::after_millis(HEARTBEAT_PERIOD_MS)).await {
+                Either::First(Ok(())) => {}
+                Either::First(Err(_e)) => warn!("Failed to send system report: {:?}", _e),
+                Either::Second(_) => warn!("System report dropped, the host does not poll"),
             }
         }
     }
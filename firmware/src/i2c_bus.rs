@@ -0,0 +1,21 @@
+use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
+use embassy_rp::{
+    i2c::{Async, I2c},
+    peripherals::I2C1,
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use static_cell::StaticCell;
+
+/// I2C bus of the optional peripherals
+type Bus = Mutex<CriticalSectionRawMutex, I2c<'static, I2C1, Async>>;
+/// Peripheral on the shared I2C bus
+pub type SharedI2c = I2cDevice<'static, CriticalSectionRawMutex, I2c<'static, I2C1, Async>>;
+
+/// The shared I2C bus
+static BUS: StaticCell<Bus> = StaticCell::new();
+
+/// Share the I2C bus between `N` peripherals
+pub fn share<const N: usize>(i2c: I2c<'static, I2C1, Async>) -> [SharedI2c; N] {
+    let bus = BUS.init(Mutex::new(i2c));
+    core::array::from_fn(|_| I2cDevice::new(bus))
+}
@@ -0,0 +1,106 @@
+use crate::keys::{FULL_COLS, ROWS};
+#[cfg(feature = "persist_key_stats")]
+use crate::settings::{self, FLASH_SIZE};
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+#[cfg(feature = "persist_key_stats")]
+use embassy_time::Timer;
+#[cfg(feature = "persist_key_stats")]
+use portable_atomic::{AtomicBool, Ordering};
+use utils::key_stats::KeyStats;
+#[cfg(feature = "persist_key_stats")]
+use utils::log::error;
+use utils::log::info;
+#[cfg(feature = "persist_key_stats")]
+use utils::settings::{REGION_SIZE, SECTOR_SIZE};
+
+/// Presses of each key of both halves
+pub type Stats = KeyStats<ROWS, FULL_COLS>;
+
+/// Offset of the key statistics sector in flash: the sector before the
+/// settings region, also excluded from the firmware in `memory.x`
+#[cfg(feature = "persist_key_stats")]
+const SECTOR_OFFSET: u32 = FLASH_SIZE as u32 - REGION_SIZE - SECTOR_SIZE;
+/// Period of the saving of the statistics, when they changed, in s. Long
+/// enough for the sector to outlive the keyboard.
+#[cfg(feature = "persist_key_stats")]
+const SAVE_PERIOD_S: u64 = 15 * 60;
+
+/// Key presses counted so far
+static STATS: Mutex<CriticalSectionRawMutex, RefCell<Stats>> =
+    Mutex::new(RefCell::new(Stats::new()));
+/// Whether the statistics changed since they were last saved
+#[cfg(feature = "persist_key_stats")]
+static CHANGED: AtomicBool = AtomicBool::new(false);
+
+/// Count a press of the key at `row`, `col`
+pub fn on_press(row: u8, col: u8) {
+    STATS.lock(|s| s.borrow_mut().on_press(row, col));
+    #[cfg(feature = "persist_key_stats")]
+    CHANGED.store(true, Ordering::Relaxed);
+}
+
+/// Write the counts of the keys from index `first` on to `buf`, see
+/// `KeyStats::write_counts`
+pub fn write_counts(first: usize, buf: &mut [u8]) -> usize {
+    STATS.lock(|s| s.borrow().write_counts(first, buf))
+}
+
+/// Log the presses of each key, row by row, and the typing speed
+pub fn dump() {
+    STATS.lock(|s| {
+        let stats = s.borrow();
+        for _row in 0..ROWS {
+            let _counts: [u32; FULL_COLS] = core::array::from_fn(|c| stats.count(_row, c));
+            info!("[KEYS] row {}: {:?}", _row, _counts);
+        }
+    });
+    info!("[KEYS] {} WPM", crate::core::wpm());
+}
+
+/// Forget the key presses counted so far
+pub fn reset() {
+    STATS.lock(|s| s.borrow_mut().reset());
+    #[cfg(feature = "persist_key_stats")]
+    CHANGED.store(true, Ordering::Relaxed);
+}
+
+/// Load the statistics saved in flash, if any
+#[cfg(feature = "persist_key_stats")]
+pub async fn load() {
+    let mut record = [0u8; Stats::RECORD_SIZE];
+    if let Err(_e) = settings::with_flash(|f| f.blocking_read(SECTOR_OFFSET, &mut record)).await {
+        error!("Failed to read the key statistics: {:?}", _e);
+        return;
+    }
+    match Stats::from_record(&record) {
+        Some(stats) => {
+            info!("Key statistics loaded");
+            STATS.lock(|s| s.replace(stats));
+        }
+        None => info!("No key statistics found"),
+    }
+}
+
+/// Save the statistics to flash periodically, when they changed
+#[cfg(feature = "persist_key_stats")]
+#[embassy_executor::task]
+pub async fn run() {
+    loop {
+        Timer::after_secs(SAVE_PERIOD_S).await;
+        if !CHANGED.swap(false, Ordering::Relaxed) {
+            continue;
+        }
+        let mut record = [0u8; Stats::RECORD_SIZE];
+        STATS.lock(|s| s.borrow().to_record(&mut record));
+        let res = settings::with_flash(|f| {
+            f.blocking_erase(SECTOR_OFFSET, SECTOR_OFFSET + SECTOR_SIZE)?;
+            f.blocking_write(SECTOR_OFFSET, &record)
+        })
+        .await;
+        match res {
+            Ok(()) => info!("Key statistics saved"),
+            Err(_e) => error!("Failed to save the key statistics: {:?}", _e),
+        }
+    }
+}
@@ -1,13 +1,26 @@
+use crate::actions::{GESC, LSPO, RSPC};
 use crate::core::CustomEvent::{self, *};
 use crate::keys::{FULL_COLS, ROWS};
 use keyberon::action::Action;
-use keyberon::layout::Layout;
+use keyberon::key_code::KeyCode;
+use keyberon::layout::{Layers, Layout};
+use utils::ballistics::PointerLayer;
+use utils::combos::Combo;
+use utils::hid::{KeyOverride, MOD_SHIFT};
+use utils::settings::Handedness;
+use utils::tap_dance;
 
 /// Number of layers
 pub const NB_LAYERS: usize = 2;
 
+/// Total number of columns, both halves
+pub const COLS: usize = FULL_COLS;
+
 /// Keyboard Layout type to mask the number of layers
-pub type KBLayout = Layout<FULL_COLS, ROWS, NB_LAYERS, CustomEvent>;
+pub type KBLayout = Layout<COLS, ROWS, NB_LAYERS, CustomEvent>;
+
+/// Layers of the keymap
+pub type KBLayers = Layers<COLS, ROWS, NB_LAYERS, CustomEvent>;
 
 /// Mouse left click
 const MLC: Action<CustomEvent> = Action::Custom(MouseLeftClick);
@@ -15,52 +28,137 @@ const MLC: Action<CustomEvent> = Action::Custom(MouseLeftClick);
 const MRC: Action<CustomEvent> = Action::Custom(MouseRightClick);
 /// Mouse middle click
 const MMC: Action<CustomEvent> = Action::Custom(MouseWheelClick);
+/// Move the pointer up
+const MSU: Action<CustomEvent> = Action::Custom(MouseUp);
+/// Move the pointer down
+const MSD: Action<CustomEvent> = Action::Custom(MouseDown);
+/// Move the pointer left
+const MSL: Action<CustomEvent> = Action::Custom(MouseLeft);
+/// Move the pointer right
+const MSR: Action<CustomEvent> = Action::Custom(MouseRight);
 /// Ball is Wheel
 const BIW: Action<CustomEvent> = Action::Custom(BallIsWheel);
-/// Increase sensor CPI
+/// Pointer 4 times slower while held
+const SNP: Action<CustomEvent> = Action::Custom(SniperMode(4));
+/// Toggle the drag scroll
+const DRG: Action<CustomEvent> = Action::Custom(ToggleDragScroll);
+/// Increase sensor CPI by 100
 #[cfg(feature = "cnano")]
-const INC: Action<CustomEvent> = Action::Custom(IncreaseCpi);
-#[cfg(feature = "dilemma")]
+const INC: Action<CustomEvent> = Action::Custom(IncreaseCpi(100));
+#[cfg(not(feature = "cnano"))]
 const INC: Action<CustomEvent> = Action::NoOp;
-/// Decrease sensor CPI
+/// Decrease sensor CPI by 100
 #[cfg(feature = "cnano")]
-const DEC: Action<CustomEvent> = Action::Custom(DecreaseCpi);
-#[cfg(feature = "dilemma")]
+const DEC: Action<CustomEvent> = Action::Custom(DecreaseCpi(100));
+#[cfg(not(feature = "cnano"))]
 const DEC: Action<CustomEvent> = Action::NoOp;
 /// Wheel up
-#[cfg(feature = "cnano")]
+#[cfg(not(feature = "dilemma"))]
 const WHUP: Action<CustomEvent> = Action::NoOp;
 #[cfg(feature = "dilemma")]
 const WHUP: Action<CustomEvent> = Action::Custom(WheelUp);
 /// Wheel down
-#[cfg(feature = "cnano")]
+#[cfg(not(feature = "dilemma"))]
 const WHDN: Action<CustomEvent> = Action::NoOp;
 #[cfg(feature = "dilemma")]
 const WHDN: Action<CustomEvent> = Action::Custom(WheelDown);
 
 /// RGB LED control
 const RGB: Action<CustomEvent> = Action::Custom(NextLedAnimation);
+/// Switch to the next settings profile
+const PRF: Action<CustomEvent> = Action::Custom(NextProfile);
+/// Switch to the next ballistic profile of the pointer
+const BAL: Action<CustomEvent> = Action::Custom(NextBallisticProfile);
+/// Toggle natural scrolling
+const NSC: Action<CustomEvent> = Action::Custom(ToggleNaturalScroll);
+/// Toggle autoshift
+const ASH: Action<CustomEvent> = Action::Custom(ToggleAutoShift);
+/// Space repeated 20 times per second, while held
+const TRB: Action<CustomEvent> = Action::Custom(Turbo {
+    keycode: KeyCode::Space as u8,
+    period_ms: 50,
+});
+/// Escape when tapped, Caps Lock when double tapped, Control when held
+const ESC: Action<CustomEvent> = Action::Custom(TapDance(0));
+/// Start recording the dynamic macro
+const MREC: Action<CustomEvent> = Action::Custom(MacroRecordStart(0));
+/// Stop recording the dynamic macro
+const MSTP: Action<CustomEvent> = Action::Custom(MacroRecordStop);
+/// Play the dynamic macro
+const MPLY: Action<CustomEvent> = Action::Custom(MacroPlay(0));
+/// Swap the hands while held
+const SWP: Action<CustomEvent> = Action::Custom(SwapHands);
+/// Lock the layer held
+const LCK: Action<CustomEvent> = Action::Custom(LayerLock);
+/// Repeat the last keys typed
+const REP: Action<CustomEvent> = Action::Custom(Repeat);
+/// Enter the steno mode
+const STN: Action<CustomEvent> = Action::Custom(EnterSteno);
+/// Put the computer to sleep
+const SLP: Action<CustomEvent> = Action::Custom(SystemSleep);
+/// Reset the settings to their defaults, when held
+const FRST: Action<CustomEvent> = Action::Custom(FactoryReset);
 /// Reset to USB Mass Storage
 const RST: Action<CustomEvent> = Action::Custom(ResetToUsbMassStorage);
+/// Lock the input, to clean the keyboard
+const LCKI: Action<CustomEvent> = Action::Custom(LockInput);
+/// Store that the half plugged to the host is the left one
+const HDL: Action<CustomEvent> = Action::Custom(SetHandedness(Handedness::Left));
+/// Store that the half plugged to the host is the right one
+const HDR: Action<CustomEvent> = Action::Custom(SetHandedness(Handedness::Right));
 
 /// No mouse action
 const NOM: Action<CustomEvent> = Action::Custom(NoMouseAction);
+/// Dump the trace of the events between the tasks
+const TRC: Action<CustomEvent> = Action::Custom(DumpTrace);
 
 // Virtual mouse key row/col
 pub const VIRTUAL_MOUSE_KEY: (u8, u8) = (3, 0);
 
+/// Keys to press together to unlock the input: the four corners
+pub const UNLOCK_CHORD: [(u8, u8); 4] = [(0, 0), (0, 9), (2, 0), (2, 9)];
+
+/// Keys not cancelling the auto-mouse mode: none
+pub const AUTO_MOUSE_EXCLUDED_KEYS: [(u8, u8); 0] = [];
+
+/// Key overrides: Shift + Backspace for Delete
+pub static KEY_OVERRIDES: [KeyOverride; 1] = [KeyOverride {
+    modifiers: MOD_SHIFT,
+    key: KeyCode::BSpace as u8,
+    replacement: KeyCode::Delete as u8,
+}];
+
+/// Keys of the tap dance keys, by index
+pub static TAP_DANCES: [tap_dance::TapDance<KeyCode>; 1] = [tap_dance::TapDance {
+    tap: KeyCode::Escape,
+    double_tap: KeyCode::CapsLock,
+    hold: KeyCode::LCtrl,
+}];
+
+/// Combos: J and K pressed together for Escape
+pub static COMBOS: [Combo<KeyCode>; 1] = [Combo {
+    keys: &[(1, 6), (1, 7)],
+    key: KeyCode::Escape,
+}];
+
+/// Color index of the RGB LEDs on each layer, 0 to run the RGB animation
+pub const LAYER_COLORS: [u8; NB_LAYERS] = [0, 1];
+
+/// Pointer behavior of each layer: speed, and whether the pointer scrolls
+pub const POINTER_LAYERS: [PointerLayer; NB_LAYERS] = [PointerLayer::DEFAULT; NB_LAYERS];
+
 #[rustfmt::skip]
 /// Layout
-pub static LAYERS: keyberon::layout::Layers<FULL_COLS, ROWS, NB_LAYERS, CustomEvent> = keyberon::layout::layout! {
+pub static LAYERS: KBLayers = keyberon::layout::layout! {
     { // 0: Base Layer
         [ Q  W  E  R  T      Y  U  I  O  P ],
         [ A  S  D  F  G      H  J  K  L  ; ],
         [ Z  X  C  V  B      N  M  ,  .  / ],
         [ n  n  1  2  3      4  5  n  n  n ],
     } { // Unreachable
-        [ n  n  n  n  n      n  n  n  n  n ],
-        [ {NOM} n n n n      n  n  n  n  n ],
-        [ {RST} n n n n      n  n  n  n  n ],
+        [ {SWP} {GESC} {LSPO} {RSPC} {LCKI} {MREC} {MSTP} {MPLY} {REP} {LCK} ],
+        [ {NOM} {PRF} {BAL} {NSC} {SLP} {ESC} {HDL} {HDR} {SNP} {DRG} ],
+        [ {RST} {FRST} {TRC} {TRB} {STN} {ASH} {MSL} {MSD} {MSU} {MSR} ],
         [ n {BIW} {INC} {DEC} {MLC}      {MRC} {MMC} {RGB} {WHUP} {WHDN} ],
     }
 };
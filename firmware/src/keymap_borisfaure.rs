@@ -1,12 +1,18 @@
 use crate::core::CustomEvent::{self, *};
 use crate::keys::{FULL_COLS, ROWS};
+use crate::tap_hold;
 use core::fmt::Debug;
 use keyberon::action::{
     d, k, l, m, Action, HoldTapAction, HoldTapConfig,
     SequenceEvent::{self, Filter, Press, Release, Restore, Tap},
 };
-use keyberon::key_code::KeyCode::*;
-use keyberon::layout::Layout;
+use keyberon::key_code::KeyCode::{self, *};
+use keyberon::layout::{Layers, Layout};
+use utils::ballistics::PointerLayer;
+use utils::combos::Combo;
+use utils::hid::KeyOverride;
+use utils::rgb_anims::MOUSE_COLOR_INDEX;
+use utils::tap_dance;
 
 /// Number of layers
 pub const NB_LAYERS: usize = 10;
@@ -17,8 +23,9 @@ pub const COLS: usize = FULL_COLS + 1;
 /// Keyboard Layout type to mask the number of layers
 pub type KBLayout = Layout<COLS, ROWS, NB_LAYERS, CustomEvent>;
 
-/// Timeout to consider a key as held
-const TIMEOUT: u16 = 200;
+/// Layers of the keymap
+pub type KBLayers = Layers<COLS, ROWS, NB_LAYERS, CustomEvent>;
+
 /// Disable tap_hold_interval
 const TAP_HOLD_INTERVAL: u16 = 0;
 
@@ -26,9 +33,9 @@ const TAP_HOLD_INTERVAL: u16 = 0;
 macro_rules! ht {
     ($h:expr, $t:expr) => {
         Action::HoldTap(&HoldTapAction {
-            timeout: TIMEOUT,
+            timeout: tap_hold::TIMEOUT,
             tap_hold_interval: TAP_HOLD_INTERVAL,
-            config: HoldTapConfig::Default,
+            config: HoldTapConfig::Custom(tap_hold::resolve),
             hold: $h,
             tap: $t,
         })
@@ -325,24 +332,24 @@ const MWC: Action<CustomEvent> = Action::Custom(MouseWheelClick);
 /// Ball is Wheel
 const BIW: Action<CustomEvent> = Action::Custom(BallIsWheel);
 
-/// Increase CPI
+/// Increase CPI by 100
 #[cfg(feature = "cnano")]
-const M1: Action<CustomEvent> = Action::Custom(IncreaseCpi);
-#[cfg(feature = "dilemma")]
+const M1: Action<CustomEvent> = Action::Custom(IncreaseCpi(100));
+#[cfg(not(feature = "cnano"))]
 const M1: Action<CustomEvent> = Action::Custom(MouseWheelClick);
-/// Decrease CPI
+/// Decrease CPI by 100
 #[cfg(feature = "cnano")]
-const M2: Action<CustomEvent> = Action::Custom(DecreaseCpi);
-#[cfg(feature = "dilemma")]
+const M2: Action<CustomEvent> = Action::Custom(DecreaseCpi(100));
+#[cfg(not(feature = "cnano"))]
 const M2: Action<CustomEvent> = Action::Custom(MouseWheelClick);
 
 /// Wheel up
-#[cfg(feature = "cnano")]
+#[cfg(not(feature = "dilemma"))]
 const WHUP: Action<CustomEvent> = Action::NoOp;
 #[cfg(feature = "dilemma")]
 const WHUP: Action<CustomEvent> = Action::Custom(WheelUp);
 /// Wheel down
-#[cfg(feature = "cnano")]
+#[cfg(not(feature = "dilemma"))]
 const WHDN: Action<CustomEvent> = Action::NoOp;
 #[cfg(feature = "dilemma")]
 const WHDN: Action<CustomEvent> = Action::Custom(WheelDown);
@@ -350,6 +357,20 @@ const WHDN: Action<CustomEvent> = Action::Custom(WheelDown);
 /// RGB LED control
 const RGB: Action<CustomEvent> = Action::Custom(NextLedAnimation);
 
+/// Switch to the next settings profile
+const PRF: Action<CustomEvent> = Action::Custom(NextProfile);
+
+/// Switch to the next ballistic profile of the pointer
+const BAL: Action<CustomEvent> = Action::Custom(NextBallisticProfile);
+/// Toggle natural scrolling
+const NSC: Action<CustomEvent> = Action::Custom(ToggleNaturalScroll);
+
+/// Put the computer to sleep
+const SLP: Action<CustomEvent> = Action::Custom(SystemSleep);
+
+/// Reset the settings to their defaults, when held
+const FRST: Action<CustomEvent> = Action::Custom(FactoryReset);
+
 /// Reset to USB Mass Storage
 const RST: Action<CustomEvent> = Action::Custom(ResetToUsbMassStorage);
 
@@ -359,12 +380,45 @@ const MSE: Action<CustomEvent> = l(L_MOUSE);
 /// Virtual mouse key row/col
 pub const VIRTUAL_MOUSE_KEY: (u8, u8) = (0, (COLS - 1) as u8);
 
+/// Keys to press together to unlock the input: the four corners
+pub const UNLOCK_CHORD: [(u8, u8); 4] = [(0, 0), (0, 9), (2, 0), (2, 9)];
+
+/// Keys not cancelling the auto-mouse mode, to click with modifiers: the
+/// modifiers of the mouse layer
+pub const AUTO_MOUSE_EXCLUDED_KEYS: [(u8, u8); 4] = [(0, 1), (1, 0), (2, 0), (2, 1)];
+
+/// Color index of the RGB LEDs on each layer, 0 to run the RGB animation
+pub const LAYER_COLORS: [u8; NB_LAYERS] = [
+    0, // Base: RGB animation
+    1, // LOWER: orange
+    2, // RAISE: green
+    3, // NUMBERS: purple
+    4, // MISC: blue
+    5, // TMUX: red
+    6, // Gaming: gray
+    7, // Caps: beige
+    8, // QWERTY: yellow
+    MOUSE_COLOR_INDEX,
+];
+
+/// Pointer behavior of each layer: speed, and whether the pointer scrolls
+pub const POINTER_LAYERS: [PointerLayer; NB_LAYERS] = [PointerLayer::DEFAULT; NB_LAYERS];
+
 /// No mouse action
 const NOM: Action<CustomEvent> = Action::Custom(NoMouseAction);
 
+/// Combos: none
+pub static COMBOS: [Combo<KeyCode>; 0] = [];
+
+/// Key overrides: none
+pub static KEY_OVERRIDES: [KeyOverride; 0] = [];
+
+/// Keys of the tap dance keys, by index: none
+pub static TAP_DANCES: [tap_dance::TapDance<KeyCode>; 0] = [];
+
 #[rustfmt::skip]
 /// Layout
-pub static LAYERS: keyberon::layout::Layers<COLS, ROWS, NB_LAYERS, CustomEvent> = keyberon::layout::layout! {
+pub static LAYERS: KBLayers = keyberon::layout::layout! {
    { /* 0: Coleman-DH */
 [  Q         {HT_W_W}   F          P         {HT_4_B}    {HT_4_K}   L         U  {HT_W_Y}     ;        {MSE}],
 [ {HT_C_A}    R         S         {HT_5_T}    G           M        {HT_3_N}   E  {HT_9_I}    {HT_C_O}  n],
@@ -386,9 +440,9 @@ pub static LAYERS: keyberon::layout::Layers<COLS, ROWS, NB_LAYERS, CustomEvent>
         [ ,  7  8  9  +                       +  F9  F10  F11  F12 n],
         [ n {VUNNUM} {UNNUM} {HT_1_SP} Tab  Enter {HT_2_BS} n n n  n],
     } { /* 4: MISC */
-        [ Pause  {GAME}           {COLEMAN}    {QWERTY}      n       n n n n   n    t],
-        [ {RGB}  VolDown          Mute         VolUp         n       n n n n   n    n],
-        [ {RST} MediaPreviousSong MediaPlayPause MediaNextSong n     n n n n {RST}  n],
+        [ Pause  {GAME}           {COLEMAN}    {QWERTY}     {FRST}   n n n n   n    t],
+        [ {RGB}  VolDown          Mute         VolUp       {PRF}     {BAL} {NSC} n n   n    n],
+        [ {RST} MediaPreviousSong MediaPlayPause MediaNextSong n     n n n {SLP} {RST}  n],
         [  n     n                {MLC}        {MWC}      {MRC}      MediaPlayPause n MediaPlayPause VolDown VolUp n],
     } { /* 5: TMUX */
         [ {T_6}   {T_7} {T_8}   {T_9}   {T_0}      {T_1}   {T_2}  {T_3}   {T_4}   {T_5}   t],
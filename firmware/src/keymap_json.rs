@@ -0,0 +1,71 @@
+use crate::core::CustomEvent;
+use crate::keys::{FULL_COLS, ROWS};
+use keyberon::action::Action;
+use keyberon::key_code::KeyCode;
+use keyberon::layout::{Layers, Layout};
+use utils::ballistics::PointerLayer;
+use utils::combos::Combo;
+use utils::hid::KeyOverride;
+use utils::tap_dance;
+
+// `NB_LAYERS`, `LAYER_COLORS`, `AUTO_MOUSE_LAYER` and `KEYS`, generated by
+// the build script from the JSON description of the keymap
+include!(concat!(env!("OUT_DIR"), "/keymap_json.rs"));
+
+/// Total number of columns, both halves, and a virtual one for the key
+/// pressed by the auto-mouse mode
+pub const COLS: usize = FULL_COLS + 1;
+
+/// Keyboard Layout type to mask the number of layers
+pub type KBLayout = Layout<COLS, ROWS, NB_LAYERS, CustomEvent>;
+
+/// Layers of the keymap
+pub type KBLayers = Layers<COLS, ROWS, NB_LAYERS, CustomEvent>;
+
+/// Virtual mouse key row/col, out of the matrix
+pub const VIRTUAL_MOUSE_KEY: (u8, u8) = (0, FULL_COLS as u8);
+
+/// Keys to press together to unlock the input: the four corners, above the
+/// thumb keys
+pub const UNLOCK_CHORD: [(u8, u8); 4] = [
+    (0, 0),
+    (0, (FULL_COLS - 1) as u8),
+    ((ROWS - 2) as u8, 0),
+    ((ROWS - 2) as u8, (FULL_COLS - 1) as u8),
+];
+
+/// Keys not cancelling the auto-mouse mode: none
+pub const AUTO_MOUSE_EXCLUDED_KEYS: [(u8, u8); 0] = [];
+
+/// Pointer behavior of each layer: speed, and whether the pointer scrolls
+pub const POINTER_LAYERS: [PointerLayer; NB_LAYERS] = [PointerLayer::DEFAULT; NB_LAYERS];
+
+/// Combos: none
+pub static COMBOS: [Combo<KeyCode>; 0] = [];
+
+/// Key overrides: none
+pub static KEY_OVERRIDES: [KeyOverride; 0] = [];
+
+/// Keys of the tap dance keys, by index: none
+pub static TAP_DANCES: [tap_dance::TapDance<KeyCode>; 0] = [];
+
+/// Layout
+pub static LAYERS: KBLayers = layers(&KEYS);
+
+/// Lay the keys of each layer out on the rows of the matrix, next to the
+/// virtual mouse key
+const fn layers(keys: &[[Action<CustomEvent>; ROWS * FULL_COLS]; NB_LAYERS]) -> KBLayers {
+    let mut layers = [[[Action::NoOp; COLS]; ROWS]; NB_LAYERS];
+    let mut layer = 0;
+    while layer < NB_LAYERS {
+        let mut key = 0;
+        while key < ROWS * FULL_COLS {
+            layers[layer][key / FULL_COLS][key % FULL_COLS] = keys[layer][key];
+            key += 1;
+        }
+        layers[layer][VIRTUAL_MOUSE_KEY.0 as usize][VIRTUAL_MOUSE_KEY.1 as usize] =
+            AUTO_MOUSE_LAYER;
+        layer += 1;
+    }
+    layers
+}
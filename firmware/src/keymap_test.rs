@@ -1,3 +1,4 @@
+use crate::actions::{GESC, LSPO, RSPC};
 use crate::core::CustomEvent::{self, *};
 use crate::keys::{FULL_COLS, ROWS};
 use core::fmt::Debug;
@@ -5,14 +6,24 @@ use keyberon::action::{
     Action,
     SequenceEvent::{self, *},
 };
-use keyberon::key_code::KeyCode::*;
-use keyberon::layout::Layout;
+use keyberon::key_code::KeyCode::{self, *};
+use keyberon::layout::{Layers, Layout};
+use utils::ballistics::PointerLayer;
+use utils::combos::Combo;
+use utils::hid::KeyOverride;
+use utils::tap_dance;
 
 /// Number of layers
 pub const NB_LAYERS: usize = 2;
 
+/// Total number of columns, both halves
+pub const COLS: usize = FULL_COLS;
+
 /// Keyboard Layout type to mask the number of layers
-pub type KBLayout = Layout<FULL_COLS, ROWS, NB_LAYERS, CustomEvent>;
+pub type KBLayout = Layout<COLS, ROWS, NB_LAYERS, CustomEvent>;
+
+/// Layers of the keymap
+pub type KBLayers = Layers<COLS, ROWS, NB_LAYERS, CustomEvent>;
 
 /// A shortcut to create a `Action::Sequence`, useful to
 /// create compact layout.
@@ -37,49 +48,75 @@ const MRC: Action<CustomEvent> = Action::Custom(MouseRightClick);
 const MMC: Action<CustomEvent> = Action::Custom(MouseWheelClick);
 /// Ball is Wheel
 const BIW: Action<CustomEvent> = Action::Custom(BallIsWheel);
-/// Increase sensor CPI
+/// Increase sensor CPI by 100
 #[cfg(feature = "cnano")]
-const INC: Action<CustomEvent> = Action::Custom(IncreaseCpi);
-#[cfg(feature = "dilemma")]
+const INC: Action<CustomEvent> = Action::Custom(IncreaseCpi(100));
+#[cfg(not(feature = "cnano"))]
 const INC: Action<CustomEvent> = Action::NoOp;
-/// Decrease sensor CPI
+/// Decrease sensor CPI by 100
 #[cfg(feature = "cnano")]
-const DEC: Action<CustomEvent> = Action::Custom(DecreaseCpi);
-#[cfg(feature = "dilemma")]
+const DEC: Action<CustomEvent> = Action::Custom(DecreaseCpi(100));
+#[cfg(not(feature = "cnano"))]
 const DEC: Action<CustomEvent> = Action::NoOp;
 /// RGB LED control
 const RGB: Action<CustomEvent> = Action::Custom(NextLedAnimation);
 /// Reset to USB Mass Storage
 const RST: Action<CustomEvent> = Action::Custom(ResetToUsbMassStorage);
 /// Wheel up
-#[cfg(feature = "cnano")]
+#[cfg(not(feature = "dilemma"))]
 const WHUP: Action<CustomEvent> = Action::NoOp;
 #[cfg(feature = "dilemma")]
 const WHUP: Action<CustomEvent> = Action::Custom(WheelUp);
 /// Wheel down
-#[cfg(feature = "cnano")]
+#[cfg(not(feature = "dilemma"))]
 const WHDN: Action<CustomEvent> = Action::NoOp;
 #[cfg(feature = "dilemma")]
 const WHDN: Action<CustomEvent> = Action::Custom(WheelDown);
 
+/// LOWER layer while held, or for the next key press when tapped
+const LT1: Action<CustomEvent> = Action::Custom(LatchLayer(1));
 /// No mouse action
 const NOM: Action<CustomEvent> = Action::Custom(NoMouseAction);
+/// Dump the trace of the events between the tasks
+const TRC: Action<CustomEvent> = Action::Custom(DumpTrace);
 
 // Virtual mouse key row/col
 pub const VIRTUAL_MOUSE_KEY: (u8, u8) = (3, 0);
 
+/// Keys to press together to unlock the input: the four corners
+pub const UNLOCK_CHORD: [(u8, u8); 4] = [(0, 0), (0, 9), (2, 0), (2, 9)];
+
+/// Keys not cancelling the auto-mouse mode: none
+pub const AUTO_MOUSE_EXCLUDED_KEYS: [(u8, u8); 0] = [];
+
+/// Color index of the RGB LEDs on each layer, 0 to run the RGB animation
+pub const LAYER_COLORS: [u8; NB_LAYERS] = [0, 1];
+
+/// Pointer behavior of each layer: speed, and whether the pointer scrolls
+pub const POINTER_LAYERS: [PointerLayer; NB_LAYERS] =
+    [PointerLayer::DEFAULT, PointerLayer::speed(50)];
+
+/// Combos: none
+pub static COMBOS: [Combo<KeyCode>; 0] = [];
+
+/// Key overrides: none
+pub static KEY_OVERRIDES: [KeyOverride; 0] = [];
+
+/// Keys of the tap dance keys, by index: none
+pub static TAP_DANCES: [tap_dance::TapDance<KeyCode>; 0] = [];
+
 #[rustfmt::skip]
 /// Layout
-pub static LAYERS: keyberon::layout::Layers<FULL_COLS, ROWS, NB_LAYERS, CustomEvent> = keyberon::layout::layout! {
+pub static LAYERS: KBLayers = keyberon::layout::layout! {
     { // 0: Base Layer
         [ {QQ}  W   E   R  T      Y  U  I  O  P ],
         [  A   S   D   F  G      H  J  K  L  ; ],
         [  Z   X   C   V  B      N  M  ,  .  / ],
-        [  n   n  (1)  2  3      4  5  6  n  n ],
+        [  n   n  (1)  2  3      4  5  6 {LT1} n ],
     } { /* 1: LOWER */
         [  !   #  $    '(' ')'     ^       &       |       *    {RST} ],
         [ {AA}  -  '`'  '{' '}'    Left    Down    Up     Right  '\\' ],
-        [ {WHUP} {WHDN} n  n  n    {RGB}   n       n      n     {NOM} ],
-        [ {INC} {DEC} {BIW} n  RAlt Escape  Delete  {MLC} {MMC} {MRC} ],
+        [ {WHUP} {WHDN} {TRC} {LSPO} {RSPC}  {RGB}   n       n      n     {NOM} ],
+        [ {INC} {DEC} {BIW} n  RAlt {GESC}  Delete  {MLC} {MMC} {MRC} ],
     }
 };
@@ -1,26 +1,65 @@
+#[cfg(feature = "dilemma")]
+use crate::board::ENCODER_KEYS;
+use crate::board::THUMBS;
 use crate::core::LAYOUT_CHANNEL;
 use crate::device::is_host;
+use crate::key_stats;
+#[cfg(feature = "timing_logs")]
+use crate::metrics::{self, Metric};
+#[cfg(feature = "matrix_diagnostics")]
+use crate::rgb_leds::{AnimCommand, ANIM_CHANNEL};
+use crate::settings;
 use crate::side::SIDE_CHANNEL;
-use embassy_executor::Spawner;
+use crate::sysclk;
+#[cfg(feature = "tracing")]
+use crate::trace::{self, Task};
+use embassy_executor::SendSpawner;
+#[cfg(feature = "dilemma")]
+use embassy_futures::select::select;
+use embassy_futures::select::select_array;
 use embassy_rp::gpio::{Input, Output};
 use embassy_time::{Duration, Ticker};
-use keyberon::debounce::Debouncer;
 use keyberon::layout::Event as KBEvent;
-use utils::log::error;
+#[cfg(feature = "dilemma")]
+use utils::debounce::DebounceTime;
+use utils::debounce::{DebounceAlgorithm, DebouncePolicy, Debouncer};
+use utils::log::{error, info};
+use utils::pipeline::KeyEvent;
 use utils::serde::Event;
+#[cfg(feature = "matrix_diagnostics")]
+use utils::{
+    log::warn,
+    matrix_diagnostics::{Diagnosis, MatrixDiagnostics},
+};
 
-/// Keyboard matrix rows
-pub const ROWS: usize = 4;
-/// Keyboard matrix columns
-pub const COLS: usize = 5;
+pub use crate::board::{COLS, ROWS};
 /// Full number of columns
 pub const FULL_COLS: usize = 2 * COLS;
-/// Keyboard matrix refresh rate, in Hz
-const REFRESH_RATE: u16 = 1000;
-/// Keyboard matrix debouncing time, in ms
-const DEBOUNCE_TIME_MS: u16 = 5;
-/// Keyboard bounce number
-const NB_BOUNCE: u16 = REFRESH_RATE * DEBOUNCE_TIME_MS / 1000;
+/// Keyboard matrix refresh rate, in Hz: every ms, as the debouncer expects
+pub const REFRESH_RATE: u16 = 1000;
+/// Time without any key change after which the matrix stops being scanned
+/// until a key is pressed, in ms
+const IDLE_AFTER_MS: u32 = 2000;
+
+/// Debouncing of the keys of each half, left then right: as in the
+/// settings
+#[cfg(not(feature = "dilemma"))]
+const DEBOUNCE_POLICIES: [DebouncePolicy<ROWS, COLS>; 2] = [DebouncePolicy::UNIFORM; 2];
+/// Debouncing of the keys of each half, left then right: the thumb
+/// switches bounce more on release
+#[cfg(feature = "dilemma")]
+const DEBOUNCE_POLICIES: [DebouncePolicy<ROWS, COLS>; 2] = [THUMBS_POLICY; 2];
+/// Debouncing of the thumb switches, on the last row, other keys following
+/// the settings
+#[cfg(feature = "dilemma")]
+const THUMBS_POLICY: DebouncePolicy<ROWS, COLS> = {
+    let mut keys = [[None; COLS]; ROWS];
+    keys[ROWS - 1] = [Some(DebounceTime {
+        press_ms: 5,
+        release_ms: 15,
+    }); COLS];
+    DebouncePolicy { keys }
+};
 
 /// Pins for the keyboard matrix
 pub struct Matrix<'a> {
@@ -29,11 +68,7 @@ pub struct Matrix<'a> {
 }
 
 /// Keyboard matrix state
-type MatrixState = [[bool; COLS]; ROWS];
-/// Create a new keyboard matrix state
-fn matrix_state_new() -> MatrixState {
-    [[false; COLS]; ROWS]
-}
+pub type MatrixState = [[bool; COLS]; ROWS];
 
 impl<'a> Matrix<'a> {
     /// Create a new keyboard matrix
@@ -41,7 +76,8 @@ impl<'a> Matrix<'a> {
         Self { rows, cols }
     }
 
-    async fn scan(&mut self) -> MatrixState {
+    /// Scan the matrix, once
+    pub async fn scan(&mut self) -> MatrixState {
         let mut matrix_state = [[false; COLS]; ROWS];
         for (c, col) in self.cols.iter_mut().enumerate() {
             col.set_low();
@@ -56,6 +92,43 @@ impl<'a> Matrix<'a> {
         }
         matrix_state
     }
+
+    /// Wait for a key to be pressed, without scanning: all the columns are
+    /// driven low, so that any key pressed pulls its row low
+    async fn wait_for_press(&mut self) {
+        for col in self.cols.iter_mut() {
+            col.set_low();
+        }
+        cortex_m::asm::delay(150);
+        let mut rows = self.rows.iter_mut();
+        select_array::<_, ROWS>(core::array::from_fn(|_| {
+            rows.next().unwrap().wait_for_low()
+        }))
+        .await;
+        for col in self.cols.iter_mut() {
+            col.set_high();
+        }
+        cortex_m::asm::delay(100);
+    }
+}
+
+/// Send a key event to the layout, or to the host half
+async fn send(is_host: bool, event: KBEvent) {
+    if is_host {
+        if LAYOUT_CHANNEL.is_full() {
+            error!("Layout channel is full");
+        }
+        LAYOUT_CHANNEL.send(event).await;
+    } else {
+        let event = match event {
+            KBEvent::Press(r, c) => Event::Press(r, c),
+            KBEvent::Release(r, c) => Event::Release(r, c),
+        };
+        if SIDE_CHANNEL.is_full() {
+            error!("Side channel is full");
+        }
+        SIDE_CHANNEL.send(event).await;
+    }
 }
 
 /// Loop that scans the keyboard matrix
@@ -64,69 +137,53 @@ async fn matrix_scanner(
     mut matrix: Matrix<'static>,
     encoder_pins: Option<(Input<'static>, Input<'static>)>,
     is_right: bool,
+    debounce: DebounceAlgorithm,
 ) {
     let mut ticker = Ticker::every(Duration::from_hz(REFRESH_RATE.into()));
-    let mut debouncer = Debouncer::new(matrix_state_new(), matrix_state_new(), NB_BOUNCE);
+    let mut debounce_ms = settings::debounce_ms();
+    let mut debouncer = Debouncer::new(DEBOUNCE_POLICIES[is_right as usize], debounce);
+    // Time since the last key change, to stop scanning once idle
+    let mut idle_ms: u32 = 0;
+    #[cfg(feature = "matrix_diagnostics")]
+    let mut diagnostics = MatrixDiagnostics::new();
 
-    #[cfg(feature = "cnano")]
+    #[cfg(not(feature = "dilemma"))]
     if encoder_pins.is_some() {
-        error!("Encoder pins are not supported on the Cnano");
+        error!("Encoder pins are only supported on the Dilemma");
     }
 
     #[cfg(feature = "dilemma")]
-    let (encoder_pin_a, encoder_pin_b) = encoder_pins.unwrap();
+    let (mut encoder_pin_a, encoder_pin_b) = encoder_pins.unwrap();
     #[cfg(feature = "dilemma")]
     let mut last_pin_a = encoder_pin_a.is_high();
 
     loop {
-        let transform = if is_right {
-            |e: KBEvent| {
-                e.transform(|r, c| {
-                    if r == 3 {
-                        match c {
-                            #[cfg(feature = "cnano")]
-                            0 => (3, 5),
-                            #[cfg(feature = "dilemma")]
-                            0 => (3, 6),
-                            #[cfg(feature = "dilemma")]
-                            1 => (3, 5),
-                            #[cfg(feature = "cnano")]
-                            2 => (3, 6),
-                            #[cfg(feature = "dilemma")]
-                            2 => (3, 7),
-                            _ => panic!("Invalid key {:?}", (r, c)),
-                        }
-                    } else {
-                        (r, 9 - c)
-                    }
-                })
-            }
-        } else {
-            |e: KBEvent| {
-                e.transform(|r, c| {
-                    if r == 3 {
-                        match c {
-                            #[cfg(feature = "cnano")]
-                            0 => (3, 4),
-                            #[cfg(feature = "dilemma")]
-                            0 => (3, 3),
-                            #[cfg(feature = "dilemma")]
-                            1 => (3, 4),
-                            #[cfg(any(feature = "cnano", feature = "dilemma"))]
-                            2 => (3, 2),
-                            #[cfg(feature = "cnano")]
-                            3 => (3, 3),
-                            _ => panic!("Invalid key {:?}", (r, c)),
-                        }
-                    } else {
-                        (r, c)
+        // The columns of the right half are mirrored, and the thumb keys
+        // laid out following the board
+        let transform = |e: KBEvent| {
+            e.transform(|r, c| {
+                if r as usize == ROWS - 1 {
+                    match THUMBS[is_right as usize][c as usize] {
+                        Some(col) => (r, col),
+                        None => panic!("Invalid key {:?}", (r, c)),
                     }
-                })
-            }
+                } else if is_right {
+                    (r, (FULL_COLS - 1) as u8 - c)
+                } else {
+                    (r, c)
+                }
+            })
         };
         let is_host = is_host();
+        let new_debounce_ms = settings::debounce_ms();
+        if new_debounce_ms != debounce_ms {
+            info!("Debouncing time: {}ms", new_debounce_ms);
+            debounce_ms = new_debounce_ms;
+        }
+        #[cfg(feature = "timing_logs")]
+        let start = embassy_time::Instant::now();
         let matrix_state = {
-            #[cfg(feature = "dilemma")]
+            #[cfg(all(feature = "dilemma", not(feature = "dilemma_max")))]
             if !is_right {
                 // disable ghosting on X when shift is pressed
                 let mut state = matrix.scan().await;
@@ -137,64 +194,88 @@ async fn matrix_scanner(
             } else {
                 matrix.scan().await
             }
-            #[cfg(not(feature = "dilemma"))]
+            #[cfg(any(not(feature = "dilemma"), feature = "dilemma_max"))]
             matrix.scan().await
         };
 
-        for event in debouncer.events(matrix_state).map(transform) {
-            if is_host {
-                if LAYOUT_CHANNEL.is_full() {
-                    error!("Layout channel is full");
+        #[cfg(feature = "matrix_diagnostics")]
+        match diagnostics.scan(&matrix_state) {
+            Some(Diagnosis::Fault(fault)) => {
+                warn!("Matrix fault: {:?}", fault);
+                if ANIM_CHANNEL.is_full() {
+                    error!("Anim channel is full");
                 }
-                LAYOUT_CHANNEL.send(event).await;
-            } else {
-                match event {
-                    KBEvent::Press(r, c) => {
-                        if SIDE_CHANNEL.is_full() {
-                            error!("Side channel is full");
-                        }
-                        SIDE_CHANNEL.send(Event::Press(r, c)).await;
-                    }
-                    KBEvent::Release(r, c) => {
-                        if SIDE_CHANNEL.is_full() {
-                            error!("Side channel is full");
-                        }
-                        SIDE_CHANNEL.send(Event::Release(r, c)).await;
-                    }
+                ANIM_CHANNEL.send(AnimCommand::MatrixFault).await;
+            }
+            Some(Diagnosis::Fixed) => {
+                info!("Matrix fault fixed");
+                if ANIM_CHANNEL.is_full() {
+                    error!("Anim channel is full");
                 }
+                ANIM_CHANNEL.send(AnimCommand::MatrixFixed).await;
             }
+            None => (),
         }
+
+        for event in debouncer.events(matrix_state, debounce_ms) {
+            let event = transform(match event {
+                KeyEvent::Press(r, c) => KBEvent::Press(r, c),
+                KeyEvent::Release(r, c) => KBEvent::Release(r, c),
+            });
+            idle_ms = 0;
+            #[cfg(feature = "tracing")]
+            trace::record(Task::Matrix, event.into());
+            sysclk::notify_activity();
+            if is_host {
+                if let KBEvent::Press(r, c) = event {
+                    key_stats::on_press(r, c);
+                    #[cfg(feature = "timing_logs")]
+                    metrics::key_pressed();
+                }
+            }
+            send(is_host, event).await;
+        }
+        #[cfg(feature = "timing_logs")]
+        metrics::record(Metric::MatrixScan, start);
         #[cfg(feature = "dilemma")]
-        if is_right && is_host {
+        if let Some([same, differ]) = ENCODER_KEYS[is_right as usize] {
             // Read the current state of the pins
             let current_a = encoder_pin_a.is_high();
             let current_b = encoder_pin_b.is_high();
 
             // Check for a transition on pin A
             if current_a != last_pin_a {
-                if LAYOUT_CHANNEL.is_full() {
-                    error!("Layout channel is full");
-                }
-                if current_b == current_a {
-                    LAYOUT_CHANNEL.send(KBEvent::Press(3, 8)).await;
-                    LAYOUT_CHANNEL.send(KBEvent::Release(3, 8)).await;
-                } else {
-                    LAYOUT_CHANNEL.send(KBEvent::Press(3, 9)).await;
-                    LAYOUT_CHANNEL.send(KBEvent::Release(3, 9)).await;
-                }
+                idle_ms = 0;
+                sysclk::notify_activity();
+                let (r, c) = if current_b == current_a { same } else { differ };
+                send(is_host, KBEvent::Press(r, c)).await;
+                send(is_host, KBEvent::Release(r, c)).await;
                 last_pin_a = current_a;
             }
         }
 
+        idle_ms = idle_ms.saturating_add(1000 / REFRESH_RATE as u32);
+        if idle_ms >= IDLE_AFTER_MS && debouncer.is_released() {
+            // Sleep on the GPIO interrupts of the rows, and of the encoder
+            #[cfg(feature = "dilemma")]
+            select(matrix.wait_for_press(), encoder_pin_a.wait_for_any_edge()).await;
+            #[cfg(not(feature = "dilemma"))]
+            matrix.wait_for_press().await;
+            idle_ms = 0;
+            ticker.reset();
+        }
+
         ticker.next().await;
     }
 }
 
+/// Scan the keyboard matrix, debounced with the algorithm `debounce`
 pub fn init(
-    spawner: &Spawner,
+    spawner: &SendSpawner,
     matrix: Matrix<'static>,
     encoder_pins: Option<(Input<'static>, Input<'static>)>,
     is_right: bool,
+    debounce: DebounceAlgorithm,
 ) {
-    spawner.spawn(matrix_scanner(matrix, encoder_pins, is_right).unwrap());
+    spawner.spawn(matrix_scanner(matrix, encoder_pins, is_right, debounce).unwrap());
 }
@@ -2,21 +2,22 @@
 #![no_main]
 
 use crate::hid::{
-    hid_consumer_writer_handler, hid_kb_writer_handler, CONSUMER_REPORT_DESCRIPTOR,
-    KB_REPORT_DESCRIPTOR, MOUSE_REPORT_DESCRIPTOR,
+    hid_consumer_writer_handler, hid_kb_writer_handler, hid_system_writer_handler,
+    CONSUMER_REPORT_DESCRIPTOR, KB_REPORT_DESCRIPTOR, MOUSE_REPORT_DESCRIPTOR,
+    RAW_HID_REPORT_DESCRIPTOR, SYSTEM_REPORT_DESCRIPTOR,
 };
 use crate::keys::Matrix;
-#[cfg(feature = "cnano")]
-use crate::trackball::Trackball;
 use cortex_m::singleton;
+#[cfg(feature = "defmt")]
+use defmt_rtt as _;
 use embassy_executor::Spawner;
-#[cfg(feature = "cnano")]
-use embassy_rp::spi::{Config as SpiConfig, Phase, Polarity, Spi};
 use embassy_rp::{
     bind_interrupts,
     dma::InterruptHandler as DmaInterruptHandler,
+    flash::Flash,
     gpio::{Input, Level, Output, Pull},
-    peripherals::{DMA_CH0, DMA_CH1, DMA_CH2, PIO0, PIO1, USB},
+    i2c::{Config as I2cConfig, I2c, InterruptHandler as I2cInterruptHandler},
+    peripherals::{DMA_CH0, DMA_CH1, DMA_CH2, I2C1, PIO0, PIO1, USB},
     pio::{InterruptHandler as PioInterruptHandler, Pio},
     usb::{Driver, InterruptHandler as USBInterruptHandler},
 };
@@ -24,35 +25,89 @@ use embassy_usb::class::hid::{
     Config as HidConfig, HidBootProtocol, HidReaderWriter, HidSubclass, HidWriter, State,
 };
 use embassy_usb::Builder;
-#[cfg(not(feature = "defmt"))]
-use panic_halt as _;
+use utils::debounce::DebounceAlgorithm;
 use utils::log::info;
-#[cfg(feature = "defmt")]
-use {defmt_rtt as _, panic_probe as _};
 
+/// Reusable keymap actions, not all of them used by every keymap
+#[allow(dead_code)]
+mod actions;
+/// Pin assignments of the supported keyboards
+mod board;
+/// Piezo buzzer
+#[cfg(feature = "buzzer")]
+mod buzzer;
+/// Depths of the channels between the tasks
+mod channels;
+/// Parts specific to the RP2040 or to the RP2350
+mod chip;
 /// Layout events processing
 mod core;
 use core::Core;
 /// Device
 mod device;
+/// OLED display
+mod display;
+/// Double reset detection to enter the bootloader
+mod double_reset;
+/// Haptic feedback
+mod haptic;
 /// USB HID configuration
 mod hid;
+/// I2C bus shared by the optional peripherals
+mod i2c_bus;
+/// Key usage statistics
+mod key_stats;
 /// Key handling
 mod keys;
+/// Latency metrics
+#[cfg(feature = "timing_logs")]
+mod metrics;
 /// Mouse handling
 mod mouse;
+/// Second core of the RP2040
+mod multicore;
+/// Panic handler persisting the panic information across reboots
+mod panic_info;
+/// Pointing device detection
+#[cfg(feature = "pointing_device")]
+mod pointer;
+/// Configuration protocol over raw HID
+mod raw_hid;
 /// RGB LEDs
 mod rgb_leds;
+/// Hardware self test, entered by holding a key at power-up
+mod self_test;
+/// Persistent settings
+mod settings;
 /// Handling the other half of the keyboard
 mod side;
+/// Stack usage reporting
+#[cfg(feature = "defmt")]
+mod stack;
+/// Status LED patterns
+mod status_led;
+/// Steno mode over Plover HID
+#[cfg(feature = "steno")]
+mod steno;
+/// Keymap edited at runtime, stored in flash
+mod storage;
+/// System clock scaling when idle
+mod sysclk;
+/// Hold-tap keys resolved following the settings
+mod tap_hold;
+/// Cross-task event tracing
+#[cfg(feature = "tracing")]
+mod trace;
 /// Trackball handling
-#[cfg(feature = "cnano")]
+#[cfg(feature = "pointing_device")]
 mod trackball;
 /// Trackpad handling
-#[cfg(feature = "dilemma")]
+#[cfg(feature = "pointing_device")]
 mod trackpad;
 /// USB handling
 mod usb;
+/// Hardware watchdog supervision
+mod watchdog;
 
 /// Basic layout for the keyboard
 #[cfg(feature = "keymap_basic")]
@@ -66,23 +121,63 @@ mod keymap_borisfaure;
 #[cfg(feature = "keymap_test")]
 mod keymap_test;
 
+/// Keymap generated from a JSON description
+#[cfg(feature = "keymap_json")]
+mod keymap_json;
+
 #[cfg(not(any(
     feature = "keymap_borisfaure",
     feature = "keymap_basic",
-    feature = "keymap_test"
+    feature = "keymap_test",
+    feature = "keymap_json"
 )))]
 compile_error!(
-    "Either feature \"keymap_basic\" or \"keymap_borisfaure\" or \"keymap_test\" must be enabled."
+    "Either feature \"keymap_basic\" or \"keymap_borisfaure\" or \"keymap_test\" or \"keymap_json\" must be enabled."
 );
 
-#[cfg(not(any(feature = "dilemma", feature = "cnano",)))]
-compile_error!("Either feature \"cnano\" or \"dilemma\" must be enabled.");
-#[cfg(all(feature = "dilemma", feature = "cnano",))]
-compile_error!("Only one of \"cnano\" or \"dilemma\" can be enabled at a time.");
+#[cfg(not(any(
+    feature = "dilemma",
+    feature = "cnano",
+    feature = "skeletyl",
+    feature = "scylla",
+    feature = "tbkmini"
+)))]
+compile_error!(
+    "Either feature \"cnano\" or \"dilemma\" or \"skeletyl\" or \"scylla\" or \"tbkmini\" must be enabled."
+);
+#[cfg(any(
+    all(feature = "dilemma", feature = "cnano"),
+    all(feature = "dilemma", feature = "skeletyl"),
+    all(feature = "dilemma", feature = "scylla"),
+    all(feature = "dilemma", feature = "tbkmini"),
+    all(feature = "cnano", feature = "skeletyl"),
+    all(feature = "cnano", feature = "scylla"),
+    all(feature = "cnano", feature = "tbkmini"),
+    all(feature = "skeletyl", feature = "scylla"),
+    all(feature = "skeletyl", feature = "tbkmini"),
+    all(feature = "scylla", feature = "tbkmini")
+))]
+compile_error!(
+    "Only one of \"cnano\" or \"dilemma\" or \"skeletyl\" or \"scylla\" or \"tbkmini\" can be enabled at a time."
+);
+#[cfg(all(feature = "dilemma_max", not(feature = "keymap_json")))]
+compile_error!(
+    "The keymaps of the Dilemma Max are only described in JSON: enable \"keymap_json\"."
+);
+#[cfg(all(
+    any(feature = "scylla", feature = "tbkmini"),
+    not(feature = "keymap_json")
+))]
+compile_error!(
+    "The keymaps of the 6-column Scylla and TBK Mini are only described in JSON: enable \"keymap_json\"."
+);
 
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => USBInterruptHandler<USB>;
 });
+bind_interrupts!(struct I2cIrqs {
+    I2C1_IRQ => I2cInterruptHandler<I2C1>;
+});
 bind_interrupts!(struct PioIrq0 {
     PIO0_IRQ_0 => PioInterruptHandler<PIO0>;
 });
@@ -96,7 +191,21 @@ bind_interrupts!(struct DmaIrqs {
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
+    let board = board::board_config!(p);
     info!("Hello World!");
+    #[cfg(feature = "defmt")]
+    stack::paint();
+    panic_info::check_last_panic();
+    double_reset::check(panic_info::last_panic().is_some());
+    spawner.spawn(double_reset::disarm().unwrap());
+    settings::init(&spawner, Flash::new_blocking(p.FLASH)).await;
+    storage::load().await;
+    spawner.spawn(storage::run().unwrap());
+    #[cfg(feature = "persist_key_stats")]
+    {
+        key_stats::load().await;
+        spawner.spawn(key_stats::run().unwrap());
+    }
 
     // Create the driver, from the HAL.
     let driver = Driver::new(p.USB, Irqs);
@@ -115,6 +224,10 @@ async fn main(spawner: Spawner) {
     let state_kb = singleton!(: State = State::new()).unwrap();
     let state_mouse = singleton!(: State = State::new()).unwrap();
     let state_consumer = singleton!(: State = State::new()).unwrap();
+    let state_system = singleton!(: State = State::new()).unwrap();
+    let state_raw_hid = singleton!(: State = State::new()).unwrap();
+    #[cfg(feature = "steno")]
+    let state_steno = singleton!(: State = State::new()).unwrap();
 
     let usb_config = usb::config();
     let mut builder = Builder::new(
@@ -129,16 +242,16 @@ async fn main(spawner: Spawner) {
     builder.handler(device_handler);
 
     info!("Detecting side...");
-    #[cfg(feature = "cnano")]
-    let is_right = device::is_right(Input::new(p.PIN_15, Pull::Up));
-    #[cfg(feature = "dilemma")]
-    let is_right = device::is_right(Input::new(p.PIN_29, Pull::Up));
+    let is_right = device::is_right(Input::new(board.side_detect, Pull::Up));
 
-    // Create classes on the builder.
+    // Create classes on the builder. The control requests of the keyboard
+    // select its protocol, shared with its writer.
+    let kb_request_handler =
+        singleton!(: hid::HidRequestHandler = hid::HidRequestHandler::new(spawner)).unwrap();
     let hidkb_config = HidConfig {
         report_descriptor: KB_REPORT_DESCRIPTOR,
-        request_handler: None,
-        poll_ms: 60,
+        request_handler: Some(kb_request_handler),
+        poll_ms: utils::hid::KB_POLL_MS,
         max_packet_size: 8,
         hid_subclass: HidSubclass::Boot,
         hid_boot_protocol: HidBootProtocol::Keyboard,
@@ -165,129 +278,138 @@ async fn main(spawner: Spawner) {
     };
     let hid_consumer = HidWriter::<_, 2>::new(&mut builder, state_consumer, hidc_config);
 
-    let mut request_handler = hid::HidRequestHandler::new(&spawner);
+    let hids_config = HidConfig {
+        report_descriptor: SYSTEM_REPORT_DESCRIPTOR,
+        request_handler: None,
+        poll_ms: 60,
+        max_packet_size: 1,
+        hid_subclass: HidSubclass::No,
+        hid_boot_protocol: HidBootProtocol::None,
+    };
+    let hid_system = HidWriter::<_, 1>::new(&mut builder, state_system, hids_config);
+
+    let hid_raw_config = HidConfig {
+        report_descriptor: RAW_HID_REPORT_DESCRIPTOR,
+        request_handler: None,
+        poll_ms: 1,
+        max_packet_size: utils::raw_hid::REPORT_SIZE as u16,
+        hid_subclass: HidSubclass::No,
+        hid_boot_protocol: HidBootProtocol::None,
+    };
+    let hid_raw = HidReaderWriter::<
+        _,
+        { utils::raw_hid::REPORT_SIZE },
+        { utils::raw_hid::REPORT_SIZE },
+    >::new(&mut builder, state_raw_hid, hid_raw_config);
+
+    #[cfg(feature = "steno")]
+    let hid_steno = {
+        let hid_steno_config = HidConfig {
+            report_descriptor: utils::steno::STENO_REPORT_DESCRIPTOR,
+            request_handler: None,
+            poll_ms: 10,
+            max_packet_size: utils::steno::REPORT_SIZE as u16,
+            hid_subclass: HidSubclass::No,
+            hid_boot_protocol: HidBootProtocol::None,
+        };
+        HidWriter::<_, { utils::steno::REPORT_SIZE }>::new(
+            &mut builder,
+            state_steno,
+            hid_steno_config,
+        )
+    };
+
+    let mut request_handler = hid::HidRequestHandler::new(spawner);
     let (hid_kb_reader, hid_kb_writer) = hidkb.split();
     let hid_kb_reader_fut = async {
         hid_kb_reader.run(false, &mut request_handler).await;
     };
     spawner.spawn(hid_kb_writer_handler(hid_kb_writer).unwrap());
     spawner.spawn(hid_consumer_writer_handler(hid_consumer).unwrap());
+    spawner.spawn(hid_system_writer_handler(hid_system).unwrap());
+    let (hid_raw_reader, hid_raw_writer) = hid_raw.split();
+    spawner.spawn(raw_hid::run(hid_raw_reader, hid_raw_writer).unwrap());
+    #[cfg(feature = "steno")]
+    spawner.spawn(steno::run(hid_steno).unwrap());
 
     // Build the builder.
     spawner.spawn(usb::run(builder).unwrap());
 
-    #[cfg(feature = "cnano")]
-    let rows = [
-        Input::new(p.PIN_26, Pull::Up), // R2
-        Input::new(p.PIN_5, Pull::Up),  // R3
-        Input::new(p.PIN_4, Pull::Up),  // R4
-        Input::new(p.PIN_9, Pull::Up),  // R5
-    ];
-    #[cfg(feature = "dilemma")]
-    let rows = [
-        Input::new(p.PIN_4, Pull::Up),  // R2
-        Input::new(p.PIN_5, Pull::Up),  // R3
-        Input::new(p.PIN_27, Pull::Up), // R4
-        Input::new(p.PIN_26, Pull::Up), // R5
-    ];
-
-    #[cfg(feature = "cnano")]
-    let cols = [
-        Output::new(p.PIN_28, Level::High), // C2
-        Output::new(p.PIN_21, Level::High), // C3
-        Output::new(p.PIN_6, Level::High),  // C4
-        Output::new(p.PIN_7, Level::High),  // C5
-        Output::new(p.PIN_8, Level::High),  // C6
-    ];
-    #[cfg(feature = "dilemma")]
-    let cols = [
-        Output::new(p.PIN_8, Level::High),  // C2
-        Output::new(p.PIN_9, Level::High),  // C3
-        Output::new(p.PIN_7, Level::High),  // C4
-        Output::new(p.PIN_6, Level::High),  // C5
-        Output::new(p.PIN_28, Level::High), // C6
-    ];
-
-    let matrix = Matrix::new(rows, cols);
-    #[cfg(feature = "cnano")]
-    let mut status_led = Output::new(p.PIN_24, Level::Low);
-    #[cfg(feature = "dilemma")]
-    let mut status_led = Output::new(p.PIN_17, Level::Low);
-    // Disable the status LED on startup
-    #[cfg(feature = "cnano")]
-    status_led.set_high();
-    #[cfg(feature = "dilemma")]
-    status_led.set_low();
+    let mut matrix = Matrix::new(
+        board.rows.map(|pin| Input::new(pin, Pull::Up)),
+        board.cols.map(|pin| Output::new(pin, Level::High)),
+    );
+    let self_test = self_test::requested(&mut matrix).await;
+    // Off on startup, whether the status LED is active low or high
+    let status_led = Output::new(board.status_led, board.status_led_off);
+    spawner.spawn(status_led::run(status_led).unwrap());
+
+    spawner.spawn(sysclk::run().unwrap());
+    spawner.spawn(watchdog::run(p.WATCHDOG).unwrap());
+    #[cfg(feature = "defmt")]
+    spawner.spawn(stack::report().unwrap());
+    #[cfg(feature = "timing_logs")]
+    spawner.spawn(metrics::report().unwrap());
+    #[cfg(feature = "defmt")]
+    spawner.spawn(channels::report().unwrap());
+
+    // Matrix scanning, RGB rendering and the side link run on the second
+    // core, so that a heavy LED or pointer load does not delay the key
+    // reports. USB, HID and the layout stay on this core.
+    let core1_spawner = multicore::start_core1(p.CORE1).await;
 
     let pio1 = Pio::new(p.PIO1, PioIrq1);
     side::init(
-        &spawner,
+        &core1_spawner,
         pio1.common,
         pio1.sm0,
-        #[cfg(feature = "cnano")]
-        p.PIN_29,
-        #[cfg(feature = "dilemma")]
-        p.PIN_1,
-        status_led,
+        board.side_link,
         is_right,
     )
     .await;
 
     let pio0 = Pio::new(p.PIO0, PioIrq0);
     rgb_leds::init(
-        &spawner,
+        &core1_spawner,
         pio0.common,
         pio0.sm0,
         p.DMA_CH0,
         DmaIrqs,
-        #[cfg(feature = "cnano")]
-        p.PIN_0,
-        #[cfg(feature = "dilemma")]
-        p.PIN_10,
+        board.rgb_leds,
     );
 
     let core = Core::new(hid_mouse);
     spawner.spawn(core::run(core).unwrap());
 
-    #[cfg(feature = "dilemma")]
-    let encoder = Some((
-        Input::new(p.PIN_24, Pull::Up),
-        Input::new(p.PIN_25, Pull::Up),
-    ));
-    #[cfg(feature = "cnano")]
-    let encoder = None;
-    keys::init(&spawner, matrix, encoder, is_right);
+    // Optional DRV2605L haptic driver and OLED display, on the I2C pins of
+    // the Pro Micro footprint
+    let mut i2c_config = I2cConfig::default();
+    i2c_config.frequency = 400_000;
+    let i2c = I2c::new_async(p.I2C1, p.PIN_3, p.PIN_2, I2cIrqs, i2c_config);
+    let [haptic_i2c, display_i2c] = i2c_bus::share(i2c);
+    spawner.spawn(haptic::run(haptic_i2c).unwrap());
+    spawner.spawn(display::run(display_i2c).unwrap());
 
-    #[cfg(feature = "cnano")]
-    if is_right {
-        let sclk = p.PIN_22; // B1
-        let mosi = p.PIN_23; // B2
-        let miso = p.PIN_20; // B3
-        let cs = Output::new(p.PIN_16, Level::High); // F0
-        let tx_dma = p.DMA_CH1;
-        let rx_dma = p.DMA_CH2;
-        let mut spi_config = SpiConfig::default();
-        spi_config.frequency = 7_000_000;
-        spi_config.polarity = Polarity::IdleHigh;
-        spi_config.phase = Phase::CaptureOnSecondTransition;
-        let ball_spi = Spi::new(
-            p.SPI0, sclk, mosi, miso, tx_dma, rx_dma, DmaIrqs, spi_config,
-        );
-        let ball = Trackball::new(ball_spi, cs);
-
-        spawner.spawn(trackball::run(ball).unwrap());
+    #[cfg(feature = "buzzer")]
+    spawner.spawn(buzzer::run(p.PWM_SLICE1, p.PIN_18).unwrap());
+
+    let encoder = board
+        .encoder
+        .map(|[a, b]| (Input::new(a, Pull::Up), Input::new(b, Pull::Up)));
+    #[cfg(feature = "eager_debounce")]
+    let debounce = DebounceAlgorithm::Eager;
+    #[cfg(not(feature = "eager_debounce"))]
+    let debounce = DebounceAlgorithm::Deferred;
+    if self_test {
+        self_test::init(&core1_spawner, matrix, is_right);
+    } else {
+        keys::init(&core1_spawner, matrix, encoder, is_right, debounce);
     }
-    #[cfg(feature = "dilemma")]
+
+    #[cfg(feature = "pointing_device")]
     if is_right {
-        let pins = trackpad::TrackpadPins {
-            clk: p.PIN_22,  // B1
-            mosi: p.PIN_23, // B2
-            miso: p.PIN_20, // B3
-            cs: p.PIN_21,   // B4
-        };
-        let tx_dma = p.DMA_CH1;
-        let rx_dma = p.DMA_CH2;
-        trackpad::init(&spawner, p.SPI0, pins, tx_dma, rx_dma, DmaIrqs);
+        let (tx_dma, rx_dma) = (p.DMA_CH1, p.DMA_CH2);
+        pointer::init(&spawner, p.SPI0, board.sensor, tx_dma, rx_dma, DmaIrqs).await;
     }
 
     info!("let's go!");
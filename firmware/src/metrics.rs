@@ -0,0 +1,106 @@
+use core::cell::{Cell, RefCell};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Ticker};
+use utils::histogram::{Histogram, NB_BUCKETS};
+use utils::log::info;
+
+/// Period of the metrics report, in seconds
+const REPORT_PERIOD_S: u64 = 10;
+
+/// Measured code paths
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Metric {
+    /// One tick of the core loop
+    CoreTick = 0,
+    /// Scanning the matrix and sending the debounced events
+    MatrixScan = 1,
+    /// One exchange of messages on the side link
+    SideLink = 2,
+    /// From a debounced key press to the write of the next keyboard report
+    KeyToReport = 3,
+}
+
+/// Number of metrics
+const NB_METRICS: usize = 4;
+
+/// All the metrics, in the order of their index
+const METRICS: [Metric; NB_METRICS] = [
+    Metric::CoreTick,
+    Metric::MatrixScan,
+    Metric::SideLink,
+    Metric::KeyToReport,
+];
+
+/// Latency histograms, in µs
+static HISTOGRAMS: Mutex<CriticalSectionRawMutex, RefCell<[Histogram; NB_METRICS]>> =
+    Mutex::new(RefCell::new([Histogram::new(); NB_METRICS]));
+
+/// Time of the oldest key press not followed by a keyboard report yet
+static PENDING_PRESS: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+
+/// Record the latency of `metric`, from `start` to now
+pub fn record(metric: Metric, start: Instant) {
+    let us = start.elapsed().as_micros().min(u32::MAX as u64) as u32;
+    HISTOGRAMS.lock(|h| h.borrow_mut()[metric as usize].record(us));
+}
+
+/// Timestamp a key press, once debounced. The presses made before the
+/// next keyboard report are measured from the oldest one.
+pub fn key_pressed() {
+    PENDING_PRESS.lock(|p| {
+        if p.get().is_none() {
+            p.set(Some(Instant::now()));
+        }
+    });
+}
+
+/// Record the latency of the pending key press, its keyboard report being
+/// written
+pub fn report_written() {
+    if let Some(start) = PENDING_PRESS.lock(|p| p.take()) {
+        record(Metric::KeyToReport, start);
+    }
+}
+
+/// Dump the histograms and reset them
+fn dump() {
+    HISTOGRAMS.lock(|h| {
+        let mut histograms = h.borrow_mut();
+        for (metric, histogram) in METRICS.iter().zip(histograms.iter_mut()) {
+            if let (Some(_min), Some(_mean), Some(_max)) =
+                (histogram.min(), histogram.mean(), histogram.max())
+            {
+                info!(
+                    "[METRICS] {:?}: n={} min={}us avg={}us max={}us",
+                    metric,
+                    histogram.count(),
+                    _min,
+                    _mean,
+                    _max
+                );
+                for (i, _n) in histogram.buckets().iter().enumerate() {
+                    if *_n == 0 {
+                        continue;
+                    }
+                    match Histogram::bucket_upper_bound(i) {
+                        Some(_bound) => info!("[METRICS]   <{}us: {}", _bound, _n),
+                        None => info!("[METRICS]   >={}us: {}", 1u32 << (NB_BUCKETS - 2), _n),
+                    }
+                }
+            }
+            histogram.reset();
+        }
+    });
+}
+
+/// Periodically dump the latency histograms
+#[embassy_executor::task]
+pub async fn report() {
+    let mut ticker = Ticker::every(Duration::from_secs(REPORT_PERIOD_S));
+    loop {
+        ticker.next().await;
+        dump();
+    }
+}
@@ -1,6 +1,17 @@
+use crate::channels::{self, Queue, MOUSE_BUTTON_DEPTH, MOUSE_MOVE_DEPTH};
 use crate::device::is_host;
 use crate::hid::MouseReport;
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
+#[cfg(feature = "pointing_device")]
+use crate::pointer;
+use crate::settings;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::Instant;
+use utils::ballistics::{Ballistics, PointerLayer};
+use utils::drag_scroll::{cpi_divisor, DragScroll};
+use utils::log::info;
+#[cfg(feature = "pointing_device")]
+use utils::tap_gesture::ReportFilter;
+use utils::tap_gesture::TapEvent;
 
 /// Mouse move event
 #[derive(Debug)]
@@ -12,12 +23,19 @@ pub struct MouseMove {
     pub dy: i16,
     /// Pressure (0-63 for trackpad, 0 for trackball)
     pub pressure: u8,
+    /// Wheel steps, from the scroll gestures of the trackpad
+    pub wheel: i8,
+    /// Pan steps, from the scroll gestures of the trackpad
+    pub pan: i8,
 }
 
-/// Maximum number of movements in the channel
-pub const NB_MOVE: usize = 128;
 /// Channel to send movement reports from the sensor
-pub static MOUSE_MOVE_CHANNEL: Channel<ThreadModeRawMutex, MouseMove, NB_MOVE> = Channel::new();
+pub static MOUSE_MOVE_CHANNEL: Channel<CriticalSectionRawMutex, MouseMove, MOUSE_MOVE_DEPTH> =
+    Channel::new();
+
+/// Channel to send the left button changes of the trackpad taps
+pub static MOUSE_BUTTON_CHANNEL: Channel<CriticalSectionRawMutex, TapEvent, MOUSE_BUTTON_DEPTH> =
+    Channel::new();
 
 /// Mouse handler
 pub struct MouseHandler {
@@ -30,6 +48,10 @@ pub struct MouseHandler {
 
     /// Moving the ball is actually moving the wheel
     ball_is_wheel: bool,
+    /// Moving the ball moves the wheel on the active layer
+    layer_scroll: bool,
+    /// Moving the ball scrolls, until toggled off
+    drag_scroll: bool,
 
     /// Direction X
     dx: i16,
@@ -40,23 +62,27 @@ pub struct MouseHandler {
     /// Positive is up, negative is Down
     /// 0 is no movement, reset on every tick
     wheel: i8,
+    /// Pan movement, from the scrolling pointer or the pan keys
+    /// Positive is right, negative is left
+    /// 0 is no movement, reset on every tick
+    pan: i8,
 
     /// Whether the state has changed
     changed: bool,
 
     /// Current pressure value (0-63 for trackpad, 0 for trackball)
     pressure: u8,
-}
-
-/// Threshold to consider the movement as a wheel movement
-const WHEEL_THRESHOLD: i16 = 16;
 
-/// Minimum pressure threshold to maintain mouse mode (dilemma only)
-/// Values range from 0-63
-#[cfg(feature = "dilemma")]
-const PRESSURE_NO_MVMT: u8 = 27;
-#[cfg(feature = "dilemma")]
-const MIN_PRESSURE_MVMT: u8 = 10;
+    /// Acceleration and smoothing of the moves
+    ballistics: Ballistics,
+    /// Conversion of the moves into scroll steps, when the pointer scrolls
+    scroll: DragScroll,
+    /// Time of the last move
+    last_move: Instant,
+    /// Filter of the reports of a trackpad
+    #[cfg(feature = "pointing_device")]
+    filter: ReportFilter,
+}
 
 /// Empty mouse report
 const MOUSE_REPORT_EMPTY: MouseReport = MouseReport {
@@ -75,11 +101,19 @@ impl MouseHandler {
             right_click: false,
             wheel_click: false,
             ball_is_wheel: false,
+            layer_scroll: false,
+            drag_scroll: false,
             dx: 0,
             dy: 0,
             wheel: 0,
+            pan: 0,
             changed: false,
             pressure: 0,
+            ballistics: Ballistics::default(),
+            scroll: DragScroll::new(settings::get().pointer.scroll_divisor as i16),
+            last_move: Instant::now(),
+            #[cfg(feature = "pointing_device")]
+            filter: ReportFilter::new(),
         }
     }
 
@@ -104,9 +138,39 @@ impl MouseHandler {
     /// On Ball is wheel
     pub fn on_ball_is_wheel(&mut self, is_pressed: bool) {
         self.ball_is_wheel = is_pressed;
+        self.scroll.reset();
+        self.changed = true;
+    }
+
+    /// On pan key
+    pub fn on_pan(&mut self, is_right: bool) {
+        self.pan = if is_right { 1 } else { -1 };
         self.changed = true;
     }
 
+    /// Toggle the drag scroll
+    pub fn toggle_drag_scroll(&mut self) {
+        self.drag_scroll = !self.drag_scroll;
+        info!("Drag scroll: {}", self.drag_scroll);
+        self.scroll.reset();
+        self.changed = true;
+    }
+
+    /// On sniper mode: divide the moves by `divisor`, 1 to leave it
+    pub fn on_sniper_mode(&mut self, divisor: u8) {
+        self.ballistics.set_divisor(divisor);
+    }
+
+    /// Set the pointer behavior of the active layer
+    pub fn set_layer(&mut self, pointer: PointerLayer) {
+        self.ballistics.set_speed(pointer.speed);
+        if self.layer_scroll != pointer.scroll {
+            self.layer_scroll = pointer.scroll;
+            self.scroll.reset();
+            self.changed = true;
+        }
+    }
+
     /// On wheel
     #[cfg(feature = "dilemma")]
     pub fn on_wheel(&mut self, is_up: bool) {
@@ -115,9 +179,57 @@ impl MouseHandler {
     }
 
     /// Handle a mouse movement event
-    fn handle_move_event(&mut self, MouseMove { dx, dy, pressure }: MouseMove) {
-        self.dx = dx;
-        self.dy = dy;
+    fn handle_move_event(
+        &mut self,
+        MouseMove {
+            dx,
+            dy,
+            pressure,
+            wheel,
+            pan,
+        }: MouseMove,
+    ) {
+        let settings = settings::get();
+        let options = settings.pointer;
+        let now = Instant::now();
+        let elapsed_ms = now.duration_since(self.last_move).as_millis();
+        self.last_move = now;
+        let (dx, dy) = self
+            .ballistics
+            .apply(&settings.ballistics.response(), dx, dy, elapsed_ms);
+        let (dx, dy) = if options.swap_axes {
+            (dy, dx)
+        } else {
+            (dx, dy)
+        };
+        self.dx = if options.invert_x {
+            dx.saturating_neg()
+        } else {
+            dx
+        };
+        self.dy = if options.invert_y {
+            dy.saturating_neg()
+        } else {
+            dy
+        };
+        if self.drag_scroll || self.ball_is_wheel || self.layer_scroll {
+            // The moves of a trackball scale with its CPI, unlike the ones
+            // of a trackpad
+            #[cfg(feature = "pointing_device")]
+            let divisor = if pointer::has_trackpad() {
+                options.scroll_divisor as i16
+            } else {
+                cpi_divisor(options.scroll_divisor, settings.cpi)
+            };
+            #[cfg(not(feature = "pointing_device"))]
+            let divisor = cpi_divisor(options.scroll_divisor, settings.cpi);
+            self.scroll.set_divisor(divisor);
+            (self.wheel, self.pan) = self.scroll.scroll(self.dx, self.dy);
+            self.dx = 0;
+            self.dy = 0;
+        }
+        self.wheel = self.wheel.saturating_add(wheel);
+        self.pan = self.pan.saturating_add(pan);
         self.pressure = pressure;
         self.changed = true;
     }
@@ -127,31 +239,32 @@ impl MouseHandler {
     /// sufficient pressure on the trackpad to maintain mouse mode without cursor movement
     pub async fn tick(&mut self) -> Option<(MouseReport, bool)> {
         if let Ok(event) = MOUSE_MOVE_CHANNEL.try_receive() {
+            channels::record(Queue::MouseMove, MOUSE_MOVE_CHANNEL.len());
             self.handle_move_event(event);
             self.changed = true;
         }
+        // One button change per tick, so that each one gets its report
+        if let Ok(event) = MOUSE_BUTTON_CHANNEL.try_receive() {
+            channels::record(Queue::MouseButton, MOUSE_BUTTON_CHANNEL.len());
+            self.on_left_click(event == TapEvent::Press);
+        }
         if self.changed && is_host() {
             self.changed = false;
             let hid_report = self.generate_hid_report();
-            #[cfg(feature = "dilemma")]
-            {
-                let res = match self.pressure {
-                    // sufficient pressure to maintain mouse mode
-                    p if p >= PRESSURE_NO_MVMT => Some((hid_report, true)),
-                    // insufficient pressure, but allow movement
-                    p if p >= MIN_PRESSURE_MVMT => Some((hid_report, false)),
-                    // no pressure, could be wheel movement only
-                    p if p == 0 && self.wheel != 0 => Some((hid_report, false)),
-                    _ => None,
-                };
-                self.wheel = 0;
-                res
-            }
-            #[cfg(not(feature = "dilemma"))]
-            {
+            #[cfg(feature = "pointing_device")]
+            if pointer::has_trackpad() {
+                let scrolls = self.wheel != 0 || self.pan != 0;
+                let res = self
+                    .filter
+                    .filter(self.pressure, hid_report.buttons, scrolls)
+                    .map(|has_pressure| (hid_report, has_pressure));
                 self.wheel = 0;
-                Some((hid_report, false))
+                self.pan = 0;
+                return res;
             }
+            self.wheel = 0;
+            self.pan = 0;
+            Some((hid_report, false))
         } else {
             None
         }
@@ -160,25 +273,22 @@ impl MouseHandler {
     /// Generate a HID report for the mouse
     fn generate_hid_report(&mut self) -> MouseReport {
         let mut report = MOUSE_REPORT_EMPTY;
-        if self.ball_is_wheel {
-            match self.dy {
-                y if y > WHEEL_THRESHOLD => report.wheel = -1,
-                y if y < -WHEEL_THRESHOLD => report.wheel = 1,
-                _ => {}
-            }
-        } else {
-            report.x = self.dx;
-            report.y = self.dy;
-            if self.left_click {
-                report.buttons |= 1;
-            }
-            if self.right_click {
-                report.buttons |= 2;
-            }
-            if self.wheel_click {
-                report.buttons |= 4;
-            }
-            report.wheel = self.wheel;
+        report.x = self.dx;
+        report.y = self.dy;
+        if self.left_click {
+            report.buttons |= 1;
+        }
+        if self.right_click {
+            report.buttons |= 2;
+        }
+        if self.wheel_click {
+            report.buttons |= 4;
+        }
+        report.wheel = self.wheel;
+        report.pan = self.pan;
+        if settings::get().pointer.natural_scroll {
+            report.wheel = report.wheel.saturating_neg();
+            report.pan = report.pan.saturating_neg();
         }
         report
     }
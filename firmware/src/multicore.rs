@@ -0,0 +1,46 @@
+#[cfg(feature = "defmt")]
+use crate::stack;
+use core::ptr::{addr_of, addr_of_mut};
+use embassy_executor::{Executor, SendSpawner};
+use embassy_rp::multicore::{spawn_core1, Stack};
+use embassy_rp::peripherals::CORE1;
+use embassy_rp::Peri;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use static_cell::StaticCell;
+use utils::log::info;
+
+/// Size of the stack of the second core, in bytes
+const CORE1_STACK_SIZE: usize = 8 * 1024;
+/// Stack of the second core
+static mut CORE1_STACK: Stack<CORE1_STACK_SIZE> = Stack::new();
+/// Executor of the second core
+static CORE1_EXECUTOR: StaticCell<Executor> = StaticCell::new();
+/// Spawner of the second core, handed over to the first one
+static CORE1_SPAWNER: Signal<CriticalSectionRawMutex, SendSpawner> = Signal::new();
+
+/// Bounds of the stack of the second core, as (bottom, top) addresses
+pub fn core1_stack_bounds() -> (usize, usize) {
+    // SAFETY: only the address of the stack is used
+    let bottom = unsafe { addr_of!(CORE1_STACK) } as usize;
+    (bottom, bottom + CORE1_STACK_SIZE)
+}
+
+/// Start the executor of the second core and return its spawner.
+///
+/// Tasks spawned with it run on the second core, while the interrupts of the
+/// peripherals they use stay handled by the first core, which creates them.
+/// Everything shared between the cores must be protected by a
+/// `CriticalSectionRawMutex`, which takes a hardware spinlock.
+pub async fn start_core1(core1: Peri<'static, CORE1>) -> SendSpawner {
+    #[cfg(feature = "defmt")]
+    stack::paint_core1();
+    // SAFETY: the stack is only handed once, to the second core
+    let stack = unsafe { &mut *addr_of_mut!(CORE1_STACK) };
+    spawn_core1(core1, stack, || {
+        let executor = CORE1_EXECUTOR.init(Executor::new());
+        executor.run(|spawner| CORE1_SPAWNER.signal(spawner.make_send()))
+    });
+    let spawner = CORE1_SPAWNER.wait().await;
+    info!("Second core started");
+    spawner
+}
@@ -0,0 +1,93 @@
+use core::fmt::Write;
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::ptr::addr_of_mut;
+use utils::log::error;
+
+/// Magic value marking a valid panic record
+const PANIC_MAGIC: u32 = 0xdead_beef;
+/// Maximum length of the panic message stored, in bytes
+pub const PANIC_MSG_LEN: usize = 128;
+
+/// Panic record, kept in RAM across resets
+#[repr(C)]
+struct PanicRecord {
+    /// `PANIC_MAGIC` if the record is valid
+    magic: u32,
+    /// Length of the message
+    len: u32,
+    /// Panic message and location
+    msg: [u8; PANIC_MSG_LEN],
+}
+
+/// Panic record in the `.uninit` section: not initialized on boot, so it
+/// survives a reset
+#[link_section = ".uninit.PANIC_RECORD"]
+static mut PANIC_RECORD: MaybeUninit<PanicRecord> = MaybeUninit::uninit();
+
+/// Message of the panic that occurred before the last reboot
+static mut LAST_PANIC: Option<([u8; PANIC_MSG_LEN], usize)> = None;
+
+/// Writer filling the panic record, truncating the message if needed
+struct RecordWriter<'a> {
+    record: &'a mut PanicRecord,
+}
+
+impl Write for RecordWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let len = self.record.len as usize;
+        let n = s.len().min(PANIC_MSG_LEN - len);
+        self.record.msg[len..len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.record.len = (len + n) as u32;
+        Ok(())
+    }
+}
+
+/// Check whether the previous run ended with a panic and report it.
+/// Must be called once, early at boot.
+pub fn check_last_panic() {
+    // SAFETY: called once at boot, before any other access to the records
+    unsafe {
+        let record = &mut *(*addr_of_mut!(PANIC_RECORD)).as_mut_ptr();
+        if record.magic != PANIC_MAGIC {
+            return;
+        }
+        record.magic = 0;
+        let len = (record.len as usize).min(PANIC_MSG_LEN);
+        *addr_of_mut!(LAST_PANIC) = Some((record.msg, len));
+    }
+    if let Some(_msg) = last_panic() {
+        error!("Rebooted after a panic: {}", _msg);
+    }
+}
+
+/// Message of the panic that caused the last reboot, if any
+pub fn last_panic() -> Option<&'static str> {
+    // SAFETY: only written in `check_last_panic`, at boot
+    let last = unsafe { &*addr_of_mut!(LAST_PANIC) };
+    last.as_ref()
+        .map(|(msg, len)| core::str::from_utf8(&msg[..*len]).unwrap_or("<invalid utf-8>"))
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+
+    // SAFETY: interrupts are disabled and the panic handler never returns
+    let record = unsafe { &mut *(*addr_of_mut!(PANIC_RECORD)).as_mut_ptr() };
+    record.magic = 0;
+    record.len = 0;
+    let _ = write!(
+        RecordWriter {
+            record: &mut *record
+        },
+        "{}",
+        info
+    );
+    record.magic = PANIC_MAGIC;
+
+    #[cfg(feature = "defmt")]
+    defmt::error!("{}", defmt::Display2Format(info));
+
+    cortex_m::peripheral::SCB::sys_reset()
+}
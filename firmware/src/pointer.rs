@@ -0,0 +1,142 @@
+use crate::board::SensorPins;
+use crate::trackball::{self, Trackball, TrackballDev};
+use crate::trackpad;
+use embassy_executor::Spawner;
+#[cfg(not(feature = "pmw3320"))]
+use embassy_rp::spi::Async;
+use embassy_rp::{
+    dma,
+    gpio::{Level, Output},
+    interrupt,
+    peripherals::SPI0,
+    spi::Spi,
+    Peri,
+};
+use portable_atomic::{AtomicU8, Ordering};
+use utils::log::{error, info};
+
+/// Pointing devices that can be detected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PointingDevice {
+    /// PMW3360, PMW3389 or PMW3320 trackball sensor, depending on the
+    /// features
+    Trackball = 1,
+    /// Cirque trackpad
+    Trackpad = 2,
+}
+
+/// Polling period of the sensor while the host is suspended, in ms: slow,
+/// but fast enough for a movement to wake the host up
+pub const SUSPENDED_POLL_MS: u64 = 100;
+
+/// Pointing device found at boot, 0 if none
+static DETECTED: AtomicU8 = AtomicU8::new(0);
+
+/// Pointing device found at boot, if any
+pub fn detected() -> Option<PointingDevice> {
+    match DETECTED.load(Ordering::Relaxed) {
+        1 => Some(PointingDevice::Trackball),
+        2 => Some(PointingDevice::Trackpad),
+        _ => None,
+    }
+}
+
+/// Whether the pointing device is a trackpad, reporting the pressure of the
+/// touches
+pub fn has_trackpad() -> bool {
+    detected() == Some(PointingDevice::Trackpad)
+}
+
+/// Start the driver of the trackball sensor found by probing
+fn start_trackball(spawner: &Spawner, ball: TrackballDev) {
+    info!("Pointing device: trackball");
+    DETECTED.store(PointingDevice::Trackball as u8, Ordering::Relaxed);
+    spawner.spawn(trackball::run(ball).unwrap());
+}
+
+/// Probe the SPI bus for the trackball sensor and start its driver. The
+/// bus is given back, set up for the trackpad, when it does not answer.
+#[cfg(not(feature = "pmw3320"))]
+async fn probe_trackball(
+    spawner: &Spawner,
+    mut spi: Spi<'static, SPI0, Async>,
+    cs: Output<'static>,
+) -> Option<(Spi<'static, SPI0, Async>, Output<'static>)> {
+    spi.set_config(&trackball::spi_config());
+    let mut ball = Trackball::new(spi, cs);
+    if ball.probe().await {
+        start_trackball(spawner, ball);
+        return None;
+    }
+    let (mut spi, cs) = ball.release();
+    spi.set_config(&trackpad::spi_config());
+    Some((spi, cs))
+}
+
+/// Probe the PMW3320 on its bidirectional data line, bit-banged on the
+/// MOSI pin, and start its driver. The pins are given back when it does
+/// not answer.
+#[cfg(feature = "pmw3320")]
+async fn probe_trackball(spawner: &Spawner, mut pins: SensorPins) -> Option<SensorPins> {
+    let found = Trackball::new(
+        trackball::Sdio::new(pins.sclk.reborrow(), pins.mosi.reborrow()),
+        Output::new(pins.cs.reborrow(), Level::High),
+    )
+    .probe()
+    .await;
+    if !found {
+        return Some(pins);
+    }
+    let ball = Trackball::new(
+        trackball::Sdio::new(pins.sclk, pins.mosi),
+        Output::new(pins.cs, Level::High),
+    );
+    start_trackball(spawner, ball);
+    None
+}
+
+/// Probe the SPI bus for a trackball sensor, then for a Cirque
+/// trackpad, and start the driver of the first one answering. Without any,
+/// the keyboard runs without a pointing device.
+pub async fn init<TxDma: dma::ChannelInstance, RxDma: dma::ChannelInstance>(
+    spawner: &Spawner,
+    spi: Peri<'static, SPI0>,
+    pins: SensorPins,
+    tx_dma: Peri<'static, TxDma>,
+    rx_dma: Peri<'static, RxDma>,
+    irq: impl interrupt::typelevel::Binding<TxDma::Interrupt, dma::InterruptHandler<TxDma>>
+        + interrupt::typelevel::Binding<RxDma::Interrupt, dma::InterruptHandler<RxDma>>
+        + 'static,
+) {
+    #[cfg(feature = "pmw3320")]
+    let pins = match probe_trackball(spawner, pins).await {
+        Some(pins) => pins,
+        None => return,
+    };
+    let spi = Spi::new(
+        spi,
+        pins.sclk,
+        pins.mosi,
+        pins.miso,
+        tx_dma,
+        rx_dma,
+        irq,
+        trackpad::spi_config(),
+    );
+    let cs = Output::new(pins.cs, Level::High);
+    #[cfg(not(feature = "pmw3320"))]
+    let (spi, cs) = match probe_trackball(spawner, spi, cs).await {
+        Some(bus) => bus,
+        None => return,
+    };
+    let mut pad = trackpad::new(spi, cs);
+    match pad.probe().await {
+        Ok(true) => {
+            info!("Pointing device: trackpad");
+            DETECTED.store(PointingDevice::Trackpad as u8, Ordering::Relaxed);
+            spawner.spawn(trackpad::run(pad).unwrap());
+        }
+        _ => error!("No pointing device found"),
+    }
+}
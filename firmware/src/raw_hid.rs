@@ -0,0 +1,407 @@
+use crate::chip;
+use crate::key_stats;
+use crate::keys::{FULL_COLS, ROWS};
+use crate::panic_info::last_panic;
+use crate::rgb_leds::{AnimCommand, ANIM_CHANNEL};
+use crate::settings;
+use crate::side::{self, SIDE_CHANNEL};
+use crate::storage;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::Driver;
+use embassy_time::{Instant, Timer};
+use embassy_usb::class::hid::{HidReader, HidWriter};
+use utils::keymap::NOT_EDITED;
+use utils::log::{error, info, warn};
+use utils::raw_hid::{
+    report, rgb_anim, rgb_color, rgb_effect, Command, CustomValue, KeyboardValue, Report,
+    KEYMAP_BUFFER_CHUNK, PROTOCOL_VERSION, REPORT_SIZE, STATUS_ERROR, STATUS_OK,
+};
+use utils::rgb_anims::RgbAnimType;
+use utils::serde::Event;
+use utils::settings::{
+    Handedness, Settings, MAX_CPI, MIN_CPI, NB_PROFILES, PROFILE_NAME_LEN, SETTINGS_SIZE,
+};
+
+/// Raw HID reader type
+pub type RawHidReader<'a> = HidReader<'a, Driver<'a, USB>, REPORT_SIZE>;
+/// Raw HID writer type
+pub type RawHidWriter<'a> = HidWriter<'a, Driver<'a, USB>, REPORT_SIZE>;
+
+// The serialized settings must fit in a report, after the command id
+const _: () = assert!(SETTINGS_SIZE < REPORT_SIZE);
+// So must the names of all the profiles, after the active profile and
+// their number
+const _: () = assert!(3 + NB_PROFILES * PROFILE_NAME_LEN <= REPORT_SIZE);
+
+/// Status answer, from whether the command succeeded
+fn status(ok: bool) -> u8 {
+    if ok {
+        STATUS_OK
+    } else {
+        STATUS_ERROR
+    }
+}
+
+/// Switch to the RGB animation `anim`, on both halves.
+/// Returns `false` if there is none.
+fn set_rgb_anim(anim: Option<RgbAnimType>) -> bool {
+    let Some(anim) = anim else {
+        return false;
+    };
+    info!("Raw HID: setting the RGB animation");
+    if ANIM_CHANNEL.try_send(AnimCommand::Set(anim)).is_err() {
+        error!("Anim channel is full");
+    }
+    if SIDE_CHANNEL.try_send(Event::RgbAnim(anim)).is_err() {
+        error!("Side channel is full");
+    }
+    true
+}
+
+/// Set the custom value `value` to `data`.
+/// Returns `false` if it is invalid or read only.
+fn set_custom_value(value: CustomValue, data: &[u8]) -> bool {
+    let anim = settings::get().rgb_anim;
+    match value {
+        CustomValue::Cpi => {
+            let cpi = u16::from_be_bytes([data[0], data[1]]);
+            let ok = (MIN_CPI..=MAX_CPI).contains(&cpi);
+            if ok {
+                info!("Raw HID: CPI set to {}", cpi);
+                settings::update(|s| s.cpi = cpi);
+            }
+            ok
+        }
+        CustomValue::Layer => false,
+        CustomValue::RgbEffect => set_rgb_anim(rgb_anim(data[0], rgb_color(anim))),
+        CustomValue::RgbColor => set_rgb_anim(rgb_anim(rgb_effect(anim), data[0])),
+    }
+}
+
+/// Current custom value `value`, written to `data`. Returns its size.
+fn get_custom_value(value: CustomValue, data: &mut [u8]) -> usize {
+    match value {
+        CustomValue::Cpi => {
+            data[..2].copy_from_slice(&settings::get().cpi.to_be_bytes());
+            2
+        }
+        CustomValue::Layer => {
+            data[0] = crate::core::current_layer();
+            1
+        }
+        CustomValue::RgbEffect => {
+            data[0] = rgb_effect(settings::get().rgb_anim);
+            1
+        }
+        CustomValue::RgbColor => {
+            data[0] = rgb_color(settings::get().rgb_anim);
+            1
+        }
+    }
+}
+
+/// Action to take once the answer has been sent
+enum After {
+    /// Nothing to do
+    Nothing,
+    /// Reboot into the bootloader
+    Bootloader,
+    /// Erase the settings and reboot
+    FactoryReset,
+}
+
+/// Process a command received from the host
+fn process_command(cmd: &Report) -> (Report, After) {
+    match Command::from_u8(cmd[0]) {
+        Some(Command::GetProtocolVersion) => (
+            report(Command::GetProtocolVersion, &PROTOCOL_VERSION.to_be_bytes()),
+            After::Nothing,
+        ),
+        Some(Command::GetKeyboardValue) => match KeyboardValue::from_u8(cmd[1]) {
+            Some(KeyboardValue::Uptime) => {
+                let uptime = (Instant::now().as_millis() as u32).to_be_bytes();
+                let data = [cmd[1], uptime[0], uptime[1], uptime[2], uptime[3]];
+                (report(Command::GetKeyboardValue, &data), After::Nothing)
+            }
+            None => unhandled(cmd),
+        },
+        Some(Command::GetKeycode) => {
+            let (layer, row, col) = (cmd[1], cmd[2], cmd[3]);
+            let code = storage::get_keycode(layer as usize, row as usize, col as usize)
+                .unwrap_or(NOT_EDITED)
+                .to_be_bytes();
+            (
+                report(Command::GetKeycode, &[layer, row, col, code[0], code[1]]),
+                After::Nothing,
+            )
+        }
+        Some(Command::SetKeycode) => {
+            let code = u16::from_be_bytes([cmd[4], cmd[5]]);
+            let ok = storage::set_keycode(cmd[1] as usize, cmd[2] as usize, cmd[3] as usize, code);
+            (report(Command::SetKeycode, &[status(ok)]), After::Nothing)
+        }
+        Some(Command::ResetKeymap) => {
+            storage::reset();
+            (report(Command::ResetKeymap, &[STATUS_OK]), After::Nothing)
+        }
+        Some(Command::SetCustomValue) => match CustomValue::from_ids(cmd[1], cmd[2]) {
+            Some(value) => {
+                let ok = set_custom_value(value, &cmd[3..]);
+                (
+                    report(Command::SetCustomValue, &[status(ok)]),
+                    After::Nothing,
+                )
+            }
+            None => unhandled(cmd),
+        },
+        Some(Command::GetCustomValue) => match CustomValue::from_ids(cmd[1], cmd[2]) {
+            Some(value) => {
+                let mut data = [0u8; REPORT_SIZE - 1];
+                data[..2].copy_from_slice(&cmd[1..3]);
+                let n = get_custom_value(value, &mut data[2..]);
+                (
+                    report(Command::GetCustomValue, &data[..2 + n]),
+                    After::Nothing,
+                )
+            }
+            None => unhandled(cmd),
+        },
+        Some(Command::SaveCustomValues) => (
+            report(Command::SaveCustomValues, &[STATUS_OK]),
+            After::Nothing,
+        ),
+        Some(Command::BootloaderJump) => {
+            info!("Raw HID: jumping to the bootloader");
+            (report(Command::BootloaderJump, &[]), After::Bootloader)
+        }
+        Some(Command::GetMacroCount) => (report(Command::GetMacroCount, &[0]), After::Nothing),
+        Some(Command::GetMacroBufferSize) => {
+            (report(Command::GetMacroBufferSize, &[0, 0]), After::Nothing)
+        }
+        Some(Command::GetLayerCount) => (
+            report(Command::GetLayerCount, &[storage::layer_count()]),
+            After::Nothing,
+        ),
+        Some(Command::GetKeymapBuffer) => {
+            let offset = u16::from_be_bytes([cmd[1], cmd[2]]) as usize;
+            let size = (cmd[3] as usize).min(KEYMAP_BUFFER_CHUNK);
+            let mut data = [0u8; REPORT_SIZE - 1];
+            data[..3].copy_from_slice(&cmd[1..4]);
+            for (i, code) in data[3..3 + size].chunks_exact_mut(2).enumerate() {
+                let keycode = storage::get_buffer_keycode(offset / 2 + i).unwrap_or(NOT_EDITED);
+                code.copy_from_slice(&keycode.to_be_bytes());
+            }
+            (report(Command::GetKeymapBuffer, &data), After::Nothing)
+        }
+        Some(Command::SetKeymapBuffer) => {
+            let offset = u16::from_be_bytes([cmd[1], cmd[2]]) as usize;
+            let size = (cmd[3] as usize).min(KEYMAP_BUFFER_CHUNK);
+            let mut ok = true;
+            for (i, code) in cmd[4..4 + size].chunks_exact(2).enumerate() {
+                let keycode = u16::from_be_bytes([code[0], code[1]]);
+                ok &= storage::set_buffer_keycode(offset / 2 + i, keycode);
+            }
+            (
+                report(Command::SetKeymapBuffer, &[status(ok)]),
+                After::Nothing,
+            )
+        }
+        Some(Command::GetSettings) => match settings::get().to_bytes() {
+            Ok(bytes) => (report(Command::GetSettings, &bytes), After::Nothing),
+            Err(_) => (report(Command::GetSettings, &[]), After::Nothing),
+        },
+        Some(Command::SetSettings) => {
+            let mut bytes = [0u8; SETTINGS_SIZE];
+            bytes.copy_from_slice(&cmd[1..1 + SETTINGS_SIZE]);
+            let ok = match Settings::from_bytes(&bytes) {
+                Ok(new_settings) => {
+                    info!("Raw HID: importing settings");
+                    settings::update(|s| *s = new_settings);
+                    true
+                }
+                Err(_e) => {
+                    warn!("Raw HID: invalid settings: {:?}", _e);
+                    false
+                }
+            };
+            (report(Command::SetSettings, &[status(ok)]), After::Nothing)
+        }
+        Some(Command::GetProfiles) => {
+            let mut data = [0u8; 2 + NB_PROFILES * PROFILE_NAME_LEN];
+            data[0] = settings::active_profile();
+            data[1] = NB_PROFILES as u8;
+            for (i, name) in data[2..].chunks_exact_mut(PROFILE_NAME_LEN).enumerate() {
+                if let Some(profile) = settings::profile(i as u8) {
+                    name.copy_from_slice(&profile.name);
+                }
+            }
+            (report(Command::GetProfiles, &data), After::Nothing)
+        }
+        Some(Command::SetProfile) => {
+            let ok = settings::select_profile(cmd[1]);
+            if ok
+                && ANIM_CHANNEL
+                    .try_send(AnimCommand::ShowProfile(cmd[1]))
+                    .is_err()
+            {
+                error!("Anim channel is full");
+            }
+            (report(Command::SetProfile, &[status(ok)]), After::Nothing)
+        }
+        Some(Command::SetProfileName) => {
+            let name = &cmd[2..2 + PROFILE_NAME_LEN];
+            let len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+            let ok = settings::rename_profile(cmd[1], &name[..len]);
+            (
+                report(Command::SetProfileName, &[status(ok)]),
+                After::Nothing,
+            )
+        }
+        Some(Command::FactoryReset) => {
+            info!("Raw HID: factory reset");
+            (report(Command::FactoryReset, &[]), After::FactoryReset)
+        }
+        Some(Command::GetHandedness) => (
+            report(Command::GetHandedness, &[settings::handedness() as u8]),
+            After::Nothing,
+        ),
+        Some(Command::SetHandedness) => {
+            let ok = match Handedness::from_u8(cmd[1]) {
+                Ok(handedness) => {
+                    info!("Raw HID: handedness set to {:?}", handedness);
+                    settings::set_handedness(handedness);
+                    true
+                }
+                Err(_) => false,
+            };
+            (
+                report(Command::SetHandedness, &[status(ok)]),
+                After::Nothing,
+            )
+        }
+        Some(Command::GetDebounce) => (
+            report(Command::GetDebounce, &[settings::debounce_ms()]),
+            After::Nothing,
+        ),
+        Some(Command::SetDebounce) => {
+            let ok = settings::set_debounce_ms(cmd[1]);
+            (report(Command::SetDebounce, &[status(ok)]), After::Nothing)
+        }
+        Some(Command::GetAngleTune) => (
+            report(Command::GetAngleTune, &[settings::angle_tune() as u8]),
+            After::Nothing,
+        ),
+        Some(Command::SetAngleTune) => {
+            let ok = settings::set_angle_tune(cmd[1] as i8);
+            (report(Command::SetAngleTune, &[status(ok)]), After::Nothing)
+        }
+        Some(Command::GetSensorRotation) => (
+            report(
+                Command::GetSensorRotation,
+                &settings::sensor_rotation().to_be_bytes(),
+            ),
+            After::Nothing,
+        ),
+        Some(Command::SetSensorRotation) => {
+            let ok = settings::set_sensor_rotation(u16::from_be_bytes([cmd[1], cmd[2]]));
+            (
+                report(Command::SetSensorRotation, &[status(ok)]),
+                After::Nothing,
+            )
+        }
+        Some(Command::GetKeyStats) => {
+            let mut data = [0u8; REPORT_SIZE - 1];
+            data[0] = ROWS as u8;
+            data[1] = FULL_COLS as u8;
+            data[2] = cmd[1];
+            key_stats::write_counts(cmd[1] as usize, &mut data[3..]);
+            (report(Command::GetKeyStats, &data), After::Nothing)
+        }
+        Some(Command::ResetKeyStats) => {
+            info!("Raw HID: resetting the key statistics");
+            key_stats::reset();
+            (report(Command::ResetKeyStats, &[STATUS_OK]), After::Nothing)
+        }
+        Some(Command::GetLinkStats) => (
+            report(Command::GetLinkStats, &side::link_stats().to_bytes()),
+            After::Nothing,
+        ),
+        Some(Command::GetWpm) => (
+            report(Command::GetWpm, &crate::core::wpm().to_le_bytes()),
+            After::Nothing,
+        ),
+        Some(Command::GetSensorQuality) => {
+            // Without a trackball, the quality is all zeros
+            #[cfg(feature = "pointing_device")]
+            let quality = crate::trackball::quality();
+            #[cfg(not(feature = "pointing_device"))]
+            let quality = utils::raw_hid::SensorQuality::default();
+            (
+                report(Command::GetSensorQuality, &quality.to_bytes()),
+                After::Nothing,
+            )
+        }
+        Some(Command::GetLiftConfig) => (
+            report(Command::GetLiftConfig, &[settings::lift_config()]),
+            After::Nothing,
+        ),
+        Some(Command::SetLiftConfig) => {
+            let ok = settings::set_lift_config(cmd[1]);
+            (
+                report(Command::SetLiftConfig, &[status(ok)]),
+                After::Nothing,
+            )
+        }
+        Some(Command::GetIdleTimeout) => (
+            report(Command::GetIdleTimeout, &[settings::idle_timeout_s()]),
+            After::Nothing,
+        ),
+        Some(Command::SetIdleTimeout) => {
+            let ok = settings::set_idle_timeout_s(cmd[1]);
+            (
+                report(Command::SetIdleTimeout, &[status(ok)]),
+                After::Nothing,
+            )
+        }
+        Some(Command::GetLastPanic) => {
+            let msg = last_panic().unwrap_or("").as_bytes();
+            let mut data = [0u8; REPORT_SIZE - 1];
+            let n = msg.len().min(data.len() - 1);
+            data[0] = n as u8;
+            data[1..1 + n].copy_from_slice(&msg[..n]);
+            (report(Command::GetLastPanic, &data), After::Nothing)
+        }
+        Some(Command::Unhandled) | None => unhandled(cmd),
+    }
+}
+
+/// Answer to a command, or an argument of it, that is unknown
+fn unhandled(cmd: &Report) -> (Report, After) {
+    warn!("Raw HID: unhandled command 0x{:02x}", cmd[0]);
+    (report(Command::Unhandled, &cmd[..]), After::Nothing)
+}
+
+/// Loop reading commands from the host and answering them
+#[embassy_executor::task]
+pub async fn run(mut reader: RawHidReader<'static>, mut writer: RawHidWriter<'static>) {
+    let mut cmd = [0u8; REPORT_SIZE];
+    loop {
+        if let Err(_e) = reader.read(&mut cmd).await {
+            warn!("Raw HID: failed to read report: {:?}", _e);
+            continue;
+        }
+        let (answer, after) = process_command(&cmd);
+        if let Err(_e) = writer.write(&answer).await {
+            warn!("Raw HID: failed to send report: {:?}", _e);
+        }
+        match after {
+            After::Nothing => {}
+            After::Bootloader => {
+                // Leave some time for the answer to reach the host
+                Timer::after_millis(10).await;
+                chip::reset_to_usb_boot();
+            }
+            After::FactoryReset => settings::factory_reset(),
+        }
+    }
+}
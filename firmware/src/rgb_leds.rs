@@ -1,8 +1,13 @@
+use crate::channels::{self, Queue, ANIM_DEPTH};
+use crate::core::WPM_WATCH;
+use crate::device::SUSPEND_WATCH;
+use crate::settings::{self, SETTINGS_WATCH};
 use crate::side::SIDE_CHANNEL;
-use embassy_executor::Spawner;
+use crate::sysclk;
+use crate::watchdog::{self, Task};
+use embassy_executor::SendSpawner;
 use embassy_futures::select::{select, Either};
 use embassy_rp::{
-    clocks,
     dma::{
         Channel as DmaChannel, ChannelInstance as DmaChannelInstance,
         InterruptHandler as DmaInterruptHandler,
@@ -15,16 +20,30 @@ use embassy_rp::{
     },
     Peri,
 };
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
 use embassy_time::{Duration, Ticker, Timer};
-use fixed::types::U24F8;
-use fixed_macro::fixed;
 use utils::log::{error, info};
-use utils::rgb_anims::{RgbAnim, RgbAnimType, ERROR_COLOR_INDEX, NUM_LEDS, RGB8};
+use utils::rgb_anims::{
+    RgbAnim, RgbAnimType, ERROR_COLOR_INDEX, NB_INDEXED_COLORS, NUM_LEDS, RGB8,
+};
 use utils::serde::Event;
+use utils::settings::NB_PROFILES;
+
+/// Basic layout for the keyboard
+#[cfg(feature = "keymap_basic")]
+use crate::keymap_basic::LAYER_COLORS;
+
+/// Keymap by Boris Faure
+#[cfg(feature = "keymap_borisfaure")]
+use crate::keymap_borisfaure::LAYER_COLORS;
 
-#[cfg(feature = "defmt")]
-use {defmt_rtt as _, panic_probe as _};
+/// Test layout for the keyboard
+#[cfg(feature = "keymap_test")]
+use crate::keymap_test::LAYER_COLORS;
+
+/// Keymap generated from a JSON description
+#[cfg(feature = "keymap_json")]
+use crate::keymap_json::LAYER_COLORS;
 
 /// Animation commands
 #[derive(Debug)]
@@ -40,11 +59,72 @@ pub enum AnimCommand {
     Error,
     /// Error has been fixed
     Fixed,
+    /// The matrix scans show a fault, such as a stuck row
+    MatrixFault,
+    /// The matrix fault is gone
+    MatrixFixed,
+    /// The input is locked
+    InputLocked,
+    /// The input is unlocked
+    InputUnlocked,
+    /// Briefly show the active settings profile
+    ShowProfile(u8),
+    /// Mix entropy received from the other half into the PRNG
+    MixEntropy(u8),
+    /// Light the LEDs of the self test, one bit per LED, instead of the
+    /// animation
+    SelfTest(u64),
+    /// Briefly show the CPI of the sensor as a bar of LEDs
+    #[cfg(feature = "pointing_device")]
+    ShowCpi(u16),
 }
-/// Number of events in the animation channel
-pub const NB_EVENTS: usize = 64;
 /// Channel to change the animation of the RGB LEDs
-pub static ANIM_CHANNEL: Channel<ThreadModeRawMutex, AnimCommand, NB_EVENTS> = Channel::new();
+pub static ANIM_CHANNEL: Channel<CriticalSectionRawMutex, AnimCommand, ANIM_DEPTH> = Channel::new();
+
+/// Receive the next animation command
+async fn receive_command() -> AnimCommand {
+    let cmd = ANIM_CHANNEL.receive().await;
+    channels::record(Queue::Anim, ANIM_CHANNEL.len());
+    cmd
+}
+
+/// Color index shown for each settings profile
+const PROFILE_COLORS: [u8; NB_PROFILES] = [2, 4, 8];
+/// Duration of the profile indication, in animation frames
+const PROFILE_INDICATION_FRAMES: u8 = 24;
+/// Color index blinking on a matrix fault: red
+const MATRIX_FAULT_COLOR_INDEX: u8 = 5;
+/// Color index shown while the input is locked: gray
+const INPUT_LOCKED_COLOR_INDEX: u8 = 6;
+/// Half period of the blinking on a matrix fault, in animation frames
+const MATRIX_FAULT_BLINK_FRAMES: u8 = 6;
+/// Color of the LEDs lit by the self test
+const SELF_TEST_COLOR: RGB8 = RGB8::new(0x40, 0x40, 0x40);
+/// Color of the LEDs showing the CPI
+const CPI_COLOR: RGB8 = RGB8::new(0x00, 0x20, 0x40);
+
+/// LEDs lit to show the CPI `cpi`, one bit per LED: a bar growing with it
+#[cfg(feature = "pointing_device")]
+fn cpi_gauge(cpi: u16) -> u64 {
+    let lit = (NUM_LEDS as u32 * cpi as u32).div_ceil(crate::trackball::MAX_CPI as u32);
+    u64::MAX >> (64 - lit.clamp(1, 64))
+}
+
+/// LEDs set in `leds`, one bit per LED, lit in `color`, the others off
+fn lit_leds(leds: u64, color: RGB8) -> [RGB8; NUM_LEDS] {
+    core::array::from_fn(|i| {
+        if leds & (1 << i) != 0 {
+            color
+        } else {
+            RGB8::default()
+        }
+    })
+}
+
+/// WS2812 bit frequency, in Hz
+const WS2812_FREQ: u64 = 800_000;
+/// Number of PIO cycles per WS2812 bit
+const CYCLES_PER_BIT: u64 = 2 + 5 + 3;
 
 /// WS2812 driver
 pub struct Ws2812<'d, P: Instance, const S: usize, const N: usize> {
@@ -74,13 +154,8 @@ impl<'d, P: Instance, const S: usize, const N: usize> Ws2812<'d, P, S, N> {
 
         cfg.use_program(&pio.load_program(&rgb_led_prog.program), &[&out_pin]);
 
-        // Clock config, measured in kHz to avoid overflows
-        // TODO CLOCK_FREQ should come from embassy_rp
-        let clock_freq = U24F8::from_num(clocks::clk_sys_freq() / 1000);
-        let ws2812_freq = fixed!(800: U24F8);
-        const CYCLES_PER_BIT: u32 = (2 + 5 + 3) as u32;
-        let bit_freq = ws2812_freq * CYCLES_PER_BIT;
-        cfg.clock_divider = clock_freq / bit_freq;
+        // Clock config
+        cfg.clock_divider = Self::clock_divider(sysclk::sys_freq());
 
         // FIFO config
         cfg.fifo_join = FifoJoin::TxOnly;
@@ -96,6 +171,17 @@ impl<'d, P: Instance, const S: usize, const N: usize> Ws2812<'d, P, S, N> {
         Self { dma, sm }
     }
 
+    /// PIO clock divider for a system clock of `sys_freq` Hz
+    fn clock_divider(sys_freq: u32) -> fixed::FixedU32<fixed::types::extra::U8> {
+        sysclk::pio_divider(sys_freq, WS2812_FREQ * CYCLES_PER_BIT)
+    }
+
+    /// Recompute the clock divider after a system clock change
+    pub fn set_sys_freq(&mut self, sys_freq: u32) {
+        self.sm.set_clock_divider(Self::clock_divider(sys_freq));
+        self.sm.clkdiv_restart();
+    }
+
     pub async fn write(&mut self, colors: &[RGB8; N]) {
         // Precompute the word bytes from the colors
         let mut words = [0u32; N];
@@ -118,12 +204,47 @@ pub async fn run(mut ws2812: Ws2812<'static, PIO0, 0, NUM_LEDS>) {
     // Loop forever making RGB values and pushing them out to the WS2812.
     let mut ticker = Ticker::every(Duration::from_hz(24));
 
-    let mut anim = RgbAnim::new(clocks::rosc_freq());
+    let mut anim = RgbAnim::new(sysclk::rosc_entropy());
+    anim.set_animation(settings::get().rgb_anim);
+    anim.set_brightness(settings::get().rgb_brightness);
+    let mut settings_rcv = SETTINGS_WATCH.receiver().unwrap();
+    let mut sys_freq_rcv = sysclk::SYS_FREQ_WATCH.receiver().unwrap();
+    let mut suspend_rcv = SUSPEND_WATCH.receiver().unwrap();
+    let mut wpm_rcv = WPM_WATCH.receiver().unwrap();
+    // LEDs are turned off while the host is suspended
+    let mut suspended = false;
+    // Remaining frames of the profile indication
+    let mut profile_indication = 0u8;
+    // Frame of the blinking while the matrix shows a fault, if it does
+    let mut matrix_fault: Option<u8> = None;
+    // LEDs lit by the self test, if running
+    let mut self_test: Option<u64> = None;
+    // LEDs showing the CPI and remaining frames of the indication, if shown
+    let mut cpi_indication: Option<(u64, u8)> = None;
+    // The ticker wakes the task up well within the heartbeat period
+    watchdog::register(Task::Rgb);
     loop {
-        match select(ANIM_CHANNEL.receive(), ticker.next()).await {
+        watchdog::heartbeat(Task::Rgb);
+        if let Some(sys_freq) = sys_freq_rcv.try_changed() {
+            ws2812.set_sys_freq(sys_freq);
+        }
+        if let Some(wpm) = wpm_rcv.try_changed() {
+            anim.set_wpm(wpm);
+        }
+        if let Some(settings) = settings_rcv.try_changed() {
+            anim.set_brightness(settings.rgb_brightness);
+        }
+        if let Some(state) = suspend_rcv.try_changed() {
+            suspended = state;
+            if suspended {
+                ws2812.write(&[RGB8::default(); NUM_LEDS]).await;
+            }
+        }
+        match select(receive_command(), ticker.next()).await {
             Either::First(cmd) => match cmd {
                 AnimCommand::Next => {
                     let new_anim = anim.next_animation();
+                    settings::update(|s| s.rgb_anim = new_anim);
                     if SIDE_CHANNEL.is_full() {
                         error!("Side channel is full");
                     }
@@ -132,32 +253,76 @@ pub async fn run(mut ws2812: Ws2812<'static, PIO0, 0, NUM_LEDS>) {
                 }
                 AnimCommand::Set(new_anim) => {
                     anim.set_animation(new_anim);
+                    settings::update(|s| s.rgb_anim = new_anim);
                 }
-                AnimCommand::ChangeLayer(layer) => {
-                    if layer == 0 {
-                        anim.restore_animation();
-                    } else {
-                        anim.temporarily_solid_color(layer);
-                    }
-                }
+                AnimCommand::ChangeLayer(layer) => match LAYER_COLORS
+                    .get(layer as usize)
+                    .filter(|&&color| color != 0 && (color as usize) < NB_INDEXED_COLORS)
+                {
+                    Some(&color) => anim.temporarily_solid_color(color),
+                    None => anim.restore_animation(),
+                },
                 AnimCommand::Error => {
                     anim.temporarily_solid_color(ERROR_COLOR_INDEX);
                 }
                 AnimCommand::Fixed => {
                     anim.restore_animation();
                 }
+                AnimCommand::MatrixFault => matrix_fault = Some(0),
+                AnimCommand::MatrixFixed => {
+                    matrix_fault = None;
+                    anim.restore_animation();
+                }
+                AnimCommand::InputLocked => anim.temporarily_solid_color(INPUT_LOCKED_COLOR_INDEX),
+                AnimCommand::InputUnlocked => anim.restore_animation(),
+                AnimCommand::ShowProfile(profile) => {
+                    anim.set_animation(settings::get().rgb_anim);
+                    if let Some(color) = PROFILE_COLORS.get(profile as usize) {
+                        anim.temporarily_solid_color(*color);
+                        profile_indication = PROFILE_INDICATION_FRAMES;
+                    }
+                }
+                AnimCommand::MixEntropy(entropy) => anim.mix_entropy(entropy as u64),
+                AnimCommand::SelfTest(leds) => self_test = Some(leds),
+                #[cfg(feature = "pointing_device")]
+                AnimCommand::ShowCpi(cpi) => {
+                    info!("CPI: {}", cpi);
+                    cpi_indication = Some((cpi_gauge(cpi), PROFILE_INDICATION_FRAMES));
+                }
             },
-            Either::Second(_) => {
+            Either::Second(_) if !suspended => {
+                if profile_indication > 0 {
+                    profile_indication -= 1;
+                    if profile_indication == 0 {
+                        anim.restore_animation();
+                    }
+                }
+                if let Some(frame) = &mut matrix_fault {
+                    match *frame {
+                        0 => anim.temporarily_solid_color(MATRIX_FAULT_COLOR_INDEX),
+                        MATRIX_FAULT_BLINK_FRAMES => anim.temporarily_solid_color(0),
+                        _ => (),
+                    }
+                    *frame = (*frame + 1) % (2 * MATRIX_FAULT_BLINK_FRAMES);
+                }
                 let data = anim.tick();
-                ws2812.write(data).await;
+                if let Some(leds) = self_test {
+                    ws2812.write(&lit_leds(leds, SELF_TEST_COLOR)).await;
+                } else if let Some((leds, frames)) = cpi_indication {
+                    cpi_indication = (frames > 1).then_some((leds, frames - 1));
+                    ws2812.write(&lit_leds(leds, CPI_COLOR)).await;
+                } else {
+                    ws2812.write(data).await;
+                }
             }
+            Either::Second(_) => {}
         }
     }
 }
 
 /// Run the LED animation control
 pub fn init<D: DmaChannelInstance>(
-    spawner: &Spawner,
+    spawner: &SendSpawner,
     mut common: Common<'static, PIO0>,
     sm0: StateMachine<'static, PIO0, 0>,
     dma: Peri<'static, D>,
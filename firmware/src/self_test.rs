@@ -0,0 +1,145 @@
+use crate::board::THUMBS;
+use crate::keys::{Matrix, MatrixState, COLS, REFRESH_RATE, ROWS};
+#[cfg(feature = "pointing_device")]
+use crate::pointer;
+use crate::rgb_leds::{AnimCommand, ANIM_CHANNEL};
+use crate::side::{self, SIDE_CHANNEL};
+use embassy_executor::SendSpawner;
+use embassy_time::{Duration, Ticker, Timer};
+use portable_atomic::{AtomicU16, Ordering};
+use utils::log::{error, info};
+use utils::rgb_anims::{NUM_LEDS, UNDERGLOW_LEDS};
+use utils::serde::Event;
+
+/// Key held at power-up to enter the self test: the top outer key of the
+/// half
+const TRIGGER_KEY: (usize, usize) = (0, 0);
+/// Time left to the other half to boot before testing the link, in ms
+const BOOT_DELAY_MS: u64 = 1000;
+/// Nibbles sent to the other half to test the link, toggling every bit of
+/// the data
+const LINK_PATTERN: [u8; 4] = [0x0, 0xf, 0x5, 0xa];
+/// Time for the link pattern to be echoed back, in ms
+const LINK_TIMEOUT_MS: u64 = 500;
+/// Nibbles of the link pattern echoed back by the other half, one bit each
+static LINK_ECHOES: AtomicU16 = AtomicU16::new(0);
+/// First LED lit by the keys: the per-key LEDs follow the underglow ones
+/// when the board has both
+const FIRST_KEY_LED: usize = if NUM_LEDS > UNDERGLOW_LEDS {
+    UNDERGLOW_LEDS
+} else {
+    0
+};
+
+/// Whether the key entering the self test is held
+pub async fn requested(matrix: &mut Matrix<'_>) -> bool {
+    matrix.scan().await[TRIGGER_KEY.0][TRIGGER_KEY.1]
+}
+
+/// LEDs lit for the keys pressed, one bit per LED. The keys light the LEDs
+/// in the order of the matrix, which may not be the order of the LEDs
+/// along their chain.
+fn leds(state: &MatrixState, is_right: bool) -> u64 {
+    let mut leds = 0;
+    let mut led = FIRST_KEY_LED;
+    for (r, row) in state.iter().enumerate() {
+        for (c, &pressed) in row.iter().enumerate() {
+            if r == ROWS - 1 && THUMBS[is_right as usize][c].is_none() {
+                continue;
+            }
+            if pressed {
+                leds |= 1 << (led % NUM_LEDS);
+            }
+            led += 1;
+        }
+    }
+    leds
+}
+
+/// Record a nibble of the link pattern echoed back by the other half
+pub fn on_link_echo(pattern: u8) {
+    LINK_ECHOES.fetch_or(1 << (pattern & 0xf), Ordering::Relaxed);
+}
+
+/// Send a pattern to the other half and check that it is echoed back
+/// without any link error
+async fn test_link() {
+    LINK_ECHOES.store(0, Ordering::Relaxed);
+    let before = side::link_stats();
+    for pattern in LINK_PATTERN {
+        if SIDE_CHANNEL.is_full() {
+            error!("Side channel is full");
+        }
+        SIDE_CHANNEL.send(Event::LinkTest(pattern)).await;
+    }
+    Timer::after_millis(LINK_TIMEOUT_MS).await;
+    let after = side::link_stats();
+    let expected = LINK_PATTERN.iter().fold(0, |acc, &p| acc | (1 << p));
+    let echoes = LINK_ECHOES.load(Ordering::Relaxed);
+    if after.link_up && after.errors == before.errors && echoes == expected {
+        info!("Self test: link OK");
+    } else {
+        error!(
+            "Self test: link FAILED (up: {}, errors: {}, echoed: {}/{})",
+            after.link_up,
+            after.errors.wrapping_sub(before.errors),
+            echoes.count_ones(),
+            LINK_PATTERN.len()
+        );
+    }
+}
+
+/// Report the pointing device found at boot
+fn test_pointer(is_right: bool) {
+    if !is_right {
+        return;
+    }
+    #[cfg(feature = "pointing_device")]
+    match pointer::detected() {
+        Some(_device) => info!("Self test: pointing device OK ({:?})", _device),
+        None => error!("Self test: pointing device FAILED, no signature"),
+    }
+    #[cfg(not(feature = "pointing_device"))]
+    info!("Self test: no pointing device on this board");
+}
+
+/// Self test, run instead of the matrix scanner: lights a LED for each key
+/// pressed and logs the key changes, after checking the link and the
+/// pointing device
+#[embassy_executor::task]
+async fn run(mut matrix: Matrix<'static>, is_right: bool) {
+    info!("Self test: starting");
+    Timer::after_millis(BOOT_DELAY_MS).await;
+    test_link().await;
+    test_pointer(is_right);
+    info!("Self test: press each key, its LED lights up");
+
+    let mut ticker = Ticker::every(Duration::from_hz(REFRESH_RATE.into()));
+    let mut last = [[false; COLS]; ROWS];
+    loop {
+        let state = matrix.scan().await;
+        if state != last {
+            for (_r, (row, last_row)) in state.iter().zip(last.iter()).enumerate() {
+                for (_c, (&pressed, &was_pressed)) in row.iter().zip(last_row.iter()).enumerate() {
+                    if pressed != was_pressed {
+                        info!("Self test: key {:?} pressed: {}", (_r, _c), pressed);
+                    }
+                }
+            }
+            if ANIM_CHANNEL.is_full() {
+                error!("Anim channel is full");
+            }
+            ANIM_CHANNEL
+                .send(AnimCommand::SelfTest(leds(&state, is_right)))
+                .await;
+            last = state;
+        }
+        ticker.next().await;
+    }
+}
+
+/// Start the self test
+pub fn init(spawner: &SendSpawner, matrix: Matrix<'static>, is_right: bool) {
+    info!("Self test requested");
+    spawner.spawn(run(matrix, is_right).unwrap());
+}
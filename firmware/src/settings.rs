@@ -0,0 +1,429 @@
+use crate::device::is_host;
+use crate::side::SIDE_CHANNEL;
+use core::cell::RefCell;
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_rp::flash::{Blocking, Error as FlashError, Flash};
+use embassy_rp::peripherals::FLASH;
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    mutex::Mutex as AsyncMutex,
+    signal::Signal,
+    watch::{Receiver, Watch},
+};
+use embassy_time::Timer;
+use utils::log::{error, info, warn};
+use utils::settings::{
+    angle_tune_is_valid, mirror_events, Handedness, Profile, Profiles, Settings, SettingsStore,
+    Storage, MAX_DEBOUNCE_MS, MAX_IDLE_TIMEOUT_S, MAX_LIFT_CONFIG, MAX_SENSOR_ROTATION,
+    MIN_IDLE_TIMEOUT_S, REGION_SIZE, SECTOR_SIZE,
+};
+
+/// Basic layout for the keyboard
+#[cfg(feature = "keymap_basic")]
+use crate::keymap_basic::NB_LAYERS;
+
+/// Keymap by Boris Faure
+#[cfg(feature = "keymap_borisfaure")]
+use crate::keymap_borisfaure::NB_LAYERS;
+
+/// Test layout for the keyboard
+#[cfg(feature = "keymap_test")]
+use crate::keymap_test::NB_LAYERS;
+
+/// Keymap generated from a JSON description
+#[cfg(feature = "keymap_json")]
+use crate::keymap_json::NB_LAYERS;
+
+/// Size of the flash, in bytes: 2MB on the RP2040 controllers, 4MB on the
+/// RP2350 ones, as on the Pico 2
+#[cfg(feature = "rp2040")]
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+#[cfg(feature = "rp2350")]
+pub const FLASH_SIZE: usize = 4 * 1024 * 1024;
+/// Offset of the settings region in flash: its last sectors, which are
+/// excluded from the firmware in `memory.x`
+const REGION_OFFSET: u32 = FLASH_SIZE as u32 - REGION_SIZE;
+/// Delay between the last change of the settings and their saving, in ms.
+/// Changes made in a row are written at once to save erase cycles.
+const SAVE_DELAY_MS: u64 = 2000;
+/// Delay between the last change of the settings and their mirroring to the
+/// other half, in ms
+const MIRROR_DELAY_MS: u64 = 500;
+/// Delay between two events mirroring the settings, in ms. It leaves time
+/// for the other half to acknowledge them.
+const MIRROR_EVENT_INTERVAL_MS: u64 = 2;
+
+/// Flash driver
+pub type SettingsFlash = Flash<'static, FLASH, Blocking, FLASH_SIZE>;
+
+/// Number of tasks that can listen to the settings changes
+const NB_SETTINGS_RECEIVERS: usize = 4;
+/// Settings changes
+pub static SETTINGS_WATCH: Watch<CriticalSectionRawMutex, Settings, NB_SETTINGS_RECEIVERS> =
+    Watch::new();
+/// Receiver of the settings changes
+pub type SettingsReceiver =
+    Receiver<'static, CriticalSectionRawMutex, Settings, NB_SETTINGS_RECEIVERS>;
+
+/// Flash driver, shared by the settings, the keymap and the key statistics.
+/// `None` until `init`. It is held without a critical section: the other
+/// core must be able to take one while it is paused by the erases and
+/// writes.
+static FLASH: AsyncMutex<CriticalSectionRawMutex, Option<SettingsFlash>> = AsyncMutex::new(None);
+
+/// Current profiles, `None` until they are loaded
+static PROFILES: Mutex<CriticalSectionRawMutex, RefCell<Option<Profiles>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Signal to save the settings
+static SAVE_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Signal to mirror the settings to the other half
+static MIRROR_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Signal to erase the settings and reboot
+static FACTORY_RESET_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Run `f` with the flash driver, once no other task uses it. Must not be
+/// called before `init`.
+pub async fn with_flash<R>(f: impl FnOnce(&mut SettingsFlash) -> R) -> R {
+    f(FLASH.lock().await.as_mut().unwrap())
+}
+
+/// Settings region of the flash
+struct FlashStorage;
+
+impl Storage for FlashStorage {
+    type Error = FlashError;
+
+    async fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        with_flash(|f| f.blocking_read(REGION_OFFSET + offset, buf)).await
+    }
+
+    async fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+        with_flash(|f| f.blocking_write(REGION_OFFSET + offset, data)).await
+    }
+
+    async fn erase(&mut self, offset: u32) -> Result<(), FlashError> {
+        let from = REGION_OFFSET + offset;
+        with_flash(|f| f.blocking_erase(from, from + SECTOR_SIZE)).await
+    }
+}
+
+/// Get all the profiles
+fn profiles() -> Profiles {
+    PROFILES.lock(|p| p.borrow().unwrap_or_default())
+}
+
+/// Modify the profiles with `f`.
+/// If the settings of the active profile change, they are broadcast on
+/// `SETTINGS_WATCH`. Any change is saved to flash later on, and mirrored to
+/// the other half if it is not about the handedness.
+fn update_profiles(f: impl FnOnce(&mut Profiles)) {
+    let (prev, profiles) = PROFILES.lock(|p| {
+        let mut p = p.borrow_mut();
+        let profiles = p.get_or_insert_with(Profiles::default);
+        let prev = *profiles;
+        f(profiles);
+        (prev, *profiles)
+    });
+    if profiles.current() != prev.current() {
+        SETTINGS_WATCH.sender().send(*profiles.current());
+    }
+    if profiles != prev {
+        SAVE_SIGNAL.signal(());
+        if profiles.handedness == prev.handedness {
+            MIRROR_SIGNAL.signal(());
+        }
+    }
+}
+
+/// Get the current settings, from the active profile
+pub fn get() -> Settings {
+    PROFILES.lock(|p| {
+        p.borrow()
+            .as_ref()
+            .map(|profiles| *profiles.current())
+            .unwrap_or_default()
+    })
+}
+
+/// Modify the current settings with `f`.
+/// Invalid values are reset to their default.
+/// The change is broadcast on `SETTINGS_WATCH` and saved to flash later on.
+pub fn update(f: impl FnOnce(&mut Settings)) {
+    update_profiles(|p| {
+        let settings = p.current_mut();
+        f(settings);
+        settings.validate(NB_LAYERS);
+    });
+}
+
+/// Index of the active profile
+pub fn active_profile() -> u8 {
+    profiles().active()
+}
+
+/// Get the profile `index`
+pub fn profile(index: u8) -> Option<Profile> {
+    profiles().get(index).copied()
+}
+
+/// Switch to the profile `index`.
+/// Returns `false` if there is no such profile.
+pub fn select_profile(index: u8) -> bool {
+    let mut ok = false;
+    update_profiles(|p| ok = p.set_active(index).is_ok());
+    if ok {
+        info!("Switched to profile {}", index);
+    }
+    ok
+}
+
+/// Rename the profile `index`.
+/// Returns `false` if there is no such profile.
+pub fn rename_profile(index: u8, name: &[u8]) -> bool {
+    let mut ok = false;
+    update_profiles(|p| {
+        if let Some(profile) = p.get_mut(index) {
+            let n = name.len().min(profile.name.len());
+            profile.name = Default::default();
+            profile.name[..n].copy_from_slice(&name[..n]);
+            ok = true;
+        }
+    });
+    ok
+}
+
+/// Handedness stored in the settings
+pub fn handedness() -> Handedness {
+    profiles().handedness
+}
+
+/// Store the handedness of this half. It is used from the next boot on.
+pub fn set_handedness(handedness: Handedness) {
+    update_profiles(|p| p.handedness = handedness);
+}
+
+/// Keyboard matrix debouncing time, in ms
+pub fn debounce_ms() -> u8 {
+    PROFILES.lock(|p| {
+        p.borrow()
+            .as_ref()
+            .map(|profiles| profiles.debounce_ms)
+            .unwrap_or_else(|| Profiles::default().debounce_ms)
+    })
+}
+
+/// Set the keyboard matrix debouncing time, in ms.
+/// Returns `false` if the value is out of range.
+pub fn set_debounce_ms(debounce_ms: u8) -> bool {
+    if debounce_ms > MAX_DEBOUNCE_MS {
+        return false;
+    }
+    update_profiles(|p| p.debounce_ms = debounce_ms);
+    true
+}
+
+/// Sensor angle tune, in degrees
+pub fn angle_tune() -> i8 {
+    PROFILES.lock(|p| {
+        p.borrow()
+            .as_ref()
+            .map(|profiles| profiles.angle_tune)
+            .unwrap_or_else(|| Profiles::default().angle_tune)
+    })
+}
+
+/// Set the sensor angle tune, in degrees. It is applied when the sensor
+/// powers up.
+/// Returns `false` if the value is out of range.
+pub fn set_angle_tune(angle: i8) -> bool {
+    if !angle_tune_is_valid(angle) {
+        return false;
+    }
+    update_profiles(|p| p.angle_tune = angle);
+    true
+}
+
+/// Rotation applied to the sensor moves, clockwise, in degrees
+pub fn sensor_rotation() -> u16 {
+    PROFILES.lock(|p| {
+        p.borrow()
+            .as_ref()
+            .map(|profiles| profiles.sensor_rotation)
+            .unwrap_or_default()
+    })
+}
+
+/// Set the rotation applied to the sensor moves, clockwise, in degrees.
+/// Returns `false` if the value is out of range.
+pub fn set_sensor_rotation(degrees: u16) -> bool {
+    if degrees > MAX_SENSOR_ROTATION {
+        return false;
+    }
+    update_profiles(|p| p.sensor_rotation = degrees);
+    true
+}
+
+/// Lift-off distance of the sensor, the value of its `LiftConfig` register
+pub fn lift_config() -> u8 {
+    PROFILES.lock(|p| {
+        p.borrow()
+            .as_ref()
+            .map(|profiles| profiles.lift_config)
+            .unwrap_or_else(|| Profiles::default().lift_config)
+    })
+}
+
+/// Set the lift-off distance of the sensor, the value of its `LiftConfig`
+/// register.
+/// Returns `false` if the value is out of range.
+pub fn set_lift_config(config: u8) -> bool {
+    if config > MAX_LIFT_CONFIG {
+        return false;
+    }
+    update_profiles(|p| p.lift_config = config);
+    true
+}
+
+/// Time without any activity before the system clock is lowered, in
+/// seconds
+pub fn idle_timeout_s() -> u8 {
+    PROFILES.lock(|p| {
+        p.borrow()
+            .as_ref()
+            .map(|profiles| profiles.idle_timeout_s)
+            .unwrap_or_else(|| Profiles::default().idle_timeout_s)
+    })
+}
+
+/// Set the time without any activity before the system clock is lowered,
+/// in seconds.
+/// Returns `false` if the value is out of range.
+pub fn set_idle_timeout_s(timeout_s: u8) -> bool {
+    if !(MIN_IDLE_TIMEOUT_S..=MAX_IDLE_TIMEOUT_S).contains(&timeout_s) {
+        return false;
+    }
+    update_profiles(|p| p.idle_timeout_s = timeout_s);
+    true
+}
+
+/// Apply the profiles mirrored by the other half, keeping the handedness of
+/// this half
+pub fn apply_mirror(mirrored: Profiles) {
+    info!("Settings mirrored from the other half");
+    update_profiles(|p| {
+        let handedness = p.handedness;
+        *p = mirrored;
+        p.handedness = handedness;
+        p.validate(NB_LAYERS);
+    });
+}
+
+/// Send the settings to the other half once they stop changing, so that both
+/// halves persist the same values whichever one is connected to the host
+#[embassy_executor::task]
+async fn mirror() {
+    loop {
+        MIRROR_SIGNAL.wait().await;
+        while let Either::First(_) =
+            select(MIRROR_SIGNAL.wait(), Timer::after_millis(MIRROR_DELAY_MS)).await
+        {}
+        // Only the host half mirrors its settings: the other one receives
+        // them
+        if !is_host() {
+            continue;
+        }
+        let events = match mirror_events(&profiles()) {
+            Ok(events) => events,
+            Err(_e) => {
+                error!("Failed to serialize the settings: {:?}", _e);
+                continue;
+            }
+        };
+        for event in events {
+            if SIDE_CHANNEL.is_full() {
+                error!("Side channel is full");
+            }
+            SIDE_CHANNEL.send(event).await;
+            Timer::after_millis(MIRROR_EVENT_INTERVAL_MS).await;
+        }
+    }
+}
+
+/// Erase the persisted settings and reboot, so that the defaults are used
+pub fn factory_reset() {
+    FACTORY_RESET_SIGNAL.signal(());
+}
+
+/// Erase the settings from flash and reboot
+async fn erase_and_reboot(store: &mut SettingsStore<FlashStorage>) -> ! {
+    info!("Factory reset: erasing the settings");
+    if let Err(_e) = store.erase_all().await {
+        error!("Failed to erase the settings: {:?}", _e);
+    }
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+/// Save the settings to flash once they stop changing
+#[embassy_executor::task]
+async fn run(mut store: SettingsStore<FlashStorage>) {
+    let mut saved = profiles();
+    loop {
+        if let Either::Second(_) = select(SAVE_SIGNAL.wait(), FACTORY_RESET_SIGNAL.wait()).await {
+            erase_and_reboot(&mut store).await;
+        }
+        loop {
+            match select3(
+                SAVE_SIGNAL.wait(),
+                FACTORY_RESET_SIGNAL.wait(),
+                Timer::after_millis(SAVE_DELAY_MS),
+            )
+            .await
+            {
+                Either3::First(_) => {}
+                Either3::Second(_) => erase_and_reboot(&mut store).await,
+                Either3::Third(_) => break,
+            }
+        }
+        let profiles = profiles();
+        if profiles == saved {
+            continue;
+        }
+        match store.save(&profiles).await {
+            Ok(()) => {
+                info!("Settings saved");
+                saved = profiles;
+            }
+            Err(_e) => error!("Failed to save the settings: {:?}", _e),
+        }
+    }
+}
+
+/// Load the settings from flash and spawn the task saving them
+pub async fn init(spawner: &Spawner, flash: SettingsFlash) {
+    *FLASH.lock().await = Some(flash);
+    let mut store = SettingsStore::new(FlashStorage);
+    let mut profiles = match store.load().await {
+        Ok(Some(profiles)) => {
+            info!("Settings loaded: {:?}", profiles);
+            profiles
+        }
+        Ok(None) => {
+            info!("No settings found, using the defaults");
+            Profiles::default()
+        }
+        Err(_e) => {
+            error!("Failed to load the settings: {:?}", _e);
+            Profiles::default()
+        }
+    };
+    // Never run with settings that could leave the keyboard unusable
+    if profiles.validate(NB_LAYERS) > 0 {
+        warn!("Some settings were invalid and have been reset");
+    }
+    PROFILES.lock(|p| p.replace(Some(profiles)));
+    SETTINGS_WATCH.sender().send(*profiles.current());
+    spawner.spawn(run(store).unwrap());
+    spawner.spawn(mirror().unwrap());
+}
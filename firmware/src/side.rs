@@ -1,42 +1,76 @@
-use crate::core::LAYOUT_CHANNEL;
+use crate::board::SideLinkPin;
+#[cfg(feature = "buzzer")]
+use crate::buzzer::{self, Sound};
+use crate::channels::{self, Queue, SIDE_DEPTH, SIDE_HW_RX_DEPTH, SIDE_HW_TX_DEPTH};
+use crate::core::{LAYOUT_CHANNEL, WPM_WATCH};
+use crate::device;
+use crate::display;
+use crate::key_stats;
+#[cfg(feature = "timing_logs")]
+use crate::metrics::{self, Metric};
 use crate::rgb_leds::{AnimCommand, ANIM_CHANNEL};
-use embassy_executor::Spawner;
+use crate::self_test;
+use crate::settings;
+use crate::status_led;
+use crate::sysclk;
+#[cfg(feature = "tracing")]
+use crate::trace::{self, Kind};
+use crate::usb::WAKEUP_SIGNAL;
+use crate::watchdog::{self, Task};
+use embassy_executor::SendSpawner;
 use embassy_futures::select::{select, Either};
-#[cfg(feature = "dilemma")]
-use embassy_rp::peripherals::PIN_1;
-#[cfg(feature = "cnano")]
-use embassy_rp::peripherals::PIN_29;
 use embassy_rp::{
-    clocks,
-    gpio::{Level, Output, Pull},
+    gpio::{Level, Pull},
     peripherals::PIO1,
     pio::{self, program::pio_asm, Direction, ShiftDirection, StateMachine},
     Peri,
 };
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
 use embassy_time::{Duration, Instant, Ticker};
-use fixed::{traits::ToFixed, types::U56F8};
 use keyberon::layout::Event as KBEvent;
+use portable_atomic::{AtomicBool, AtomicI32, AtomicU32, Ordering};
+use utils::drift::{compensated_speed, DriftEstimator};
 #[cfg(feature = "defmt")]
 use utils::log::Debug2Format;
 use utils::log::{error, info, warn};
 use utils::protocol::{Hardware, SideProtocol};
+use utils::raw_hid::LinkStats;
 use utils::serde::Event;
+use utils::settings::MirrorReceiver;
 
 /// Speed of the PIO state machine, in bps
 const SPEED: u64 = 460_800;
 
-/// Number of events in the channel to the other half of the keyboard
-const NB_EVENTS: usize = 16;
 /// Channel to send `utils::serde::event` events to the layout handler
-pub static SIDE_CHANNEL: Channel<ThreadModeRawMutex, Event, NB_EVENTS> = Channel::new();
+pub static SIDE_CHANNEL: Channel<CriticalSectionRawMutex, Event, SIDE_DEPTH> = Channel::new();
 
-/// Hardware queue size (for decoupling protocol from hardware timing)
-const HW_QUEUE_SIZE: usize = 128;
-/// Hardware TX queue: protocol layer queues messages here to be sent
-static HW_TX_QUEUE: Channel<ThreadModeRawMutex, u32, HW_QUEUE_SIZE> = Channel::new();
+/// Hardware TX queue: protocol layer queues messages here to be sent.
+/// It decouples the protocol from the hardware timing.
+static HW_TX_QUEUE: Channel<CriticalSectionRawMutex, u32, SIDE_HW_TX_DEPTH> = Channel::new();
 /// Hardware RX queue: hardware task places received messages here
-static HW_RX_QUEUE: Channel<ThreadModeRawMutex, u32, HW_QUEUE_SIZE> = Channel::new();
+static HW_RX_QUEUE: Channel<CriticalSectionRawMutex, u32, SIDE_HW_RX_DEPTH> = Channel::new();
+
+/// Whether the link is on error
+static LINK_ERROR: AtomicBool = AtomicBool::new(false);
+/// Number of times the link went on error
+static LINK_ERRORS: AtomicU32 = AtomicU32::new(0);
+/// Number of events sent to the other half, keepalives excluded
+static EVENTS_SENT: AtomicU32 = AtomicU32::new(0);
+/// Number of events received from the other half, keepalives excluded
+static EVENTS_RECEIVED: AtomicU32 = AtomicU32::new(0);
+/// Drift of the clock of the master, in ppm, measured on the slave
+static DRIFT_PPM: AtomicI32 = AtomicI32::new(0);
+
+/// Statistics of the link with the other half
+pub fn link_stats() -> LinkStats {
+    LinkStats {
+        link_up: !LINK_ERROR.load(Ordering::Relaxed),
+        errors: LINK_ERRORS.load(Ordering::Relaxed),
+        events_sent: EVENTS_SENT.load(Ordering::Relaxed),
+        events_received: EVENTS_RECEIVED.load(Ordering::Relaxed),
+        drift_ppm: DRIFT_PPM.load(Ordering::Relaxed),
+    }
+}
 
 /// Compound state machine that handles both TX and RX
 pub type SmCompound<'a> = StateMachine<'a, PIO1, 0>;
@@ -46,8 +80,8 @@ pub type PioPin<'a> = pio::Pin<'a, PIO1>;
 struct SidesComms<W: Sized + Hardware> {
     /// Protocol to communicate with the other side
     protocol: SideProtocol<W>,
-    /// Status LED
-    status_led: Output<'static>,
+    /// Settings mirrored by the other half
+    mirror: MirrorReceiver,
     /// Message statistics: real messages sent counter
     msg_sent_real: usize,
     /// Message statistics: noop messages sent counter
@@ -74,13 +108,21 @@ impl Hardware for HwProtocol {
     }
 
     async fn receive(&mut self) -> u32 {
-        HW_RX_QUEUE.receive().await
+        let msg = HW_RX_QUEUE.receive().await;
+        channels::record(Queue::SideHwRx, HW_RX_QUEUE.len());
+        msg
     }
 
     // Set error state
     async fn set_error_state(&mut self, error: bool) {
+        display::set_link_ok(!error);
+        status_led::set_link_down(error);
+        LINK_ERROR.store(error, Ordering::Relaxed);
         if error && !self.on_error {
             self.on_error = true;
+            LINK_ERRORS.fetch_add(1, Ordering::Relaxed);
+            #[cfg(feature = "buzzer")]
+            buzzer::play(Sound::LinkError);
             if ANIM_CHANNEL.is_full() {
                 error!("Anim channel is full");
             }
@@ -97,27 +139,49 @@ impl Hardware for HwProtocol {
 }
 
 /// Independent hardware task that maintains strict 1ms bidirectional communication
-/// This runs independently and maintains continuous 1ms timing
+/// This runs independently and maintains continuous 1ms timing.
+/// The slave measures the drift of the clock of the master from the frames
+/// it receives, and tunes its bit rate to it.
 #[embassy_executor::task]
-async fn hardware_task(mut sm: SmCompound<'static>) {
+async fn hardware_task(mut sm: SmCompound<'static>, is_master: bool) {
     info!(
         "Starting side comms hardware task (PIO SM0 at {} bps)",
         SPEED
     );
     let mut ticker = Ticker::every(Duration::from_millis(1));
+    let mut sys_freq_rcv = sysclk::SYS_FREQ_WATCH.receiver().unwrap();
+    let mut sys_freq = sysclk::sys_freq();
+    let mut drift = DriftEstimator::default();
 
     let mut tick_count: u32 = 0;
     let mut next_log: u32 = 1;
+    watchdog::register(Task::Side);
     loop {
         ticker.next().await;
+        watchdog::heartbeat(Task::Side);
+        #[cfg(feature = "timing_logs")]
+        let start = Instant::now();
         tick_count = tick_count.wrapping_add(1);
         if tick_count == next_log {
             info!("Side comms running... (tick_count={})", tick_count);
             next_log = next_log.wrapping_mul(2);
         }
 
+        // Keep the bit rate constant when the system clock changes
+        if let Some(freq) = sys_freq_rcv.try_changed() {
+            sys_freq = freq;
+            sm.set_clock_divider(pio_freq(sys_freq, drift.drift_ppm()));
+            sm.clkdiv_restart();
+        }
+
         // ALWAYS send something to maintain 1ms timing
-        let msg_to_send = HW_TX_QUEUE.try_receive().unwrap_or_default();
+        let msg_to_send = match HW_TX_QUEUE.try_receive() {
+            Ok(msg) => {
+                channels::record(Queue::SideHwTx, HW_TX_QUEUE.len());
+                msg
+            }
+            Err(_) => 0,
+        };
 
         // Send via PIO (compound state machine handles TX automatically)
         sm.tx().wait_push(msg_to_send).await;
@@ -125,20 +189,34 @@ async fn hardware_task(mut sm: SmCompound<'static>) {
         // Check if we received anything (non-blocking)
         if sm.rx().level() > 0 {
             let received_msg = sm.rx().wait_pull().await;
+            if !is_master {
+                if let Some(ppm) = drift.on_frame(Instant::now().as_micros()) {
+                    info!("Side link clock drift: {} ppm", ppm);
+                    DRIFT_PPM.store(ppm, Ordering::Relaxed);
+                    sm.set_clock_divider(pio_freq(sys_freq, ppm));
+                    sm.clkdiv_restart();
+                }
+            }
             // Filter out keepalive messages (0x00000000)
             if received_msg != 0x00000000 {
                 // Queue it for the protocol layer (non-blocking)
                 let _ = HW_RX_QUEUE.try_send(received_msg);
             }
         }
+        #[cfg(feature = "timing_logs")]
+        metrics::record(Metric::SideLink, start);
     }
 }
 
 /// Process an event
-async fn process_event(event: Event) {
+async fn process_event(event: Event, mirror: &mut MirrorReceiver) {
     match event {
         Event::Noop => {}
         Event::Press(i, j) => {
+            key_stats::on_press(i, j);
+            // The latency of the link is not measured
+            #[cfg(feature = "timing_logs")]
+            metrics::key_pressed();
             if LAYOUT_CHANNEL.is_full() {
                 error!("Layout channel is full");
             }
@@ -157,13 +235,36 @@ async fn process_event(event: Event) {
             ANIM_CHANNEL.send(AnimCommand::Set(anim)).await;
         }
         Event::RgbAnimChangeLayer(layer) => {
+            display::set_layer(layer);
             if ANIM_CHANNEL.is_full() {
                 error!("Anim channel is full");
             }
             ANIM_CHANNEL.send(AnimCommand::ChangeLayer(layer)).await;
         }
         Event::SeedRng(seed) => {
-            todo!("Seed random {}", seed);
+            if ANIM_CHANNEL.is_full() {
+                error!("Anim channel is full");
+            }
+            ANIM_CHANNEL.send(AnimCommand::MixEntropy(seed)).await;
+        }
+        Event::DisplayWpm(wpm) => {
+            display::set_wpm(wpm);
+            WPM_WATCH.sender().send(wpm as u16);
+        }
+        Event::DisplayLocks(locks) => display::set_locks(locks),
+        Event::HostSuspended(suspended) => device::set_suspended(suspended),
+        Event::WakeHost => WAKEUP_SIGNAL.signal(()),
+        // The side task drains the channel itself, so it must not wait on it
+        Event::LinkTest(pattern) => {
+            if SIDE_CHANNEL.try_send(Event::LinkTestEcho(pattern)).is_err() {
+                error!("Side channel is full");
+            }
+        }
+        Event::LinkTestEcho(pattern) => self_test::on_link_echo(pattern),
+        Event::SettingsStart | Event::SettingsNibble(_) => {
+            if let Some(profiles) = mirror.on_event(event) {
+                settings::apply_mirror(profiles);
+            }
         }
         _ => {
             warn!("Unhandled event {:?}", Debug2Format(&event));
@@ -173,18 +274,14 @@ async fn process_event(event: Event) {
 
 impl<W: Sized + Hardware> SidesComms<W> {
     /// Create a new event buffer
-    pub fn new(
-        #[cfg(feature = "defmt")] name: &'static str,
-        hw: W,
-        status_led: Output<'static>,
-    ) -> Self {
+    pub fn new(#[cfg(feature = "defmt")] name: &'static str, hw: W) -> Self {
         Self {
             protocol: SideProtocol::new(
                 hw,
                 #[cfg(feature = "defmt")]
                 name,
             ),
-            status_led,
+            mirror: MirrorReceiver::default(),
             msg_sent_real: 0,
             msg_sent_noop: 0,
             msg_received_real: 0,
@@ -195,6 +292,10 @@ impl<W: Sized + Hardware> SidesComms<W> {
 
     /// Run the communication between the two sides
     pub async fn run(&mut self) {
+        // Share some entropy with the other half, for its random animations
+        for seed in (sysclk::rosc_entropy() as u32).to_le_bytes() {
+            self.protocol.queue_event(Event::SeedRng(seed)).await;
+        }
         // Wait for the other side to boot
         loop {
             // Check if it's time to report stats (non-blocking)
@@ -218,31 +319,33 @@ impl<W: Sized + Hardware> SidesComms<W> {
 
             match result {
                 Either::First(event) => {
+                    channels::record(Queue::Side, SIDE_CHANNEL.len());
                     // Track noop vs real messages
                     if matches!(event, Event::Noop) {
                         self.msg_sent_noop += 1;
                     } else {
                         self.msg_sent_real += 1;
+                        EVENTS_SENT.fetch_add(1, Ordering::Relaxed);
+                        #[cfg(feature = "tracing")]
+                        trace::record(trace::Task::Side, Kind::SideSend(event));
                     }
 
                     self.protocol.queue_event(event).await;
                 }
                 Either::Second(x) => {
-                    #[cfg(feature = "cnano")]
-                    self.status_led.set_low();
-                    #[cfg(feature = "dilemma")]
-                    self.status_led.set_high();
-                    process_event(x).await;
-                    #[cfg(feature = "cnano")]
-                    self.status_led.set_high();
-                    #[cfg(feature = "dilemma")]
-                    self.status_led.set_low();
+                    if !matches!(x, Event::Noop) {
+                        sysclk::notify_activity();
+                        #[cfg(feature = "tracing")]
+                        trace::record(trace::Task::Side, Kind::SideReceive(x));
+                    }
+                    process_event(x, &mut self.mirror).await;
 
                     // Track noop vs real messages
                     if matches!(x, Event::Noop) {
                         self.msg_received_noop += 1;
                     } else {
                         self.msg_received_real += 1;
+                        EVENTS_RECEIVED.fetch_add(1, Ordering::Relaxed);
                     }
                 }
             }
@@ -250,9 +353,10 @@ impl<W: Sized + Hardware> SidesComms<W> {
     }
 }
 
-/// Frequency of the PIO state machine
-fn pio_freq() -> fixed::FixedU32<fixed::types::extra::U8> {
-    (U56F8::from_num(clocks::clk_sys_freq()) / (8 * SPEED)).to_fixed()
+/// Clock divider of the PIO state machine, for a system clock of `sys_freq` Hz
+/// and a clock of the master drifting by `drift_ppm`
+fn pio_freq(sys_freq: u32, drift_ppm: i32) -> fixed::FixedU32<fixed::types::extra::U8> {
+    sysclk::pio_divider(sys_freq, 8 * compensated_speed(SPEED, drift_ppm))
 }
 
 /// Master: Transmit first, then receive
@@ -298,7 +402,7 @@ fn setup_master_compound(
     cfg.set_set_pins(&[pin]);
     cfg.set_out_pins(&[pin]);
     cfg.set_in_pins(&[pin]);
-    cfg.clock_divider = pio_freq();
+    cfg.clock_divider = pio_freq(sysclk::sys_freq(), 0);
     cfg.shift_out.auto_fill = false;
     cfg.shift_out.direction = ShiftDirection::Right;
     cfg.shift_out.threshold = 32;
@@ -350,7 +454,7 @@ fn setup_slave_compound(
     cfg.set_set_pins(&[pin]);
     cfg.set_out_pins(&[pin]);
     cfg.set_in_pins(&[pin]);
-    cfg.clock_divider = pio_freq();
+    cfg.clock_divider = pio_freq(sysclk::sys_freq(), 0);
     cfg.shift_out.auto_fill = false;
     cfg.shift_out.direction = ShiftDirection::Right;
     cfg.shift_out.threshold = 32;
@@ -371,12 +475,10 @@ async fn run(mut sides_comms: SidesComms<HwProtocol>) {
 }
 
 pub async fn init(
-    spawner: &Spawner,
+    spawner: &SendSpawner,
     mut pio_common: PioCommon<'static>,
     sm0: SmCompound<'static>,
-    #[cfg(feature = "cnano")] gpio_pin: Peri<'static, PIN_29>,
-    #[cfg(feature = "dilemma")] gpio_pin: Peri<'static, PIN_1>,
-    status_led: Output<'static>,
+    gpio_pin: Peri<'static, SideLinkPin>,
     is_right: bool,
 ) {
     let mut pio_pin = pio_common.make_pio_pin(gpio_pin);
@@ -395,7 +497,7 @@ pub async fn init(
     info!("setup complete");
 
     // Spawn the hardware task that maintains 1ms timing
-    spawner.spawn(hardware_task(sm).unwrap());
+    spawner.spawn(hardware_task(sm, is_right).unwrap());
     info!("hardware task spawned");
 
     #[cfg(feature = "defmt")]
@@ -406,7 +508,6 @@ pub async fn init(
         #[cfg(feature = "defmt")]
         name,
         protocol_hw,
-        status_led,
     );
     spawner.spawn(run(comms).unwrap());
     info!("protocol task spawned");
@@ -0,0 +1,95 @@
+use crate::multicore;
+use core::ptr::{addr_of, read_volatile, write_volatile};
+use embassy_time::{Duration, Ticker};
+use utils::log::info;
+
+/// Pattern painted on the unused stack at boot
+const STACK_PAINT: u32 = 0xcccc_cccc;
+/// Number of bytes below the current stack pointer left unpainted, to be
+/// safe with the stack frame of the painting function itself
+const PAINT_MARGIN: usize = 256;
+/// Period of the stack usage report, in seconds
+const REPORT_PERIOD_S: u64 = 10;
+
+extern "C" {
+    /// Highest address of the stack, provided by cortex-m-rt
+    static _stack_start: u32;
+    /// Lowest address of the stack, provided by cortex-m-rt
+    static _stack_end: u32;
+}
+
+/// Bounds of the main stack, as (bottom, top) addresses
+fn bounds() -> (usize, usize) {
+    // SAFETY: only the addresses of the linker symbols are used
+    unsafe {
+        (
+            addr_of!(_stack_end) as usize,
+            addr_of!(_stack_start) as usize,
+        )
+    }
+}
+
+/// Paint the unused part of the stack so that its high-water mark can be
+/// computed later on. Must be called early at boot.
+///
+/// Embassy tasks do not have their own stack: they are state machines stored
+/// in static memory and run on the stack of their core. Tuning the
+/// `singleton!` buffers or adding tasks shows up in the static RAM usage, and
+/// in the stack high-water marks reported here.
+pub fn paint() {
+    let (bottom, _top) = bounds();
+    let sp = cortex_m::register::msp::read() as usize;
+    // SAFETY: the memory between the bottom of the stack and the current
+    // stack pointer is not in use
+    unsafe { paint_range(bottom, sp.saturating_sub(PAINT_MARGIN)) };
+}
+
+/// Paint the whole stack of the second core, before it is started
+pub fn paint_core1() {
+    let (bottom, top) = multicore::core1_stack_bounds();
+    // SAFETY: the second core is not running yet, nothing uses its stack
+    unsafe { paint_range(bottom, top) };
+}
+
+/// Paint the memory from `bottom` up to `end`, word by word.
+///
+/// # Safety
+///
+/// The memory must be a stack, not in use.
+unsafe fn paint_range(bottom: usize, end: usize) {
+    let end = end & !3;
+    let mut addr = (bottom + 3) & !3;
+    while addr < end {
+        write_volatile(addr as *mut u32, STACK_PAINT);
+        addr += 4;
+    }
+}
+
+/// Maximum number of bytes ever used on the stack between `bottom` and
+/// `top` since it was painted
+fn high_water_mark(bottom: usize, top: usize) -> usize {
+    let mut addr = (bottom + 3) & !3;
+    // SAFETY: the addresses are within the stack
+    while addr < top && unsafe { read_volatile(addr as *const u32) } == STACK_PAINT {
+        addr += 4;
+    }
+    top.saturating_sub(addr)
+}
+
+/// Periodically report the stack usage
+#[embassy_executor::task]
+pub async fn report() {
+    let (bottom, top) = bounds();
+    let (core1_bottom, core1_top) = multicore::core1_stack_bounds();
+    let mut ticker = Ticker::every(Duration::from_secs(REPORT_PERIOD_S));
+    loop {
+        ticker.next().await;
+        info!(
+            "Stack high-water mark: {} / {} bytes, core1: {} / {} bytes",
+            high_water_mark(bottom, top),
+            top - bottom,
+            high_water_mark(core1_bottom, core1_top),
+            core1_top - core1_bottom
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use crate::device::{usb_state, UsbState};
+use crate::panic_info;
+use embassy_rp::gpio::{Level, Output};
+use embassy_time::{Duration, Instant, Ticker};
+use portable_atomic::{AtomicBool, Ordering};
+use utils::log::info;
+use utils::status_led::{Pattern, Status};
+
+/// Refresh period of the LED, in ms
+const REFRESH_MS: u64 = 50;
+
+/// Whether a reset would enter the bootloader
+static BOOTLOADER_PENDING: AtomicBool = AtomicBool::new(false);
+/// Whether the link with the other half is down
+static LINK_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Set whether a reset would enter the bootloader
+pub fn set_bootloader_pending(pending: bool) {
+    BOOTLOADER_PENDING.store(pending, Ordering::Relaxed);
+}
+
+/// Set whether the link with the other half is down
+pub fn set_link_down(down: bool) {
+    LINK_DOWN.store(down, Ordering::Relaxed);
+}
+
+/// Current conditions of the system
+fn status() -> Status {
+    Status {
+        bootloader_pending: BOOTLOADER_PENDING.load(Ordering::Relaxed),
+        error: panic_info::last_panic().is_some(),
+        link_down: LINK_DOWN.load(Ordering::Relaxed),
+        usb_not_enumerated: usb_state() == UsbState::Attached,
+    }
+}
+
+/// Light the LED or not. It is active low on the Elite-C holder of the
+/// Charybdis Nano, the Skeletyl, the Scylla and the TBK Mini.
+fn set(led: &mut Output<'static>, on: bool) {
+    #[cfg(any(
+        feature = "cnano",
+        feature = "skeletyl",
+        feature = "scylla",
+        feature = "tbkmini"
+    ))]
+    led.set_level(if on { Level::Low } else { Level::High });
+    #[cfg(feature = "dilemma")]
+    led.set_level(if on { Level::High } else { Level::Low });
+}
+
+/// Show the pattern of the most important condition on the status LED
+#[embassy_executor::task]
+pub async fn run(mut led: Output<'static>) {
+    let mut ticker = Ticker::every(Duration::from_millis(REFRESH_MS));
+    let mut pattern = Pattern::Off;
+    let mut start = Instant::now();
+    loop {
+        let new_pattern = status().pattern();
+        if new_pattern != pattern {
+            info!("Status LED: {:?}", new_pattern);
+            pattern = new_pattern;
+            start = Instant::now();
+        }
+        set(&mut led, pattern.is_on(start.elapsed().as_millis()));
+        ticker.next().await;
+    }
+}
@@ -0,0 +1,74 @@
+use crate::channels::STENO_DEPTH;
+use crate::device::is_host;
+use crate::keys::{FULL_COLS, ROWS};
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::Driver;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_usb::class::hid::HidWriter;
+use utils::log::{error, warn};
+use utils::steno::{
+    report,
+    StenoAction::{self, Exit, Key},
+    StenoKey::*,
+    REPORT_SIZE,
+};
+
+/// HID writer type for the Plover HID reports
+pub type StenoWriter = HidWriter<'static, Driver<'static, USB>, REPORT_SIZE>;
+
+/// Chord capture of the steno mode
+pub type Steno = utils::steno::StenoMode<ROWS, FULL_COLS>;
+
+/// Key of the matrix not used by the steno layout
+const NO: StenoAction = StenoAction::None;
+
+#[rustfmt::skip]
+/// Steno layout: the steno keys on the top two rows, the number keys on
+/// the bottom one, the vowels and a star on the thumbs. The outer left
+/// thumb key leaves the steno mode. The encoder keeps going through the
+/// keymap.
+pub static STENO_LAYOUT: [[StenoAction; FULL_COLS]; ROWS] = [
+    [
+        Key(S1), Key(T), Key(P), Key(H), Key(Star1),
+        Key(F), Key(RightP), Key(L), Key(RightT), Key(D),
+    ],
+    [
+        Key(S2), Key(K), Key(W), Key(R), Key(Star2),
+        Key(RightR), Key(B), Key(G), Key(RightS), Key(Z),
+    ],
+    [
+        Key(Num1), Key(Num2), Key(Num3), Key(Num4), Key(Num5),
+        Key(Num6), Key(Num7), Key(Num8), Key(Num9), Key(NumA),
+    ],
+    [
+        NO, NO, Exit, Key(A), Key(O),
+        Key(E), Key(U), Key(Star3), NO, NO,
+    ],
+];
+
+/// Channel of the chords to send to the host
+static STENO_CHANNEL: Channel<CriticalSectionRawMutex, u64, STENO_DEPTH> = Channel::new();
+
+/// Send the chord `chord` to the host
+pub fn send(chord: u64) {
+    if STENO_CHANNEL.try_send(chord).is_err() {
+        error!("Steno channel is full");
+    }
+}
+
+/// Send the chords over Plover HID, each followed by an empty report for
+/// Plover to see all its keys released
+#[embassy_executor::task]
+pub async fn run(mut writer: StenoWriter) {
+    loop {
+        let chord = STENO_CHANNEL.receive().await;
+        if !is_host() {
+            continue;
+        }
+        for chord in [chord, 0] {
+            if let Err(_e) = writer.write(&report(chord)).await {
+                warn!("Failed to send steno report: {:?}", _e);
+            }
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use crate::core::CustomEvent;
+use crate::keys::ROWS;
+use crate::settings::{self, FLASH_SIZE};
+use core::cell::RefCell;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::ptr::addr_of_mut;
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::Timer;
+use keyberon::action::Action;
+use keyberon::key_code::KeyCode;
+use keyberon::layout::Layout;
+use portable_atomic::{AtomicBool, Ordering};
+use utils::keymap::{Keycode, KeymapEdits, NOT_EDITED};
+use utils::log::{error, info, warn};
+use utils::raw_hid::buffer_key;
+use utils::settings::{REGION_SIZE, SECTOR_SIZE};
+
+/// Basic layout for the keyboard
+#[cfg(feature = "keymap_basic")]
+use crate::keymap_basic::{KBLayers, KBLayout, COLS, LAYERS, NB_LAYERS};
+
+/// Keymap by Boris Faure
+#[cfg(feature = "keymap_borisfaure")]
+use crate::keymap_borisfaure::{KBLayers, KBLayout, COLS, LAYERS, NB_LAYERS};
+
+/// Test layout for the keyboard
+#[cfg(feature = "keymap_test")]
+use crate::keymap_test::{KBLayers, KBLayout, COLS, LAYERS, NB_LAYERS};
+
+/// Keymap generated from a JSON description
+#[cfg(feature = "keymap_json")]
+use crate::keymap_json::{KBLayers, KBLayout, COLS, LAYERS, NB_LAYERS};
+
+/// Edits of the keymap
+pub type Edits = KeymapEdits<NB_LAYERS, ROWS, COLS>;
+
+/// Offset of the keymap sector in flash: the sector before the key
+/// statistics one, also excluded from the firmware in `memory.x`
+const SECTOR_OFFSET: u32 = FLASH_SIZE as u32 - REGION_SIZE - 2 * SECTOR_SIZE;
+/// Delay between the last edit of the keymap and its saving, in ms. Edits
+/// made in a row are written at once to save erase cycles.
+const SAVE_DELAY_MS: u64 = 2000;
+
+// The edits of all the keys must fit in the sector
+const _: () = assert!(Edits::RECORD_SIZE <= SECTOR_SIZE as usize);
+
+/// Edits of the keymap, applied on top of `LAYERS`
+static EDITS: Mutex<CriticalSectionRawMutex, RefCell<Edits>> =
+    Mutex::new(RefCell::new(Edits::new()));
+/// Signal to save the edits
+static SAVE_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Signal to the core that the keymap was edited
+pub static KEYMAP_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// RAM copies of the layers with the edits applied: the layout uses one of
+/// them while the other one gets rebuilt on edits
+static mut RAM_LAYERS: MaybeUninit<[KBLayers; 2]> = MaybeUninit::uninit();
+/// Whether the `EditableLayout` using `RAM_LAYERS` was created
+static RAM_LAYERS_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Keyberon keycode of a usage of the keyboard usage page, if it has one
+fn key_code(usage: u8) -> Option<KeyCode> {
+    match usage {
+        // SAFETY: `KeyCode` is `repr(u8)`, with a variant for each usage up
+        // to ExSel and for each modifier
+        0x04..=0xa4 | 0xe0..=0xe7 => Some(unsafe { core::mem::transmute::<u8, KeyCode>(usage) }),
+        _ => None,
+    }
+}
+
+/// Action of a keycode, if it can be assigned
+fn action(keycode: Keycode) -> Option<Action<CustomEvent>> {
+    match keycode {
+        Keycode::No => Some(Action::NoOp),
+        Keycode::Transparent => Some(Action::Trans),
+        Keycode::Key(usage) => key_code(usage).map(Action::KeyCode),
+        Keycode::Layer(layer) => Some(Action::Layer(layer as usize)),
+        Keycode::DefaultLayer(layer) => Some(Action::DefaultLayer(layer as usize)),
+    }
+}
+
+/// Keycode of a built-in action, if it has one
+fn keycode(action: &Action<CustomEvent>) -> Option<Keycode> {
+    match action {
+        Action::NoOp => Some(Keycode::No),
+        Action::Trans => Some(Keycode::Transparent),
+        Action::KeyCode(kc) => Keycode::from_u16(*kc as u16),
+        Action::Layer(layer) => Some(Keycode::Layer(*layer as u8)),
+        Action::DefaultLayer(layer) => Some(Keycode::DefaultLayer(*layer as u8)),
+        _ => None,
+    }
+}
+
+/// Keycode of the key at `row`, `col` of `layer`: the one assigned, or the
+/// one of its built-in action, `NOT_EDITED` if it has none. `None` if there
+/// is no such key.
+pub fn get_keycode(layer: usize, row: usize, col: usize) -> Option<u16> {
+    let builtin = LAYERS.get(layer)?.get(row)?.get(col)?;
+    let edited = EDITS.lock(|e| e.borrow().get(layer, row, col));
+    Some(
+        edited
+            .or_else(|| keycode(builtin))
+            .map_or(NOT_EDITED, Keycode::to_u16),
+    )
+}
+
+/// Assign the keycode `code` to the key at `row`, `col` of `layer`, or
+/// restore its built-in action if `NOT_EDITED`. The layout uses it at once
+/// and it is saved to flash later on. Returns `false` if there is no such
+/// key or if the keycode cannot be assigned.
+pub fn set_keycode(layer: usize, row: usize, col: usize, code: u16) -> bool {
+    let keycode = match code {
+        NOT_EDITED => None,
+        _ => match Keycode::from_u16(code).filter(|k| action(*k).is_some()) {
+            Some(keycode) => Some(keycode),
+            None => return false,
+        },
+    };
+    if !EDITS.lock(|e| e.borrow_mut().set(layer, row, col, keycode)) {
+        return false;
+    }
+    info!(
+        "Keymap: key {},{} of layer {} set to 0x{:04x}",
+        row, col, layer, code
+    );
+    SAVE_SIGNAL.signal(());
+    KEYMAP_SIGNAL.signal(());
+    true
+}
+
+/// Number of layers of the keymap
+pub fn layer_count() -> u8 {
+    NB_LAYERS as u8
+}
+
+/// Keycode of the key at `index` of the keymap buffer, see
+/// `get_keycode`
+pub fn get_buffer_keycode(index: usize) -> Option<u16> {
+    let (layer, row, col) = buffer_key(index, ROWS, COLS);
+    get_keycode(layer, row, col)
+}
+
+/// Assign the keycode `code` to the key at `index` of the keymap buffer,
+/// see `set_keycode`
+pub fn set_buffer_keycode(index: usize, code: u16) -> bool {
+    let (layer, row, col) = buffer_key(index, ROWS, COLS);
+    set_keycode(layer, row, col, code)
+}
+
+/// Restore the keymap as built in the firmware
+pub fn reset() {
+    info!("Keymap: reset");
+    EDITS.lock(|e| e.borrow_mut().reset());
+    SAVE_SIGNAL.signal(());
+    KEYMAP_SIGNAL.signal(());
+}
+
+/// Load the edits of the keymap saved in flash, if any
+pub async fn load() {
+    let mut record = [0u8; Edits::RECORD_SIZE];
+    if let Err(_e) = settings::with_flash(|f| f.blocking_read(SECTOR_OFFSET, &mut record)).await {
+        error!("Failed to read the keymap: {:?}", _e);
+        return;
+    }
+    match Edits::from_record(&record) {
+        Some(edits) => {
+            info!("Keymap loaded, {} keys edited", edits.edits().count());
+            EDITS.lock(|e| e.replace(edits));
+        }
+        None => info!("No keymap edits found"),
+    }
+}
+
+/// Save the edits of the keymap to flash once they stop changing
+#[embassy_executor::task]
+pub async fn run() {
+    loop {
+        SAVE_SIGNAL.wait().await;
+        while let Either::First(_) =
+            select(SAVE_SIGNAL.wait(), Timer::after_millis(SAVE_DELAY_MS)).await
+        {}
+        let mut record = [0u8; Edits::RECORD_SIZE];
+        EDITS.lock(|e| e.borrow().to_record(&mut record));
+        let res = settings::with_flash(|f| {
+            f.blocking_erase(SECTOR_OFFSET, SECTOR_OFFSET + SECTOR_SIZE)?;
+            f.blocking_write(SECTOR_OFFSET, &record)
+        })
+        .await;
+        match res {
+            Ok(()) => info!("Keymap saved"),
+            Err(_e) => error!("Failed to save the keymap: {:?}", _e),
+        }
+    }
+}
+
+/// Layout on a RAM copy of the layers, with the edits applied
+pub struct EditableLayout {
+    /// Layout
+    layout: KBLayout,
+    /// Index of the RAM copy to rebuild on the next reload
+    next: usize,
+}
+
+impl EditableLayout {
+    /// Layout on the layers with the edits loaded. There can be only one.
+    pub fn new() -> Self {
+        assert!(
+            !RAM_LAYERS_TAKEN.swap(true, Ordering::Relaxed),
+            "the editable layout is already in use"
+        );
+        Self {
+            layout: Layout::new(Self::build(0)),
+            next: 1,
+        }
+    }
+
+    /// Rebuild the RAM copy `index` from `LAYERS` and the edits
+    fn build(index: usize) -> &'static KBLayers {
+        // SAFETY: there is a single `EditableLayout`, and it only rebuilds
+        // the copy its layout does not use, the layout built on it before
+        // having been dropped
+        let layers = unsafe { &mut (*(*addr_of_mut!(RAM_LAYERS)).as_mut_ptr())[index] };
+        *layers = LAYERS;
+        EDITS.lock(|e| {
+            for (l, r, c, keycode) in e.borrow().edits() {
+                match action(keycode) {
+                    Some(action) => layers[l][r][c] = action,
+                    None => warn!("Keymap: cannot assign {:?}", keycode),
+                }
+            }
+        });
+        layers
+    }
+
+    /// Apply the edits of the keymap. The state of the layout is lost: the
+    /// keys held are released and the default layer is the first one again.
+    pub fn reload(&mut self) {
+        self.layout = Layout::new(Self::build(self.next));
+        self.next = 1 - self.next;
+        info!("Keymap reloaded");
+    }
+}
+
+impl Deref for EditableLayout {
+    type Target = KBLayout;
+
+    fn deref(&self) -> &KBLayout {
+        &self.layout
+    }
+}
+
+impl DerefMut for EditableLayout {
+    fn deref_mut(&mut self) -> &mut KBLayout {
+        &mut self.layout
+    }
+}
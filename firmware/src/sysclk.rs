@@ -0,0 +1,102 @@
+use crate::settings;
+use core::sync::atomic::{AtomicU32, Ordering};
+use embassy_futures::select::{select, Either};
+use embassy_rp::{clocks, pac};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal, watch::Watch};
+use embassy_time::{Duration, Timer};
+use fixed::{traits::ToFixed, types::U56F8, FixedU32};
+use utils::log::info;
+
+/// Divider applied to `clk_sys` when idle.
+/// The USB controller is clocked by `clk_usb` and the timer by `clk_ref`, so
+/// only the CPU, the PIO blocks and the SPI peripherals are slowed down.
+/// Keep it small enough for the USB AHB interface to keep up (>= 48MHz).
+const IDLE_CLK_DIV: u32 = 2;
+
+/// Number of samples of the ring oscillator random bit gathered for some
+/// entropy: the bit is biased, so 4 samples are folded into each bit
+const ROSC_ENTROPY_SAMPLES: u32 = 4 * u64::BITS;
+
+/// Number of receivers of the system clock frequency changes
+const NB_CLK_RECEIVERS: usize = 2;
+
+/// Signal raised on any user activity (key press, pointer move, ...)
+static ACTIVITY_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Current system clock frequency, in Hz. 0 until the task is started
+static SYS_FREQ: AtomicU32 = AtomicU32::new(0);
+
+/// Current system clock frequency, in Hz, published on every change so that
+/// the PIO state machines can recompute their clock dividers
+pub static SYS_FREQ_WATCH: Watch<CriticalSectionRawMutex, u32, NB_CLK_RECEIVERS> = Watch::new();
+
+/// Notify that there was some activity: restores the full speed clock if
+/// needed
+pub fn notify_activity() {
+    ACTIVITY_SIGNAL.signal(());
+}
+
+/// Current system clock frequency, in Hz
+pub fn sys_freq() -> u32 {
+    match SYS_FREQ.load(Ordering::Relaxed) {
+        0 => clocks::clk_sys_freq(),
+        freq => freq,
+    }
+}
+
+/// Entropy gathered from the jitter of the ring oscillator
+pub fn rosc_entropy() -> u64 {
+    let random_bit = pac::ROSC.randombit();
+    (0..ROSC_ENTROPY_SAMPLES).fold(0u64, |entropy, _| {
+        entropy.rotate_left(5) ^ random_bit.read().randombit() as u64
+    })
+}
+
+/// PIO clock divider to run a state machine at `target_hz` cycles per second
+/// given a system clock of `sys_freq` Hz
+pub fn pio_divider(sys_freq: u32, target_hz: u64) -> FixedU32<fixed::types::extra::U8> {
+    (U56F8::from_num(sys_freq) / target_hz).to_fixed()
+}
+
+/// Set the integer divider of `clk_sys` and publish the new frequency
+fn set_clk_sys_div(div: u32) {
+    let freq = clocks::clk_sys_freq() / div;
+    pac::CLOCKS.clk_sys_div().write(|w| {
+        #[cfg(feature = "rp2040")]
+        w.set_int(div);
+        #[cfg(feature = "rp2350")]
+        w.set_int(div as u16);
+        w.set_frac(0);
+    });
+    info!("System clock set to {} Hz", freq);
+    SYS_FREQ.store(freq, Ordering::Relaxed);
+    SYS_FREQ_WATCH.sender().send(freq);
+}
+
+/// Task lowering the system clock when the keyboard is idle for the timeout
+/// stored in the settings, restoring the full speed on the first activity
+#[embassy_executor::task]
+pub async fn run() {
+    SYS_FREQ.store(clocks::clk_sys_freq(), Ordering::Relaxed);
+    let mut is_idle = false;
+    loop {
+        if is_idle {
+            ACTIVITY_SIGNAL.wait().await;
+            set_clk_sys_div(1);
+            is_idle = false;
+        } else {
+            match select(
+                ACTIVITY_SIGNAL.wait(),
+                Timer::after(Duration::from_secs(settings::idle_timeout_s() as u64)),
+            )
+            .await
+            {
+                Either::First(_) => {}
+                Either::Second(_) => {
+                    set_clk_sys_div(IDLE_CLK_DIV);
+                    is_idle = true;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use keyberon::layout::{Event as KBEvent, QueuedIter, WaitingAction};
+use utils::pipeline::KeyEvent;
+use utils::settings::{TapHold, TapHoldMode, MAX_TAP_HOLD_TIMEOUT_MS};
+use utils::tap_hold::TapHolds;
+
+/// Timeout of the hold-tap keys as seen by keyberon: the longest one
+/// allowed, `resolve` applying the one from the settings
+pub const TIMEOUT: u16 = MAX_TAP_HOLD_TIMEOUT_MS;
+
+/// Resolution of the hold-tap keys, shared with keyberon through `resolve`
+static TAP_HOLDS: Mutex<CriticalSectionRawMutex, RefCell<TapHolds>> =
+    Mutex::new(RefCell::new(TapHolds::new(TapHold {
+        timeout_ms: TIMEOUT,
+        mode: TapHoldMode::TapPreferred,
+    })));
+
+/// Apply the hold-tap configuration from the settings
+pub fn set_config(config: TapHold) {
+    TAP_HOLDS.lock(|t| t.borrow_mut().set_config(config));
+}
+
+/// End the current ms, once the layout got refreshed
+pub fn tick() {
+    TAP_HOLDS.lock(|t| t.borrow_mut().tick());
+}
+
+/// Custom hold-tap configuration of the keymaps, called by keyberon every
+/// ms a hold-tap key is waiting
+pub fn resolve(queued: QueuedIter) -> Option<WaitingAction> {
+    let queued = queued.map(|q| match q.event() {
+        KBEvent::Press(i, j) => KeyEvent::Press(i, j),
+        KBEvent::Release(i, j) => KeyEvent::Release(i, j),
+    });
+    TAP_HOLDS
+        .lock(|t| t.borrow_mut().resolve(queued))
+        .then_some(WaitingAction::Hold)
+}
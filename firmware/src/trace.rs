@@ -0,0 +1,89 @@
+use arraydeque::{behavior::Wrapping, ArrayDeque};
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+use keyberon::layout::Event as KBEvent;
+use utils::hid::KeyboardReport;
+use utils::log::info;
+use utils::serde::Event;
+
+/// Number of records kept, the oldest ones being overwritten
+const TRACE_DEPTH: usize = 256;
+
+/// Tasks recording traces
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Task {
+    /// Matrix scanning, `keys::Matrix`
+    Matrix,
+    /// Communication with the other half, `side::SidesComms`
+    Side,
+    /// Layout processing, `core::run`
+    Core,
+}
+
+/// What happened
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Kind {
+    /// Key pressed, with its row and column
+    Press(u8, u8),
+    /// Key released, with its row and column
+    Release(u8, u8),
+    /// Event queued to be sent to the other half
+    SideSend(Event),
+    /// Event received from the other half
+    SideReceive(Event),
+    /// Keyboard report sent to the HID writer
+    KeyboardReport(KeyboardReport),
+}
+
+impl From<KBEvent> for Kind {
+    fn from(event: KBEvent) -> Self {
+        match event {
+            KBEvent::Press(i, j) => Kind::Press(i, j),
+            KBEvent::Release(i, j) => Kind::Release(i, j),
+        }
+    }
+}
+
+/// A trace record
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    /// Time of the record, in µs since boot
+    timestamp: u64,
+    /// Task recording it
+    task: Task,
+    /// What happened
+    kind: Kind,
+}
+
+/// Ring buffer of the last records
+static RECORDS: Mutex<CriticalSectionRawMutex, RefCell<ArrayDeque<Record, TRACE_DEPTH, Wrapping>>> =
+    Mutex::new(RefCell::new(ArrayDeque::new()));
+
+/// Record that `task` did `kind`, now
+pub fn record(task: Task, kind: Kind) {
+    let timestamp = Instant::now().as_micros();
+    RECORDS.lock(|r| {
+        r.borrow_mut().push_back(Record {
+            timestamp,
+            task,
+            kind,
+        })
+    });
+}
+
+/// Dump the records, oldest first, and clear them
+pub fn dump() {
+    RECORDS.lock(|r| {
+        let mut records = r.borrow_mut();
+        info!("[TRACE] {} records", records.len());
+        for _record in records.drain(..) {
+            info!(
+                "[TRACE] {}us {:?}: {:?}",
+                _record.timestamp, _record.task, _record.kind
+            );
+        }
+    });
+}
@@ -1,39 +1,90 @@
 #![allow(dead_code)]
 
+use crate::channels::{self, Queue, SENSOR_CMD_DEPTH};
+use crate::device::{self, SUSPEND_WATCH};
 use crate::mouse::{MouseMove, MOUSE_MOVE_CHANNEL};
+use crate::pointer::SUSPENDED_POLL_MS;
+use crate::rgb_leds::{AnimCommand, ANIM_CHANNEL};
+use crate::settings::{self, SETTINGS_WATCH};
+use crate::watchdog::{self, Task};
+use core::cell::Cell;
 use core::fmt::Debug;
 use embassy_futures::select::{select, Either};
 use embassy_rp::gpio::Output;
+#[cfg(not(feature = "pmw3320"))]
 use embassy_rp::peripherals::SPI0;
-use embassy_rp::spi::{Async, Error as SpiError, Instance as SpiInstance, Mode, Spi};
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
-use embassy_time::{Duration, Ticker, Timer};
+#[cfg(not(feature = "pmw3320"))]
+use embassy_rp::spi::{Async, Config as SpiConfig, Phase, Polarity};
+use embassy_rp::spi::{Error as SpiError, Instance as SpiInstance, Mode, Spi};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant, Ticker, Timer};
 use embedded_hal::spi::SpiBus;
 use utils::log::{error, info};
+use utils::raw_hid::SensorQuality;
+use utils::rotation::Rotation;
 
-mod firmware;
+#[cfg(all(feature = "pmw3389", feature = "pmw3320"))]
+compile_error!("Only one of \"pmw3389\" or \"pmw3320\" can be enabled at a time.");
 
-use firmware::Register;
+// One module per sensor, the PMW3360 unless another one is enabled. The
+// selected one is `sensor`.
+#[cfg(not(feature = "pmw3320"))]
+mod pmw33x0;
 
-/// Maximum number of commands in the channel
-pub const NB_CMD: usize = 64;
+#[cfg(not(any(feature = "pmw3389", feature = "pmw3320")))]
+mod pmw3360;
+#[cfg(not(any(feature = "pmw3389", feature = "pmw3320")))]
+use pmw3360 as sensor;
 
-/// Channel to send commands to the sensor
-pub static SENSOR_CMD_CHANNEL: Channel<ThreadModeRawMutex, SensorCommand, NB_CMD> = Channel::new();
+#[cfg(feature = "pmw3389")]
+mod pmw3389;
+#[cfg(feature = "pmw3389")]
+use pmw3389 as sensor;
+
+#[cfg(feature = "pmw3320")]
+mod pmw3320;
+#[cfg(feature = "pmw3320")]
+use pmw3320 as sensor;
+#[cfg(feature = "pmw3320")]
+pub use pmw3320::Sdio;
 
-const DEFAULT_CPI: u16 = 800;
+use sensor::Register;
+pub use sensor::{MAX_CPI, MIN_CPI};
 
-/// Default angle tune value, the sensor will be turned 32 degrees
-const DEFAULT_ANGLE_TUNE: u8 = 32;
+/// Channel to send commands to the sensor
+pub static SENSOR_CMD_CHANNEL: Channel<CriticalSectionRawMutex, SensorCommand, SENSOR_CMD_DEPTH> =
+    Channel::new();
 
 /// Sensor refresh rate, in ms
 const REFRESH_RATE_MS: u64 = 10;
+/// Period over which the tracking quality is measured, in seconds
+const QUALITY_PERIOD_S: u64 = 10;
+
+/// Tracking quality measured over the last period
+static QUALITY: Mutex<CriticalSectionRawMutex, Cell<SensorQuality>> =
+    Mutex::new(Cell::new(SensorQuality {
+        squal: 0,
+        raw_data_sum: 0,
+        shutter: 0,
+        samples: 0,
+    }));
+
+/// Tracking quality of the sensor over the last seconds
+pub fn quality() -> SensorQuality {
+    QUALITY.lock(|q| q.get())
+}
 
+/// Commands to the sensor
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SensorCommand {
-    IncreaseCpi,
-    DecreaseCpi,
+    /// Increase the CPI by the given step
+    IncreaseCpi(u16),
+    /// Decrease the CPI by the given step
+    DecreaseCpi(u16),
+    /// Set the CPI
+    SetCpi(u16),
 }
 
 #[derive(Debug)]
@@ -42,6 +93,53 @@ pub struct BurstData {
     pub motion: bool,
     pub dx: i16,
     pub dy: i16,
+    /// Surface quality
+    pub squal: u8,
+    /// Raw data sum, the brightness of the surface
+    pub raw_data_sum: u8,
+    /// Shutter time, in clock cycles
+    pub shutter: u16,
+}
+
+/// Tracking quality of the motion bursts of the current period
+#[derive(Default)]
+struct QualityStats {
+    /// Lowest surface quality
+    squal: u8,
+    /// Sum of the raw data sums
+    raw_data_sum: u32,
+    /// Highest shutter time
+    shutter: u16,
+    /// Number of motion bursts
+    samples: u16,
+}
+
+impl QualityStats {
+    /// Account for the motion burst `burst`
+    fn add(&mut self, burst: &BurstData) {
+        self.squal = if self.samples == 0 {
+            burst.squal
+        } else {
+            self.squal.min(burst.squal)
+        };
+        self.raw_data_sum += burst.raw_data_sum as u32;
+        self.shutter = self.shutter.max(burst.shutter);
+        self.samples = self.samples.saturating_add(1);
+    }
+
+    /// Quality over the period, starting a new one
+    fn take(&mut self) -> SensorQuality {
+        let stats = core::mem::take(self);
+        SensorQuality {
+            squal: stats.squal,
+            raw_data_sum: stats
+                .raw_data_sum
+                .checked_div(stats.samples as u32)
+                .unwrap_or(0) as u8,
+            shutter: stats.shutter,
+            samples: stats.samples,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -56,9 +154,36 @@ impl From<SpiError> for TrackballError {
     }
 }
 
-pub struct Trackball<'a, T: SpiInstance, M: Mode> {
-    /// The SPI bus
-    spi: Spi<'a, T, M>,
+/// Bus of the sensor, its CS pin being low
+pub trait SensorBus {
+    /// Send the bytes of `data`
+    fn send(&mut self, data: &[u8]) -> Result<(), TrackballError>;
+    /// Receive bytes into `data`
+    fn receive(&mut self, data: &mut [u8]) -> Result<(), TrackballError>;
+}
+
+/// SPI bus, one byte per transfer
+impl<I: SpiInstance, M: Mode> SensorBus for Spi<'_, I, M> {
+    fn send(&mut self, data: &[u8]) -> Result<(), TrackballError> {
+        for &byte in data {
+            self.transfer_in_place(&mut [byte])?;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, data: &mut [u8]) -> Result<(), TrackballError> {
+        for byte in data.iter_mut() {
+            let mut buf = [0x00];
+            self.transfer_in_place(&mut buf)?;
+            *byte = buf[0];
+        }
+        Ok(())
+    }
+}
+
+pub struct Trackball<'a, B: SensorBus> {
+    /// The bus of the sensor
+    bus: B,
     /// The CS pin
     cs: Output<'a>,
     // in_burst is set if any writes or reads were performed
@@ -69,7 +194,20 @@ pub struct Trackball<'a, T: SpiInstance, M: Mode> {
     last_dy: i16,
 }
 
-pub type TrackballDev = Trackball<'static, SPI0, Async>;
+#[cfg(not(feature = "pmw3320"))]
+pub type TrackballDev = Trackball<'static, Spi<'static, SPI0, Async>>;
+#[cfg(feature = "pmw3320")]
+pub type TrackballDev = Trackball<'static, Sdio<'static>>;
+
+/// Configuration of the SPI bus for the sensor
+#[cfg(not(feature = "pmw3320"))]
+pub fn spi_config() -> SpiConfig {
+    let mut config = SpiConfig::default();
+    config.frequency = sensor::SPI_FREQUENCY;
+    config.polarity = Polarity::IdleHigh;
+    config.phase = Phase::CaptureOnSecondTransition;
+    config
+}
 
 #[embassy_executor::task]
 pub async fn run(mut ball: TrackballDev) {
@@ -80,11 +218,11 @@ pub async fn run(mut ball: TrackballDev) {
     ball.run().await;
 }
 
-impl<'a, I: SpiInstance, M: Mode> Trackball<'a, I, M> {
+impl<'a, B: SensorBus> Trackball<'a, B> {
     /// Create a new Trackball driver
-    pub fn new(spi: Spi<'a, I, M>, cs: Output<'a>) -> Self {
+    pub fn new(bus: B, cs: Output<'a>) -> Self {
         Self {
-            spi,
+            bus,
             cs,
             in_burst: false,
             last_dx: 0,
@@ -92,89 +230,32 @@ impl<'a, I: SpiInstance, M: Mode> Trackball<'a, I, M> {
         }
     }
 
-    pub async fn burst_get(&mut self) -> Result<BurstData, TrackballError> {
-        // Write any value to Motion_burst register
-        // if any write occured before
-        if !self.in_burst {
-            self.write(Register::MotionBurst, 0x00).await?;
+    pub async fn set_cpi(&mut self, cpi: u16) -> Result<(), TrackballError> {
+        info!("Setting CPI to {}", cpi);
+        let config = sensor::RESOLUTION.config(cpi).to_le_bytes();
+        for (&register, value) in sensor::CPI_REGISTERS.iter().zip(config) {
+            self.write(register, value).await?;
         }
+        Ok(())
+    }
 
-        // Lower NCS
-        self.cs.set_low();
-        // Send Motion_burst address
-        self.spi
-            .transfer_in_place(&mut [Register::MotionBurst as u8])?;
-
-        // NOTE: The datasheet says to wait for 35us here, but it seems to work without it.
-        // It seems that embassy_time is not good at waiting for such small values,
-        // and simply turning off Timer reduces the processing time of this function from maxium 3 ms to almost 0.
-
-        // tSRAD_MOTBR
-        // Timer::after_micros(35).await;
-
-        // Read the 6 bytes of burst data
-        let mut buf = [0u8; 6];
-        for b in buf.iter_mut() {
-            let t_buf = &mut [0x00];
-            match self.spi.transfer_in_place(t_buf) {
-                Ok(()) => *b = *t_buf.first().unwrap(),
-                Err(_) => *b = 0,
+    /// Set the CPI, persist it in the settings and show it on the LEDs
+    async fn store_cpi(&mut self, cpi: u16) {
+        if self.set_cpi(cpi).await.is_ok() {
+            settings::update(|s| s.cpi = cpi);
+            if ANIM_CHANNEL.is_full() {
+                error!("Anim channel is full");
             }
+            ANIM_CHANNEL.send(AnimCommand::ShowCpi(cpi)).await;
         }
-
-        // Raise NCS
-        self.cs.set_high();
-
-        // NOTE: Same as tSRAD_MOTBR. temporary disabled.
-        //
-        // tBEXIT
-        // Timer::after_micros(1).await;
-
-        //combine the register values
-        let mut data = BurstData {
-            motion: (buf[0] & 0x80) != 0,
-            dy: ((buf[3] as i16) << 8) | (buf[2] as i16),
-            dx: ((buf[5] as i16) << 8) | (buf[4] as i16),
-        };
-        if buf[0] & 0b111 != 0 {
-            error!("Motion burst error");
-            self.in_burst = false;
-        }
-        // if the motion bit is not set, the dx and dy values are not valid
-        if !data.motion {
-            data.dx = 0;
-            data.dy = 0;
-        }
-        // avoid small glitches
-        if data.dx == 1 || data.dx == -1 {
-            data.dx = 0;
-        }
-        if data.dy == 1 || data.dy == -1 {
-            data.dy = 0;
-        }
-        // if the dx or dy values are 0, the sensor is not moving
-        if data.dx == 0 && data.dy == 0 {
-            data.motion = false;
-        }
-
-        Ok(data)
-    }
-
-    pub async fn set_cpi(&mut self, cpi: u16) -> Result<(), TrackballError> {
-        info!("Setting CPI to {}", cpi);
-        let val: u8 = if cpi < 100 {
-            0
-        } else if cpi > 12000 {
-            0x77
-        } else {
-            ((cpi - 100) / 100) as u8
-        };
-        self.write(Register::Config1, val).await
     }
 
     pub async fn get_cpi(&mut self) -> Result<u16, TrackballError> {
-        let val = self.read(Register::Config1).await.unwrap_or_default() as u16;
-        Ok((val + 1) * 100)
+        let mut config = [0; 2];
+        for (&register, value) in sensor::CPI_REGISTERS.iter().zip(config.iter_mut()) {
+            *value = self.read(register).await.unwrap_or_default();
+        }
+        Ok(sensor::RESOLUTION.cpi(u16::from_le_bytes(config)))
     }
 
     /// Write to a register on the sensor
@@ -185,10 +266,9 @@ impl<'a, I: SpiInstance, M: Mode> Trackball<'a, I, M> {
 
         self.in_burst = register == Register::MotionBurst;
 
-        // send adress of the register, with MSBit = 1 to indicate it's a write
-        self.spi.transfer_in_place(&mut [register as u8 | 0x80])?;
-        // send data
-        self.spi.transfer_in_place(&mut [data])?;
+        // send adress of the register, with MSBit = 1 to indicate it's a write,
+        // then the data
+        self.bus.send(&[register as u8 | 0x80, data])?;
 
         // tSCLK-NCS (write)
         Timer::after_micros(35).await;
@@ -207,14 +287,14 @@ impl<'a, I: SpiInstance, M: Mode> Trackball<'a, I, M> {
         Timer::after_micros(1).await;
 
         // send adress of the register, with MSBit = 0 to indicate it's a read
-        self.spi.transfer_in_place(&mut [register as u8 & 0x7f])?;
+        self.bus.send(&[register as u8 & 0x7f])?;
 
         // tSRAD
         Timer::after_micros(160).await;
 
         let mut ret = 0;
         let mut buf = [0x00];
-        if self.spi.transfer_in_place(&mut buf).is_ok() {
+        if self.bus.receive(&mut buf).is_ok() {
             ret = *buf.first().unwrap();
         }
 
@@ -228,64 +308,25 @@ impl<'a, I: SpiInstance, M: Mode> Trackball<'a, I, M> {
         Ok(ret)
     }
 
-    /// Check if the sensor is connected and has the correct signature
-    pub async fn check_signature(&mut self) -> Result<(), TrackballError> {
-        let srom = self.read(Register::SromId).await.unwrap_or(0);
+    /// Check whether the sensor answers on the bus. Its product ID can be
+    /// read before the upload of its firmware, if it has any.
+    pub async fn probe(&mut self) -> bool {
         let pid = self.read(Register::ProductId).await.unwrap_or(0);
         let ipid = self.read(Register::InverseProductId).await.unwrap_or(0);
-
-        // signature for SROM 0x04
-        if srom != 0x04 || pid != 0x42 || ipid != 0xBD {
-            Err(TrackballError::InvalidSignature)
-        } else {
-            Ok(())
-        }
+        pid == sensor::PRODUCT_ID && ipid == !sensor::PRODUCT_ID
     }
 
-    /// Power up the sensor
-    async fn power_up(&mut self) -> Result<(), TrackballError> {
-        // sensor reset not active
-        // self.reset_pin.set_high().ok();
-
-        // reset the spi bus on the sensor
-        self.cs.set_high();
-        Timer::after_micros(50).await;
-        self.cs.set_low();
-        Timer::after_micros(50).await;
-
-        // Write to reset register
-        self.write(Register::PowerUpReset, 0x5A).await?;
-        // 100 ms delay
-        Timer::after_micros(100).await;
-
-        // read registers 0x02 to 0x06 (and discard the data)
-        self.read(Register::Motion).await?;
-        self.read(Register::DeltaXL).await?;
-        self.read(Register::DeltaXH).await?;
-        self.read(Register::DeltaYL).await?;
-        self.read(Register::DeltaYH).await?;
-
-        // upload the firmware
-        self.upload_fw().await?;
-
-        let is_valid_signature = self.check_signature().await;
-
-        // Write 0x00 (rest disable) to Config2 register for wired mouse or 0x20 for
-        // wireless mouse design.
-        self.write(Register::Config2, 0x00).await?;
-        // Tune the angle
-        self.write(Register::AngleTune, DEFAULT_ANGLE_TUNE).await?;
-        self.write(Register::LiftConfig, 0x02).await?;
-
-        Timer::after_micros(100).await;
-
-        is_valid_signature
+    /// Give back the bus and the CS pin
+    pub fn release(self) -> (B, Output<'a>) {
+        (self.bus, self.cs)
     }
 
+    /// Power up the sensor, with the CPI and the angle tune stored in the
+    /// settings
     pub async fn start(&mut self) -> Result<(), TrackballError> {
         self.power_up().await?;
         Timer::after_millis(35).await;
-        self.set_cpi(DEFAULT_CPI).await?;
+        self.set_cpi(settings::get().cpi).await?;
         Ok(())
     }
 
@@ -293,20 +334,77 @@ impl<'a, I: SpiInstance, M: Mode> Trackball<'a, I, M> {
     pub async fn run(&mut self) {
         Timer::after_millis(250).await;
         let mut ticker = Ticker::every(Duration::from_millis(REFRESH_RATE_MS));
+        watchdog::register(Task::Pointer);
+        let mut suspend_rcv = SUSPEND_WATCH.receiver().unwrap();
+        let mut settings_rcv = SETTINGS_WATCH.receiver().unwrap();
+        let mut cpi = settings::get().cpi;
+        let mut rotation = Rotation::new(settings::sensor_rotation());
+        let mut lift_config = settings::lift_config();
+        let mut stats = QualityStats::default();
+        let mut quality_start = Instant::now();
+        // The sensor is only polled to wake the host up while it is suspended
+        let mut suspended = false;
+        let mut suspended_polled = Instant::now();
         loop {
             match select(ticker.next(), SENSOR_CMD_CHANNEL.receive()).await {
                 Either::First(_) => {
+                    watchdog::heartbeat(Task::Pointer);
+                    if let Some(state) = suspend_rcv.try_changed() {
+                        suspended = state;
+                    }
+                    // The CPI changes when switching settings profile
+                    if let Some(settings) = settings_rcv.try_changed() {
+                        if settings.cpi != cpi {
+                            cpi = settings.cpi;
+                            let _ = self.set_cpi(cpi).await;
+                        }
+                    }
+                    if suspended {
+                        if suspended_polled.elapsed() >= Duration::from_millis(SUSPENDED_POLL_MS) {
+                            suspended_polled = Instant::now();
+                            if let Ok(burst) = self.burst_get().await {
+                                if burst.dx != 0 || burst.dy != 0 {
+                                    device::wake_host();
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                    let degrees = settings::sensor_rotation();
+                    if degrees != rotation.degrees() {
+                        info!("Sensor rotated by {} degrees", degrees);
+                        rotation = Rotation::new(degrees);
+                    }
+                    let new_lift_config = settings::lift_config();
+                    if new_lift_config != lift_config {
+                        info!("Sensor lift-off distance: {}", new_lift_config);
+                        lift_config = new_lift_config;
+                        let _ = self.set_lift_config(lift_config).await;
+                    }
+                    if quality_start.elapsed() >= Duration::from_secs(QUALITY_PERIOD_S) {
+                        quality_start = Instant::now();
+                        let quality = stats.take();
+                        info!(
+                            "[SENSOR] squal={} raw_data_sum={} shutter={} samples={}",
+                            quality.squal, quality.raw_data_sum, quality.shutter, quality.samples
+                        );
+                        QUALITY.lock(|q| q.set(quality));
+                    }
                     let burst_res = self.burst_get().await;
                     if let Ok(burst) = burst_res {
+                        stats.add(&burst);
                         if self.last_dx != burst.dx || self.last_dy != burst.dy {
+                            let (dx, dy) = rotation.apply(burst.dx, burst.dy);
                             if MOUSE_MOVE_CHANNEL.is_full() {
                                 error!("Mouse move channel is full");
                             }
                             MOUSE_MOVE_CHANNEL
                                 .send(MouseMove {
-                                    dx: burst.dx,
-                                    dy: burst.dy,
+                                    dx,
+                                    dy,
                                     pressure: 0,
+                                    wheel: 0,
+                                    pan: 0,
                                 })
                                 .await;
                             self.last_dx = burst.dx;
@@ -316,61 +414,23 @@ impl<'a, I: SpiInstance, M: Mode> Trackball<'a, I, M> {
                         error!("Error: {:?}", utils::log::Debug2Format(&_e));
                     }
                 }
-                Either::Second(event) => match event {
-                    SensorCommand::IncreaseCpi => {
-                        let cpi = self.get_cpi().await.unwrap_or(DEFAULT_CPI);
-                        let _ = self.set_cpi(cpi + 100).await;
-                    }
-                    SensorCommand::DecreaseCpi => {
-                        let cpi = self.get_cpi().await.unwrap_or(DEFAULT_CPI);
-                        let _ = self.set_cpi(cpi - 100).await;
+                Either::Second(event) => {
+                    channels::record(Queue::SensorCmd, SENSOR_CMD_CHANNEL.len());
+                    match event {
+                        SensorCommand::IncreaseCpi(step) => {
+                            let cpi = self.get_cpi().await.unwrap_or(settings::get().cpi);
+                            self.store_cpi(cpi.saturating_add(step).min(MAX_CPI)).await;
+                        }
+                        SensorCommand::DecreaseCpi(step) => {
+                            let cpi = self.get_cpi().await.unwrap_or(settings::get().cpi);
+                            self.store_cpi(cpi.saturating_sub(step).max(MIN_CPI)).await;
+                        }
+                        SensorCommand::SetCpi(cpi) => {
+                            self.store_cpi(cpi.clamp(MIN_CPI, MAX_CPI)).await;
+                        }
                     }
-                },
+                }
             }
         }
     }
-
-    async fn upload_fw(&mut self) -> Result<(), TrackballError> {
-        // Write 0 to Rest_En bit of Config2 register to disable Rest mode.
-        self.write(Register::Config2, 0x00).await?;
-
-        // write 0x1d in SROM_enable reg for initializing
-        self.write(Register::SromEnable, 0x1d).await?;
-
-        // wait for 10 ms
-        Timer::after_micros(10000).await;
-
-        // write 0x18 to SROM_enable to start SROM download
-        self.write(Register::SromEnable, 0x18).await?;
-
-        // lower CS
-        self.cs.set_low();
-
-        // first byte is address
-        self.spi
-            .transfer_in_place(&mut [Register::SromLoadBurst as u8 | 0x80])?;
-        Timer::after_micros(15).await;
-
-        // send the rest of the firmware
-        for element in firmware::SROM_TRACKING_FW.iter() {
-            self.spi.transfer_in_place(&mut [*element])?;
-            Timer::after_micros(15).await;
-        }
-
-        Timer::after_micros(2).await;
-        self.cs.set_high();
-        Timer::after_micros(200).await;
-        Ok(())
-    }
-
-    #[allow(dead_code)]
-    pub async fn self_test(&mut self) -> Result<bool, TrackballError> {
-        self.write(Register::SromEnable, 0x15).await?;
-        Timer::after_micros(10000).await;
-
-        let u = self.read(Register::DataOutUpper).await.unwrap_or(0); // should be 0xBE
-        let l = self.read(Register::DataOutLower).await.unwrap_or(0); // should be 0xEF
-
-        Ok(u == 0xBE && l == 0xEF)
-    }
 }
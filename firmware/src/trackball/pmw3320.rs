@@ -0,0 +1,169 @@
+//! Register map, specifics and driver of the PMW3320: it has no SROM, its
+//! deltas are on 8 bits, its lift-off distance is not tunable and its data
+//! line is bidirectional
+
+use super::{BurstData, SensorBus, Trackball, TrackballError};
+use embassy_rp::gpio::{Flex, Level, Output, Pin};
+use embassy_rp::Peri;
+use embassy_time::{block_for, Duration, Timer};
+use utils::resolution::{self, Resolution};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Register {
+    ProductId = 0x00,
+    RevisionId = 0x01,
+    Motion = 0x02,
+    DeltaX = 0x03,
+    DeltaY = 0x04,
+    Squal = 0x05,
+    ShutterUpper = 0x06,
+    ShutterLower = 0x07,
+    MaximumPixel = 0x08,
+    PixelSum = 0x09,
+    MinimumPixel = 0x0A,
+    Resolution = 0x0D,
+    PowerUpReset = 0x3A,
+    Shutdown = 0x3B,
+    InverseProductId = 0x3F,
+    MotionBurst = 0x63,
+}
+
+/// Product ID of the sensor, its inverse being in `InverseProductId`
+pub const PRODUCT_ID: u8 = 0x3B;
+/// Resolution range of the sensor, and its encoding
+pub const RESOLUTION: Resolution = resolution::PMW3320;
+/// Lowest resolution of the sensor, in CPI
+pub const MIN_CPI: u16 = RESOLUTION.min;
+/// Highest resolution of the sensor, in CPI
+pub const MAX_CPI: u16 = RESOLUTION.max;
+/// Registers of the resolution, from its lower byte
+pub const CPI_REGISTERS: &[Register] = &[Register::Resolution];
+/// Half period of the clock of the SDIO line, in µs: 500kHz, below the
+/// 1MHz of the sensor
+const SDIO_HALF_PERIOD_US: u64 = 1;
+
+/// Bus of the sensor: a single SDIO line carries the data both ways. It is
+/// bit-banged on the SCLK and MOSI pins of the SPI bus of the other
+/// sensors, in mode 3, the most significant bit first: the sensor reads
+/// the data on the rising edges of the clock and changes it on the falling
+/// ones.
+pub struct Sdio<'a> {
+    /// Clock, idle high
+    sclk: Output<'a>,
+    /// Data line, only driven while sending
+    sdio: Flex<'a>,
+}
+
+impl<'a> Sdio<'a> {
+    /// Create the bus on the `sclk` and `sdio` pins
+    pub fn new(sclk: Peri<'a, impl Pin>, sdio: Peri<'a, impl Pin>) -> Self {
+        let mut sdio = Flex::new(sdio);
+        sdio.set_as_input();
+        Self {
+            sclk: Output::new(sclk, Level::High),
+            sdio,
+        }
+    }
+
+    /// Wait for half a clock period
+    fn half_period() {
+        block_for(Duration::from_micros(SDIO_HALF_PERIOD_US));
+    }
+}
+
+impl SensorBus for Sdio<'_> {
+    fn send(&mut self, data: &[u8]) -> Result<(), TrackballError> {
+        self.sdio.set_as_output();
+        for byte in data {
+            for bit in (0..8).rev() {
+                self.sclk.set_low();
+                self.sdio.set_level(Level::from(byte & (1 << bit) != 0));
+                Self::half_period();
+                self.sclk.set_high();
+                Self::half_period();
+            }
+        }
+        // Let the sensor drive the line
+        self.sdio.set_as_input();
+        Ok(())
+    }
+
+    fn receive(&mut self, data: &mut [u8]) -> Result<(), TrackballError> {
+        for byte in data.iter_mut() {
+            *byte = 0;
+            for _ in 0..8 {
+                self.sclk.set_low();
+                Self::half_period();
+                self.sclk.set_high();
+                *byte = (*byte << 1) | self.sdio.is_high() as u8;
+                Self::half_period();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<B: SensorBus> Trackball<'_, B> {
+    pub async fn burst_get(&mut self) -> Result<BurstData, TrackballError> {
+        self.cs.set_low();
+        self.bus.send(&[Register::MotionBurst as u8])?;
+        // tSRAD
+        Timer::after_micros(4).await;
+
+        // Motion, deltas, surface quality and shutter
+        let mut buf = [0u8; 6];
+        self.bus.receive(&mut buf)?;
+        self.cs.set_high();
+
+        let motion = (buf[0] & 0x80) != 0;
+        // if the motion bit is not set, the dx and dy values are not valid
+        let (dx, dy) = if motion {
+            (buf[1] as i8 as i16, buf[2] as i8 as i16)
+        } else {
+            (0, 0)
+        };
+        Ok(BurstData {
+            motion: dx != 0 || dy != 0,
+            dx,
+            dy,
+            squal: buf[3],
+            raw_data_sum: 0,
+            shutter: ((buf[4] as u16) << 8) | (buf[5] as u16),
+        })
+    }
+
+    /// Check if the sensor is connected and has the correct signature
+    pub async fn check_signature(&mut self) -> Result<(), TrackballError> {
+        if self.probe().await {
+            Ok(())
+        } else {
+            Err(TrackballError::InvalidSignature)
+        }
+    }
+
+    /// The lift-off distance of the sensor is not tunable
+    pub(super) async fn set_lift_config(&mut self, _lift_config: u8) -> Result<(), TrackballError> {
+        Ok(())
+    }
+
+    /// Power up the sensor
+    pub(super) async fn power_up(&mut self) -> Result<(), TrackballError> {
+        // reset the serial port of the sensor
+        self.cs.set_high();
+        Timer::after_micros(50).await;
+        self.cs.set_low();
+        Timer::after_micros(50).await;
+
+        self.write(Register::PowerUpReset, 0x5A).await?;
+        Timer::after_millis(50).await;
+
+        // read the motion registers (and discard the data)
+        self.read(Register::Motion).await?;
+        self.read(Register::DeltaX).await?;
+        self.read(Register::DeltaY).await?;
+
+        self.check_signature().await
+    }
+}
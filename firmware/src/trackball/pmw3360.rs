@@ -1,60 +1,25 @@
-#[derive(Debug, PartialEq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(u8)]
-pub enum Register {
-    ProductId = 0x00,
-    RevisionId = 0x01,
-    Motion = 0x02,
-    DeltaXL = 0x03,
-    DeltaXH = 0x04,
-    DeltaYL = 0x05,
-    DeltaYH = 0x06,
-    Squal = 0x07,
-    RawDataSum = 0x08,
-    MaximumRawData = 0x09,
-    MinimumRawData = 0x0A,
-    ShutterLower = 0x0B,
-    ShutterUpper = 0x0C,
-    Control = 0x0D,
-    Config1 = 0x0F,
-    Config2 = 0x10,
-    AngleTune = 0x11,
-    FrameCapture = 0x12,
-    SromEnable = 0x13,
-    RunDownShift = 0x14,
-    Rest1RateLower = 0x15,
-    Rest1RateUpper = 0x16,
-    Rest1DownShift = 0x17,
-    Rest2RateLower = 0x18,
-    Rest2RateUpper = 0x19,
-    Rest2DownShift = 0x1A,
-    Rest3RateLower = 0x1B,
-    Rest3RateUpper = 0x1C,
-    Observation = 0x24,
-    DataOutLower = 0x25,
-    DataOutUpper = 0x26,
-    RawDataDump = 0x29,
-    SromId = 0x2A,
-    MinSqRun = 0x2B,
-    RawDataThreshold = 0x2C,
-    Config5 = 0x2F,
-    PowerUpReset = 0x3A,
-    Shutdown = 0x3B,
-    InverseProductId = 0x3F,
-    LiftCutOffTune3 = 0x41,
-    AngleSnap = 0x42,
-    LiftCutOffTune1 = 0x4A,
-    MotionBurst = 0x50,
-    LiftCutOffTuneTimeOut = 0x58,
-    LiftCutOffTuneMinLength = 0x5A,
-    SromLoadBurst = 0x62,
-    LiftConfig = 0x63,
-    RawDataBurst = 0x64,
-    LiftCutOffTune2 = 0x65,
-}
+//! Specifics of the PMW3360
+
+pub use super::pmw33x0::Register;
+use utils::resolution::{self, Resolution};
+
+/// Product ID of the sensor, its inverse being in `InverseProductId`
+pub const PRODUCT_ID: u8 = 0x42;
+/// Resolution range of the sensor, and its encoding
+pub const RESOLUTION: Resolution = resolution::PMW3360;
+/// Lowest resolution of the sensor, in CPI
+pub const MIN_CPI: u16 = RESOLUTION.min;
+/// Highest resolution of the sensor, in CPI
+pub const MAX_CPI: u16 = RESOLUTION.max;
+/// Registers of the resolution, from its lower byte
+pub const CPI_REGISTERS: &[Register] = &[Register::Config1];
+/// Clock of the SPI bus, in Hz
+pub const SPI_FREQUENCY: u32 = 7_000_000;
+/// Version of the SROM, as read in `SromId` once uploaded
+pub const SROM_ID: u8 = 0x04;
 
 /// SROM 0x04
-pub const SROM_TRACKING_FW: [u8; 4094] = [
+pub const SROM: [u8; 4094] = [
     0x01, 0x04, 0x8e, 0x96, 0x6e, 0x77, 0x3e, 0xfe, 0x7e, 0x5f, 0x1d, 0xb8, 0xf2, 0x66, 0x4e, 0xff,
     0x5d, 0x19, 0xb0, 0xc2, 0x04, 0x69, 0x54, 0x2a, 0xd6, 0x2e, 0xbf, 0xdd, 0x19, 0xb0, 0xc3, 0xe5,
     0x29, 0xb1, 0xe0, 0x23, 0xa5, 0xa9, 0xb1, 0xc1, 0x00, 0x82, 0x67, 0x4c, 0x1a, 0x97, 0x8d, 0x79,
@@ -0,0 +1,23 @@
+//! Specifics of the PMW3389. Its SROM is not part of the tree: `build.rs`
+//! copies the file given by `PMW3389_SROM` next to the generated code.
+
+pub use super::pmw33x0::Register;
+use utils::resolution::{self, Resolution};
+
+/// Product ID of the sensor, its inverse being in `InverseProductId`
+pub const PRODUCT_ID: u8 = 0x47;
+/// Resolution range of the sensor, and its encoding
+pub const RESOLUTION: Resolution = resolution::PMW3389;
+/// Lowest resolution of the sensor, in CPI
+pub const MIN_CPI: u16 = RESOLUTION.min;
+/// Highest resolution of the sensor, in CPI
+pub const MAX_CPI: u16 = RESOLUTION.max;
+/// Registers of the resolution, from its lower byte
+pub const CPI_REGISTERS: &[Register] = &[Register::ResolutionL, Register::Config1];
+/// Clock of the SPI bus, in Hz
+pub const SPI_FREQUENCY: u32 = 7_000_000;
+/// Version of the SROM, its second byte, as read in `SromId` once uploaded
+pub const SROM_ID: u8 = SROM[1];
+
+/// SROM, as given at build time
+pub const SROM: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/pmw3389_srom.bin"));
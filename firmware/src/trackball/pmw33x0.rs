@@ -0,0 +1,252 @@
+//! Register map and driver of the PMW3360 and PMW3389, which only differ by
+//! their SROM and resolution
+
+use super::{sensor, BurstData, SensorBus, Trackball, TrackballError};
+use crate::settings;
+use embassy_futures::yield_now;
+use embassy_time::{block_for, Duration, Timer};
+use utils::log::error;
+
+/// Minimum time between two bytes of the SROM download, in µs
+const SROM_BYTE_DELAY_US: u64 = 15;
+/// Number of bytes of the SROM downloaded between two yields to the other
+/// tasks. The delays between the bytes are busy waits, much shorter than
+/// awaiting a timer for each byte, so the other tasks only get to run
+/// between the chunks, about every millisecond.
+const SROM_CHUNK_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Register {
+    ProductId = 0x00,
+    RevisionId = 0x01,
+    Motion = 0x02,
+    DeltaXL = 0x03,
+    DeltaXH = 0x04,
+    DeltaYL = 0x05,
+    DeltaYH = 0x06,
+    Squal = 0x07,
+    RawDataSum = 0x08,
+    MaximumRawData = 0x09,
+    MinimumRawData = 0x0A,
+    ShutterLower = 0x0B,
+    ShutterUpper = 0x0C,
+    Control = 0x0D,
+    /// Lower byte of the resolution of the PMW3389
+    ResolutionL = 0x0E,
+    /// Resolution of the PMW3360, upper byte of the one of the PMW3389
+    Config1 = 0x0F,
+    Config2 = 0x10,
+    AngleTune = 0x11,
+    FrameCapture = 0x12,
+    SromEnable = 0x13,
+    RunDownShift = 0x14,
+    Rest1RateLower = 0x15,
+    Rest1RateUpper = 0x16,
+    Rest1DownShift = 0x17,
+    Rest2RateLower = 0x18,
+    Rest2RateUpper = 0x19,
+    Rest2DownShift = 0x1A,
+    Rest3RateLower = 0x1B,
+    Rest3RateUpper = 0x1C,
+    Observation = 0x24,
+    DataOutLower = 0x25,
+    DataOutUpper = 0x26,
+    RawDataDump = 0x29,
+    SromId = 0x2A,
+    MinSqRun = 0x2B,
+    RawDataThreshold = 0x2C,
+    Config5 = 0x2F,
+    PowerUpReset = 0x3A,
+    Shutdown = 0x3B,
+    InverseProductId = 0x3F,
+    LiftCutOffTune3 = 0x41,
+    AngleSnap = 0x42,
+    LiftCutOffTune1 = 0x4A,
+    MotionBurst = 0x50,
+    LiftCutOffTuneTimeOut = 0x58,
+    LiftCutOffTuneMinLength = 0x5A,
+    SromLoadBurst = 0x62,
+    LiftConfig = 0x63,
+    RawDataBurst = 0x64,
+    LiftCutOffTune2 = 0x65,
+}
+
+impl<B: SensorBus> Trackball<'_, B> {
+    pub async fn burst_get(&mut self) -> Result<BurstData, TrackballError> {
+        // Write any value to Motion_burst register
+        // if any write occured before
+        if !self.in_burst {
+            self.write(Register::MotionBurst, 0x00).await?;
+        }
+
+        // Lower NCS
+        self.cs.set_low();
+        // Send Motion_burst address
+        self.bus.send(&[Register::MotionBurst as u8])?;
+
+        // NOTE: The datasheet says to wait for 35us here, but it seems to work without it.
+        // It seems that embassy_time is not good at waiting for such small values,
+        // and simply turning off Timer reduces the processing time of this function from maxium 3 ms to almost 0.
+
+        // tSRAD_MOTBR
+        // Timer::after_micros(35).await;
+
+        // Read the 12 bytes of burst data, up to the shutter
+        let mut buf = [0u8; 12];
+        for b in buf.iter_mut() {
+            let t_buf = &mut [0x00];
+            match self.bus.receive(t_buf) {
+                Ok(()) => *b = *t_buf.first().unwrap(),
+                Err(_) => *b = 0,
+            }
+        }
+
+        // Raise NCS
+        self.cs.set_high();
+
+        // NOTE: Same as tSRAD_MOTBR. temporary disabled.
+        //
+        // tBEXIT
+        // Timer::after_micros(1).await;
+
+        //combine the register values
+        let mut data = BurstData {
+            motion: (buf[0] & 0x80) != 0,
+            dy: ((buf[3] as i16) << 8) | (buf[2] as i16),
+            dx: ((buf[5] as i16) << 8) | (buf[4] as i16),
+            squal: buf[6],
+            raw_data_sum: buf[7],
+            shutter: ((buf[10] as u16) << 8) | (buf[11] as u16),
+        };
+        if buf[0] & 0b111 != 0 {
+            error!("Motion burst error");
+            self.in_burst = false;
+        }
+        // if the motion bit is not set, the dx and dy values are not valid
+        if !data.motion {
+            data.dx = 0;
+            data.dy = 0;
+        }
+        // avoid small glitches
+        if data.dx == 1 || data.dx == -1 {
+            data.dx = 0;
+        }
+        if data.dy == 1 || data.dy == -1 {
+            data.dy = 0;
+        }
+        // if the dx or dy values are 0, the sensor is not moving
+        if data.dx == 0 && data.dy == 0 {
+            data.motion = false;
+        }
+
+        Ok(data)
+    }
+
+    /// Check if the sensor is connected and has the correct signature
+    pub async fn check_signature(&mut self) -> Result<(), TrackballError> {
+        let srom = self.read(Register::SromId).await.unwrap_or(0);
+        let pid = self.read(Register::ProductId).await.unwrap_or(0);
+        let ipid = self.read(Register::InverseProductId).await.unwrap_or(0);
+
+        if srom != sensor::SROM_ID || pid != sensor::PRODUCT_ID || ipid != !sensor::PRODUCT_ID {
+            Err(TrackballError::InvalidSignature)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Set the lift-off distance of the sensor
+    pub(super) async fn set_lift_config(&mut self, lift_config: u8) -> Result<(), TrackballError> {
+        self.write(Register::LiftConfig, lift_config).await
+    }
+
+    /// Power up the sensor
+    pub(super) async fn power_up(&mut self) -> Result<(), TrackballError> {
+        // sensor reset not active
+        // self.reset_pin.set_high().ok();
+
+        // reset the spi bus on the sensor
+        self.cs.set_high();
+        Timer::after_micros(50).await;
+        self.cs.set_low();
+        Timer::after_micros(50).await;
+
+        // Write to reset register
+        self.write(Register::PowerUpReset, 0x5A).await?;
+        // 100 ms delay
+        Timer::after_micros(100).await;
+
+        // read registers 0x02 to 0x06 (and discard the data)
+        self.read(Register::Motion).await?;
+        self.read(Register::DeltaXL).await?;
+        self.read(Register::DeltaXH).await?;
+        self.read(Register::DeltaYL).await?;
+        self.read(Register::DeltaYH).await?;
+
+        // upload the firmware
+        self.upload_fw().await?;
+
+        let is_valid_signature = self.check_signature().await;
+
+        // Write 0x00 (rest disable) to Config2 register for wired mouse or 0x20 for
+        // wireless mouse design.
+        self.write(Register::Config2, 0x00).await?;
+        // Tune the angle, as stored in the settings
+        self.write(Register::AngleTune, settings::angle_tune() as u8)
+            .await?;
+        self.write(Register::LiftConfig, settings::lift_config())
+            .await?;
+
+        Timer::after_micros(100).await;
+
+        is_valid_signature
+    }
+
+    async fn upload_fw(&mut self) -> Result<(), TrackballError> {
+        // Write 0 to Rest_En bit of Config2 register to disable Rest mode.
+        self.write(Register::Config2, 0x00).await?;
+
+        // write 0x1d in SROM_enable reg for initializing
+        self.write(Register::SromEnable, 0x1d).await?;
+
+        // wait for 10 ms
+        Timer::after_micros(10000).await;
+
+        // write 0x18 to SROM_enable to start SROM download
+        self.write(Register::SromEnable, 0x18).await?;
+
+        // lower CS
+        self.cs.set_low();
+
+        // first byte is address
+        self.bus.send(&[Register::SromLoadBurst as u8 | 0x80])?;
+        Timer::after_micros(15).await;
+
+        // send the rest of the firmware
+        for chunk in sensor::SROM.chunks(SROM_CHUNK_SIZE) {
+            for element in chunk {
+                self.bus.send(&[*element])?;
+                block_for(Duration::from_micros(SROM_BYTE_DELAY_US));
+            }
+            yield_now().await;
+        }
+
+        Timer::after_micros(2).await;
+        self.cs.set_high();
+        Timer::after_micros(200).await;
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub async fn self_test(&mut self) -> Result<bool, TrackballError> {
+        self.write(Register::SromEnable, 0x15).await?;
+        Timer::after_micros(10000).await;
+
+        let u = self.read(Register::DataOutUpper).await.unwrap_or(0); // should be 0xBE
+        let l = self.read(Register::DataOutLower).await.unwrap_or(0); // should be 0xEF
+
+        Ok(u == 0xBE && l == 0xEF)
+    }
+}
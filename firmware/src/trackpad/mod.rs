@@ -1,73 +1,92 @@
-use crate::mouse::{MouseMove, MOUSE_MOVE_CHANNEL};
-use embassy_executor::Spawner;
+use crate::device::{self, SUSPEND_WATCH};
+use crate::haptic::{self, HapticEvent};
+use crate::mouse::{MouseMove, MOUSE_BUTTON_CHANNEL, MOUSE_MOVE_CHANNEL};
+use crate::pointer::SUSPENDED_POLL_MS;
+use crate::settings;
+use crate::watchdog::{self, Task};
+use cirque_pinnacle_async::{Config, Trackpad, TransformMode};
 use embassy_rp::{
-    dma,
-    gpio::{self, Output},
-    interrupt,
-    peripherals::{PIN_20, PIN_21, PIN_22, PIN_23, SPI0},
+    gpio::Output,
+    peripherals::SPI0,
     spi::{self, Async, Spi},
-    Peri,
 };
-use embassy_time::{Duration, Ticker};
+use embassy_time::{Duration, Instant, Ticker, Timer};
 use embedded_hal_bus::spi::ExclusiveDevice;
+use utils::edge_scroll::EdgeScroll;
 use utils::log::error;
-
-pub mod driver;
-mod glide;
-pub mod regs;
+use utils::tap_gesture::{TapEvent, TapGesture};
 
 /// Sensor refresh rate, in ms
 const REFRESH_RATE_MS: u64 = 10;
 
 type TrackpadSpi = ExclusiveDevice<Spi<'static, SPI0, Async>, Output<'static>, embassy_time::Delay>;
 
-pub struct TrackpadPins {
-    pub clk: Peri<'static, PIN_22>,
-    pub mosi: Peri<'static, PIN_23>,
-    pub miso: Peri<'static, PIN_20>,
-    pub cs: Peri<'static, PIN_21>,
-}
+/// Trackpad driver, on the SPI bus of the pointing device
+pub type TrackpadDev = Trackpad<TrackpadSpi, 35>;
 
-pub fn init<TxDma: dma::ChannelInstance, RxDma: dma::ChannelInstance>(
-    spawner: &Spawner,
-    spi: Peri<'static, SPI0>,
-    pins: TrackpadPins,
-    tx_dma: Peri<'static, TxDma>,
-    rx_dma: Peri<'static, RxDma>,
-    irq: impl interrupt::typelevel::Binding<TxDma::Interrupt, dma::InterruptHandler<TxDma>>
-        + interrupt::typelevel::Binding<RxDma::Interrupt, dma::InterruptHandler<RxDma>>
-        + 'static,
-) {
+/// Configuration of the SPI bus for the trackpad
+pub fn spi_config() -> spi::Config {
     let mut config = spi::Config::default();
     config.phase = spi::Phase::CaptureOnSecondTransition;
-    let spi = Spi::new(
-        spi, pins.clk, pins.mosi, pins.miso, tx_dma, rx_dma, irq, config,
-    );
-    let spi = ExclusiveDevice::new(
+    config
+}
+
+/// Create the trackpad driver. The sensor is set up by `run()`.
+pub fn new(spi: Spi<'static, SPI0, Async>, cs: Output<'static>) -> TrackpadDev {
+    let spi = ExclusiveDevice::new(spi, cs, embassy_time::Delay).unwrap();
+    Trackpad::new(
         spi,
-        Output::new(pins.cs, gpio::Level::Low),
-        embassy_time::Delay,
+        Config {
+            transform: TransformMode::Rotate90,
+            ..Default::default()
+        },
     )
-    .unwrap();
+}
 
-    spawner.spawn(trackpad_task(spi).unwrap());
+/// Send the left button change `event` of a tap gesture
+async fn send_button(event: TapEvent) {
+    if MOUSE_BUTTON_CHANNEL.is_full() {
+        error!("Mouse button channel is full");
+    }
+    MOUSE_BUTTON_CHANNEL.send(event).await;
 }
 
 #[embassy_executor::task]
-async fn trackpad_task(spi: TrackpadSpi) {
-    let mut trackpad = driver::Trackpad::<_, 35>::new(spi, None);
-
+pub async fn run(mut trackpad: TrackpadDev) {
     if let Err(_e) = trackpad.init().await {
         error!("Couldn't init trackpad");
         return;
     }
 
     let mut ticker = Ticker::every(Duration::from_millis(REFRESH_RATE_MS));
+    watchdog::register(Task::Pointer);
 
     let mut last_dx = 0_i8;
     let mut last_dy = 0_i8;
     let mut last_pressure = 0_u8;
+    let mut gesture = TapGesture::new();
+    let mut edge_scroll = EdgeScroll::new(settings::get().pointer.scroll_divisor as i16);
+    let mut suspend_rcv = SUSPEND_WATCH.receiver().unwrap();
+    // The sensor is only polled to wake the host up while it is suspended
+    let mut suspended = false;
     loop {
+        watchdog::heartbeat(Task::Pointer);
+        if let Some(state) = suspend_rcv.try_changed() {
+            suspended = state;
+        }
+        if suspended {
+            if let Ok(Some((_, _, pressure))) = trackpad.get_report().await {
+                if pressure != 0 {
+                    device::wake_host();
+                }
+            }
+            Timer::after_millis(SUSPENDED_POLL_MS).await;
+            ticker.reset();
+            continue;
+        }
+        if let Some(event) = gesture.tick(Instant::now().as_millis()) {
+            send_button(event).await;
+        }
         match trackpad.get_report().await {
             Ok(Some((dx, dy, pressure)))
                 if last_dx != dx || last_dy != dy || last_pressure != pressure =>
@@ -78,16 +97,50 @@ async fn trackpad_task(spi: TrackpadSpi) {
                 if pressure != 0 && last_pressure != pressure {
                     utils::log::info!("Trackpad pressure: {}", pressure);
                 }
+                let now = Instant::now().as_millis();
+                let events = if pressure != 0 && last_pressure == 0 {
+                    if let Some((x, y)) = trackpad.touch_position() {
+                        let divisor = settings::get().pointer.scroll_divisor;
+                        edge_scroll.on_touch(x, y, divisor as i16);
+                    }
+                    gesture.on_touch(now)
+                } else if pressure == 0 && last_pressure != 0 {
+                    edge_scroll.on_lift();
+                    gesture.on_lift(now)
+                } else {
+                    &[]
+                };
+                if pressure != 0 {
+                    gesture.on_move(dx.into(), dy.into());
+                }
+                if events.contains(&TapEvent::Press) {
+                    haptic::trigger(HapticEvent::Tap);
+                }
+                for event in events {
+                    send_button(*event).await;
+                }
                 last_dx = dx;
                 last_dy = dy;
                 last_pressure = pressure;
-                MOUSE_MOVE_CHANNEL
-                    .send(MouseMove {
+                // A touch starting on an edge scrolls instead of moving the
+                // pointer
+                let mouse_move = match edge_scroll.on_move(dx.into(), dy.into()) {
+                    Some((wheel, pan)) => MouseMove {
+                        dx: 0,
+                        dy: 0,
+                        pressure,
+                        wheel,
+                        pan,
+                    },
+                    None => MouseMove {
                         dx: dx.into(),
                         dy: dy.into(),
                         pressure,
-                    })
-                    .await;
+                        wheel: 0,
+                        pan: 0,
+                    },
+                };
+                MOUSE_MOVE_CHANNEL.send(mouse_move).await;
             }
             Err(_e) => {
                 error!("Failed to get a trackpad report");
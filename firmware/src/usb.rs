@@ -1,7 +1,10 @@
+use embassy_futures::select::{select, Either};
 use embassy_rp::peripherals::USB;
 use embassy_rp::usb::Driver;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
 use embassy_usb::Builder;
 use embassy_usb::Config as USBConfig;
+use utils::log::{info, warn};
 
 /// USB VID based on
 /// <https://github.com/obdev/v-usb/blob/master/usbdrv/USB-IDs-for-free.txt>
@@ -13,11 +16,22 @@ const PID: u16 = 0x27db;
 /// USB Product
 #[cfg(feature = "cnano")]
 const PRODUCT: &str = "Charybdis Nano keyboard";
-#[cfg(feature = "dilemma")]
+#[cfg(all(feature = "dilemma", not(feature = "dilemma_max")))]
 const PRODUCT: &str = "Dilemma keyboard";
+#[cfg(feature = "dilemma_max")]
+const PRODUCT: &str = "Dilemma Max keyboard";
+#[cfg(feature = "skeletyl")]
+const PRODUCT: &str = "Skeletyl keyboard";
+#[cfg(feature = "scylla")]
+const PRODUCT: &str = "Scylla keyboard";
+#[cfg(feature = "tbkmini")]
+const PRODUCT: &str = "TBK Mini keyboard";
 /// USB Manufacturer
 const MANUFACTURER: &str = "Bastard Keyboards & Boris Faure";
 
+/// Signal to resume the suspended host
+pub static WAKEUP_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
 /// Generate the Embassy-USB configuration
 pub fn config() -> USBConfig<'static> {
     let mut config = USBConfig::new(VID, PID);
@@ -26,6 +40,8 @@ pub fn config() -> USBConfig<'static> {
     config.serial_number = Some(env!("CARGO_PKG_VERSION"));
     config.max_power = 100;
     config.max_packet_size_0 = 64;
+    // The pointer wakes the host up
+    config.supports_remote_wakeup = true;
 
     // Required for windows compatibility.
     // https://developer.nordicsemi.com/nRF_Connect_SDK/doc/1.9.1/kconfig/CONFIG_CDC_ACM_IAD.html#help
@@ -38,5 +54,19 @@ pub fn config() -> USBConfig<'static> {
 
 #[embassy_executor::task]
 pub async fn run(builder: Builder<'static, Driver<'static, USB>>) {
-    builder.build().run().await;
+    let mut device = builder.build();
+    loop {
+        device.run_until_suspend().await;
+        // Only the pointer activity while suspended wakes the host up
+        WAKEUP_SIGNAL.reset();
+        match select(device.wait_resume(), WAKEUP_SIGNAL.wait()).await {
+            Either::First(_) => {}
+            Either::Second(_) => {
+                info!("Waking the host up");
+                if let Err(_e) = device.remote_wakeup().await {
+                    warn!("Unable to wake the host up: {:?}", _e);
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,82 @@
+use embassy_rp::{peripherals::WATCHDOG, watchdog::Watchdog, Peri};
+use embassy_time::{Duration, Ticker};
+use portable_atomic::{AtomicU8, Ordering};
+use utils::log::{error, info};
+
+/// Period at which the supervisor checks the heartbeats, in ms
+const CHECK_PERIOD_MS: u64 = 500;
+/// Hardware watchdog timeout, in ms. Two consecutive failed checks reboot
+/// the keyboard
+const WATCHDOG_TIMEOUT_MS: u64 = 3 * CHECK_PERIOD_MS;
+/// Maximum time a supervised task may wait without sending a heartbeat, in
+/// ms. Tasks blocked on a channel must wake up at least at this rate
+pub const HEARTBEAT_PERIOD_MS: u64 = 100;
+
+/// Supervised tasks
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Task {
+    /// Layout processing, `core::run`
+    Core = 0,
+    /// Communication with the other half
+    Side = 1,
+    /// HID keyboard report writer
+    HidKb = 2,
+    /// HID consumer control report writer
+    HidConsumer = 3,
+    /// Trackball or trackpad
+    Pointer = 4,
+    /// HID system control report writer
+    HidSystem = 5,
+    /// RGB LEDs animation, `rgb_leds::run`, on the second core
+    Rgb = 6,
+}
+
+impl Task {
+    /// Bit of the task in the heartbeat masks
+    const fn bit(self) -> u8 {
+        1 << (self as u8)
+    }
+}
+
+/// Tasks that have registered to be supervised
+static EXPECTED: AtomicU8 = AtomicU8::new(0);
+/// Tasks that sent a heartbeat since the last check
+static ALIVE: AtomicU8 = AtomicU8::new(0);
+
+/// Register a task to be supervised. From now on, it must send heartbeats
+/// regularly or the keyboard will reboot
+pub fn register(task: Task) {
+    info!("Watchdog: supervising {:?}", task);
+    EXPECTED.fetch_or(task.bit(), Ordering::Relaxed);
+    ALIVE.fetch_or(task.bit(), Ordering::Relaxed);
+}
+
+/// Signal that the task is alive
+pub fn heartbeat(task: Task) {
+    ALIVE.fetch_or(task.bit(), Ordering::Relaxed);
+}
+
+/// Supervisor task: feeds the hardware watchdog only when all the
+/// registered tasks have sent a heartbeat since the last check
+#[embassy_executor::task]
+pub async fn run(watchdog: Peri<'static, WATCHDOG>) {
+    let mut watchdog = Watchdog::new(watchdog);
+    watchdog.pause_on_debug(true);
+    watchdog.start(Duration::from_millis(WATCHDOG_TIMEOUT_MS));
+
+    let mut ticker = Ticker::every(Duration::from_millis(CHECK_PERIOD_MS));
+    loop {
+        ticker.next().await;
+        let expected = EXPECTED.load(Ordering::Relaxed);
+        let alive = ALIVE.swap(0, Ordering::Relaxed);
+        if alive & expected == expected {
+            watchdog.feed();
+        } else {
+            error!(
+                "Watchdog: missing heartbeats (expected: {:#x}, alive: {:#x})",
+                expected, alive
+            );
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use std::future;
+use utils::protocol::{Hardware, SideProtocol};
+use utils::serde::{serialize, Event, Message};
+use utils::sid::Sid;
+
+/// Number of messages received per iteration
+const NB_MESSAGES: usize = 64;
+
+/// Hardware replaying a list of received messages, discarding what is sent
+struct ReplayHardware {
+    messages: Vec<Message>,
+}
+
+impl Hardware for ReplayHardware {
+    async fn queue_send(&mut self, _msg: Message) {}
+
+    fn receive(&mut self) -> impl future::Future<Output = Message> + Send {
+        let msg = self.messages.pop().unwrap();
+        async move { msg }
+    }
+
+    async fn set_error_state(&mut self, _error: bool) {}
+}
+
+/// Messages received in order: key presses and releases with consecutive
+/// sequence ids, to be popped from the end
+fn in_order_messages() -> Vec<Message> {
+    let mut sid = Sid::default();
+    let mut messages = Vec::with_capacity(NB_MESSAGES);
+    for i in 0..NB_MESSAGES {
+        let event = if i % 2 == 0 {
+            Event::Press(1, 2)
+        } else {
+            Event::Release(1, 2)
+        };
+        messages.push(serialize(event, sid).unwrap());
+        sid = sid.next();
+    }
+    messages.reverse();
+    messages
+}
+
+fn protocol(messages: Vec<Message>) -> SideProtocol<ReplayHardware> {
+    SideProtocol::new(ReplayHardware { messages }, "bench")
+}
+
+fn bench_receive(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    c.bench_function("protocol_receive", |b| {
+        b.to_async(&rt).iter_batched(
+            || protocol(in_order_messages()),
+            |mut protocol| async move {
+                for _ in 0..NB_MESSAGES {
+                    protocol.run_once_continuous().await;
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    c.bench_function("protocol_receive_corrupted", |b| {
+        b.to_async(&rt).iter_batched(
+            || {
+                let mut messages = in_order_messages();
+                for msg in messages.iter_mut().step_by(8) {
+                    *msg ^= 0x100;
+                }
+                protocol(messages)
+            },
+            |mut protocol| async move {
+                for _ in 0..NB_MESSAGES {
+                    protocol.run_once_continuous().await;
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench_receive);
+criterion_main!(benches);
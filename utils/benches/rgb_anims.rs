@@ -0,0 +1,25 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use utils::rgb_anims::{RgbAnim, RgbAnimType};
+
+fn bench_tick(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rgb_anim_tick");
+    for (name, animation) in [
+        ("off", RgbAnimType::Off),
+        ("solid", RgbAnimType::SolidColor(3)),
+        ("wheel", RgbAnimType::Wheel),
+        ("pulse", RgbAnimType::Pulse),
+        ("pulse_solid", RgbAnimType::PulseSolid(3)),
+    ] {
+        let mut anim = RgbAnim::new(0xdead_beef);
+        anim.set_animation(animation);
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                black_box(anim.tick());
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_tick);
+criterion_main!(benches);
@@ -0,0 +1,45 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use utils::rgb_anims::RgbAnimType;
+use utils::serde::{deserialize, serialize, Event};
+use utils::sid::Sid;
+
+/// Events of the most common kinds
+const EVENTS: [Event; 6] = [
+    Event::Press(1, 2),
+    Event::Release(3, 9),
+    Event::Ack(Sid::new(13)),
+    Event::Ping,
+    Event::RgbAnim(RgbAnimType::Wheel),
+    Event::RgbAnimChangeLayer(2),
+];
+
+fn bench_serialize(c: &mut Criterion) {
+    c.bench_function("serialize", |b| {
+        b.iter(|| {
+            for event in EVENTS {
+                black_box(serialize(black_box(event), black_box(Sid::new(7))).unwrap());
+            }
+        })
+    });
+}
+
+fn bench_deserialize(c: &mut Criterion) {
+    let messages = EVENTS.map(|event| serialize(event, Sid::new(7)).unwrap());
+    c.bench_function("deserialize", |b| {
+        b.iter(|| {
+            for msg in messages {
+                black_box(deserialize(black_box(msg)).unwrap());
+            }
+        })
+    });
+    c.bench_function("deserialize_corrupted", |b| {
+        b.iter(|| {
+            for msg in messages {
+                black_box(deserialize(black_box(msg ^ 0x10)).ok());
+            }
+        })
+    });
+}
+
+criterion_group!(benches, bench_serialize, bench_deserialize);
+criterion_main!(benches);
@@ -0,0 +1,222 @@
+//! Autoshift: letters and digits held long enough are shifted
+//!
+//! The letter and digit keys pressed without any modifier are held back
+//! from the reports until resolved:
+//! - released before the timeout, they are tapped for `TAP_MS`
+//! - still held at the timeout, they are pressed with Left Shift until
+//!   released
+
+use crate::hid::Usage;
+use crate::settings::AutoShift as Config;
+
+/// Time a key resolved as not shifted stays pressed, for the host to see
+/// it, in ms
+pub const TAP_MS: u16 = 10;
+/// Maximum number of keys tracked at once. Others are not shifted.
+const MAX_KEYS: usize = 6;
+/// Left Shift, in the modifier byte
+const LEFT_SHIFT: u8 = 1 << 1;
+
+/// Whether the key of HID keycode `keycode` is shifted when held: letters
+/// and digits
+fn is_shiftable(keycode: u8) -> bool {
+    // a to z, then 1 to 0
+    (0x04..=0x27).contains(&keycode)
+}
+
+/// Resolution of a key
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum State {
+    /// Held for `held_ms`, not resolved yet
+    Pending { held_ms: u16 },
+    /// Held long enough: shifted until released
+    Shifted,
+    /// Released in time: tapped for `remaining_ms` more
+    Tapped { remaining_ms: u16 },
+}
+
+/// Autoshift engine, refreshed every ms
+#[derive(Debug, Default)]
+pub struct AutoShift {
+    /// Configuration
+    config: Config,
+    /// Keys tracked, with their state
+    keys: [Option<(u8, State)>; MAX_KEYS],
+}
+
+impl AutoShift {
+    /// Autoshift engine with the configuration `config`
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            keys: [None; MAX_KEYS],
+        }
+    }
+
+    /// Apply a configuration changed at runtime
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    /// Whether some keys are tracked, needing ticks
+    pub fn is_active(&self) -> bool {
+        self.keys.iter().any(Option::is_some)
+    }
+
+    /// Advance by 1ms, given what the pressed keys report to the host
+    pub fn tick(&mut self, usages: impl Iterator<Item = Usage>) {
+        let mut seen = [false; MAX_KEYS];
+        let mut new = [0u8; MAX_KEYS];
+        let mut nb_new = 0;
+        let mut modifiers = false;
+        for usage in usages {
+            match usage {
+                Usage::Modifier(_) => modifiers = true,
+                Usage::Key(kc) if is_shiftable(kc) => {
+                    match self
+                        .keys
+                        .iter()
+                        .position(|k| matches!(k, Some((c, _)) if *c == kc))
+                    {
+                        Some(i) => seen[i] = true,
+                        None if nb_new < MAX_KEYS => {
+                            new[nb_new] = kc;
+                            nb_new += 1;
+                        }
+                        None => (),
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        for (key, seen) in self.keys.iter_mut().zip(seen) {
+            let Some((_, state)) = key else {
+                continue;
+            };
+            *state = match (*state, seen) {
+                (State::Pending { held_ms }, true) if held_ms + 1 >= self.config.timeout_ms => {
+                    State::Shifted
+                }
+                (State::Pending { held_ms }, true) => State::Pending {
+                    held_ms: held_ms + 1,
+                },
+                (State::Pending { .. }, false) => State::Tapped {
+                    remaining_ms: TAP_MS,
+                },
+                (State::Shifted, true) => State::Shifted,
+                // Pressed again while being tapped
+                (State::Tapped { .. }, true) => State::Pending { held_ms: 1 },
+                (State::Tapped { remaining_ms }, false) if remaining_ms > 1 => State::Tapped {
+                    remaining_ms: remaining_ms - 1,
+                },
+                (State::Shifted | State::Tapped { .. }, false) => {
+                    *key = None;
+                    continue;
+                }
+            };
+        }
+
+        // Keys pressed with a modifier are left untouched
+        if !self.config.enabled || modifiers {
+            return;
+        }
+        for &kc in &new[..nb_new] {
+            if let Some(key) = self.keys.iter_mut().find(|k| k.is_none()) {
+                *key = Some((kc, State::Pending { held_ms: 1 }));
+            }
+        }
+    }
+
+    /// What the pressed keys report to the host, given what they would
+    /// report without autoshift
+    pub fn usages<'a>(
+        &'a self,
+        usages: impl Iterator<Item = Usage> + 'a,
+    ) -> impl Iterator<Item = Usage> + 'a {
+        let tracked = move |kc: u8| self.keys.iter().flatten().any(|(c, _)| *c == kc);
+        let resolved = self.keys.iter().flatten().flat_map(|(kc, state)| {
+            let (key, shift) = match state {
+                State::Pending { .. } => (None, None),
+                State::Shifted => (Some(Usage::Key(*kc)), Some(Usage::Modifier(LEFT_SHIFT))),
+                State::Tapped { .. } => (Some(Usage::Key(*kc)), None),
+            };
+            key.into_iter().chain(shift)
+        });
+        usages
+            .filter(move |u| !matches!(u, Usage::Key(kc) if tracked(*kc)))
+            .chain(resolved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hid::{generate_reports, KeyboardReport};
+
+    /// Config with autoshift enabled, after 100ms
+    const CONFIG: Config = Config {
+        enabled: true,
+        timeout_ms: 100,
+    };
+
+    /// Keyboard report of `usages` once filtered by `autoshift`, after a
+    /// tick
+    fn report(autoshift: &mut AutoShift, usages: &[Usage]) -> KeyboardReport {
+        autoshift.tick(usages.iter().copied());
+        generate_reports(autoshift.usages(usages.iter().copied()), &[]).0
+    }
+
+    /// Keyboard report with `modifier` and `keycode`
+    fn kb(modifier: u8, keycode: u8) -> KeyboardReport {
+        KeyboardReport {
+            modifier,
+            keycodes: [keycode, 0, 0, 0, 0, 0],
+        }
+    }
+
+    #[test]
+    fn test_tap() {
+        let mut autoshift = AutoShift::new(CONFIG);
+        let a = [Usage::Key(0x04)];
+        for _ in 0..CONFIG.timeout_ms - 1 {
+            assert_eq!(report(&mut autoshift, &a), kb(0, 0));
+        }
+        for _ in 0..TAP_MS {
+            assert_eq!(report(&mut autoshift, &[]), kb(0, 0x04));
+        }
+        assert_eq!(report(&mut autoshift, &[]), kb(0, 0));
+        assert!(!autoshift.is_active());
+    }
+
+    #[test]
+    fn test_shifted() {
+        let mut autoshift = AutoShift::new(CONFIG);
+        let one = [Usage::Key(0x1E)];
+        for _ in 0..CONFIG.timeout_ms - 1 {
+            assert_eq!(report(&mut autoshift, &one), kb(0, 0));
+        }
+        for _ in 0..500 {
+            assert_eq!(report(&mut autoshift, &one), kb(LEFT_SHIFT, 0x1E));
+        }
+        assert_eq!(report(&mut autoshift, &[]), kb(0, 0));
+        assert!(!autoshift.is_active());
+    }
+
+    #[test]
+    fn test_untouched() {
+        // Not a letter
+        let mut autoshift = AutoShift::new(CONFIG);
+        assert_eq!(report(&mut autoshift, &[Usage::Key(0x2C)]), kb(0, 0x2C));
+        // With a modifier
+        let ctrl_a = [Usage::Modifier(1), Usage::Key(0x04)];
+        assert_eq!(report(&mut autoshift, &ctrl_a), kb(1, 0x04));
+        assert!(!autoshift.is_active());
+        // Disabled
+        let mut autoshift = AutoShift::new(Config {
+            enabled: false,
+            ..CONFIG
+        });
+        assert_eq!(report(&mut autoshift, &[Usage::Key(0x04)]), kb(0, 0x04));
+    }
+}
@@ -0,0 +1,304 @@
+//! Pointer ballistics: acceleration and smoothing of the pointer moves
+//!
+//! A ballistic profile bundles a sensor CPI, an acceleration and a
+//! smoothing, so that a good pointer response is picked at once instead of
+//! tuning each of them. The moves are smoothed with an exponential moving
+//! average, then accelerated with a gain growing linearly with the speed
+//! up to `ACCEL_MAX_SPEED`, and by the speed of the active layer. The
+//! fractions of counts are kept between moves, so that slow moves are not
+//! lost. Besides its speed, a layer can make the pointer scroll. The
+//! sniper mode divides the moves further while its key is held.
+
+/// Speed, in counts per move, from which the gain no longer grows
+pub const ACCEL_MAX_SPEED: i32 = 32;
+/// Time without moves after which the smoothing starts over, in ms
+pub const IDLE_RESET_MS: u64 = 50;
+
+/// Pointer speed of the layers not slowing it down nor speeding it up, in
+/// percent
+pub const DEFAULT_SPEED: u16 = 100;
+
+/// Pointer behavior of a layer, set in the keymap
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PointerLayer {
+    /// Pointer speed, in percent
+    pub speed: u16,
+    /// Moving the pointer scrolls, as when ball-is-wheel is held
+    pub scroll: bool,
+}
+
+impl PointerLayer {
+    /// Regular pointer
+    pub const DEFAULT: Self = Self {
+        speed: DEFAULT_SPEED,
+        scroll: false,
+    };
+    /// Moving the pointer scrolls
+    pub const SCROLL: Self = Self {
+        speed: DEFAULT_SPEED,
+        scroll: true,
+    };
+
+    /// Regular pointer, at `speed` percent
+    pub const fn speed(speed: u16) -> Self {
+        Self {
+            speed,
+            scroll: false,
+        }
+    }
+}
+
+impl Default for PointerLayer {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Fractional bits of the fixed-point computations
+const FRAC_BITS: u32 = 8;
+
+/// Pointer response of a ballistic profile
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Response {
+    /// Sensor CPI
+    pub cpi: u16,
+    /// Extra gain at `ACCEL_MAX_SPEED` and above, in percent
+    pub acceleration: u8,
+    /// Weight of the previous moves in the smoothed move, in percent
+    pub smoothing: u8,
+}
+
+/// Ballistic profiles
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum BallisticProfile {
+    /// Low CPI, no acceleration and strong smoothing, for precise work
+    Precise = 0,
+    /// Default CPI with some acceleration and smoothing
+    #[default]
+    Balanced = 1,
+    /// High CPI, strong acceleration and no smoothing
+    Fast = 2,
+}
+
+impl BallisticProfile {
+    /// Profile serialized as `v`, if any
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(BallisticProfile::Precise),
+            1 => Some(BallisticProfile::Balanced),
+            2 => Some(BallisticProfile::Fast),
+            _ => None,
+        }
+    }
+
+    /// Next profile, cycling back to the first one
+    pub fn next(self) -> Self {
+        match self {
+            BallisticProfile::Precise => BallisticProfile::Balanced,
+            BallisticProfile::Balanced => BallisticProfile::Fast,
+            BallisticProfile::Fast => BallisticProfile::Precise,
+        }
+    }
+
+    /// Pointer response of the profile
+    pub fn response(self) -> Response {
+        match self {
+            BallisticProfile::Precise => Response {
+                cpi: 400,
+                acceleration: 0,
+                smoothing: 50,
+            },
+            BallisticProfile::Balanced => Response {
+                cpi: 800,
+                acceleration: 100,
+                smoothing: 25,
+            },
+            BallisticProfile::Fast => Response {
+                cpi: 1600,
+                acceleration: 200,
+                smoothing: 0,
+            },
+        }
+    }
+}
+
+/// Smoothing and acceleration state
+#[derive(Debug)]
+pub struct Ballistics {
+    /// Smoothed move, in fixed point
+    smoothed: (i32, i32),
+    /// Part of the moves not reported yet, in fixed point
+    remainder: (i32, i32),
+    /// Pointer speed of the active layer, in percent
+    speed: u16,
+    /// Factor dividing the moves, 1 unless in sniper mode
+    divisor: u8,
+}
+
+impl Default for Ballistics {
+    fn default() -> Self {
+        Self {
+            smoothed: (0, 0),
+            remainder: (0, 0),
+            speed: DEFAULT_SPEED,
+            divisor: 1,
+        }
+    }
+}
+
+impl Ballistics {
+    /// Set the pointer speed of the active layer, in percent
+    pub fn set_speed(&mut self, speed: u16) {
+        self.speed = speed;
+    }
+
+    /// Set the factor dividing the moves, 1 to leave the sniper mode
+    pub fn set_divisor(&mut self, divisor: u8) {
+        self.divisor = divisor.max(1);
+    }
+
+    /// Apply `response` to the move `(dx, dy)`, `elapsed_ms` after the
+    /// previous one
+    pub fn apply(&mut self, response: &Response, dx: i16, dy: i16, elapsed_ms: u64) -> (i16, i16) {
+        if elapsed_ms > IDLE_RESET_MS {
+            self.smoothed = (0, 0);
+            self.remainder = (0, 0);
+        }
+        let smoothing = response.smoothing.min(99) as i32;
+        let smooth = |prev: i32, d: i16| {
+            (prev * smoothing + ((d as i32) << FRAC_BITS) * (100 - smoothing)) / 100
+        };
+        self.smoothed = (smooth(self.smoothed.0, dx), smooth(self.smoothed.1, dy));
+
+        let speed = (self.smoothed.0.abs() + self.smoothed.1.abs()) >> FRAC_BITS;
+        let gain =
+            100 + response.acceleration as i32 * speed.min(ACCEL_MAX_SPEED) / ACCEL_MAX_SPEED;
+        let gain = gain as i64 * self.speed as i64;
+        let scale = 10_000 * self.divisor as i64;
+        let accelerate = |smoothed: i32, remainder: &mut i32| {
+            let total = smoothed as i64 * gain / scale + *remainder as i64;
+            let counts = total / (1 << FRAC_BITS);
+            *remainder = (total - counts * (1 << FRAC_BITS)) as i32;
+            counts.clamp(i16::MIN as i64, i16::MAX as i64) as i16
+        };
+        (
+            accelerate(self.smoothed.0, &mut self.remainder.0),
+            accelerate(self.smoothed.1, &mut self.remainder.1),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles() {
+        for v in 0..=2 {
+            let profile = BallisticProfile::from_u8(v).unwrap();
+            assert_eq!(profile as u8, v);
+            assert_eq!(profile.next().next().next(), profile);
+        }
+        assert_eq!(BallisticProfile::from_u8(3), None);
+        assert_eq!(
+            BallisticProfile::default().response().cpi,
+            crate::settings::Settings::default().cpi
+        );
+    }
+
+    #[test]
+    fn test_linear() {
+        let response = Response {
+            cpi: 800,
+            acceleration: 0,
+            smoothing: 0,
+        };
+        let mut ballistics = Ballistics::default();
+        for (dx, dy) in [(0, 0), (1, -1), (-50, 7), (i16::MAX, i16::MIN)] {
+            assert_eq!(ballistics.apply(&response, dx, dy, 1), (dx, dy));
+        }
+    }
+
+    #[test]
+    fn test_acceleration() {
+        let response = Response {
+            cpi: 800,
+            acceleration: 100,
+            smoothing: 0,
+        };
+        let mut ballistics = Ballistics::default();
+        // Slow moves are barely accelerated, and never lost
+        let total: i32 = (0..8)
+            .map(|_| ballistics.apply(&response, 1, 0, 1).0 as i32)
+            .sum();
+        assert_eq!(total, 8);
+        // Fast moves get the full gain
+        assert_eq!(ballistics.apply(&response, 40, -40, 1), (80, -80));
+    }
+
+    #[test]
+    fn test_smoothing() {
+        let response = Response {
+            cpi: 800,
+            acceleration: 0,
+            smoothing: 50,
+        };
+        let mut ballistics = Ballistics::default();
+        assert_eq!(ballistics.apply(&response, 16, 0, 1), (8, 0));
+        assert_eq!(ballistics.apply(&response, 16, 0, 1), (12, 0));
+        assert_eq!(ballistics.apply(&response, 0, 0, 1), (6, 0));
+        // Starting over after some idle time
+        assert_eq!(
+            ballistics.apply(&response, 16, 0, IDLE_RESET_MS + 1),
+            (8, 0)
+        );
+    }
+
+    #[test]
+    fn test_speed() {
+        let response = Response {
+            cpi: 800,
+            acceleration: 0,
+            smoothing: 0,
+        };
+        let mut ballistics = Ballistics::default();
+        ballistics.set_speed(50);
+        assert_eq!(ballistics.apply(&response, 10, -10, 1), (5, -5));
+        // Fractions are kept
+        assert_eq!(ballistics.apply(&response, 1, 0, 1), (0, 0));
+        assert_eq!(ballistics.apply(&response, 1, 0, 1), (1, 0));
+        // Speed kept when idle
+        assert_eq!(
+            ballistics.apply(&response, 10, 0, IDLE_RESET_MS + 1),
+            (5, 0)
+        );
+        ballistics.set_speed(300);
+        assert_eq!(ballistics.apply(&response, 10, 0, 1), (30, 0));
+    }
+
+    #[test]
+    fn test_divisor() {
+        let response = Response {
+            cpi: 800,
+            acceleration: 0,
+            smoothing: 0,
+        };
+        let mut ballistics = Ballistics::default();
+        ballistics.set_divisor(4);
+        assert_eq!(ballistics.apply(&response, 8, -8, 1), (2, -2));
+        // Slow moves are not lost
+        let total: i32 = (0..8)
+            .map(|_| ballistics.apply(&response, 1, 0, 1).0 as i32)
+            .sum();
+        assert_eq!(total, 2);
+        // Combined with the speed of the layer
+        ballistics.set_speed(200);
+        assert_eq!(ballistics.apply(&response, 8, 0, 1), (4, 0));
+        ballistics.set_divisor(0);
+        assert_eq!(ballistics.apply(&response, 8, 0, 1), (16, 0));
+    }
+}
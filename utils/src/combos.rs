@@ -0,0 +1,293 @@
+//! Combos: keys pressed together within `COMBO_TERM_MS` acting as another
+//! key
+//!
+//! The presses of the keys belonging to some combo are held back until
+//! either:
+//! - they form a combo, which then presses its key until one of its keys is
+//!   released. The releases of its keys are swallowed.
+//! - they can no longer form a combo, as `COMBO_TERM_MS` elapsed, one of
+//!   them got released, or another key got pressed. They are then forwarded
+//!   to the keymap, in order.
+//!
+//! When a combo is part of a larger one, it is only triggered once
+//! `COMBO_TERM_MS` elapsed without the other keys of the larger one being
+//! pressed.
+
+use crate::pipeline::KeyEvent;
+
+/// Time within which the keys of a combo must be pressed, in ms
+pub const COMBO_TERM_MS: u16 = 30;
+/// Maximum number of keys of a combo
+pub const MAX_COMBO_KEYS: usize = 4;
+
+/// Keys pressed together to press another key
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Combo<K: 'static> {
+    /// Row and column of the keys to press together, at most
+    /// `MAX_COMBO_KEYS`
+    pub keys: &'static [(u8, u8)],
+    /// Key pressed by the combo
+    pub key: K,
+}
+
+impl<K> Combo<K> {
+    /// Whether `coord` is one of the keys of the combo
+    fn contains(&self, coord: (u8, u8)) -> bool {
+        self.keys.contains(&coord)
+    }
+
+    /// Whether the combo is made of the keys `coords`, in any order
+    fn is_made_of(&self, coords: &[(u8, u8)]) -> bool {
+        self.keys.len() == coords.len() && coords.iter().all(|c| self.contains(*c))
+    }
+}
+
+/// Combo engine, refreshed every ms
+#[derive(Debug)]
+pub struct Combos<K: 'static> {
+    /// Combos of the keymap
+    table: &'static [Combo<K>],
+    /// Keys whose presses are held back, in order
+    pending: [(u8, u8); MAX_COMBO_KEYS],
+    /// Number of keys held back
+    nb_pending: usize,
+    /// Time elapsed since the first key was held back, in ms
+    elapsed_ms: u16,
+    /// Index of the combo triggered, and bitmap of its keys still held
+    active: Option<(usize, u8)>,
+}
+
+impl<K: Copy> Combos<K> {
+    /// Combo engine for the combos of `table`
+    pub const fn new(table: &'static [Combo<K>]) -> Self {
+        Self {
+            table,
+            pending: [(0, 0); MAX_COMBO_KEYS],
+            nb_pending: 0,
+            elapsed_ms: 0,
+            active: None,
+        }
+    }
+
+    /// Keys held back
+    fn pending(&self) -> &[(u8, u8)] {
+        &self.pending[..self.nb_pending]
+    }
+
+    /// Whether the keys held back may still form a combo, with other keys
+    /// when `strict`
+    fn may_combo(&self, strict: bool) -> bool {
+        let pending = self.pending();
+        self.table.iter().any(|c| {
+            (!strict || c.keys.len() > pending.len()) && pending.iter().all(|k| c.contains(*k))
+        })
+    }
+
+    /// Forward the presses held back
+    fn flush(&mut self, forward: &mut impl FnMut(KeyEvent)) {
+        for &(i, j) in self.pending() {
+            forward(KeyEvent::Press(i, j));
+        }
+        self.nb_pending = 0;
+    }
+
+    /// Trigger the combo made of the keys held back, if any
+    fn trigger(&mut self) -> bool {
+        let pending = self.pending();
+        let Some(index) = self.table.iter().position(|c| c.is_made_of(pending)) else {
+            return false;
+        };
+        let held = (1u8 << self.table[index].keys.len()) - 1;
+        self.active = Some((index, held));
+        self.nb_pending = 0;
+        true
+    }
+
+    /// Process a key event, forwarding the events left for the keymap to
+    /// `forward`
+    pub fn on_event(&mut self, event: KeyEvent, mut forward: impl FnMut(KeyEvent)) {
+        match event {
+            KeyEvent::Press(i, j) if self.table.iter().any(|c| c.contains((i, j))) => {
+                if self.nb_pending == MAX_COMBO_KEYS {
+                    self.flush(&mut forward);
+                }
+                if self.nb_pending == 0 {
+                    self.elapsed_ms = 0;
+                }
+                self.pending[self.nb_pending] = (i, j);
+                self.nb_pending += 1;
+                if !self.may_combo(false) {
+                    // Forward the keys held back so far, and see whether
+                    // this one starts another combo
+                    self.nb_pending -= 1;
+                    self.flush(&mut forward);
+                    self.pending[0] = (i, j);
+                    self.nb_pending = 1;
+                    self.elapsed_ms = 0;
+                } else if !self.may_combo(true) {
+                    self.trigger();
+                }
+            }
+            KeyEvent::Press(_, _) => {
+                self.flush(&mut forward);
+                forward(event);
+            }
+            KeyEvent::Release(i, j) => {
+                if let Some((index, held)) = &mut self.active {
+                    let keys = self.table[*index].keys;
+                    if let Some(k) = keys.iter().position(|c| *c == (i, j)) {
+                        if *held & (1 << k) != 0 {
+                            *held &= !(1 << k);
+                            if *held == 0 {
+                                self.active = None;
+                            }
+                            return;
+                        }
+                    }
+                }
+                if self.pending().contains(&(i, j)) {
+                    self.flush(&mut forward);
+                }
+                forward(event);
+            }
+        }
+    }
+
+    /// Advance by 1ms, forwarding the events left for the keymap to
+    /// `forward`
+    pub fn tick(&mut self, mut forward: impl FnMut(KeyEvent)) {
+        if self.nb_pending == 0 {
+            return;
+        }
+        self.elapsed_ms += 1;
+        if self.elapsed_ms >= COMBO_TERM_MS && !self.trigger() {
+            self.flush(&mut forward);
+        }
+    }
+
+    /// Key pressed by the triggered combo, if any
+    pub fn key(&self) -> Option<K> {
+        match self.active {
+            Some((index, held)) if held.count_ones() as usize == self.table[index].keys.len() => {
+                Some(self.table[index].key)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static TABLE: [Combo<u8>; 3] = [
+        Combo {
+            keys: &[(1, 6), (1, 7)],
+            key: 0x29,
+        },
+        Combo {
+            keys: &[(1, 1), (1, 2)],
+            key: 1,
+        },
+        Combo {
+            keys: &[(1, 1), (1, 2), (1, 3)],
+            key: 2,
+        },
+    ];
+
+    /// Process `event`, returning the events forwarded
+    fn event(combos: &mut Combos<u8>, event: KeyEvent) -> Vec<KeyEvent> {
+        let mut out = Vec::new();
+        combos.on_event(event, |e| out.push(e));
+        out
+    }
+
+    /// Advance by `ms`, returning the events forwarded
+    fn run(combos: &mut Combos<u8>, ms: u16) -> Vec<KeyEvent> {
+        let mut out = Vec::new();
+        for _ in 0..ms {
+            combos.tick(|e| out.push(e));
+        }
+        out
+    }
+
+    #[test]
+    fn test_combo() {
+        let mut combos = Combos::new(&TABLE);
+        assert!(event(&mut combos, KeyEvent::Press(1, 7)).is_empty());
+        run(&mut combos, 10);
+        assert!(event(&mut combos, KeyEvent::Press(1, 6)).is_empty());
+        assert_eq!(combos.key(), Some(0x29));
+        assert!(run(&mut combos, 100).is_empty());
+        assert!(event(&mut combos, KeyEvent::Release(1, 6)).is_empty());
+        assert_eq!(combos.key(), None);
+        assert!(event(&mut combos, KeyEvent::Release(1, 7)).is_empty());
+        // Not swallowed anymore
+        assert_eq!(
+            event(&mut combos, KeyEvent::Release(1, 7)),
+            [KeyEvent::Release(1, 7)]
+        );
+    }
+
+    #[test]
+    fn test_no_combo() {
+        let mut combos = Combos::new(&TABLE);
+        // Key out of any combo
+        assert_eq!(
+            event(&mut combos, KeyEvent::Press(0, 0)),
+            [KeyEvent::Press(0, 0)]
+        );
+
+        // Too slow
+        assert!(event(&mut combos, KeyEvent::Press(1, 6)).is_empty());
+        assert_eq!(run(&mut combos, COMBO_TERM_MS), [KeyEvent::Press(1, 6)]);
+        assert_eq!(
+            event(&mut combos, KeyEvent::Release(1, 6)),
+            [KeyEvent::Release(1, 6)]
+        );
+
+        // Released before the combo completes
+        assert!(event(&mut combos, KeyEvent::Press(1, 6)).is_empty());
+        assert_eq!(
+            event(&mut combos, KeyEvent::Release(1, 6)),
+            [KeyEvent::Press(1, 6), KeyEvent::Release(1, 6)]
+        );
+
+        // Another key pressed in between
+        assert!(event(&mut combos, KeyEvent::Press(1, 6)).is_empty());
+        assert_eq!(
+            event(&mut combos, KeyEvent::Press(2, 2)),
+            [KeyEvent::Press(1, 6), KeyEvent::Press(2, 2)]
+        );
+
+        // Keys of different combos
+        assert!(event(&mut combos, KeyEvent::Press(1, 1)).is_empty());
+        assert_eq!(
+            event(&mut combos, KeyEvent::Press(1, 7)),
+            [KeyEvent::Press(1, 1)]
+        );
+        assert_eq!(combos.key(), None);
+    }
+
+    #[test]
+    fn test_nested_combos() {
+        let mut combos = Combos::new(&TABLE);
+        // The larger combo
+        event(&mut combos, KeyEvent::Press(1, 1));
+        event(&mut combos, KeyEvent::Press(1, 2));
+        assert_eq!(combos.key(), None);
+        event(&mut combos, KeyEvent::Press(1, 3));
+        assert_eq!(combos.key(), Some(2));
+        event(&mut combos, KeyEvent::Release(1, 1));
+        event(&mut combos, KeyEvent::Release(1, 2));
+        event(&mut combos, KeyEvent::Release(1, 3));
+
+        // The smaller one, once the term elapsed
+        event(&mut combos, KeyEvent::Press(1, 2));
+        event(&mut combos, KeyEvent::Press(1, 1));
+        assert!(run(&mut combos, COMBO_TERM_MS - 1).is_empty());
+        assert_eq!(combos.key(), None);
+        assert!(run(&mut combos, 1).is_empty());
+        assert_eq!(combos.key(), Some(1));
+    }
+}
@@ -0,0 +1,220 @@
+//! Debouncing of the keyboard matrix, key by key
+//!
+//! Two algorithms are available:
+//! - deferred: a key changes state once its scans disagreed with its
+//!   debounced state for its debouncing time in a row
+//! - eager: a key changes state as soon as a scan disagrees with its
+//!   debounced state, then ignores its scans for its debouncing time. No
+//!   latency is added, at the risk of reporting twice a key bouncing longer
+//!   than its debouncing time.
+//!
+//! The debouncing time may differ from key to key, and between presses and
+//! releases, as given by a `DebouncePolicy`. The matrix is expected to be
+//! scanned every ms.
+
+use crate::pipeline::KeyEvent;
+
+/// Debouncing times of a key, in ms
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct DebounceTime {
+    /// Debouncing time of the presses
+    pub press_ms: u8,
+    /// Debouncing time of the releases
+    pub release_ms: u8,
+}
+
+/// Debouncing algorithm
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum DebounceAlgorithm {
+    /// Report a change once stable for the debouncing time
+    #[default]
+    Deferred,
+    /// Report a change at once, then ignore the key for the debouncing time
+    Eager,
+}
+
+/// Debouncing times of the keys of a half, row by row. The keys without
+/// one use the debouncing time of the settings for both transitions.
+#[derive(Debug, Clone, Copy)]
+pub struct DebouncePolicy<const ROWS: usize, const COLS: usize> {
+    /// Debouncing times of each key, if specific
+    pub keys: [[Option<DebounceTime>; COLS]; ROWS],
+}
+
+impl<const ROWS: usize, const COLS: usize> DebouncePolicy<ROWS, COLS> {
+    /// Every key debounced as in the settings
+    pub const UNIFORM: Self = Self {
+        keys: [[None; COLS]; ROWS],
+    };
+
+    /// Debouncing time of the key at row `row` and column `col` to become
+    /// `pressed`, `debounce_ms` being the one of the settings
+    fn time_ms(&self, row: usize, col: usize, pressed: bool, debounce_ms: u8) -> u8 {
+        match self.keys[row][col] {
+            Some(time) if pressed => time.press_ms,
+            Some(time) => time.release_ms,
+            None => debounce_ms,
+        }
+    }
+}
+
+/// Debouncer of a matrix of `ROWS` rows and `COLS` columns
+#[derive(Debug)]
+pub struct Debouncer<const ROWS: usize, const COLS: usize> {
+    /// Debouncing times of the keys
+    policy: DebouncePolicy<ROWS, COLS>,
+    /// Debouncing algorithm
+    algorithm: DebounceAlgorithm,
+    /// Debounced state
+    state: [[bool; COLS]; ROWS],
+    /// Deferred: number of scans in a row each key disagreed with its
+    /// debounced state. Eager: number of scans each key is still ignored.
+    counts: [[u8; COLS]; ROWS],
+}
+
+impl<const ROWS: usize, const COLS: usize> Debouncer<ROWS, COLS> {
+    /// Debouncer of a matrix with no key pressed, following `policy` with
+    /// the algorithm `algorithm`
+    pub const fn new(policy: DebouncePolicy<ROWS, COLS>, algorithm: DebounceAlgorithm) -> Self {
+        Self {
+            policy,
+            algorithm,
+            state: [[false; COLS]; ROWS],
+            counts: [[0; COLS]; ROWS],
+        }
+    }
+
+    /// Whether no key is pressed nor being debounced
+    pub fn is_released(&self) -> bool {
+        self.state.iter().flatten().all(|pressed| !pressed)
+            && self.counts.iter().flatten().all(|count| *count == 0)
+    }
+
+    /// Process a scan of the matrix, `debounce_ms` being the debouncing time
+    /// of the settings. Returns the events of the keys that changed state.
+    pub fn events(
+        &mut self,
+        scan: [[bool; COLS]; ROWS],
+        debounce_ms: u8,
+    ) -> impl Iterator<Item = KeyEvent> {
+        let mut changed = [[false; COLS]; ROWS];
+        for (r, row) in scan.iter().enumerate() {
+            for (c, &pressed) in row.iter().enumerate() {
+                changed[r][c] = match self.algorithm {
+                    DebounceAlgorithm::Deferred => self.deferred(r, c, pressed, debounce_ms),
+                    DebounceAlgorithm::Eager => self.eager(r, c, pressed, debounce_ms),
+                };
+            }
+        }
+        let state = self.state;
+        (0..ROWS).flat_map(move |r| {
+            (0..COLS).filter_map(move |c| match (changed[r][c], state[r][c]) {
+                (false, _) => None,
+                (true, true) => Some(KeyEvent::Press(r as u8, c as u8)),
+                (true, false) => Some(KeyEvent::Release(r as u8, c as u8)),
+            })
+        })
+    }
+
+    /// Deferred debouncing of the key at row `r` and column `c`, scanned
+    /// `pressed`. Returns whether it changed state.
+    fn deferred(&mut self, r: usize, c: usize, pressed: bool, debounce_ms: u8) -> bool {
+        if pressed == self.state[r][c] {
+            self.counts[r][c] = 0;
+            return false;
+        }
+        self.counts[r][c] = self.counts[r][c].saturating_add(1);
+        if self.counts[r][c] < self.policy.time_ms(r, c, pressed, debounce_ms) {
+            return false;
+        }
+        self.state[r][c] = pressed;
+        self.counts[r][c] = 0;
+        true
+    }
+
+    /// Eager debouncing of the key at row `r` and column `c`, scanned
+    /// `pressed`. Returns whether it changed state.
+    fn eager(&mut self, r: usize, c: usize, pressed: bool, debounce_ms: u8) -> bool {
+        if self.counts[r][c] > 0 {
+            self.counts[r][c] -= 1;
+            return false;
+        }
+        if pressed == self.state[r][c] {
+            return false;
+        }
+        self.state[r][c] = pressed;
+        // This scan is the first of the window
+        self.counts[r][c] = self
+            .policy
+            .time_ms(r, c, pressed, debounce_ms)
+            .saturating_sub(1);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scan with the key (0, 1) pressed or not
+    fn scan(pressed: bool) -> [[bool; 2]; 2] {
+        [[false, pressed], [false, false]]
+    }
+
+    /// Events of `n` identical scans
+    fn run(d: &mut Debouncer<2, 2>, pressed: bool, n: usize, ms: u8) -> Vec<KeyEvent> {
+        (0..n).flat_map(|_| d.events(scan(pressed), ms)).collect()
+    }
+
+    #[test]
+    fn test_uniform() {
+        let mut d = Debouncer::new(DebouncePolicy::UNIFORM, DebounceAlgorithm::Deferred);
+        assert!(d.is_released());
+        assert!(run(&mut d, true, 4, 5).is_empty());
+        assert!(!d.is_released());
+        assert_eq!(run(&mut d, true, 1, 5), [KeyEvent::Press(0, 1)]);
+        // Bounces are ignored
+        assert!(run(&mut d, false, 4, 5).is_empty());
+        assert!(run(&mut d, true, 1, 5).is_empty());
+        assert!(run(&mut d, false, 4, 5).is_empty());
+        assert_eq!(run(&mut d, false, 1, 5), [KeyEvent::Release(0, 1)]);
+        assert!(d.is_released());
+        // No debouncing
+        assert_eq!(run(&mut d, true, 1, 0), [KeyEvent::Press(0, 1)]);
+    }
+
+    #[test]
+    fn test_per_key() {
+        let time = DebounceTime {
+            press_ms: 1,
+            release_ms: 10,
+        };
+        let mut d = Debouncer::new(
+            DebouncePolicy {
+                keys: [[None, Some(time)], [None, None]],
+            },
+            DebounceAlgorithm::Deferred,
+        );
+        assert_eq!(run(&mut d, true, 1, 5), [KeyEvent::Press(0, 1)]);
+        assert!(run(&mut d, false, 9, 5).is_empty());
+        assert_eq!(run(&mut d, false, 1, 5), [KeyEvent::Release(0, 1)]);
+        // Other keys follow the settings
+        let events: Vec<_> = (0..5)
+            .flat_map(|_| d.events([[true, false], [false, false]], 5))
+            .collect();
+        assert_eq!(events, [KeyEvent::Press(0, 0)]);
+    }
+
+    #[test]
+    fn test_eager() {
+        let mut d = Debouncer::new(DebouncePolicy::UNIFORM, DebounceAlgorithm::Eager);
+        assert_eq!(run(&mut d, true, 1, 5), [KeyEvent::Press(0, 1)]);
+        // Bounces within the window are ignored
+        assert!(run(&mut d, false, 1, 5).is_empty());
+        assert!(run(&mut d, true, 3, 5).is_empty());
+        assert_eq!(run(&mut d, false, 1, 5), [KeyEvent::Release(0, 1)]);
+        assert!(run(&mut d, true, 4, 5).is_empty());
+        // Still pressed after the window
+        assert_eq!(run(&mut d, true, 1, 5), [KeyEvent::Press(0, 1)]);
+    }
+}
@@ -0,0 +1,112 @@
+//! Drag scroll: the pointer moves scroll, vertically and horizontally
+//!
+//! The drag scroll is toggled, ball-is-wheel held and a layer can make the
+//! pointer scroll: all of them divide the moves into wheel and pan steps,
+//! and keep the counts left over for the next moves so that slow drags
+//! still scroll.
+//!
+//! With a sensor of configurable CPI, the scroll divisor is given at the
+//! default CPI and scaled with the CPI, so that raising the CPI of the
+//! pointer does not make it scroll faster.
+
+use crate::settings::DEFAULT_CPI;
+
+/// Pointer counts per scroll step at `cpi`, for a scroll divisor `divisor`
+/// given at the default CPI
+pub fn cpi_divisor(divisor: u8, cpi: u16) -> i16 {
+    (divisor as u32 * cpi as u32 / DEFAULT_CPI as u32).clamp(1, i16::MAX as u32) as i16
+}
+
+/// Conversion of the pointer moves into wheel and pan steps
+#[derive(Debug)]
+pub struct DragScroll {
+    /// Pointer counts per scroll step
+    divisor: i16,
+    /// Counts not turned into steps yet, on X and Y
+    remainder: (i16, i16),
+}
+
+impl DragScroll {
+    /// Drag scroll of one step every `divisor` counts
+    pub const fn new(divisor: i16) -> Self {
+        Self {
+            divisor: if divisor > 0 { divisor } else { 1 },
+            remainder: (0, 0),
+        }
+    }
+
+    /// Set the pointer counts per scroll step
+    pub fn set_divisor(&mut self, divisor: i16) {
+        self.divisor = divisor.max(1);
+    }
+
+    /// Forget the counts left over, when the pointer starts scrolling
+    pub fn reset(&mut self) {
+        self.remainder = (0, 0);
+    }
+
+    /// Wheel and pan steps of the move `(dx, dy)`: moving the pointer down
+    /// scrolls down, moving it right pans right
+    pub fn scroll(&mut self, dx: i16, dy: i16) -> (i8, i8) {
+        let divisor = self.divisor;
+        let steps = |d: i16, remainder: &mut i16| {
+            let total = *remainder as i32 + d as i32;
+            let steps = total / divisor as i32;
+            *remainder = (total - steps * divisor as i32) as i16;
+            steps.clamp(i8::MIN as i32, i8::MAX as i32) as i8
+        };
+        let pan = steps(dx, &mut self.remainder.0);
+        let wheel = steps(dy, &mut self.remainder.1);
+        (wheel.saturating_neg(), pan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_steps() {
+        let mut drag = DragScroll::new(16);
+        assert_eq!(drag.scroll(0, 0), (0, 0));
+        assert_eq!(drag.scroll(32, 16), (-1, 2));
+        assert_eq!(drag.scroll(-16, -48), (3, -1));
+        // Large moves are clamped
+        assert_eq!(drag.scroll(i16::MAX, i16::MIN), (127, 127));
+    }
+
+    #[test]
+    fn test_remainder() {
+        let mut drag = DragScroll::new(16);
+        // Slow drags still scroll
+        let wheel: i32 = (0..20).map(|_| drag.scroll(0, 4).0 as i32).sum();
+        assert_eq!(wheel, -5);
+        // Going back the other way uses the counts left over
+        assert_eq!(drag.scroll(0, 12), (0, 0));
+        assert_eq!(drag.scroll(0, -20), (0, 0));
+        assert_eq!(drag.scroll(0, -8), (1, 0));
+        drag.scroll(0, 15);
+        drag.reset();
+        assert_eq!(drag.scroll(0, 1), (0, 0));
+    }
+
+    #[test]
+    fn test_divisor() {
+        let mut drag = DragScroll::new(0);
+        assert_eq!(drag.scroll(3, -2), (2, 3));
+        let mut drag = DragScroll::new(4);
+        assert_eq!(drag.scroll(9, 9), (-2, 2));
+        assert_eq!(drag.scroll(3, 3), (-1, 1));
+        drag.set_divisor(-3);
+        assert_eq!(drag.scroll(3, 3), (-3, 3));
+    }
+
+    #[test]
+    fn test_cpi_divisor() {
+        assert_eq!(cpi_divisor(16, DEFAULT_CPI), 16);
+        assert_eq!(cpi_divisor(16, DEFAULT_CPI * 2), 32);
+        assert_eq!(cpi_divisor(16, 200), 4);
+        assert_eq!(cpi_divisor(4, 100), 1);
+        assert_eq!(cpi_divisor(60, 12000), 900);
+    }
+}
@@ -0,0 +1,117 @@
+//! Clock drift between the halves
+//!
+//! The master half sends a frame on the side link every millisecond of its
+//! own clock. Counting the frames received over a long window of the local
+//! clock gives the drift of the master clock relative to the local one, so
+//! that the slave half can tune the bit rate of its PIO state machine to
+//! the one of the master, and keep sampling the bits in their middle.
+
+/// Period of the frames sent by the master, in µs
+pub const FRAME_PERIOD_US: u64 = 1000;
+/// Number of frames over which the drift is measured: about a minute
+pub const WINDOW_FRAMES: u32 = 60_000;
+/// Largest plausible drift, in ppm. Measures above it come from frames
+/// lost or delayed, and are dropped.
+pub const MAX_DRIFT_PPM: i32 = 1000;
+
+/// Estimation of the drift of the master clock
+#[derive(Debug, Default)]
+pub struct DriftEstimator {
+    /// Local time of the first frame of the window, in µs
+    window_start_us: Option<u64>,
+    /// Number of frames received in the window, after the first one
+    frames: u32,
+    /// Smoothed drift, in ppm: positive when the master clock is slower
+    drift_ppm: i32,
+}
+
+impl DriftEstimator {
+    /// Current drift estimate, in ppm: positive when the master clock is
+    /// slower than the local one
+    pub fn drift_ppm(&self) -> i32 {
+        self.drift_ppm
+    }
+
+    /// Account for a frame received at the local time `now_us`. Returns the
+    /// new drift estimate when a window ends with a plausible measure.
+    pub fn on_frame(&mut self, now_us: u64) -> Option<i32> {
+        let Some(start_us) = self.window_start_us else {
+            self.window_start_us = Some(now_us);
+            return None;
+        };
+        self.frames += 1;
+        if self.frames < WINDOW_FRAMES {
+            return None;
+        }
+        let expected_us = self.frames as i64 * FRAME_PERIOD_US as i64;
+        let elapsed_us = now_us.saturating_sub(start_us) as i64;
+        self.window_start_us = Some(now_us);
+        self.frames = 0;
+        let ppm = (elapsed_us - expected_us) * 1_000_000 / expected_us;
+        if ppm.abs() > MAX_DRIFT_PPM as i64 {
+            return None;
+        }
+        // Average with the previous windows, to smooth the jitter of the
+        // frame arrivals
+        self.drift_ppm = (3 * self.drift_ppm + ppm as i32) / 4;
+        Some(self.drift_ppm)
+    }
+}
+
+/// Bit rate to use locally to match the one of the master running at
+/// `speed` bps on its own clock, given its drift `drift_ppm`
+pub fn compensated_speed(speed: u64, drift_ppm: i32) -> u64 {
+    speed * 1_000_000 / (1_000_000 + drift_ppm as i64) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed a whole window of frames to `estimator`, sent every
+    /// `period_ns` from `start_ns`, returning the outcome of the last one
+    fn window(estimator: &mut DriftEstimator, start_ns: u64, period_ns: u64) -> Option<i32> {
+        let mut res = None;
+        for i in 1..=WINDOW_FRAMES as u64 {
+            res = estimator.on_frame((start_ns + i * period_ns) / 1000);
+        }
+        res
+    }
+
+    #[test]
+    fn test_drift() {
+        let mut estimator = DriftEstimator::default();
+        assert_eq!(estimator.on_frame(0), None);
+        // Master clock 100ppm slower
+        let period_ns = 1_000_100;
+        assert_eq!(window(&mut estimator, 0, period_ns), Some(25));
+        for n in 1..20 {
+            window(
+                &mut estimator,
+                n * WINDOW_FRAMES as u64 * period_ns,
+                period_ns,
+            );
+        }
+        assert!((95..=100).contains(&estimator.drift_ppm()));
+        assert_eq!(compensated_speed(1_000_000, estimator.drift_ppm()), 999_903);
+    }
+
+    #[test]
+    fn test_lost_frames() {
+        let mut estimator = DriftEstimator::default();
+        assert_eq!(estimator.on_frame(0), None);
+        // One frame out of 100 lost: 1% drift, not plausible
+        assert_eq!(window(&mut estimator, 0, 1_010_000), None);
+        assert_eq!(estimator.drift_ppm(), 0);
+        // Synchronous clocks
+        let start_ns = WINDOW_FRAMES as u64 * 1_010_000;
+        assert_eq!(window(&mut estimator, start_ns, 1_000_000), Some(0));
+    }
+
+    #[test]
+    fn test_compensated_speed() {
+        assert_eq!(compensated_speed(460_800, 0), 460_800);
+        assert_eq!(compensated_speed(1_000_000, 1000), 999_000);
+        assert_eq!(compensated_speed(1_000_000, -1000), 1_001_001);
+    }
+}
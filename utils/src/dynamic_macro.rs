@@ -0,0 +1,160 @@
+//! Dynamic macros: keyboard reports recorded at runtime and replayed with
+//! a key
+//!
+//! While a slot is being recorded, every keyboard report sent to the host
+//! is appended to it, up to `MACRO_LEN` reports. Playing a slot sends its
+//! reports again, in order. The slots live in RAM and are lost on reboot.
+
+use crate::hid::KeyboardReport;
+use crate::log::{info, warn};
+
+/// Number of macro slots
+pub const MACRO_SLOTS: usize = 2;
+/// Maximum number of keyboard reports of a macro
+pub const MACRO_LEN: usize = 128;
+/// Time between two reports of a macro being played, in ms
+pub const PLAYBACK_PERIOD_MS: u64 = 10;
+
+/// Empty keyboard report
+const EMPTY: KeyboardReport = KeyboardReport {
+    modifier: 0,
+    keycodes: [0; 6],
+};
+
+/// Macro slots, and the recording in progress
+#[derive(Debug)]
+pub struct DynamicMacros {
+    /// Keyboard reports of each slot
+    slots: [[KeyboardReport; MACRO_LEN]; MACRO_SLOTS],
+    /// Number of reports of each slot
+    lens: [usize; MACRO_SLOTS],
+    /// Slot being recorded, if any
+    recording: Option<usize>,
+}
+
+impl Default for DynamicMacros {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DynamicMacros {
+    /// Empty macro slots
+    pub const fn new() -> Self {
+        Self {
+            slots: [[EMPTY; MACRO_LEN]; MACRO_SLOTS],
+            lens: [0; MACRO_SLOTS],
+            recording: None,
+        }
+    }
+
+    /// Start recording the slot `slot`, erasing its previous macro
+    pub fn start_recording(&mut self, slot: u8) {
+        let slot = slot as usize;
+        if slot >= MACRO_SLOTS {
+            warn!("No macro slot {}", slot);
+            return;
+        }
+        info!("Recording macro {}", slot);
+        self.lens[slot] = 0;
+        self.recording = Some(slot);
+    }
+
+    /// Stop the recording in progress, if any
+    pub fn stop_recording(&mut self) {
+        if let Some(slot) = self.recording.take() {
+            info!("Recorded macro {}: {} reports", slot, self.lens[slot]);
+        }
+    }
+
+    /// Whether a slot is being recorded
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// Append `report`, sent to the host, to the slot being recorded, if
+    /// any
+    pub fn record(&mut self, report: KeyboardReport) {
+        let Some(slot) = self.recording else {
+            return;
+        };
+        let len = self.lens[slot];
+        if len > 0 && self.slots[slot][len - 1] == report {
+            return;
+        }
+        if len == MACRO_LEN {
+            warn!("Macro {} is full", slot);
+            return;
+        }
+        self.slots[slot][len] = report;
+        self.lens[slot] = len + 1;
+    }
+
+    /// Reports to send to play the slot `slot`, ending with all the keys
+    /// released. Nothing while recording, so that a macro does not replay
+    /// itself.
+    pub fn playback(&self, slot: u8) -> impl Iterator<Item = KeyboardReport> + '_ {
+        let slot = slot as usize;
+        let reports = match self.recording {
+            None if slot < MACRO_SLOTS => &self.slots[slot][..self.lens[slot]],
+            _ => &[],
+        };
+        let release = (!reports.is_empty()).then_some(EMPTY);
+        reports.iter().copied().chain(release)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Report with the keycode `keycode` pressed
+    fn key(keycode: u8) -> KeyboardReport {
+        let mut report = EMPTY;
+        report.keycodes[0] = keycode;
+        report
+    }
+
+    #[test]
+    fn test_record_playback() {
+        let mut macros = DynamicMacros::new();
+        assert_eq!(macros.playback(0).count(), 0);
+        macros.record(key(4));
+        assert_eq!(macros.playback(0).count(), 0);
+
+        macros.start_recording(0);
+        assert!(macros.is_recording());
+        for report in [key(4), key(4), EMPTY, key(5), EMPTY] {
+            macros.record(report);
+        }
+        // No playback while recording
+        assert_eq!(macros.playback(0).count(), 0);
+        macros.stop_recording();
+        assert!(!macros.is_recording());
+        macros.record(key(6));
+        assert!(macros.playback(0).eq([key(4), EMPTY, key(5), EMPTY, EMPTY]));
+        assert_eq!(macros.playback(1).count(), 0);
+        assert_eq!(macros.playback(7).count(), 0);
+
+        // Recording again erases the slot
+        macros.start_recording(0);
+        macros.record(key(7));
+        macros.stop_recording();
+        assert!(macros.playback(0).eq([key(7), EMPTY]));
+
+        // Unknown slot
+        macros.start_recording(7);
+        assert!(!macros.is_recording());
+    }
+
+    #[test]
+    fn test_full() {
+        let mut macros = DynamicMacros::new();
+        macros.start_recording(1);
+        for i in 0..2 * MACRO_LEN {
+            macros.record(key(i as u8));
+        }
+        macros.stop_recording();
+        assert_eq!(macros.playback(1).count(), MACRO_LEN + 1);
+    }
+}
@@ -0,0 +1,103 @@
+//! Edge scrolling of a trackpad
+//!
+//! A touch starting on the right edge of the trackpad scrolls vertically,
+//! one starting on its bottom edge scrolls horizontally, until the finger
+//! is lifted. Other touches move the pointer.
+
+use crate::drag_scroll::DragScroll;
+
+/// Width of the edges, out of the 256 positions across the trackpad
+const EDGE_WIDTH: u8 = 32;
+
+/// Edge on which a scrolling touch started
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum Edge {
+    /// Right edge: vertical scroll
+    Right,
+    /// Bottom edge: horizontal scroll
+    Bottom,
+}
+
+/// Conversion of the touches starting on the edges into wheel and pan steps
+#[derive(Debug)]
+pub struct EdgeScroll {
+    /// Edge of the current touch, if it scrolls
+    edge: Option<Edge>,
+    /// Conversion of the moves into steps
+    scroll: DragScroll,
+}
+
+impl EdgeScroll {
+    /// Edge scrolling of one step every `divisor` counts
+    pub const fn new(divisor: i16) -> Self {
+        Self {
+            edge: None,
+            scroll: DragScroll::new(divisor),
+        }
+    }
+
+    /// A finger touches the trackpad at `(x, y)`, each from 0 to 255 across
+    /// it, the moves being converted into steps every `divisor` counts
+    pub fn on_touch(&mut self, x: u8, y: u8, divisor: i16) {
+        self.edge = if x >= u8::MAX - EDGE_WIDTH {
+            Some(Edge::Right)
+        } else if y >= u8::MAX - EDGE_WIDTH {
+            Some(Edge::Bottom)
+        } else {
+            None
+        };
+        self.scroll.set_divisor(divisor);
+        self.scroll.reset();
+    }
+
+    /// The finger is lifted from the trackpad
+    pub fn on_lift(&mut self) {
+        self.edge = None;
+    }
+
+    /// Wheel and pan steps of the move `(dx, dy)`, or `None` if the touch
+    /// moves the pointer
+    pub fn on_move(&mut self, dx: i16, dy: i16) -> Option<(i8, i8)> {
+        match self.edge? {
+            Edge::Right => Some(self.scroll.scroll(0, dy)),
+            Edge::Bottom => Some(self.scroll.scroll(dx, 0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edges() {
+        let mut edge = EdgeScroll::new(8);
+        assert_eq!(edge.on_move(8, 8), None);
+        edge.on_touch(128, 128, 8);
+        assert_eq!(edge.on_move(8, 8), None);
+        // Right edge: only vertical
+        edge.on_touch(250, 128, 8);
+        assert_eq!(edge.on_move(20, 16), Some((-2, 0)));
+        assert_eq!(edge.on_move(0, 0), Some((0, 0)));
+        edge.on_lift();
+        assert_eq!(edge.on_move(8, 8), None);
+        // Bottom edge: only horizontal
+        edge.on_touch(10, 240, 8);
+        assert_eq!(edge.on_move(-16, 20), Some((0, -2)));
+        // The right edge wins in the corner
+        edge.on_touch(255, 255, 8);
+        assert_eq!(edge.on_move(8, 8), Some((-1, 0)));
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut edge = EdgeScroll::new(8);
+        edge.on_touch(250, 0, 8);
+        assert_eq!(edge.on_move(0, 7), Some((0, 0)));
+        // The counts left over are forgotten on the next touch
+        edge.on_touch(250, 0, 4);
+        assert_eq!(edge.on_move(0, 3), Some((0, 0)));
+        assert_eq!(edge.on_move(0, 1), Some((-1, 0)));
+    }
+}
@@ -0,0 +1,474 @@
+use crate::log::error;
+
+/// Keycode reported for all the keys when too many keys are pressed
+pub const ERROR_ROLL_OVER: u8 = 0x01;
+/// First keycode that does not fit in the keyboard report
+const KEYCODE_MAX: u8 = 0xE8;
+/// Last keycode of the boot keyboard of the HID specification, the only
+/// ones the BIOSes are expected to understand
+pub const BOOT_KEYCODE_MAX: u8 = 0x65;
+
+/// Default polling interval of the keyboard HID endpoint, in ms
+pub const DEFAULT_KB_POLL_MS: u8 = 1;
+/// Polling interval of the keyboard HID endpoint, in ms: the
+/// `KB_POLL_MS` environment variable of the build if set, to trade latency
+/// for USB bandwidth, `DEFAULT_KB_POLL_MS` otherwise
+pub const KB_POLL_MS: u8 = parse_poll_ms(option_env!("KB_POLL_MS"), DEFAULT_KB_POLL_MS);
+
+/// Keyboard HID report
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct KeyboardReport {
+    /// Modifier keys, in the following order (from least significant bit):
+    /// - Left Control
+    /// - Left Shift
+    /// - Left Alt
+    /// - Left GUI
+    /// - Right Control
+    /// - Right Shift
+    /// - Right Alt
+    /// - Right GUI
+    pub modifier: u8,
+    /// Keycodes for up to 6 simultaneously pressed keys
+    pub keycodes: [u8; 6],
+}
+
+impl KeyboardReport {
+    /// Serialize the report
+    pub fn serialize(&self) -> [u8; 8] {
+        [
+            self.modifier,
+            0u8,
+            self.keycodes[0],
+            self.keycodes[1],
+            self.keycodes[2],
+            self.keycodes[3],
+            self.keycodes[4],
+            self.keycodes[5],
+        ]
+    }
+
+    /// The report to send with the boot protocol: the same layout, without
+    /// the keycodes beyond those of the boot keyboard
+    pub fn to_boot(&self) -> Self {
+        let mut report = Self {
+            modifier: self.modifier,
+            ..Default::default()
+        };
+        let keycodes = self.keycodes.iter().filter(|&&kc| kc <= BOOT_KEYCODE_MAX);
+        for (dst, &kc) in report.keycodes.iter_mut().zip(keycodes) {
+            *dst = kc;
+        }
+        report
+    }
+
+    /// Set the report as an error, with the error keycode `code`
+    fn set_error(&mut self, code: u8) {
+        self.modifier = 0;
+        self.keycodes = [code; 6];
+        error!("Keyboard report error: {}", code);
+    }
+}
+
+/// Control keys, left and right, in the modifier byte
+pub const MOD_CTRL: u8 = (1 << 0) | (1 << 4);
+/// Shift keys, left and right, in the modifier byte
+pub const MOD_SHIFT: u8 = (1 << 1) | (1 << 5);
+/// Alt keys, left and right, in the modifier byte
+pub const MOD_ALT: u8 = (1 << 2) | (1 << 6);
+/// GUI keys, left and right, in the modifier byte
+pub const MOD_GUI: u8 = (1 << 3) | (1 << 7);
+
+/// Key pressed with some modifiers replaced by another key, without those
+/// modifiers. For instance, Shift + Backspace sending Delete.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct KeyOverride {
+    /// Modifiers triggering the override, any of them
+    pub modifiers: u8,
+    /// Keycode of the key overridden
+    pub key: u8,
+    /// Keycode sent instead
+    pub replacement: u8,
+}
+
+impl KeyOverride {
+    /// Whether the override applies to `report`
+    fn matches(&self, report: &KeyboardReport) -> bool {
+        report.modifier & self.modifiers != 0 && report.keycodes.contains(&self.key)
+    }
+
+    /// Replace the key by its replacement in `report`, without the
+    /// modifiers triggering the override
+    fn apply(&self, report: &mut KeyboardReport) {
+        for kc in report.keycodes.iter_mut().filter(|kc| **kc == self.key) {
+            *kc = self.replacement;
+        }
+        report.modifier &= !self.modifiers;
+    }
+}
+
+/// Mouse HID report
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct MouseReport {
+    /// Buttons state
+    /// Button 1 to 8 where Button1 is the LSB
+    pub buttons: u8,
+    /// x movement
+    pub x: i16,
+    /// y movement
+    pub y: i16,
+    /// Scroll down (negative) or up (positive) this many units
+    pub wheel: i8,
+    /// Scroll left (negative) or right (positive) this many units
+    pub pan: i8,
+}
+
+impl MouseReport {
+    /// Serialize the report
+    pub fn serialize(&self) -> [u8; 7] {
+        let x = self.x.to_le_bytes();
+        let y = self.y.to_le_bytes();
+        [
+            self.buttons,
+            x[0],
+            x[1],
+            y[0],
+            y[1],
+            self.wheel as u8,
+            self.pan as u8,
+        ]
+    }
+}
+
+/// Consumer Control HID report
+/// Used for media keys (Play/Pause, Volume, etc.)
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct ConsumerReport {
+    /// Consumer usage code (16-bit)
+    /// Common values:
+    /// - 0x0000: No key pressed (release)
+    /// - 0x00B5: Scan Next Track
+    /// - 0x00B6: Scan Previous Track
+    /// - 0x00CD: Play/Pause
+    /// - 0x00E2: Mute
+    /// - 0x00E9: Volume Up
+    /// - 0x00EA: Volume Down
+    pub usage: u16,
+}
+
+impl ConsumerReport {
+    /// Serialize the report
+    pub fn serialize(&self) -> [u8; 2] {
+        self.usage.to_le_bytes()
+    }
+}
+
+/// System Control usage: System Power Down
+pub const SYSTEM_POWER_DOWN: u8 = 0x81;
+/// System Control usage: System Sleep
+pub const SYSTEM_SLEEP: u8 = 0x82;
+/// System Control usage: System Wake Up
+pub const SYSTEM_WAKE_UP: u8 = 0x83;
+
+/// System Control HID report
+/// Used for the power management keys (Power Down, Sleep, Wake Up)
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct SystemReport {
+    /// System control usage code, 0 when no key is pressed
+    pub usage: u8,
+}
+
+impl SystemReport {
+    /// Serialize the report
+    pub fn serialize(&self) -> [u8; 1] {
+        [self.usage]
+    }
+}
+
+/// What a pressed key reports to the host
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Usage {
+    /// Keyboard key, with its keycode
+    Key(u8),
+    /// Modifier key, with its bit in the modifier byte
+    Modifier(u8),
+    /// Consumer control key, with its usage code
+    Consumer(u16),
+    /// Error keycode, reported for all the keys
+    Error(u8),
+}
+
+#[rustfmt::skip]
+/// Keyboard HID report descriptor
+pub const KB_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,        // Usage Page (Generic Desktop Ctrls)
+    0x09, 0x06,        // Usage (Keyboard)
+    0xA1, 0x01,        // Collection (Application)
+    0x05, 0x07,        //   Usage Page (Kbrd/Keypad)
+    0x19, 0xE0,        //   Usage Minimum (0xE0)
+    0x29, 0xE7,        //   Usage Maximum (0xE7)
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x25, 0x01,        //   Logical Maximum (1)
+    0x75, 0x01,        //   Report Size (1)
+    0x95, 0x08,        //   Report Count (8)
+    0x81, 0x02,        //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x19, 0x00,        //   Usage Minimum (0x00)
+    0x29, 0xFF,        //   Usage Maximum (0xFF)
+    0x26, 0xFF, 0x00,  //   Logical Maximum (255)
+    0x75, 0x08,        //   Report Size (8)
+    0x95, 0x01,        //   Report Count (1)
+    0x81, 0x03,        //   Input (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x05, 0x08,        //   Usage Page (LEDs)
+    0x19, 0x01,        //   Usage Minimum (Num Lock)
+    0x29, 0x05,        //   Usage Maximum (Kana)
+    0x25, 0x01,        //   Logical Maximum (1)
+    0x75, 0x01,        //   Report Size (1)
+    0x95, 0x05,        //   Report Count (5)
+    0x91, 0x02,        //   Output (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+    0x95, 0x03,        //   Report Count (3)
+    0x91, 0x03,        //   Output (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+    0x05, 0x07,        //   Usage Page (Kbrd/Keypad)
+    0x19, 0x00,        //   Usage Minimum (0x00)
+    0x29, 0xDD,        //   Usage Maximum (0xDD)
+    0x26, 0xFF, 0x00,  //   Logical Maximum (255)
+    0x75, 0x08,        //   Report Size (8)
+    0x95, 0x06,        //   Report Count (6)
+    0x81, 0x00,        //   Input (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0,              // End Collection
+// 69 bytes
+];
+
+#[rustfmt::skip]
+/// Mouse HID report descriptor
+pub const MOUSE_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,        // Usage Page (Generic Desktop Ctrls)
+    0x09, 0x02,        // Usage (Mouse)
+    0xA1, 0x01,        // Collection (Application)
+    0x09, 0x01,        //   Usage (Pointer)
+    0xA1, 0x00,        //   Collection (Physical)
+    0x05, 0x09,        //     Usage Page (Button)
+    0x19, 0x01,        //     Usage Minimum (0x01)
+    0x29, 0x05,        //     Usage Maximum (0x05)
+    0x15, 0x00,        //     Logical Minimum (0)
+    0x25, 0x01,        //     Logical Maximum (1)
+    0x95, 0x05,        //     Report Count (5)
+    0x75, 0x01,        //     Report Size (1)
+    0x81, 0x02,        //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x95, 0x01,        //     Report Count (1)
+    0x75, 0x03,        //     Report Size (3)
+    0x81, 0x01,        //     Input (Const,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x05, 0x01,        //     Usage Page (Generic Desktop Ctrls)
+    0x09, 0x30,        //     Usage (X)
+    0x09, 0x31,        //     Usage (Y)
+    0x16, 0x00, 0x80,  //     Logical Minimum (-32768)
+    0x26, 0xFF, 0x7F,  //     Logical Maximum (32767)
+    0x75, 0x10,        //     Report Size (16)
+    0x95, 0x02,        //     Report Count (2)
+    0x81, 0x06,        //     Input (Data,Var,Rel,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0,              //   End Collection
+    0xA1, 0x00,        //   Collection (Physical)
+    0x05, 0x01,        //     Usage Page (Generic Desktop Ctrls)
+    0x09, 0x38,        //     Usage (Wheel)
+    0x15, 0x81,        //     Logical Minimum (-127)
+    0x25, 0x7F,        //     Logical Maximum (127)
+    0x75, 0x08,        //     Report Size (8)
+    0x95, 0x01,        //     Report Count (1)
+    0x81, 0x06,        //     Input (Data,Var,Rel,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0,              //   End Collection
+    0xA1, 0x00,        //   Collection (Physical)
+    0x05, 0x0C,        //     Usage Page (Consumer)
+    0x0A, 0x38, 0x02,  //     Usage (AC Pan)
+    0x95, 0x01,        //     Report Count (1)
+    0x75, 0x08,        //     Report Size (8)
+    0x15, 0x81,        //     Logical Minimum (-127)
+    0x25, 0x7F,        //     Logical Maximum (127)
+    0x81, 0x06,        //     Input (Data,Var,Rel,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0,              //   End Collection
+    0xC0,              // End Collection
+// 87 bytes
+];
+
+#[rustfmt::skip]
+/// Consumer Control HID report descriptor
+/// Supports media keys like Play/Pause, Volume Up/Down, Mute, etc.
+pub const CONSUMER_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x0C,        // Usage Page (Consumer)
+    0x09, 0x01,        // Usage (Consumer Control)
+    0xA1, 0x01,        // Collection (Application)
+    0x19, 0x00,        //   Usage Minimum (0x00)
+    0x2A, 0x3C, 0x02,  //   Usage Maximum (0x023C)
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x26, 0x3C, 0x02,  //   Logical Maximum (572)
+    0x95, 0x01,        //   Report Count (1)
+    0x75, 0x10,        //   Report Size (16)
+    0x81, 0x00,        //   Input (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0,              // End Collection
+// 21 bytes
+];
+
+#[rustfmt::skip]
+/// System Control HID report descriptor
+/// Supports the Power Down, Sleep and Wake Up keys
+pub const SYSTEM_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,        // Usage Page (Generic Desktop Ctrls)
+    0x09, 0x80,        // Usage (Sys Control)
+    0xA1, 0x01,        // Collection (Application)
+    0x19, 0x81,        //   Usage Minimum (Sys Power Down)
+    0x29, 0x83,        //   Usage Maximum (Sys Wake Up)
+    0x16, 0x81, 0x00,  //   Logical Minimum (129)
+    0x26, 0x83, 0x00,  //   Logical Maximum (131)
+    0x95, 0x01,        //   Report Count (1)
+    0x75, 0x08,        //   Report Size (8)
+    0x81, 0x00,        //   Input (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0,              // End Collection
+// 25 bytes
+];
+
+/// Parse a polling interval, in ms, or `default` if there is none. Panics,
+/// at build time for a constant, if it is not between 1 and 255.
+pub const fn parse_poll_ms(value: Option<&str>, default: u8) -> u8 {
+    let Some(value) = value else {
+        return default;
+    };
+    let bytes = value.as_bytes();
+    assert!(!bytes.is_empty(), "empty polling interval");
+    let mut ms: u32 = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit(), "invalid polling interval");
+        ms = ms * 10 + (bytes[i] - b'0') as u32;
+        assert!(ms <= u8::MAX as u32, "polling interval above 255ms");
+        i += 1;
+    }
+    assert!(ms != 0, "polling interval of 0ms");
+    ms as u8
+}
+
+/// Generate the HID reports (keyboard and consumer) of the pressed keys,
+/// applying the first of the key overrides `overrides` that matches
+pub fn generate_reports(
+    usages: impl IntoIterator<Item = Usage>,
+    overrides: &[KeyOverride],
+) -> (KeyboardReport, ConsumerReport) {
+    let mut kb_report = KeyboardReport::default();
+    let mut consumer_report = ConsumerReport::default();
+
+    for usage in usages {
+        match usage {
+            Usage::Error(code) => kb_report.set_error(code),
+            Usage::Modifier(bit) => kb_report.modifier |= bit,
+            Usage::Consumer(code) => consumer_report.usage = code,
+            // Keycodes from 0xE8 are not part of the keyboard report
+            Usage::Key(kc) if kc >= KEYCODE_MAX => (),
+            Usage::Key(kc) => {
+                kb_report.keycodes[..]
+                    .iter_mut()
+                    .find(|c| **c == 0)
+                    .map(|c| *c = kc)
+                    .unwrap_or_else(|| kb_report.set_error(ERROR_ROLL_OVER));
+            }
+        }
+    }
+    if let Some(o) = overrides.iter().find(|o| o.matches(&kb_report)) {
+        o.apply(&mut kb_report);
+    }
+    (kb_report, consumer_report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_reports() {
+        let (kb, consumer) = generate_reports(
+            [
+                Usage::Key(0x04),
+                Usage::Modifier(0x02),
+                Usage::Key(0x05),
+                Usage::Consumer(0x00CD),
+                Usage::Key(0xF0),
+            ],
+            &[],
+        );
+        assert_eq!(kb.modifier, 0x02);
+        assert_eq!(kb.keycodes, [0x04, 0x05, 0, 0, 0, 0]);
+        assert_eq!(consumer.usage, 0x00CD);
+        assert_eq!(kb.serialize(), [0x02, 0, 0x04, 0x05, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_boot_report() {
+        let (kb, _) = generate_reports(
+            [
+                Usage::Key(0x04),
+                // F13 and Volume Up: not on the boot keyboard
+                Usage::Key(0x68),
+                Usage::Key(0x80),
+                Usage::Modifier(0x01),
+                Usage::Key(0x65),
+            ],
+            &[],
+        );
+        let boot = kb.to_boot();
+        assert_eq!(boot.serialize(), [0x01, 0, 0x04, 0x65, 0, 0, 0, 0]);
+        let (kb, _) = generate_reports([Usage::Error(ERROR_ROLL_OVER)], &[]);
+        assert_eq!(kb.to_boot(), kb);
+    }
+
+    #[test]
+    fn test_roll_over() {
+        let (kb, _) = generate_reports((0x04..0x0B).map(Usage::Key), &[]);
+        assert_eq!(kb.modifier, 0);
+        assert_eq!(kb.keycodes, [ERROR_ROLL_OVER; 6]);
+    }
+
+    #[test]
+    fn test_key_overrides() {
+        // Shift + Backspace = Delete, Ctrl + Escape = `
+        let overrides = [
+            KeyOverride {
+                modifiers: MOD_SHIFT,
+                key: 0x2A,
+                replacement: 0x4C,
+            },
+            KeyOverride {
+                modifiers: MOD_CTRL,
+                key: 0x29,
+                replacement: 0x35,
+            },
+        ];
+        let (kb, _) = generate_reports([Usage::Modifier(0x20), Usage::Key(0x2A)], &overrides);
+        assert_eq!(kb.serialize(), [0, 0, 0x4C, 0, 0, 0, 0, 0]);
+        // Other modifiers are kept
+        let (kb, _) = generate_reports(
+            [
+                Usage::Modifier(0x02 | 0x04),
+                Usage::Key(0x04),
+                Usage::Key(0x2A),
+            ],
+            &overrides,
+        );
+        assert_eq!(kb.serialize(), [0x04, 0, 0x04, 0x4C, 0, 0, 0, 0]);
+        // Not triggered
+        let (kb, _) = generate_reports([Usage::Key(0x2A)], &overrides);
+        assert_eq!(kb.serialize(), [0, 0, 0x2A, 0, 0, 0, 0, 0]);
+        let (kb, _) = generate_reports([Usage::Modifier(0x02), Usage::Key(0x29)], &overrides);
+        assert_eq!(kb.serialize(), [0x02, 0, 0x29, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_parse_poll_ms() {
+        assert_eq!(parse_poll_ms(None, 8), 8);
+        assert_eq!(parse_poll_ms(Some("4"), 8), 4);
+        assert_eq!(parse_poll_ms(Some("255"), 8), 255);
+        assert!(std::panic::catch_unwind(|| parse_poll_ms(Some("0"), 8)).is_err());
+        assert!(std::panic::catch_unwind(|| parse_poll_ms(Some("256"), 8)).is_err());
+        assert!(std::panic::catch_unwind(|| parse_poll_ms(Some("1ms"), 8)).is_err());
+        assert!(std::panic::catch_unwind(|| parse_poll_ms(Some(""), 8)).is_err());
+    }
+}
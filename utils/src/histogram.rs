@@ -0,0 +1,138 @@
+//! Histogram of durations, with power-of-two buckets
+
+/// Number of buckets
+pub const NB_BUCKETS: usize = 12;
+
+/// Histogram of values (typically durations in µs).
+/// Bucket `i` counts values in `[2^(i-1), 2^i)`, bucket 0 counts zeros and
+/// the last bucket counts everything above.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Histogram {
+    /// Number of values per bucket
+    buckets: [u32; NB_BUCKETS],
+    /// Number of values recorded
+    count: u32,
+    /// Sum of the values recorded
+    sum: u64,
+    /// Minimum value recorded
+    min: u32,
+    /// Maximum value recorded
+    max: u32,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Histogram {
+    /// Create an empty histogram
+    pub const fn new() -> Self {
+        Self {
+            buckets: [0; NB_BUCKETS],
+            count: 0,
+            sum: 0,
+            min: u32::MAX,
+            max: 0,
+        }
+    }
+
+    /// Bucket index of a value
+    fn bucket(v: u32) -> usize {
+        ((u32::BITS - v.leading_zeros()) as usize).min(NB_BUCKETS - 1)
+    }
+
+    /// Upper bound (exclusive) of the bucket `i`, `None` for the last bucket
+    pub fn bucket_upper_bound(i: usize) -> Option<u32> {
+        if i + 1 < NB_BUCKETS {
+            Some(1 << i)
+        } else {
+            None
+        }
+    }
+
+    /// Record a value
+    pub fn record(&mut self, v: u32) {
+        self.buckets[Self::bucket(v)] += 1;
+        self.count += 1;
+        self.sum += v as u64;
+        self.min = self.min.min(v);
+        self.max = self.max.max(v);
+    }
+
+    /// Reset the histogram
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Number of values per bucket
+    pub fn buckets(&self) -> &[u32; NB_BUCKETS] {
+        &self.buckets
+    }
+
+    /// Number of values recorded
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Minimum value recorded, if any
+    pub fn min(&self) -> Option<u32> {
+        (self.count > 0).then_some(self.min)
+    }
+
+    /// Maximum value recorded, if any
+    pub fn max(&self) -> Option<u32> {
+        (self.count > 0).then_some(self.max)
+    }
+
+    /// Average of the values recorded, if any
+    pub fn mean(&self) -> Option<u32> {
+        (self.count > 0).then(|| (self.sum / self.count as u64) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty() {
+        let h = Histogram::new();
+        assert_eq!(h.count(), 0);
+        assert_eq!(h.min(), None);
+        assert_eq!(h.max(), None);
+        assert_eq!(h.mean(), None);
+    }
+
+    #[test]
+    fn test_buckets() {
+        let mut h = Histogram::new();
+        for v in [0, 1, 2, 3, 4, 1000, u32::MAX] {
+            h.record(v);
+        }
+        let b = h.buckets();
+        assert_eq!(b[0], 1);
+        assert_eq!(b[1], 1);
+        assert_eq!(b[2], 2);
+        assert_eq!(b[3], 1);
+        assert_eq!(b[10], 1);
+        assert_eq!(b[NB_BUCKETS - 1], 1);
+        assert_eq!(h.count(), 7);
+        assert_eq!(h.min(), Some(0));
+        assert_eq!(h.max(), Some(u32::MAX));
+        assert_eq!(Histogram::bucket_upper_bound(3), Some(8));
+        assert_eq!(Histogram::bucket_upper_bound(NB_BUCKETS - 1), None);
+    }
+
+    #[test]
+    fn test_mean_and_reset() {
+        let mut h = Histogram::new();
+        h.record(10);
+        h.record(20);
+        assert_eq!(h.mean(), Some(15));
+        h.reset();
+        assert_eq!(h, Histogram::new());
+    }
+}
@@ -0,0 +1,168 @@
+//! Key usage statistics
+//!
+//! The presses of each key of the matrix are counted, so that heatmaps of
+//! the layout can be drawn on the host, or on the keyboard itself from the
+//! heat of each key. The keys are indexed row by row.
+//! The counts can be persisted as a record made of a magic value, the
+//! counts and a CRC.
+
+/// Magic value at the start of a record
+const RECORD_MAGIC: u16 = 0x57a7;
+
+/// Number of presses of each key of a `ROWS`x`COLS` matrix
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct KeyStats<const ROWS: usize, const COLS: usize> {
+    /// Presses of each key, saturating
+    counts: [[u32; COLS]; ROWS],
+}
+
+impl<const ROWS: usize, const COLS: usize> Default for KeyStats<ROWS, COLS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize> KeyStats<ROWS, COLS> {
+    /// Number of keys
+    pub const NB_KEYS: usize = ROWS * COLS;
+    /// Size of a record: magic, counts and CRC
+    pub const RECORD_SIZE: usize = 2 + 4 * Self::NB_KEYS + 2;
+
+    /// No key pressed yet
+    pub const fn new() -> Self {
+        Self {
+            counts: [[0; COLS]; ROWS],
+        }
+    }
+
+    /// Count a press of the key at `row`, `col`. Keys outside of the
+    /// matrix, such as virtual keys, are ignored.
+    pub fn on_press(&mut self, row: u8, col: u8) {
+        if let Some(count) = self
+            .counts
+            .get_mut(row as usize)
+            .and_then(|r| r.get_mut(col as usize))
+        {
+            *count = count.saturating_add(1);
+        }
+    }
+
+    /// Number of presses of the key at `row`, `col`
+    pub fn count(&self, row: usize, col: usize) -> u32 {
+        self.counts[row][col]
+    }
+
+    /// Heat of the key at `row`, `col` for a heatmap: its presses relative
+    /// to the ones of the most pressed key, from 0 to 255
+    pub fn heat(&self, row: usize, col: usize) -> u8 {
+        let max = self.counts.iter().flatten().copied().max().unwrap_or(0);
+        if max == 0 {
+            return 0;
+        }
+        (self.counts[row][col] as u64 * u8::MAX as u64 / max as u64) as u8
+    }
+
+    /// Forget all the presses
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Write the counts of the keys from index `first` on to `buf`, as
+    /// little endian u32, as many as fit. Returns the number of counts
+    /// written.
+    pub fn write_counts(&self, first: usize, buf: &mut [u8]) -> usize {
+        let counts = self.counts.iter().flatten().skip(first);
+        let mut n = 0;
+        for (chunk, count) in buf.chunks_exact_mut(4).zip(counts) {
+            chunk.copy_from_slice(&count.to_le_bytes());
+            n += 1;
+        }
+        n
+    }
+
+    /// Serialize the counts as a record, to `record` of `RECORD_SIZE` bytes
+    pub fn to_record(&self, record: &mut [u8]) {
+        record[..2].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        self.write_counts(0, &mut record[2..Self::RECORD_SIZE - 2]);
+        let crc = record_crc(&record[..Self::RECORD_SIZE - 2]);
+        record[Self::RECORD_SIZE - 2..Self::RECORD_SIZE].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    /// Deserialize a record of `RECORD_SIZE` bytes. Returns `None` if it is
+    /// erased or corrupted.
+    pub fn from_record(record: &[u8]) -> Option<Self> {
+        if record.len() < Self::RECORD_SIZE
+            || record[..2] != RECORD_MAGIC.to_le_bytes()
+            || record[Self::RECORD_SIZE - 2..Self::RECORD_SIZE]
+                != record_crc(&record[..Self::RECORD_SIZE - 2]).to_le_bytes()
+        {
+            return None;
+        }
+        let mut stats = Self::new();
+        for (count, bytes) in stats
+            .counts
+            .iter_mut()
+            .flatten()
+            .zip(record[2..].chunks_exact(4))
+        {
+            *count = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        Some(stats)
+    }
+}
+
+/// CRC of a record, without its CRC
+fn record_crc(data: &[u8]) -> u16 {
+    crc16::State::<crc16::KERMIT>::calculate(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Stats = KeyStats<4, 10>;
+
+    #[test]
+    fn test_presses() {
+        let mut stats = Stats::new();
+        stats.on_press(0, 0);
+        stats.on_press(3, 9);
+        stats.on_press(3, 9);
+        // Virtual keys are ignored
+        stats.on_press(0, 10);
+        stats.on_press(4, 0);
+        assert_eq!(stats.count(0, 0), 1);
+        assert_eq!(stats.count(3, 9), 2);
+        let mut buf = [0u8; 8];
+        assert_eq!(stats.write_counts(38, &mut buf), 2);
+        assert_eq!(buf, [0, 0, 0, 0, 2, 0, 0, 0]);
+        assert_eq!(stats.write_counts(40, &mut buf), 0);
+        assert_eq!(stats.heat(3, 9), 255);
+        assert_eq!(stats.heat(0, 0), 127);
+        assert_eq!(stats.heat(1, 1), 0);
+        stats.reset();
+        assert_eq!(stats, Stats::new());
+    }
+
+    #[test]
+    fn test_no_heat() {
+        let stats = Stats::new();
+        assert_eq!(stats.heat(0, 0), 0);
+    }
+
+    #[test]
+    fn test_record() {
+        let mut stats = Stats::new();
+        for i in 0..4 {
+            for _ in 0..=i {
+                stats.on_press(i, 2 * i);
+            }
+        }
+        let mut record = [0xffu8; Stats::RECORD_SIZE];
+        assert_eq!(Stats::from_record(&record), None);
+        stats.to_record(&mut record);
+        assert_eq!(Stats::from_record(&record), Some(stats));
+        record[10] ^= 1;
+        assert_eq!(Stats::from_record(&record), None);
+    }
+}
@@ -0,0 +1,267 @@
+//! Keymap edited at runtime
+//!
+//! Keys of the keymap built in the firmware can be assigned another keycode
+//! from the host. The keycodes are 16 bits values following QMK's, so that
+//! Via-like host tools can use them. Only the simple ones are supported:
+//! no action, transparent, a key of the keyboard usage page, a momentary
+//! layer and a default layer.
+//!
+//! The edits can be persisted as a record made of a magic value, the
+//! keycode of each key of each layer, `NOT_EDITED` for the keys left as
+//! built, and a CRC.
+
+/// Magic value at the start of a record
+const RECORD_MAGIC: u16 = 0xce7a;
+
+/// Keycode of a key not edited, in records. Also answered for the keys
+/// whose built-in action has no keycode.
+pub const NOT_EDITED: u16 = 0xffff;
+
+/// First keycode of the momentary layers: `MO(0)`
+const MOMENTARY_LAYER: u16 = 0x5220;
+/// First keycode of the default layers: `DF(0)`
+const DEFAULT_LAYER: u16 = 0x5240;
+/// Mask of the layer in the layer keycodes
+const LAYER_MASK: u16 = 0x1f;
+
+/// Keycode that can be assigned to a key
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Keycode {
+    /// No action
+    No,
+    /// Action of the same key on the layers below
+    Transparent,
+    /// Key of the keyboard usage page, such as a letter or a modifier
+    Key(u8),
+    /// Layer active while held
+    Layer(u8),
+    /// Change the default layer
+    DefaultLayer(u8),
+}
+
+impl Keycode {
+    /// Parse a QMK keycode. Returns `None` if it is not supported.
+    pub fn from_u16(code: u16) -> Option<Self> {
+        match code {
+            0x0000 => Some(Keycode::No),
+            0x0001 => Some(Keycode::Transparent),
+            // Usages up to ExSel, and the modifiers
+            0x0004..=0x00a4 | 0x00e0..=0x00e7 => Some(Keycode::Key(code as u8)),
+            _ if code & !LAYER_MASK == MOMENTARY_LAYER => {
+                Some(Keycode::Layer((code & LAYER_MASK) as u8))
+            }
+            _ if code & !LAYER_MASK == DEFAULT_LAYER => {
+                Some(Keycode::DefaultLayer((code & LAYER_MASK) as u8))
+            }
+            _ => None,
+        }
+    }
+
+    /// QMK keycode
+    pub fn to_u16(self) -> u16 {
+        match self {
+            Keycode::No => 0x0000,
+            Keycode::Transparent => 0x0001,
+            Keycode::Key(usage) => usage as u16,
+            Keycode::Layer(layer) => MOMENTARY_LAYER | (layer as u16 & LAYER_MASK),
+            Keycode::DefaultLayer(layer) => DEFAULT_LAYER | (layer as u16 & LAYER_MASK),
+        }
+    }
+
+    /// Whether the layer used by the keycode, if any, is one of the
+    /// `layers` first ones
+    fn fits(self, layers: usize) -> bool {
+        match self {
+            Keycode::Layer(layer) | Keycode::DefaultLayer(layer) => (layer as usize) < layers,
+            _ => true,
+        }
+    }
+}
+
+/// Edits of a keymap of `LAYERS` layers of `ROWS`x`COLS` keys
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct KeymapEdits<const LAYERS: usize, const ROWS: usize, const COLS: usize> {
+    /// Keycode assigned to each key of each layer, if edited
+    keys: [[[Option<Keycode>; COLS]; ROWS]; LAYERS],
+}
+
+impl<const LAYERS: usize, const ROWS: usize, const COLS: usize> Default
+    for KeymapEdits<LAYERS, ROWS, COLS>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const LAYERS: usize, const ROWS: usize, const COLS: usize> KeymapEdits<LAYERS, ROWS, COLS> {
+    /// Number of keys of all the layers
+    pub const NB_KEYS: usize = LAYERS * ROWS * COLS;
+    /// Size of a record: magic, keycodes and CRC
+    pub const RECORD_SIZE: usize = 2 + 2 * Self::NB_KEYS + 2;
+
+    /// Keymap as built
+    pub const fn new() -> Self {
+        Self {
+            keys: [[[None; COLS]; ROWS]; LAYERS],
+        }
+    }
+
+    /// Keycode assigned to the key at `row`, `col` of `layer`, if edited
+    pub fn get(&self, layer: usize, row: usize, col: usize) -> Option<Keycode> {
+        *self.keys.get(layer)?.get(row)?.get(col)?
+    }
+
+    /// Assign `keycode` to the key at `row`, `col` of `layer`, or restore
+    /// its built-in action if `None`. Returns `false` if there is no such
+    /// key or if the keycode uses a layer that does not exist.
+    pub fn set(&mut self, layer: usize, row: usize, col: usize, keycode: Option<Keycode>) -> bool {
+        if keycode.is_some_and(|k| !k.fits(LAYERS)) {
+            return false;
+        }
+        match self
+            .keys
+            .get_mut(layer)
+            .and_then(|l| l.get_mut(row))
+            .and_then(|r| r.get_mut(col))
+        {
+            Some(key) => {
+                *key = keycode;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Restore the keymap as built
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Edited keys, as layer, row, column and keycode
+    pub fn edits(&self) -> impl Iterator<Item = (usize, usize, usize, Keycode)> + '_ {
+        self.keys.iter().enumerate().flat_map(|(l, layer)| {
+            layer.iter().enumerate().flat_map(move |(r, row)| {
+                row.iter()
+                    .enumerate()
+                    .filter_map(move |(c, key)| key.map(|k| (l, r, c, k)))
+            })
+        })
+    }
+
+    /// Serialize the edits as a record, to `record` of `RECORD_SIZE` bytes
+    pub fn to_record(&self, record: &mut [u8]) {
+        record[..2].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        let keys = self.keys.iter().flatten().flatten();
+        for (chunk, key) in record[2..Self::RECORD_SIZE - 2]
+            .chunks_exact_mut(2)
+            .zip(keys)
+        {
+            let code = key.map_or(NOT_EDITED, Keycode::to_u16);
+            chunk.copy_from_slice(&code.to_le_bytes());
+        }
+        let crc = record_crc(&record[..Self::RECORD_SIZE - 2]);
+        record[Self::RECORD_SIZE - 2..Self::RECORD_SIZE].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    /// Deserialize a record of `RECORD_SIZE` bytes. Returns `None` if it is
+    /// erased or corrupted. Unsupported keycodes, as could be saved by
+    /// another firmware, are read as not edited.
+    pub fn from_record(record: &[u8]) -> Option<Self> {
+        if record.len() < Self::RECORD_SIZE
+            || record[..2] != RECORD_MAGIC.to_le_bytes()
+            || record[Self::RECORD_SIZE - 2..Self::RECORD_SIZE]
+                != record_crc(&record[..Self::RECORD_SIZE - 2]).to_le_bytes()
+        {
+            return None;
+        }
+        let mut edits = Self::new();
+        for (key, bytes) in edits
+            .keys
+            .iter_mut()
+            .flatten()
+            .flatten()
+            .zip(record[2..].chunks_exact(2))
+        {
+            *key = Keycode::from_u16(u16::from_le_bytes([bytes[0], bytes[1]]))
+                .filter(|k| k.fits(LAYERS));
+        }
+        Some(edits)
+    }
+}
+
+/// CRC of a record, without its CRC
+fn record_crc(data: &[u8]) -> u16 {
+    crc16::State::<crc16::KERMIT>::calculate(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Edits = KeymapEdits<2, 4, 10>;
+
+    #[test]
+    fn test_keycodes() {
+        for keycode in [
+            Keycode::No,
+            Keycode::Transparent,
+            Keycode::Key(0x04),
+            Keycode::Key(0xe7),
+            Keycode::Layer(3),
+            Keycode::DefaultLayer(0),
+        ] {
+            assert_eq!(Keycode::from_u16(keycode.to_u16()), Some(keycode));
+        }
+        assert_eq!(Keycode::from_u16(0x5221), Some(Keycode::Layer(1)));
+        assert_eq!(Keycode::from_u16(0x0002), None);
+        assert_eq!(Keycode::from_u16(0x00b0), None);
+        assert_eq!(Keycode::from_u16(NOT_EDITED), None);
+    }
+
+    #[test]
+    fn test_edits() {
+        let mut edits = Edits::new();
+        assert_eq!(edits.get(1, 3, 9), None);
+        assert!(edits.set(1, 3, 9, Some(Keycode::Key(0x04))));
+        assert!(edits.set(0, 0, 0, Some(Keycode::Layer(1))));
+        assert_eq!(edits.get(1, 3, 9), Some(Keycode::Key(0x04)));
+        // Out of the keymap
+        assert!(!edits.set(2, 0, 0, Some(Keycode::No)));
+        assert!(!edits.set(0, 4, 0, Some(Keycode::No)));
+        assert!(!edits.set(0, 0, 0, Some(Keycode::Layer(2))));
+        assert_eq!(edits.get(0, 0, 10), None);
+        assert_eq!(
+            edits.edits().collect::<Vec<_>>(),
+            [(0, 0, 0, Keycode::Layer(1)), (1, 3, 9, Keycode::Key(0x04))]
+        );
+        assert!(edits.set(1, 3, 9, None));
+        assert_eq!(edits.edits().count(), 1);
+        edits.reset();
+        assert_eq!(edits, Edits::new());
+    }
+
+    #[test]
+    fn test_record() {
+        let mut edits = Edits::new();
+        edits.set(0, 1, 2, Some(Keycode::Transparent));
+        edits.set(1, 2, 3, Some(Keycode::DefaultLayer(1)));
+        let mut record = [0xffu8; Edits::RECORD_SIZE];
+        assert_eq!(Edits::from_record(&record), None);
+        edits.to_record(&mut record);
+        assert_eq!(Edits::from_record(&record), Some(edits.clone()));
+        record[10] ^= 1;
+        assert_eq!(Edits::from_record(&record), None);
+        // A layer beyond the keymap, saved by another firmware
+        let mut other = KeymapEdits::<4, 4, 10>::new();
+        other.set(0, 0, 0, Some(Keycode::Layer(3)));
+        let mut record = [0u8; KeymapEdits::<4, 4, 10>::RECORD_SIZE];
+        other.to_record(&mut record);
+        let mut record = record.to_vec();
+        // Keep only the first 2 layers, as a smaller keymap would
+        record.truncate(2 + 2 * Edits::NB_KEYS);
+        let crc = record_crc(&record);
+        record.extend_from_slice(&crc.to_le_bytes());
+        assert_eq!(Edits::from_record(&record), Some(Edits::new()));
+    }
+}
@@ -12,6 +12,9 @@ pub mod prng;
 /// Logger
 pub mod log;
 
+/// Histograms of durations
+pub mod histogram;
+
 /// Mouse moves
 pub mod mouse_move;
 
@@ -20,3 +23,78 @@ pub mod sid;
 
 /// Protocol
 pub mod protocol;
+
+/// Configuration protocol over raw HID
+pub mod raw_hid;
+
+/// Persistent settings
+pub mod settings;
+
+/// HID reports
+pub mod hid;
+
+/// Processing of the key events and pointer moves into HID reports
+pub mod pipeline;
+
+/// Typing speed
+pub mod wpm;
+
+/// Pointer ballistics
+pub mod ballistics;
+
+/// Key usage statistics
+pub mod key_stats;
+
+/// Steno mode
+pub mod steno;
+
+/// Status LED patterns
+pub mod status_led;
+
+/// Clock drift between the halves
+pub mod drift;
+
+/// Tap dance keys
+pub mod tap_dance;
+
+/// Combos of keys
+pub mod combos;
+
+/// Dynamic macros
+pub mod dynamic_macro;
+
+/// Autoshift
+pub mod autoshift;
+
+/// Swap hands
+pub mod swap_hands;
+
+/// Hold-tap keys resolved at runtime
+pub mod tap_hold;
+
+/// Mouse keys
+pub mod mouse_keys;
+
+/// Debouncing of the keyboard matrix
+pub mod debounce;
+
+/// Diagnostics of the keyboard matrix
+pub mod matrix_diagnostics;
+
+/// Keymap edited at runtime
+pub mod keymap;
+
+/// Drag scroll of the pointer
+pub mod drag_scroll;
+
+/// Edge scrolling of a trackpad
+pub mod edge_scroll;
+
+/// Software rotation of the pointer moves
+pub mod rotation;
+
+/// Tap gestures of a trackpad
+pub mod tap_gesture;
+
+/// Resolution of the trackball sensors
+pub mod resolution;
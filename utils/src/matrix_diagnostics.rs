@@ -0,0 +1,155 @@
+//! Diagnostics of the keyboard matrix, to help debugging soldering issues
+//!
+//! Scans showing an impossible, or at least unlikely, state for
+//! `FAULT_MS` in a row are reported as a fault:
+//! - a stuck row: every key of a row pressed
+//! - a shorted column: every key of a column pressed
+//! - a ghost: the four corners of a rectangle pressed, as a missing or
+//!   shorted diode makes the fourth one appear pressed with the three
+//!   others
+//!
+//! The fault is reported as fixed once the scans no longer show it.
+
+/// Time a fault must be seen in a row to be reported, in ms, the matrix
+/// being scanned every ms
+pub const FAULT_MS: u16 = 1000;
+
+/// Fault of the matrix
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MatrixFault {
+    /// Every key of the row pressed
+    StuckRow(u8),
+    /// Every key of the column pressed
+    ShortedColumn(u8),
+    /// The four keys at the corners of the rectangle between rows `rows`
+    /// and columns `cols` pressed
+    Ghost { rows: (u8, u8), cols: (u8, u8) },
+}
+
+/// Change of the faults reported
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Diagnosis {
+    /// New fault
+    Fault(MatrixFault),
+    /// The fault reported is gone
+    Fixed,
+}
+
+/// Fault shown by the scan `state`, if any
+pub fn fault<const ROWS: usize, const COLS: usize>(
+    state: &[[bool; COLS]; ROWS],
+) -> Option<MatrixFault> {
+    if COLS > 1 {
+        if let Some(r) = state.iter().position(|row| row.iter().all(|&p| p)) {
+            return Some(MatrixFault::StuckRow(r as u8));
+        }
+    }
+    if ROWS > 1 {
+        if let Some(c) = (0..COLS).find(|&c| state.iter().all(|row| row[c])) {
+            return Some(MatrixFault::ShortedColumn(c as u8));
+        }
+    }
+    for r1 in 0..ROWS {
+        for r2 in r1 + 1..ROWS {
+            let mut cols = (0..COLS).filter(|&c| state[r1][c] && state[r2][c]);
+            if let (Some(c1), Some(c2)) = (cols.next(), cols.next()) {
+                return Some(MatrixFault::Ghost {
+                    rows: (r1 as u8, r2 as u8),
+                    cols: (c1 as u8, c2 as u8),
+                });
+            }
+        }
+    }
+    None
+}
+
+/// Diagnostics of the successive scans of the matrix
+#[derive(Debug, Default)]
+pub struct MatrixDiagnostics {
+    /// Fault seen in the last scans, and for how many scans
+    seen: Option<(MatrixFault, u16)>,
+    /// Fault reported, if any
+    reported: Option<MatrixFault>,
+}
+
+impl MatrixDiagnostics {
+    /// Diagnostics with no fault seen
+    pub const fn new() -> Self {
+        Self {
+            seen: None,
+            reported: None,
+        }
+    }
+
+    /// Process the scan `state`. Returns the change of the faults reported,
+    /// if any.
+    pub fn scan<const ROWS: usize, const COLS: usize>(
+        &mut self,
+        state: &[[bool; COLS]; ROWS],
+    ) -> Option<Diagnosis> {
+        let Some(fault) = fault(state) else {
+            self.seen = None;
+            return self.reported.take().map(|_| Diagnosis::Fixed);
+        };
+        let scans = match self.seen {
+            Some((seen, scans)) if seen == fault => scans.saturating_add(1),
+            _ => 1,
+        };
+        self.seen = Some((fault, scans));
+        if scans >= FAULT_MS && self.reported != Some(fault) {
+            self.reported = Some(fault);
+            return Some(Diagnosis::Fault(fault));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_faults() {
+        let mut state = [[false; 5]; 4];
+        assert_eq!(fault(&state), None);
+        state[1] = [true; 5];
+        assert_eq!(fault(&state), Some(MatrixFault::StuckRow(1)));
+        state[1] = [false; 5];
+        for row in state.iter_mut() {
+            row[3] = true;
+        }
+        assert_eq!(fault(&state), Some(MatrixFault::ShortedColumn(3)));
+        let mut state = [[false; 5]; 4];
+        state[0][1] = true;
+        state[0][4] = true;
+        state[2][1] = true;
+        assert_eq!(fault(&state), None);
+        state[2][4] = true;
+        assert_eq!(
+            fault(&state),
+            Some(MatrixFault::Ghost {
+                rows: (0, 2),
+                cols: (1, 4)
+            })
+        );
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let mut diagnostics = MatrixDiagnostics::new();
+        let mut state = [[false; 5]; 4];
+        state[3] = [true; 5];
+        for _ in 1..FAULT_MS {
+            assert_eq!(diagnostics.scan(&state), None);
+        }
+        assert_eq!(
+            diagnostics.scan(&state),
+            Some(Diagnosis::Fault(MatrixFault::StuckRow(3)))
+        );
+        // Reported once
+        assert_eq!(diagnostics.scan(&state), None);
+        assert_eq!(diagnostics.scan(&[[false; 5]; 4]), Some(Diagnosis::Fixed));
+        assert_eq!(diagnostics.scan(&[[false; 5]; 4]), None);
+    }
+}
@@ -0,0 +1,138 @@
+//! Mouse keys: the pointer moved from the keyboard
+//!
+//! While some direction keys are held, the pointer moves every
+//! `MOVE_PERIOD_MS`, by `MIN_SPEED` at first, accelerating linearly up to
+//! `MAX_SPEED` once held for `ACCEL_MS`. Opposite directions cancel out.
+
+/// Time between two moves, in ms
+pub const MOVE_PERIOD_MS: u16 = 10;
+/// Distance of the first moves
+pub const MIN_SPEED: i16 = 2;
+/// Distance of the moves once accelerated
+pub const MAX_SPEED: i16 = 24;
+/// Time to reach `MAX_SPEED`, in ms
+pub const ACCEL_MS: u16 = 1500;
+
+/// Direction of a mouse key
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Direction {
+    /// Up
+    Up = 1 << 0,
+    /// Down
+    Down = 1 << 1,
+    /// Left
+    Left = 1 << 2,
+    /// Right
+    Right = 1 << 3,
+}
+
+/// Mouse keys engine, refreshed every ms
+#[derive(Debug, Default)]
+pub struct MouseKeys {
+    /// Bitmap of the directions held
+    directions: u8,
+    /// Time since the first direction was pressed, in ms
+    held_ms: u16,
+    /// Time until the next move, in ms
+    next_move_ms: u16,
+}
+
+impl MouseKeys {
+    /// Mouse keys, none held
+    pub const fn new() -> Self {
+        Self {
+            directions: 0,
+            held_ms: 0,
+            next_move_ms: 0,
+        }
+    }
+
+    /// Press or release the key of `direction`
+    pub fn on_event(&mut self, direction: Direction, is_pressed: bool) {
+        if is_pressed {
+            if self.directions == 0 {
+                self.held_ms = 0;
+                self.next_move_ms = 0;
+            }
+            self.directions |= direction as u8;
+        } else {
+            self.directions &= !(direction as u8);
+        }
+    }
+
+    /// Whether some direction is held, needing ticks
+    pub fn is_active(&self) -> bool {
+        self.directions != 0
+    }
+
+    /// Whether `direction` is held, as 1 or 0
+    fn held(&self, direction: Direction) -> i16 {
+        (self.directions & direction as u8 != 0) as i16
+    }
+
+    /// Advance by 1ms. Returns the move of the pointer, if any.
+    pub fn tick(&mut self) -> Option<(i16, i16)> {
+        if self.directions == 0 {
+            return None;
+        }
+        let elapsed_ms = self.held_ms;
+        self.held_ms = self.held_ms.saturating_add(1);
+        if self.next_move_ms > 0 {
+            self.next_move_ms -= 1;
+            return None;
+        }
+        self.next_move_ms = MOVE_PERIOD_MS - 1;
+        let accel = elapsed_ms.min(ACCEL_MS) as i32;
+        let speed = MIN_SPEED + ((MAX_SPEED - MIN_SPEED) as i32 * accel / ACCEL_MS as i32) as i16;
+        let dx = speed * (self.held(Direction::Right) - self.held(Direction::Left));
+        let dy = speed * (self.held(Direction::Down) - self.held(Direction::Up));
+        (dx != 0 || dy != 0).then_some((dx, dy))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Moves of the pointer during the next `ms` ticks
+    fn run(keys: &mut MouseKeys, ms: u16) -> Vec<(i16, i16)> {
+        (0..ms).filter_map(|_| keys.tick()).collect()
+    }
+
+    #[test]
+    fn test_acceleration() {
+        let mut keys = MouseKeys::new();
+        assert!(run(&mut keys, 100).is_empty());
+        keys.on_event(Direction::Right, true);
+        assert!(keys.is_active());
+        let moves = run(&mut keys, 2 * ACCEL_MS);
+        assert_eq!(moves.len(), (2 * ACCEL_MS / MOVE_PERIOD_MS) as usize);
+        assert_eq!(moves[0], (MIN_SPEED, 0));
+        assert!(moves.windows(2).all(|m| m[0].0 <= m[1].0));
+        assert_eq!(moves.last(), Some(&(MAX_SPEED, 0)));
+
+        // Diagonal, still accelerated
+        keys.on_event(Direction::Up, true);
+        assert_eq!(run(&mut keys, MOVE_PERIOD_MS), [(MAX_SPEED, -MAX_SPEED)]);
+
+        // Released: slow again once pressed anew
+        keys.on_event(Direction::Right, false);
+        keys.on_event(Direction::Up, false);
+        assert!(!keys.is_active());
+        assert!(run(&mut keys, 100).is_empty());
+        keys.on_event(Direction::Down, true);
+        assert_eq!(run(&mut keys, 1), [(0, MIN_SPEED)]);
+    }
+
+    #[test]
+    fn test_opposite_directions() {
+        let mut keys = MouseKeys::new();
+        keys.on_event(Direction::Left, true);
+        keys.on_event(Direction::Right, true);
+        assert!(run(&mut keys, 100).is_empty());
+        keys.on_event(Direction::Right, false);
+        assert_eq!(run(&mut keys, MOVE_PERIOD_MS).len(), 1);
+    }
+}
@@ -0,0 +1,1248 @@
+//! Processing of the key events and pointer moves into HID reports
+//!
+//! The pipeline feeds the key events to the keymap, refreshes it every
+//! tick, generates the HID reports, with autoshift applied, tracks the
+//! layer to color the RGB LEDs and runs the auto-mouse state machine. The keymap and everything the
+//! pipeline talks to are abstracted behind the `Keymap` and `Io` traits, so
+//! that it runs the same on the keyboard and in host tests.
+
+use crate::autoshift::AutoShift;
+use crate::hid::{
+    generate_reports, ConsumerReport, KeyOverride, KeyboardReport, MouseReport, SystemReport,
+    Usage, SYSTEM_POWER_DOWN, SYSTEM_SLEEP, SYSTEM_WAKE_UP,
+};
+use crate::log::{info, warn};
+use crate::settings::{AutoMouse, Handedness, Settings, TapHold};
+use core::future;
+
+/// Time during which the layout keeps being refreshed after the last event,
+/// in ticks of 1ms. It is longer than any timeout of the keymaps, so that
+/// hold-taps, sequences and tap-hold intervals resolve as if it was always
+/// refreshed.
+pub const LAYOUT_SETTLE_MS: usize = 1000;
+
+/// Time the factory reset key must be held for the settings to be erased,
+/// in ticks of 1ms. Avoids erasing them by mistake.
+pub const FACTORY_RESET_HOLD_MS: usize = 5000;
+
+/// Whether a pointing device can activate the mouse layer. The mouse keys
+/// alone do not, they are already on a layer.
+const AUTO_MOUSE: bool = !cfg!(any(
+    feature = "skeletyl",
+    feature = "scylla",
+    feature = "tbkmini"
+));
+
+/// Custom events for the layout, mostly mouse events
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CustomEvent {
+    /// Mouse left click
+    MouseLeftClick,
+    /// Mouse right click
+    MouseRightClick,
+    /// Mouse Wheel click
+    MouseWheelClick,
+    /// Move the pointer up while held, accelerating
+    MouseUp,
+    /// Move the pointer down while held, accelerating
+    MouseDown,
+    /// Move the pointer left while held, accelerating
+    MouseLeft,
+    /// Move the pointer right while held, accelerating
+    MouseRight,
+    /// Ball is wheel
+    BallIsWheel,
+    /// Divide the pointer moves by the given factor while held, for
+    /// precise pointing
+    SniperMode(u8),
+    /// Toggle the drag scroll: moving the pointer scrolls vertically and
+    /// horizontally
+    ToggleDragScroll,
+    /// Increase sensor CPI by the given step, a multiple of 100 CPI, the
+    /// resolution of the sensor
+    #[cfg(feature = "cnano")]
+    IncreaseCpi(u16),
+    /// Decrease sensor CPI by the given step, a multiple of 100 CPI
+    #[cfg(feature = "cnano")]
+    DecreaseCpi(u16),
+    /// Set the sensor CPI
+    #[cfg(feature = "cnano")]
+    SetCpi(u16),
+    /// Switch to the next lift-off distance of the sensor, wrapping around
+    #[cfg(feature = "cnano")]
+    NextLiftConfig,
+    /// Next Animation of the RGB LEDs
+    NextLedAnimation,
+    /// Switch to the next settings profile
+    NextProfile,
+    /// Switch to the next ballistic profile of the pointer
+    NextBallisticProfile,
+    /// Enter the steno mode, left with the exit key of the steno layout
+    EnterSteno,
+    /// Toggle natural scrolling: invert the wheel and pan directions
+    ToggleNaturalScroll,
+    /// Toggle autoshift: letters and digits held long enough are shifted
+    ToggleAutoShift,
+    /// Reset to usb mass storage
+    ResetToUsbMassStorage,
+    /// Power down the host
+    SystemPowerDown,
+    /// Put the host to sleep
+    SystemSleep,
+    /// Wake the host up
+    SystemWakeUp,
+    /// Erase the settings and reboot, when held for
+    /// `FACTORY_RESET_HOLD_MS`
+    FactoryReset,
+    /// Wheel up
+    #[cfg(feature = "dilemma")]
+    WheelUp,
+    /// Wheel down
+    #[cfg(feature = "dilemma")]
+    WheelDown,
+    /// Scroll left by one step
+    PanLeft,
+    /// Scroll right by one step
+    PanRight,
+    /// Stop the automouse feature
+    NoMouseAction,
+    /// Auto-repeat the key of HID keycode `keycode` while held, pressing
+    /// and releasing it every `period_ms`, independently of the key repeat
+    /// of the host
+    Turbo { keycode: u8, period_ms: u16 },
+    /// Mirror the keys of both halves while held, for one-handed typing
+    SwapHands,
+    /// Keep the layer currently held active once its layer key is
+    /// released, until pressed again
+    LayerLock,
+    /// Activate the given layer while held, or, when tapped, for the next
+    /// key press only
+    LatchLayer(u8),
+    /// Repeat the last keys sent, with their modifiers, while held
+    Repeat,
+    /// Escape, or Grave when pressed with Shift or GUI
+    GraveEscape,
+    /// Tap dance key, given its index in the `TAP_DANCES` table of the keymap
+    TapDance(u8),
+    /// Start recording the dynamic macro of the given slot
+    MacroRecordStart(u8),
+    /// Stop recording the dynamic macro
+    MacroRecordStop,
+    /// Play the dynamic macro of the given slot
+    MacroPlay(u8),
+    /// Dump the trace of the events between the tasks
+    DumpTrace,
+    /// Log the presses of each key and the typing speed
+    DumpKeyStats,
+    /// Stop sending reports until the keys of the unlock chord are pressed
+    /// together, so that the keyboard can be cleaned while plugged
+    LockInput,
+    /// Store the handedness of the half plugged to the host, used from the
+    /// next boot on, for the halves whose side-detect pin is not wired
+    SetHandedness(Handedness),
+}
+
+/// Key event, with the row and column of the key
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum KeyEvent {
+    /// Key pressed
+    Press(u8, u8),
+    /// Key released
+    Release(u8, u8),
+}
+
+/// Keymap turning the key events into pressed keys
+pub trait Keymap {
+    /// Process a key event
+    fn event(&mut self, event: KeyEvent);
+
+    /// Refresh the keymap, every 1ms. Returns the custom event triggered,
+    /// if any, and whether it is a press or a release.
+    fn tick(&mut self) -> Option<(CustomEvent, bool)>;
+
+    /// Current layer
+    fn current_layer(&self) -> usize;
+
+    /// Set the default layer
+    fn set_default_layer(&mut self, layer: usize);
+
+    /// Set how the hold-tap keys are resolved
+    fn set_tap_hold(&mut self, config: TapHold);
+
+    /// Apply the edits of the keymap made at runtime
+    fn reload(&mut self);
+
+    /// What the pressed keys report to the host
+    fn usages(&self) -> impl Iterator<Item = Usage> + '_;
+
+    /// Modifier and key combinations replaced by another key
+    fn key_overrides(&self) -> &'static [KeyOverride];
+}
+
+/// Everything the pipeline gets events from or sends events to
+pub trait Io {
+    /// Next key event waiting to be processed, if any
+    fn key_event(&mut self) -> Option<KeyEvent>;
+
+    /// Next mouse report to send, if any, and whether the pointer is being
+    /// pressed on
+    fn mouse_report(&mut self) -> impl future::Future<Output = Option<(MouseReport, bool)>>;
+
+    /// Send a mouse report to the host
+    fn send_mouse_report(&mut self, report: MouseReport) -> impl future::Future<Output = ()>;
+
+    /// Send a keyboard report to the host
+    fn send_keyboard_report(&mut self, report: KeyboardReport) -> impl future::Future<Output = ()>;
+
+    /// Send a consumer control report to the host
+    fn send_consumer_report(&mut self, report: ConsumerReport) -> impl future::Future<Output = ()>;
+
+    /// Send a system control report to the host
+    fn send_system_report(&mut self, report: SystemReport) -> impl future::Future<Output = ()>;
+
+    /// Color the RGB LEDs of both halves for layer `layer`
+    fn set_color_layer(&mut self, layer: u8) -> impl future::Future<Output = ()>;
+
+    /// Handle a custom event not handled by the pipeline itself
+    fn custom_event(
+        &mut self,
+        event: CustomEvent,
+        is_pressed: bool,
+    ) -> impl future::Future<Output = ()>;
+
+    /// Signal some user activity
+    fn notify_activity(&mut self);
+
+    /// Signal that the pointer activated the mouse layer
+    fn notify_auto_mouse(&mut self);
+
+    /// Signal that the input got locked or unlocked
+    fn notify_input_lock(&mut self, locked: bool);
+
+    /// Erase the settings and reboot
+    fn factory_reset(&mut self);
+}
+
+/// State of the input lock, see `CustomEvent::LockInput`
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum InputLock {
+    /// The reports are sent
+    Unlocked,
+    /// No report is sent. Bit `n` is set while the key `n` of the unlock
+    /// chord is held.
+    Locked(u32),
+    /// The unlock chord was pressed: the reports are sent again once all the
+    /// keys are released, so that the chord is not typed
+    Unlocking,
+}
+
+/// Keyboard/mouse processing pipeline
+pub struct Pipeline<K: Keymap, I: Io> {
+    /// Keymap
+    keymap: K,
+    /// Events sources and sinks
+    io: I,
+    /// Key pressed while the mouse is active, to switch to the mouse layer
+    virtual_mouse_key: (u8, u8),
+    /// Keys to press together to unlock the input
+    unlock_chord: &'static [(u8, u8)],
+    /// Keys not cancelling the auto-mouse mode: the mouse layer does not
+    /// time out while they are held, for modifier + click
+    auto_mouse_excluded: &'static [(u8, u8)],
+    /// Bit `n` is set while the key `n` of `auto_mouse_excluded` is held
+    auto_mouse_held: u32,
+    /// Whether the input is locked
+    input_lock: InputLock,
+    /// Current layer
+    current_layer: usize,
+    /// Keyboard HID report
+    kb_report: KeyboardReport,
+    /// Consumer Control HID report
+    consumer_report: ConsumerReport,
+    /// Timeout for the automouse feature. When this is non-zero, the mouse
+    /// will be considered active. Goes down to 0 every tick.
+    auto_mouse_timeout: usize,
+    /// Automouse configuration: when the mouse is not used for
+    /// `timeout_ms`, it will be considered inactive.
+    auto_mouse: AutoMouse,
+    /// Autoshift of the keys reported
+    autoshift: AutoShift,
+    /// Last keyboard report sent with some keys pressed, sent again by the
+    /// repeat key
+    last_keys: KeyboardReport,
+    /// Whether the repeat key is held
+    repeating: bool,
+    /// Remaining time the factory reset key must be held, in ticks. 0 when
+    /// not pressed.
+    factory_reset_hold: usize,
+    /// Number of keys currently pressed
+    pressed_keys: usize,
+    /// Remaining ticks before the layout settles after the last event
+    settle_ticks: usize,
+    /// Current color layer
+    color_layer: u8,
+    /// Is mouse active
+    mouse_active: bool,
+}
+
+impl<K: Keymap, I: Io> Pipeline<K, I> {
+    /// Create a new pipeline. The input can be unlocked by pressing the
+    /// keys of `unlock_chord` together, at most 32, none to never lock it.
+    /// The mouse layer is kept while any of the keys of
+    /// `auto_mouse_excluded`, at most 32, is held.
+    pub fn new(
+        mut keymap: K,
+        io: I,
+        virtual_mouse_key: (u8, u8),
+        unlock_chord: &'static [(u8, u8)],
+        auto_mouse_excluded: &'static [(u8, u8)],
+        settings: &Settings,
+    ) -> Self {
+        assert!(unlock_chord.len() <= 32, "the unlock chord is too long");
+        assert!(
+            auto_mouse_excluded.len() <= 32,
+            "too many keys excluded from the auto-mouse"
+        );
+        keymap.set_default_layer(settings.default_layer as usize);
+        keymap.set_tap_hold(settings.tap_hold);
+        Self {
+            keymap,
+            io,
+            virtual_mouse_key,
+            unlock_chord,
+            auto_mouse_excluded,
+            auto_mouse_held: 0,
+            input_lock: InputLock::Unlocked,
+            current_layer: 0,
+            kb_report: KeyboardReport::default(),
+            consumer_report: ConsumerReport::default(),
+            auto_mouse_timeout: 0,
+            auto_mouse: settings.auto_mouse,
+            autoshift: AutoShift::new(settings.autoshift),
+            last_keys: KeyboardReport::default(),
+            repeating: false,
+            factory_reset_hold: 0,
+            pressed_keys: 0,
+            settle_ticks: 0,
+            color_layer: 0,
+            mouse_active: false,
+        }
+    }
+
+    /// Events sources and sinks
+    pub fn io(&mut self) -> &mut I {
+        &mut self.io
+    }
+
+    /// Apply settings that changed at runtime
+    pub fn apply_settings(&mut self, settings: &Settings) {
+        self.auto_mouse = settings.auto_mouse;
+        self.autoshift.set_config(settings.autoshift);
+        self.keymap
+            .set_default_layer(settings.default_layer as usize);
+        self.keymap.set_tap_hold(settings.tap_hold);
+    }
+
+    /// Apply the edits of the keymap made at runtime
+    pub fn reload_keymap(&mut self) {
+        self.keymap.reload();
+    }
+
+    /// Set the color layer of the RGB LEDs
+    async fn set_color_layer(&mut self, layer: u8) {
+        if self.color_layer != layer {
+            info!("Setting color layer to {}", layer);
+            self.color_layer = layer;
+            self.io.set_color_layer(layer).await;
+        }
+    }
+
+    /// (Re)Set mouse active timeout
+    /// Also set the leds to the mouse active color
+    fn on_mouse_active(&mut self) {
+        if !self.mouse_active {
+            self.mouse_active = true;
+            info!("Set Mouse Active");
+            let (i, j) = self.virtual_mouse_key;
+            self.keymap.event(KeyEvent::Press(i, j));
+            self.settle_ticks = LAYOUT_SETTLE_MS;
+            self.io.notify_auto_mouse();
+        }
+    }
+
+    /// When the mouse becomes inactive, reset the leds to the current layer
+    /// color
+    fn on_mouse_inactive(&mut self) {
+        info!("On Mouse Inactive");
+        if self.mouse_active {
+            self.mouse_active = false;
+            info!("Set Mouse Inactive");
+            let (i, j) = self.virtual_mouse_key;
+            self.keymap.event(KeyEvent::Release(i, j));
+            self.settle_ticks = LAYOUT_SETTLE_MS;
+        }
+    }
+
+    /// Process a key event
+    pub fn on_key_event(&mut self, event: KeyEvent) {
+        let key = match event {
+            KeyEvent::Press(i, j) => {
+                self.pressed_keys += 1;
+                (i, j)
+            }
+            KeyEvent::Release(i, j) => {
+                self.pressed_keys = self.pressed_keys.saturating_sub(1);
+                (i, j)
+            }
+        };
+        if let InputLock::Locked(held) = &mut self.input_lock {
+            if let Some(n) = self.unlock_chord.iter().position(|k| *k == key) {
+                match event {
+                    KeyEvent::Press(_, _) => *held |= 1 << n,
+                    KeyEvent::Release(_, _) => *held &= !(1 << n),
+                }
+            }
+            if *held == u32::MAX >> (32 - self.unlock_chord.len()) {
+                info!("Unlock chord pressed");
+                self.input_lock = InputLock::Unlocking;
+            }
+        }
+        if let Some(n) = self.auto_mouse_excluded.iter().position(|k| *k == key) {
+            match event {
+                KeyEvent::Press(_, _) => self.auto_mouse_held |= 1 << n,
+                KeyEvent::Release(_, _) => {
+                    self.auto_mouse_held &= !(1 << n);
+                    // Leave the time to click once the modifiers are released
+                    if self.auto_mouse_held == 0 && self.mouse_active {
+                        self.auto_mouse_timeout = self.auto_mouse.timeout_ms.max(1) as usize;
+                    }
+                }
+            }
+        }
+        self.settle_ticks = LAYOUT_SETTLE_MS;
+        self.keymap.event(event);
+    }
+
+    /// Lock the input: release all the keys and send no report until the
+    /// keys of the unlock chord are pressed together
+    async fn lock_input(&mut self) {
+        if self.unlock_chord.is_empty() {
+            warn!("No unlock chord, not locking the input");
+            return;
+        }
+        info!("Input locked");
+        self.input_lock = InputLock::Locked(0);
+        self.repeating = false;
+        self.io.notify_input_lock(true);
+        if self.kb_report != KeyboardReport::default() {
+            self.kb_report = KeyboardReport::default();
+            self.io.send_keyboard_report(self.kb_report).await;
+        }
+        if self.consumer_report != ConsumerReport::default() {
+            self.consumer_report = ConsumerReport::default();
+            self.io.send_consumer_report(self.consumer_report).await;
+        }
+    }
+
+    /// Whether the reports can be sent. The input gets unlocked once all
+    /// the keys are released after the unlock chord.
+    fn input_unlocked(&mut self) -> bool {
+        match self.input_lock {
+            InputLock::Unlocked => true,
+            InputLock::Unlocking if self.pressed_keys == 0 => {
+                info!("Input unlocked");
+                self.input_lock = InputLock::Unlocked;
+                self.io.notify_input_lock(false);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether some state depends on time and needs the pipeline to be
+    /// ticked every ms
+    pub fn needs_tick(&self) -> bool {
+        self.pressed_keys > 0
+            || self.settle_ticks > 0
+            || self.auto_mouse_timeout > 0
+            || self.factory_reset_hold > 0
+            || self.autoshift.is_active()
+    }
+
+    /// Process the state of the keyboard and mouse, every ms
+    pub async fn tick(&mut self) {
+        // Process all mouse events first since they are time sensitive
+        while let Some((mouse_report, has_pressure)) = self.io.mouse_report().await {
+            if self.input_lock != InputLock::Unlocked {
+                continue;
+            }
+            let pending_mouse_clicks = mouse_report.buttons != 0;
+            // Don't consider wheel movement as mouse activity since it may
+            // just be scrolling and not actual mouse movement
+            let mouse_moved = mouse_report.x != 0 || mouse_report.y != 0;
+            self.io.send_mouse_report(mouse_report).await;
+            self.settle_ticks = LAYOUT_SETTLE_MS;
+            if mouse_moved || pending_mouse_clicks || has_pressure {
+                self.io.notify_activity();
+                if AUTO_MOUSE && self.auto_mouse.enabled {
+                    self.auto_mouse_timeout = if pending_mouse_clicks {
+                        self.auto_mouse.click_delay_ms
+                    } else {
+                        self.auto_mouse.timeout_ms
+                    } as usize;
+                    self.on_mouse_active();
+                }
+            }
+        }
+        self.settle_ticks = self.settle_ticks.saturating_sub(1);
+        if self.factory_reset_hold > 0 {
+            self.factory_reset_hold -= 1;
+            if self.factory_reset_hold == 0 {
+                self.io.factory_reset();
+            }
+        }
+        if self.auto_mouse_timeout > 0 && self.auto_mouse_held == 0 {
+            self.auto_mouse_timeout -= 1;
+            if self.auto_mouse_timeout == 0 {
+                self.on_mouse_inactive();
+            }
+        }
+
+        // Process all the pending key events if any
+        // This is where the keymap is processed
+        while let Some(event) = self.io.key_event() {
+            self.on_key_event(event);
+        }
+        let custom_event = self.keymap.tick();
+        if !self.input_unlocked() {
+            return;
+        }
+        let new_layer = self.keymap.current_layer();
+        if let Some((event, is_pressed)) = custom_event {
+            self.process_custom_event(event, is_pressed).await;
+        }
+        self.autoshift.tick(self.keymap.usages());
+        let repeated = self.repeating.then_some(self.last_keys);
+        let repeated = repeated.into_iter().flat_map(|r| {
+            let modifier = (r.modifier != 0).then_some(Usage::Modifier(r.modifier));
+            r.keycodes
+                .into_iter()
+                .filter(|kc| *kc != 0)
+                .map(Usage::Key)
+                .chain(modifier)
+        });
+        let (new_kb_report, new_consumer_report) = generate_reports(
+            self.autoshift.usages(self.keymap.usages()).chain(repeated),
+            self.keymap.key_overrides(),
+        );
+        if !self.repeating && new_kb_report.keycodes.iter().any(|kc| *kc != 0) {
+            self.last_keys = new_kb_report;
+        }
+        if new_kb_report != self.kb_report {
+            self.kb_report = new_kb_report;
+            self.io.send_keyboard_report(new_kb_report).await;
+        }
+        if new_consumer_report != self.consumer_report {
+            self.consumer_report = new_consumer_report;
+            self.io.send_consumer_report(new_consumer_report).await;
+        }
+        if new_layer != self.current_layer {
+            info!("Layer: {}", new_layer);
+            self.current_layer = new_layer;
+            self.set_color_layer(new_layer as u8).await;
+        }
+    }
+
+    /// Press or release the system control key of usage `usage`
+    async fn send_system(&mut self, usage: u8, is_pressed: bool) {
+        let usage = if is_pressed { usage } else { 0 };
+        self.io.send_system_report(SystemReport { usage }).await;
+    }
+
+    /// Process a custom event from the keymap
+    async fn process_custom_event(&mut self, event: CustomEvent, is_pressed: bool) {
+        match event {
+            CustomEvent::FactoryReset if is_pressed => {
+                info!("Hold for {}ms to reset the settings", FACTORY_RESET_HOLD_MS);
+                self.factory_reset_hold = FACTORY_RESET_HOLD_MS;
+            }
+            CustomEvent::FactoryReset => {
+                self.factory_reset_hold = 0;
+            }
+            CustomEvent::NoMouseAction if is_pressed => {
+                if self.auto_mouse_timeout != 0 {
+                    self.auto_mouse_timeout = 0;
+                    self.on_mouse_inactive();
+                }
+            }
+            CustomEvent::NoMouseAction => (),
+            CustomEvent::LockInput if is_pressed => self.lock_input().await,
+            CustomEvent::LockInput => (),
+            CustomEvent::Repeat => self.repeating = is_pressed,
+            CustomEvent::SystemPowerDown => self.send_system(SYSTEM_POWER_DOWN, is_pressed).await,
+            CustomEvent::SystemSleep => self.send_system(SYSTEM_SLEEP, is_pressed).await,
+            CustomEvent::SystemWakeUp => self.send_system(SYSTEM_WAKE_UP, is_pressed).await,
+            _ => self.io.custom_event(event, is_pressed).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::AutoShift as AutoShiftConfig;
+    use std::collections::VecDeque;
+
+    /// Key toggling the mouse layer
+    const VIRTUAL_MOUSE_KEY: (u8, u8) = (3, 9);
+    /// Layer activated by the virtual mouse key
+    const MOUSE_LAYER: usize = 2;
+    /// Keys unlocking the input
+    const UNLOCK_CHORD: [(u8, u8); 2] = [(0, 1), (0, 2)];
+    /// Keys keeping the mouse layer while held: the shift key
+    const AUTO_MOUSE_EXCLUDED_KEYS: [(u8, u8); 1] = [(0, 3)];
+
+    /// Action of a key of the mock keymap
+    enum Action {
+        /// Keyboard key, whose keycode depends on the layer
+        Key([u8; 3]),
+        /// Momentary layer
+        Layer(usize),
+        /// Custom event
+        Custom(CustomEvent),
+    }
+
+    /// Keymap with hardcoded actions: row 0 holds keys, row 1 custom
+    /// events, and (0, 0) activates layer 1
+    #[derive(Default)]
+    struct MockKeymap {
+        pressed: Vec<(u8, u8)>,
+        custom_events: VecDeque<(CustomEvent, bool)>,
+        default_layer: usize,
+    }
+
+    impl MockKeymap {
+        fn action(i: u8, j: u8) -> Action {
+            match (i, j) {
+                (0, 0) => Action::Layer(1),
+                (0, 1) => Action::Key([0x04, 0x1E, 0x00]),
+                (0, 2) => Action::Key([0x05, 0x1F, 0x00]),
+                (0, 3) => Action::Key([0xE1, 0xE1, 0xE1]),
+                (1, 0) => Action::Custom(CustomEvent::MouseLeftClick),
+                (1, 1) => Action::Custom(CustomEvent::MouseRightClick),
+                (1, 2) => Action::Custom(CustomEvent::FactoryReset),
+                (1, 3) => Action::Custom(CustomEvent::NoMouseAction),
+                (1, 4) => Action::Custom(CustomEvent::NextLedAnimation),
+                (1, 5) => Action::Custom(CustomEvent::SystemSleep),
+                (1, 6) => Action::Custom(CustomEvent::Repeat),
+                (1, 7) => Action::Custom(CustomEvent::LockInput),
+                VIRTUAL_MOUSE_KEY => Action::Layer(MOUSE_LAYER),
+                _ => Action::Key([0x00; 3]),
+            }
+        }
+    }
+
+    impl Keymap for MockKeymap {
+        fn event(&mut self, event: KeyEvent) {
+            let (key, is_pressed) = match event {
+                KeyEvent::Press(i, j) => ((i, j), true),
+                KeyEvent::Release(i, j) => ((i, j), false),
+            };
+            if is_pressed {
+                self.pressed.push(key);
+            } else {
+                self.pressed.retain(|k| *k != key);
+            }
+            if let Action::Custom(e) = Self::action(key.0, key.1) {
+                self.custom_events.push_back((e, is_pressed));
+            }
+        }
+
+        fn tick(&mut self) -> Option<(CustomEvent, bool)> {
+            self.custom_events.pop_front()
+        }
+
+        fn current_layer(&self) -> usize {
+            self.pressed
+                .iter()
+                .filter_map(|(i, j)| match Self::action(*i, *j) {
+                    Action::Layer(l) => Some(l),
+                    _ => None,
+                })
+                .max()
+                .unwrap_or(self.default_layer)
+        }
+
+        fn set_default_layer(&mut self, layer: usize) {
+            self.default_layer = layer;
+        }
+
+        fn set_tap_hold(&mut self, _config: TapHold) {}
+
+        fn reload(&mut self) {}
+
+        fn usages(&self) -> impl Iterator<Item = Usage> + '_ {
+            let layer = self.current_layer().min(2);
+            self.pressed
+                .iter()
+                .filter_map(move |(i, j)| match Self::action(*i, *j) {
+                    Action::Key(kcs) if (0xE0..0xE8).contains(&kcs[layer]) => {
+                        Some(Usage::Modifier(1 << (kcs[layer] - 0xE0)))
+                    }
+                    Action::Key(kcs) if kcs[layer] != 0 => Some(Usage::Key(kcs[layer])),
+                    _ => None,
+                })
+        }
+
+        fn key_overrides(&self) -> &'static [KeyOverride] {
+            &[]
+        }
+    }
+
+    /// Records everything sent by the pipeline
+    #[derive(Default)]
+    struct MockIo {
+        key_events: VecDeque<KeyEvent>,
+        mouse_moves: VecDeque<(MouseReport, bool)>,
+        mouse_reports: Vec<MouseReport>,
+        kb_reports: Vec<KeyboardReport>,
+        consumer_reports: Vec<ConsumerReport>,
+        system_reports: Vec<SystemReport>,
+        color_layers: Vec<u8>,
+        custom_events: Vec<(CustomEvent, bool)>,
+        activity: usize,
+        auto_mouse: usize,
+        input_locks: Vec<bool>,
+        factory_reset: bool,
+    }
+
+    impl Io for MockIo {
+        fn key_event(&mut self) -> Option<KeyEvent> {
+            self.key_events.pop_front()
+        }
+
+        async fn mouse_report(&mut self) -> Option<(MouseReport, bool)> {
+            self.mouse_moves.pop_front()
+        }
+
+        async fn send_mouse_report(&mut self, report: MouseReport) {
+            self.mouse_reports.push(report);
+        }
+
+        async fn send_keyboard_report(&mut self, report: KeyboardReport) {
+            self.kb_reports.push(report);
+        }
+
+        async fn send_consumer_report(&mut self, report: ConsumerReport) {
+            self.consumer_reports.push(report);
+        }
+
+        async fn send_system_report(&mut self, report: SystemReport) {
+            self.system_reports.push(report);
+        }
+
+        async fn set_color_layer(&mut self, layer: u8) {
+            self.color_layers.push(layer);
+        }
+
+        async fn custom_event(&mut self, event: CustomEvent, is_pressed: bool) {
+            self.custom_events.push((event, is_pressed));
+        }
+
+        fn notify_activity(&mut self) {
+            self.activity += 1;
+        }
+
+        fn notify_auto_mouse(&mut self) {
+            self.auto_mouse += 1;
+        }
+
+        fn notify_input_lock(&mut self, locked: bool) {
+            self.input_locks.push(locked);
+        }
+
+        fn factory_reset(&mut self) {
+            self.factory_reset = true;
+        }
+    }
+
+    /// Auto-mouse settings of the tests. The Skeletyl has no auto-mouse
+    /// layer: the tests relying on it are not built for it.
+    const AUTO_MOUSE: AutoMouse = AutoMouse {
+        enabled: true,
+        timeout_ms: 100,
+        click_delay_ms: 300,
+    };
+
+    fn pipeline(auto_mouse: AutoMouse) -> Pipeline<MockKeymap, MockIo> {
+        let settings = Settings {
+            auto_mouse,
+            ..Default::default()
+        };
+        Pipeline::new(
+            MockKeymap::default(),
+            MockIo::default(),
+            VIRTUAL_MOUSE_KEY,
+            &UNLOCK_CHORD,
+            &AUTO_MOUSE_EXCLUDED_KEYS,
+            &settings,
+        )
+    }
+
+    async fn ticks(p: &mut Pipeline<MockKeymap, MockIo>, n: usize) {
+        for _ in 0..n {
+            p.tick().await;
+        }
+    }
+
+    fn kb_report(modifier: u8, keycodes: &[u8]) -> KeyboardReport {
+        let mut report = KeyboardReport {
+            modifier,
+            ..Default::default()
+        };
+        report.keycodes[..keycodes.len()].copy_from_slice(keycodes);
+        report
+    }
+
+    fn mouse_move(x: i16, y: i16) -> (MouseReport, bool) {
+        (
+            MouseReport {
+                x,
+                y,
+                ..Default::default()
+            },
+            false,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_keys() {
+        let mut p = pipeline(AUTO_MOUSE);
+        p.io().key_events.extend([
+            KeyEvent::Press(0, 3),
+            KeyEvent::Press(0, 1),
+            KeyEvent::Press(0, 2),
+        ]);
+        p.tick().await;
+        p.io().key_events.push_back(KeyEvent::Release(0, 1));
+        p.tick().await;
+        // Unchanged reports are not sent again
+        p.tick().await;
+        p.io()
+            .key_events
+            .extend([KeyEvent::Release(0, 2), KeyEvent::Release(0, 3)]);
+        p.tick().await;
+        assert_eq!(
+            p.io.kb_reports,
+            [
+                kb_report(0x02, &[0x04, 0x05]),
+                kb_report(0x02, &[0x05]),
+                kb_report(0, &[]),
+            ]
+        );
+        assert!(p.io.color_layers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_lock_input() {
+        let mut p = pipeline(AUTO_MOUSE);
+        p.io()
+            .key_events
+            .extend([KeyEvent::Press(0, 1), KeyEvent::Press(1, 7)]);
+        ticks(&mut p, 2).await;
+        assert_eq!(p.io.input_locks, [true]);
+        // Nothing is sent while locked
+        p.io().key_events.extend([
+            KeyEvent::Release(1, 7),
+            KeyEvent::Release(0, 1),
+            KeyEvent::Press(0, 2),
+            KeyEvent::Press(1, 4),
+        ]);
+        ticks(&mut p, 2).await;
+        p.io().mouse_moves.push_back(mouse_move(1, 1));
+        p.io().key_events.push_back(KeyEvent::Release(1, 4));
+        ticks(&mut p, 2).await;
+        // The chord unlocks the input once released
+        p.io().key_events.push_back(KeyEvent::Press(0, 1));
+        ticks(&mut p, 2).await;
+        assert_eq!(p.io.input_locks, [true]);
+        p.io()
+            .key_events
+            .extend([KeyEvent::Release(0, 1), KeyEvent::Release(0, 2)]);
+        ticks(&mut p, 2).await;
+        assert_eq!(p.io.input_locks, [true, false]);
+        p.io().key_events.push_back(KeyEvent::Press(0, 1));
+        p.tick().await;
+        assert_eq!(
+            p.io.kb_reports,
+            [
+                kb_report(0, &[0x04]),
+                kb_report(0, &[]),
+                kb_report(0, &[0x04])
+            ]
+        );
+        assert!(p.io.mouse_reports.is_empty());
+        assert!(p.io.custom_events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_layer_change() {
+        let mut p = pipeline(AUTO_MOUSE);
+        p.io().key_events.push_back(KeyEvent::Press(0, 0));
+        p.tick().await;
+        p.io().key_events.push_back(KeyEvent::Press(0, 1));
+        p.tick().await;
+        p.io().key_events.push_back(KeyEvent::Release(0, 1));
+        p.tick().await;
+        p.io().key_events.push_back(KeyEvent::Release(0, 0));
+        p.tick().await;
+        assert_eq!(p.io.color_layers, [1, 0]);
+        assert_eq!(p.io.kb_reports, [kb_report(0, &[0x1E]), kb_report(0, &[])]);
+    }
+
+    #[tokio::test]
+    async fn test_default_layer() {
+        let mut p = pipeline(AUTO_MOUSE);
+        p.apply_settings(&Settings {
+            default_layer: 1,
+            ..Default::default()
+        });
+        p.io().key_events.push_back(KeyEvent::Press(0, 1));
+        p.tick().await;
+        assert_eq!(p.io.color_layers, [1]);
+        assert_eq!(p.io.kb_reports, [kb_report(0, &[0x1E])]);
+    }
+
+    #[cfg(not(any(feature = "skeletyl", feature = "scylla", feature = "tbkmini")))]
+    #[tokio::test]
+    async fn test_pointer_burst() {
+        let mut p = pipeline(AUTO_MOUSE);
+        p.io()
+            .mouse_moves
+            .extend((1..=10).map(|i| mouse_move(i, -i)));
+        p.tick().await;
+        // The whole burst is sent at once
+        assert_eq!(p.io.mouse_reports.len(), 10);
+        assert_eq!(p.io.activity, 10);
+        assert_eq!(p.io.color_layers, [MOUSE_LAYER as u8]);
+        assert_eq!(p.io.auto_mouse, 1);
+
+        // Moves keep the mouse layer active
+        ticks(&mut p, 50).await;
+        p.io().mouse_moves.push_back(mouse_move(1, 1));
+        ticks(&mut p, 99).await;
+        assert_eq!(p.io.color_layers, [MOUSE_LAYER as u8]);
+        assert_eq!(p.io.auto_mouse, 1);
+        p.tick().await;
+        assert_eq!(p.io.color_layers, [MOUSE_LAYER as u8, 0]);
+        assert!(p.io.kb_reports.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_wheel_is_not_activity() {
+        let mut p = pipeline(AUTO_MOUSE);
+        p.io().mouse_moves.push_back((
+            MouseReport {
+                wheel: 1,
+                ..Default::default()
+            },
+            false,
+        ));
+        ticks(&mut p, 2).await;
+        assert_eq!(p.io.mouse_reports.len(), 1);
+        assert_eq!(p.io.activity, 0);
+        assert!(p.io.color_layers.is_empty());
+    }
+
+    #[cfg(not(any(feature = "skeletyl", feature = "scylla", feature = "tbkmini")))]
+    #[tokio::test]
+    async fn test_pressure_is_activity() {
+        let mut p = pipeline(AUTO_MOUSE);
+        // A touch on the trackpad without any movement
+        p.io().mouse_moves.push_back((MouseReport::default(), true));
+        p.tick().await;
+        assert_eq!(p.io.activity, 1);
+        assert_eq!(p.io.color_layers, [MOUSE_LAYER as u8]);
+        assert_eq!(p.io.auto_mouse, 1);
+    }
+
+    #[cfg(not(any(feature = "skeletyl", feature = "scylla", feature = "tbkmini")))]
+    #[tokio::test]
+    async fn test_click_delay() {
+        let mut p = pipeline(AUTO_MOUSE);
+        p.io().mouse_moves.push_back((
+            MouseReport {
+                buttons: 1,
+                ..Default::default()
+            },
+            false,
+        ));
+        // A click keeps the mouse layer for the click delay, longer than
+        // the timeout after a move
+        ticks(&mut p, AUTO_MOUSE.click_delay_ms as usize - 1).await;
+        assert_eq!(p.io.color_layers, [MOUSE_LAYER as u8]);
+        p.tick().await;
+        assert_eq!(p.io.color_layers, [MOUSE_LAYER as u8, 0]);
+
+        // A move after the click goes back to the shorter timeout
+        p.io().mouse_moves.push_back((
+            MouseReport {
+                buttons: 1,
+                ..Default::default()
+            },
+            false,
+        ));
+        p.tick().await;
+        p.io().mouse_moves.push_back(mouse_move(1, 1));
+        ticks(&mut p, AUTO_MOUSE.timeout_ms as usize).await;
+        assert_eq!(
+            p.io.color_layers,
+            [MOUSE_LAYER as u8, 0, MOUSE_LAYER as u8, 0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_auto_mouse_disabled() {
+        let mut p = pipeline(AutoMouse {
+            enabled: false,
+            ..AUTO_MOUSE
+        });
+        p.io()
+            .mouse_moves
+            .extend((1..=10).map(|i| mouse_move(i, i)));
+        ticks(&mut p, 2).await;
+        assert_eq!(p.io.mouse_reports.len(), 10);
+        assert_eq!(p.io.activity, 10);
+        assert!(p.io.color_layers.is_empty());
+        assert_eq!(p.io.auto_mouse, 0);
+    }
+
+    #[cfg(not(any(feature = "skeletyl", feature = "scylla", feature = "tbkmini")))]
+    #[tokio::test]
+    async fn test_chorded_clicks() {
+        let mut p = pipeline(AUTO_MOUSE);
+        p.io()
+            .key_events
+            .extend([KeyEvent::Press(1, 0), KeyEvent::Press(1, 1)]);
+        ticks(&mut p, 2).await;
+        assert_eq!(
+            p.io.custom_events,
+            [
+                (CustomEvent::MouseLeftClick, true),
+                (CustomEvent::MouseRightClick, true)
+            ]
+        );
+        // The mouse handler reports both buttons
+        p.io().mouse_moves.push_back((
+            MouseReport {
+                buttons: 0b11,
+                ..Default::default()
+            },
+            false,
+        ));
+        p.io()
+            .key_events
+            .extend([KeyEvent::Release(1, 1), KeyEvent::Release(1, 0)]);
+        ticks(&mut p, 2).await;
+        assert_eq!(
+            p.io.custom_events[2..],
+            [
+                (CustomEvent::MouseRightClick, false),
+                (CustomEvent::MouseLeftClick, false)
+            ]
+        );
+        assert_eq!(p.io.color_layers, [MOUSE_LAYER as u8]);
+        // A click keeps the mouse layer for the click delay
+        ticks(&mut p, 297).await;
+        assert_eq!(p.io.color_layers, [MOUSE_LAYER as u8]);
+        p.tick().await;
+        assert_eq!(p.io.color_layers, [MOUSE_LAYER as u8, 0]);
+        assert!(p.io.kb_reports.is_empty());
+    }
+
+    #[cfg(not(any(feature = "skeletyl", feature = "scylla", feature = "tbkmini")))]
+    #[tokio::test]
+    async fn test_no_mouse_action() {
+        let mut p = pipeline(AUTO_MOUSE);
+        p.io().mouse_moves.push_back(mouse_move(5, 5));
+        p.tick().await;
+        p.io().key_events.push_back(KeyEvent::Press(1, 3));
+        p.tick().await;
+        assert_eq!(p.io.color_layers, [MOUSE_LAYER as u8]);
+        p.tick().await;
+        assert_eq!(p.io.color_layers, [MOUSE_LAYER as u8, 0]);
+        assert!(p.io.custom_events.is_empty());
+    }
+
+    #[cfg(not(any(feature = "skeletyl", feature = "scylla", feature = "tbkmini")))]
+    #[tokio::test]
+    async fn test_auto_mouse_excluded_keys() {
+        let mut p = pipeline(AUTO_MOUSE);
+        p.io().mouse_moves.push_back(mouse_move(5, 5));
+        p.tick().await;
+        // Holding shift keeps the mouse layer past its timeout
+        p.io().key_events.push_back(KeyEvent::Press(0, 3));
+        ticks(&mut p, 2 * AUTO_MOUSE.timeout_ms as usize).await;
+        assert_eq!(p.io.color_layers, [MOUSE_LAYER as u8]);
+        assert_eq!(p.io.kb_reports, [kb_report(0x02, &[])]);
+
+        // The timeout starts again once it is released
+        p.io().key_events.push_back(KeyEvent::Release(0, 3));
+        p.tick().await;
+        ticks(&mut p, AUTO_MOUSE.timeout_ms as usize - 1).await;
+        assert_eq!(p.io.color_layers, [MOUSE_LAYER as u8]);
+        p.tick().await;
+        assert_eq!(p.io.color_layers, [MOUSE_LAYER as u8, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_custom_events_forwarded() {
+        let mut p = pipeline(AUTO_MOUSE);
+        p.io()
+            .key_events
+            .extend([KeyEvent::Press(1, 4), KeyEvent::Release(1, 4)]);
+        ticks(&mut p, 2).await;
+        assert_eq!(
+            p.io.custom_events,
+            [
+                (CustomEvent::NextLedAnimation, true),
+                (CustomEvent::NextLedAnimation, false)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_system_control() {
+        let mut p = pipeline(AUTO_MOUSE);
+        p.io()
+            .key_events
+            .extend([KeyEvent::Press(1, 5), KeyEvent::Release(1, 5)]);
+        ticks(&mut p, 2).await;
+        assert_eq!(
+            p.io.system_reports,
+            [
+                SystemReport {
+                    usage: SYSTEM_SLEEP
+                },
+                SystemReport { usage: 0 }
+            ]
+        );
+        assert!(p.io.custom_events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_autoshift() {
+        let settings = Settings {
+            autoshift: AutoShiftConfig {
+                enabled: true,
+                timeout_ms: 100,
+            },
+            ..Default::default()
+        };
+        let mut p = Pipeline::new(
+            MockKeymap::default(),
+            MockIo::default(),
+            VIRTUAL_MOUSE_KEY,
+            &UNLOCK_CHORD,
+            &AUTO_MOUSE_EXCLUDED_KEYS,
+            &settings,
+        );
+        // Tapped
+        p.io().key_events.push_back(KeyEvent::Press(0, 1));
+        ticks(&mut p, 50).await;
+        assert!(p.io.kb_reports.is_empty());
+        p.io().key_events.push_back(KeyEvent::Release(0, 1));
+        ticks(&mut p, 20).await;
+        assert_eq!(p.io.kb_reports, [kb_report(0, &[0x04]), kb_report(0, &[])]);
+
+        // Held
+        p.io.kb_reports.clear();
+        p.io().key_events.push_back(KeyEvent::Press(0, 1));
+        ticks(&mut p, 150).await;
+        p.io().key_events.push_back(KeyEvent::Release(0, 1));
+        ticks(&mut p, 1).await;
+        assert_eq!(
+            p.io.kb_reports,
+            [kb_report(1 << 1, &[0x04]), kb_report(0, &[])]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_repeat() {
+        let mut p = pipeline(AUTO_MOUSE);
+        // Nothing to repeat yet
+        p.io().key_events.push_back(KeyEvent::Press(1, 6));
+        ticks(&mut p, 2).await;
+        assert!(p.io.kb_reports.is_empty());
+        p.io().key_events.push_back(KeyEvent::Release(1, 6));
+        ticks(&mut p, 2).await;
+
+        // Shift + a, then repeated
+        p.io()
+            .key_events
+            .extend([KeyEvent::Press(0, 3), KeyEvent::Press(0, 1)]);
+        ticks(&mut p, 2).await;
+        p.io()
+            .key_events
+            .extend([KeyEvent::Release(0, 1), KeyEvent::Release(0, 3)]);
+        ticks(&mut p, 2).await;
+        p.io.kb_reports.clear();
+        p.io().key_events.push_back(KeyEvent::Press(1, 6));
+        ticks(&mut p, 2).await;
+        p.io().key_events.push_back(KeyEvent::Release(1, 6));
+        ticks(&mut p, 2).await;
+        assert_eq!(
+            p.io.kb_reports,
+            [kb_report(1 << 1, &[0x04]), kb_report(0, &[])]
+        );
+        assert!(p.io.custom_events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_factory_reset() {
+        let mut p = pipeline(AUTO_MOUSE);
+        // Released too early
+        p.io().key_events.push_back(KeyEvent::Press(1, 2));
+        ticks(&mut p, FACTORY_RESET_HOLD_MS - 10).await;
+        p.io().key_events.push_back(KeyEvent::Release(1, 2));
+        ticks(&mut p, 20).await;
+        assert!(!p.io.factory_reset);
+
+        p.io().key_events.push_back(KeyEvent::Press(1, 2));
+        ticks(&mut p, FACTORY_RESET_HOLD_MS).await;
+        assert!(!p.io.factory_reset);
+        p.tick().await;
+        assert!(p.io.factory_reset);
+    }
+
+    #[cfg(not(any(feature = "skeletyl", feature = "scylla", feature = "tbkmini")))]
+    #[tokio::test]
+    async fn test_needs_tick() {
+        let mut p = pipeline(AUTO_MOUSE);
+        assert!(!p.needs_tick());
+        p.on_key_event(KeyEvent::Press(0, 1));
+        ticks(&mut p, LAYOUT_SETTLE_MS + 10).await;
+        // Ticks while a key is held
+        assert!(p.needs_tick());
+        p.on_key_event(KeyEvent::Release(0, 1));
+        ticks(&mut p, LAYOUT_SETTLE_MS - 1).await;
+        assert!(p.needs_tick());
+        p.tick().await;
+        assert!(!p.needs_tick());
+        assert_eq!(p.io.kb_reports, [kb_report(0, &[0x04]), kb_report(0, &[])]);
+
+        // Ticks while the mouse is active, then until the layout settles
+        p.io().mouse_moves.push_back(mouse_move(5, 5));
+        p.tick().await;
+        ticks(
+            &mut p,
+            AUTO_MOUSE.timeout_ms as usize + LAYOUT_SETTLE_MS - 2,
+        )
+        .await;
+        assert!(p.needs_tick());
+        p.tick().await;
+        assert!(!p.needs_tick());
+    }
+}
@@ -1,38 +1,108 @@
 //! Pseudo-random number generator
 //!
 //! This module provides a pseudo-random number generator (PRNG) based on the
-//! Xorshift32 algorithm by George Marsaglia.
+//! PCG32 algorithm by Melissa O'Neill, seeded through SplitMix64 so that
+//! seeds with few bits of entropy still give unrelated sequences.
 //!
-//! See: https://en.wikipedia.org/wiki/Xorshift
+//! See: https://www.pcg-random.org/
 
-/// Xorshift32 PRNG
-pub struct XorShift32 {
-    state: u32,
+/// Multiplier of the linear congruential generator of PCG32
+const PCG_MULTIPLIER: u64 = 6_364_136_223_846_793_005;
+
+/// Next output of the SplitMix64 generator of state `state`
+pub fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// PCG32 PRNG: 64 bits of state, 32-bit outputs
+#[derive(Debug, Clone)]
+pub struct Pcg32 {
+    /// State of the generator
+    state: u64,
+    /// Increment, selecting the stream: always odd
+    inc: u64,
 }
 
-impl XorShift32 {
-    /// Create a new XorShift32 PRNG
-    pub fn new(seed: u32) -> Self {
-        Self { state: seed }
+impl Pcg32 {
+    /// Create a new PCG32 PRNG from `seed`
+    pub fn new(seed: u64) -> Self {
+        let mut sm = seed;
+        let state = splitmix64(&mut sm);
+        let stream = splitmix64(&mut sm);
+        Self::seeded(state, stream)
     }
 
-    /// Seed the PRNG
-    pub fn seed(&mut self, seed: u32) {
-        self.state = seed;
+    /// Create a PCG32 PRNG with the initial state `state` on the stream
+    /// `stream`, as the reference implementation does
+    fn seeded(state: u64, stream: u64) -> Self {
+        let mut rng = Self {
+            state: 0,
+            inc: (stream << 1) | 1,
+        };
+        rng.step();
+        rng.state = rng.state.wrapping_add(state);
+        rng.step();
+        rng
     }
 
-    /// Get the current state
-    pub fn get_state(&self) -> u32 {
-        self.state
+    /// Advance the state
+    fn step(&mut self) {
+        self.state = self
+            .state
+            .wrapping_mul(PCG_MULTIPLIER)
+            .wrapping_add(self.inc);
+    }
+
+    /// Mix `entropy` into the state, for example entropy received from the
+    /// other half
+    pub fn mix(&mut self, entropy: u64) {
+        *self = Self::new(self.state ^ entropy);
     }
 
     /// Get the next random number
     pub fn random(&mut self) -> u32 {
-        let mut x = self.state;
-        x ^= x << 13;
-        x ^= x >> 17;
-        x ^= x << 5;
-        self.state = x;
-        x
+        let old = self.state;
+        self.step();
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        xorshifted.rotate_right((old >> 59) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reference() {
+        // Output of the pcg32-demo of the reference implementation
+        let mut rng = Pcg32::seeded(42, 54);
+        let out: [u32; 6] = core::array::from_fn(|_| rng.random());
+        assert_eq!(
+            out,
+            [0xa15c02b7, 0x7b47f409, 0xba1d3330, 0x83d2f293, 0xbfa4784b, 0xcbed606e]
+        );
+    }
+
+    #[test]
+    fn test_seeds() {
+        // Close seeds give unrelated sequences
+        let mut a = Pcg32::new(0);
+        let mut b = Pcg32::new(1);
+        assert!((0..16).all(|_| a.random() != b.random()));
+
+        // Mixing entropy changes the sequence
+        let mut a = Pcg32::new(0xdead_beef);
+        let mut b = a.clone();
+        b.mix(0x42);
+        assert!((0..16).all(|_| a.random() != b.random()));
+        let mut c = Pcg32::new(0xdead_beef);
+        c.mix(0x42);
+        let mut b = Pcg32::new(0xdead_beef);
+        b.mix(0x42);
+        assert!((0..16).all(|_| c.random() == b.random()));
     }
 }
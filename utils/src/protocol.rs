@@ -45,7 +45,7 @@ pub trait Hardware {
 const MAX_QUEUED_EVENTS: usize = 64;
 
 pub struct SideProtocol<W: Sized + Hardware> {
-    #[cfg(feature = "defmt")]
+    #[cfg(any(feature = "defmt", target_arch = "x86_64"))]
     // Name
     name: &'static str,
 
@@ -77,9 +77,12 @@ pub struct SideProtocol<W: Sized + Hardware> {
 
 impl<W: Sized + Hardware> SideProtocol<W> {
     /// Create a new side protocol
-    pub fn new(hw: W, #[cfg(feature = "defmt")] name: &'static str) -> Self {
+    pub fn new(
+        hw: W,
+        #[cfg(any(feature = "defmt", target_arch = "x86_64"))] name: &'static str,
+    ) -> Self {
         Self {
-            #[cfg(feature = "defmt")]
+            #[cfg(any(feature = "defmt", target_arch = "x86_64"))]
             name,
             sent: CircBuf::new(),
             queued_events: ArrayDeque::new(),
@@ -95,7 +98,10 @@ impl<W: Sized + Hardware> SideProtocol<W> {
     /// Send an event
     async fn send_event(&mut self, event: Event) {
         self.need_ping = false;
-        let msg = serialize(event, self.next_tx_sid).unwrap();
+        let Ok(msg) = serialize(event, self.next_tx_sid) else {
+            error!("[{}] Unable to serialize event: {:?}", self.name, event);
+            return;
+        };
         #[cfg(feature = "log-protocol")]
         info!(
             "[{}] Sending [Sid#{}] Event: {} (0x{:04x})",
@@ -379,11 +385,8 @@ mod tests {
             self.send_queue.push_front(msg).unwrap();
             async {}
         }
-        async fn try_receive(&mut self) -> Option<Message> {
-            match self.rx.try_recv().ok() {
-                Some(msg) if msg == 0x00000000 => None, // Filter out keepalive
-                other => other,
-            }
+        async fn receive(&mut self) -> Message {
+            self.rx.recv().await.unwrap()
         }
         fn set_error_state(&mut self, error: bool) -> impl future::Future<Output = ()> + Send {
             self.on_error = error;
@@ -405,6 +408,16 @@ mod tests {
         }
     }
 
+    /// Run one protocol cycle on a side, giving up on the reception when
+    /// nothing was sent to it
+    async fn run_once(side: &mut SideProtocol<MockHardware>) {
+        let _ = tokio::time::timeout(
+            std::time::Duration::from_millis(1),
+            side.run_once_continuous(),
+        )
+        .await;
+    }
+
     /// One exchange of messages between the two sides
     async fn communicate_once(
         right: &mut SideProtocol<MockHardware>,
@@ -415,14 +428,14 @@ mod tests {
             right.hw.to_rx.send(msg).await.unwrap();
         }
         // Always run protocol cycle on right
-        right.run_once_continuous().await;
+        run_once(right).await;
 
         // Transfer messages from right to left
         if let Some(msg) = right.hw.send_queue.pop_back() {
             left.hw.to_rx.send(msg).await.unwrap();
         }
         // Always run protocol cycle on left
-        left.run_once_continuous().await;
+        run_once(left).await;
 
         info!(
             "QUEUES: right rx:{} send:{}/{} left rx:{} send:{}/{}",
@@ -521,8 +534,8 @@ mod tests {
         let _ = lovely_env_logger::try_init_default();
         let hw_right = MockHardware::new("right");
         let hw_left = MockHardware::new("left");
-        let mut right = SideProtocol::new(hw_right, "right", true);
-        let mut left = SideProtocol::new(hw_left, "left", false);
+        let mut right = SideProtocol::new(hw_right, "right");
+        let mut left = SideProtocol::new(hw_left, "left");
 
         // Send a message from right to left
         right.send_event(Event::Ping).await;
@@ -540,8 +553,8 @@ mod tests {
         let _ = lovely_env_logger::try_init_default();
         let hw_right = MockHardware::new("right");
         let hw_left = MockHardware::new("left");
-        let mut right = SideProtocol::new(hw_right, "right", true);
-        let mut left = SideProtocol::new(hw_left, "left", false);
+        let mut right = SideProtocol::new(hw_right, "right");
+        let mut left = SideProtocol::new(hw_left, "left");
 
         // Both sides are synced
         right.next_rx_sid = Some(Sid::new(0));
@@ -568,8 +581,8 @@ mod tests {
         let _ = lovely_env_logger::try_init_default();
         let hw_right = MockHardware::new("right");
         let hw_left = MockHardware::new("left");
-        let mut right = SideProtocol::new(hw_right, "right", true);
-        let mut left = SideProtocol::new(hw_left, "left", false);
+        let mut right = SideProtocol::new(hw_right, "right");
+        let mut left = SideProtocol::new(hw_left, "left");
 
         // Both sides are synced
         right.next_rx_sid = Some(Sid::new(0));
@@ -583,13 +596,11 @@ mod tests {
         right.send_event(Event::SeedRng(2)).await;
         right.send_event(Event::SeedRng(3)).await;
         let mut bad = [0u32, 0];
-        for i in 0..=1 {
-            let mut msg = right.hw.send_queue.pop_front().unwrap();
-            msg ^= 0x1234;
-            bad[i] = msg;
+        for bad in bad.iter_mut() {
+            *bad = right.hw.send_queue.pop_front().unwrap() ^ 0x1234;
         }
-        for i in 0..=1 {
-            right.hw.send_queue.push_front(bad[i]).unwrap();
+        for bad in bad {
+            right.hw.send_queue.push_front(bad).unwrap();
         }
         // Let it commmunicate and stabilize
         communicate(&mut right, &mut left, 30).await;
@@ -604,18 +615,21 @@ mod tests {
         let _ = lovely_env_logger::try_init_default();
         let hw_right = MockHardware::new("right");
         let hw_left = MockHardware::new("left");
-        let mut right = SideProtocol::new(hw_right, "right", true);
-        let mut left = SideProtocol::new(hw_left, "left", false);
+        let mut right = SideProtocol::new(hw_right, "right");
+        let mut left = SideProtocol::new(hw_left, "left");
 
         right.next_rx_sid = Some(Sid::new(30));
         right.next_tx_sid = Sid::new(2);
         left.next_rx_sid = None;
         left.next_tx_sid = Sid::new(0);
-        // In continuous mode, both sides always send Noop if nothing queued
+        // Only the keepalives, filtered by the hardware, are exchanged until
+        // an event is sent: the first ones synchronize the sides
+        right.send_event(Event::Ping).await;
+        left.send_event(Event::Ping).await;
         // Let it commmunicate and stabilize
         communicate(&mut right, &mut left, 50).await;
         info!("Right: {:?}", right.hw.msg_sent);
-        info!("Left: {:?}", right.hw.msg_sent);
+        info!("Left: {:?}", left.hw.msg_sent);
         assert!(is_synced(&right, &left));
         left.send_event(Event::Press(3, 3)).await;
         // Let it commmunicate and stabilize
@@ -628,8 +642,8 @@ mod tests {
         let _ = lovely_env_logger::try_init_default();
         let hw_right = MockHardware::new("right");
         let hw_left = MockHardware::new("left");
-        let mut right = SideProtocol::new(hw_right, "right", true);
-        let mut left = SideProtocol::new(hw_left, "left", false);
+        let mut right = SideProtocol::new(hw_right, "right");
+        let mut left = SideProtocol::new(hw_left, "left");
 
         // Both sides are 2 messages out of sync
         right.next_rx_sid = Some(Sid::new(30));
@@ -648,6 +662,18 @@ mod tests {
         assert!(is_synced(&right, &left));
     }
 
+    #[tokio::test]
+    async fn test_unserializable_event() {
+        let _ = lovely_env_logger::try_init_default();
+        let mut right = SideProtocol::new(MockHardware::new("right"), "right");
+        // Dropped, without using a sequence id
+        right.send_event(Event::Press(0x10, 0)).await;
+        assert!(right.hw.send_queue.is_empty());
+        assert_eq!(right.next_tx_sid, Sid::default());
+        right.send_event(Event::Press(4, 11)).await;
+        assert_eq!(right.hw.send_queue.len(), 1);
+    }
+
     // TODO Test when a side got a corrupted message and sends a retransmit
     // that is also corrupted
 
@@ -0,0 +1,567 @@
+//! Configuration protocol over a vendor-defined raw HID interface
+//!
+//! The interface uses the same usage page, usage and report size as QMK's
+//! raw HID, so that host tools written for it can find the keyboard.
+//! Each output report sent by the host is a command: its first byte is the
+//! command id and the rest are its arguments. The keyboard answers every
+//! command with an input report starting with the same command id, or with
+//! `Command::Unhandled` if the command is unknown.
+//!
+//! The commands below 0x40 follow Via's protocol, so that Via can edit the
+//! keymap, inspect the layers and control the RGB LEDs, given a definition
+//! of the keyboard. The values Via reads and writes on its lighting and
+//! custom channels are listed by `CustomValue`.
+
+use crate::rgb_anims::{RgbAnimType, NB_INDEXED_COLORS};
+
+/// Size of the raw HID reports, in bytes
+pub const REPORT_SIZE: usize = 32;
+
+/// Raw HID usage page
+pub const USAGE_PAGE: u16 = 0xFF60;
+/// Raw HID usage
+pub const USAGE: u8 = 0x61;
+
+/// Version of the protocol: the one of Via it follows, as Via checks it
+pub const PROTOCOL_VERSION: u16 = 12;
+
+/// Status answered when a command succeeded
+pub const STATUS_OK: u8 = 0x00;
+/// Status answered when a command failed
+pub const STATUS_ERROR: u8 = 0x01;
+
+/// Raw HID report
+pub type Report = [u8; REPORT_SIZE];
+
+/// Commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Command {
+    /// Get the protocol version.
+    /// Answer: version as big endian u16
+    GetProtocolVersion = 0x01,
+    /// Get a value of the keyboard, whose id is given as argument, see
+    /// `KeyboardValue`.
+    /// Answer: the id and the value
+    GetKeyboardValue = 0x02,
+    /// Get the keycode of a key. Arguments: layer, row and column.
+    /// Answer: layer, row, column and keycode as big endian u16,
+    /// `keymap::NOT_EDITED` if its built-in action has no keycode
+    GetKeycode = 0x04,
+    /// Assign a keycode to a key. Arguments: layer, row, column and
+    /// keycode as big endian u16, `keymap::NOT_EDITED` to restore its
+    /// built-in action.
+    /// Answer: status, `STATUS_OK` or `STATUS_ERROR`
+    SetKeycode = 0x05,
+    /// Restore the keymap as built in the firmware.
+    /// Answer: status, `STATUS_OK`
+    ResetKeymap = 0x06,
+    /// Set a value of a channel. Arguments: channel id, value id and the
+    /// value, see `CustomValue`.
+    /// Answer: status, `STATUS_OK` or `STATUS_ERROR`
+    SetCustomValue = 0x07,
+    /// Get a value of a channel. Arguments: channel id and value id, see
+    /// `CustomValue`.
+    /// Answer: channel id, value id and the value
+    GetCustomValue = 0x08,
+    /// Persist the values of a channel. They are saved along the settings
+    /// anyway.
+    /// Answer: status, `STATUS_OK`
+    SaveCustomValues = 0x09,
+    /// Reboot into the bootloader (BOOTSEL mode).
+    /// Answer: sent before rebooting, no data
+    BootloaderJump = 0x0B,
+    /// Get the number of dynamic macros editable from the host: none.
+    /// Answer: number of macros, as u8
+    GetMacroCount = 0x0C,
+    /// Get the size of the buffer of the macros editable from the host.
+    /// Answer: size as big endian u16, 0
+    GetMacroBufferSize = 0x0D,
+    /// Get the number of layers of the keymap.
+    /// Answer: number of layers, as u8
+    GetLayerCount = 0x11,
+    /// Get the keycodes of the keymap, layer by layer and row by row, as
+    /// big endian u16. Arguments: offset in bytes as big endian u16, and
+    /// size in bytes, up to `KEYMAP_BUFFER_CHUNK`.
+    /// Answer: offset, size, then the keycodes, see `Command::GetKeycode`
+    GetKeymapBuffer = 0x12,
+    /// Assign keycodes to the keys of the keymap. Arguments: offset and
+    /// size, as for `Command::GetKeymapBuffer`, then the keycodes.
+    /// Answer: status, `STATUS_OK` or `STATUS_ERROR` if some keycode could
+    /// not be assigned
+    SetKeymapBuffer = 0x13,
+    /// Export the settings.
+    /// Answer: the serialized settings
+    GetSettings = 0x40,
+    /// Import the settings, given serialized as arguments.
+    /// Answer: status, `STATUS_OK` or `STATUS_ERROR`
+    SetSettings = 0x41,
+    /// Get the settings profiles.
+    /// Answer: active profile, number of profiles, then the name of each
+    /// profile, padded with zeros
+    GetProfiles = 0x42,
+    /// Switch to the profile given as argument.
+    /// Answer: status, `STATUS_OK` or `STATUS_ERROR`
+    SetProfile = 0x43,
+    /// Rename a profile. Arguments: profile index and name, padded with
+    /// zeros.
+    /// Answer: status, `STATUS_OK` or `STATUS_ERROR`
+    SetProfileName = 0x44,
+    /// Erase the persisted settings and reboot with the defaults.
+    /// Answer: sent before rebooting, no data
+    FactoryReset = 0x45,
+    /// Get the handedness stored in the settings.
+    /// Answer: 0 when detected from the GPIO strap, 1 for left, 2 for right
+    GetHandedness = 0x46,
+    /// Store the handedness given as argument, used from the next boot on:
+    /// 0 to detect it from the GPIO strap, 1 for left, 2 for right.
+    /// Answer: status, `STATUS_OK` or `STATUS_ERROR`
+    SetHandedness = 0x47,
+    /// Get the keyboard matrix debouncing time.
+    /// Answer: debouncing time in ms, as u8
+    GetDebounce = 0x48,
+    /// Set the keyboard matrix debouncing time, in ms, given as u8.
+    /// Answer: status, `STATUS_OK` or `STATUS_ERROR`
+    SetDebounce = 0x49,
+    /// Get the number of presses of each key, from the key index given as
+    /// u8 on. The keys are indexed row by row.
+    /// Answer: number of rows, number of columns, index of the first key,
+    /// then the counts as little endian u32, as many as fit
+    GetKeyStats = 0x4A,
+    /// Forget the key presses counted so far.
+    /// Answer: status, `STATUS_OK`
+    ResetKeyStats = 0x4B,
+    /// Get the statistics of the link between the halves.
+    /// Answer: the serialized `LinkStats`
+    GetLinkStats = 0x4C,
+    /// Get the typing speed.
+    /// Answer: words per minute, smoothed over the last seconds, as little
+    /// endian u16
+    GetWpm = 0x4D,
+    /// Get the sensor angle tune.
+    /// Answer: angle in degrees, as i8
+    GetAngleTune = 0x4E,
+    /// Set the sensor angle tune, in degrees, given as i8. It is applied
+    /// from the next power up of the sensor on.
+    /// Answer: status, `STATUS_OK` or `STATUS_ERROR`
+    SetAngleTune = 0x4F,
+    /// Get the rotation applied to the sensor moves.
+    /// Answer: clockwise angle in degrees, as big endian u16
+    GetSensorRotation = 0x50,
+    /// Set the rotation applied to the sensor moves, clockwise, in degrees,
+    /// given as big endian u16.
+    /// Answer: status, `STATUS_OK` or `STATUS_ERROR`
+    SetSensorRotation = 0x51,
+    /// Get the tracking quality of the trackball sensor, to diagnose a bad
+    /// ball, bearings or surface.
+    /// Answer: the serialized `SensorQuality`
+    GetSensorQuality = 0x52,
+    /// Get the lift-off distance of the sensor.
+    /// Answer: value of its `LiftConfig` register, as u8
+    GetLiftConfig = 0x53,
+    /// Set the lift-off distance of the sensor, the value of its
+    /// `LiftConfig` register, given as u8.
+    /// Answer: status, `STATUS_OK` or `STATUS_ERROR`
+    SetLiftConfig = 0x54,
+    /// Get the time without any activity before the system clock is
+    /// lowered.
+    /// Answer: timeout in seconds, as u8
+    GetIdleTimeout = 0x55,
+    /// Set the time without any activity before the system clock is
+    /// lowered, in seconds, given as u8. It is applied from the next idle
+    /// period on.
+    /// Answer: status, `STATUS_OK` or `STATUS_ERROR`
+    SetIdleTimeout = 0x56,
+    /// Get the message of the panic that caused the last reboot.
+    /// Answer: length as u8, followed by the first bytes of the message
+    GetLastPanic = 0x80,
+    /// Answer to an unknown command
+    Unhandled = 0xFF,
+}
+
+impl Command {
+    /// Parse a command id
+    pub fn from_u8(id: u8) -> Option<Self> {
+        match id {
+            0x01 => Some(Command::GetProtocolVersion),
+            0x02 => Some(Command::GetKeyboardValue),
+            0x04 => Some(Command::GetKeycode),
+            0x05 => Some(Command::SetKeycode),
+            0x06 => Some(Command::ResetKeymap),
+            0x07 => Some(Command::SetCustomValue),
+            0x08 => Some(Command::GetCustomValue),
+            0x09 => Some(Command::SaveCustomValues),
+            0x0B => Some(Command::BootloaderJump),
+            0x0C => Some(Command::GetMacroCount),
+            0x0D => Some(Command::GetMacroBufferSize),
+            0x11 => Some(Command::GetLayerCount),
+            0x12 => Some(Command::GetKeymapBuffer),
+            0x13 => Some(Command::SetKeymapBuffer),
+            0x40 => Some(Command::GetSettings),
+            0x41 => Some(Command::SetSettings),
+            0x42 => Some(Command::GetProfiles),
+            0x43 => Some(Command::SetProfile),
+            0x44 => Some(Command::SetProfileName),
+            0x45 => Some(Command::FactoryReset),
+            0x46 => Some(Command::GetHandedness),
+            0x47 => Some(Command::SetHandedness),
+            0x48 => Some(Command::GetDebounce),
+            0x49 => Some(Command::SetDebounce),
+            0x4A => Some(Command::GetKeyStats),
+            0x4B => Some(Command::ResetKeyStats),
+            0x4C => Some(Command::GetLinkStats),
+            0x4D => Some(Command::GetWpm),
+            0x4E => Some(Command::GetAngleTune),
+            0x4F => Some(Command::SetAngleTune),
+            0x50 => Some(Command::GetSensorRotation),
+            0x51 => Some(Command::SetSensorRotation),
+            0x52 => Some(Command::GetSensorQuality),
+            0x53 => Some(Command::GetLiftConfig),
+            0x54 => Some(Command::SetLiftConfig),
+            0x55 => Some(Command::GetIdleTimeout),
+            0x56 => Some(Command::SetIdleTimeout),
+            0x80 => Some(Command::GetLastPanic),
+            0xFF => Some(Command::Unhandled),
+            _ => None,
+        }
+    }
+}
+
+/// Values of the keyboard, for `Command::GetKeyboardValue`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum KeyboardValue {
+    /// Time since boot, in ms, as big endian u32
+    Uptime = 0x01,
+}
+
+impl KeyboardValue {
+    /// Parse a value id
+    pub fn from_u8(id: u8) -> Option<Self> {
+        match id {
+            0x01 => Some(KeyboardValue::Uptime),
+            _ => None,
+        }
+    }
+}
+
+/// Values of the channels, for `Command::SetCustomValue` and
+/// `Command::GetCustomValue`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CustomValue {
+    /// CPI of the pointer, as big endian u16, on the custom channel
+    Cpi,
+    /// Active layer, read only, on the custom channel
+    Layer,
+    /// RGB animation, on the RGB light channel, see `rgb_effect`
+    RgbEffect,
+    /// Color index of the solid and pulse solid RGB animations, on the
+    /// custom channel
+    RgbColor,
+}
+
+/// Id of the custom channel, whose values are specific to the keyboard
+const CUSTOM_CHANNEL: u8 = 0x00;
+/// Id of the RGB light channel
+const RGB_LIGHT_CHANNEL: u8 = 0x02;
+/// Id of the effect value of the RGB light channel
+const RGB_LIGHT_EFFECT: u8 = 0x02;
+
+impl CustomValue {
+    /// Parse a channel id and a value id
+    pub fn from_ids(channel: u8, id: u8) -> Option<Self> {
+        match (channel, id) {
+            (CUSTOM_CHANNEL, 0x01) => Some(CustomValue::Cpi),
+            (CUSTOM_CHANNEL, 0x02) => Some(CustomValue::Layer),
+            (CUSTOM_CHANNEL, 0x03) => Some(CustomValue::RgbColor),
+            (RGB_LIGHT_CHANNEL, RGB_LIGHT_EFFECT) => Some(CustomValue::RgbEffect),
+            _ => None,
+        }
+    }
+
+    /// Channel id and value id
+    pub fn ids(self) -> (u8, u8) {
+        match self {
+            CustomValue::Cpi => (CUSTOM_CHANNEL, 0x01),
+            CustomValue::Layer => (CUSTOM_CHANNEL, 0x02),
+            CustomValue::RgbColor => (CUSTOM_CHANNEL, 0x03),
+            CustomValue::RgbEffect => (RGB_LIGHT_CHANNEL, RGB_LIGHT_EFFECT),
+        }
+    }
+}
+
+/// Effect id of an RGB animation: 0 for off, 1 for a solid color, 2 for the
+/// wheel, 3 for the pulse and 4 for the pulse of a solid color
+pub fn rgb_effect(anim: RgbAnimType) -> u8 {
+    match anim {
+        RgbAnimType::Off => 0,
+        RgbAnimType::SolidColor(_) => 1,
+        RgbAnimType::Wheel => 2,
+        RgbAnimType::Pulse => 3,
+        RgbAnimType::PulseSolid(_) => 4,
+    }
+}
+
+/// Color index of an RGB animation, 0 if it has none
+pub fn rgb_color(anim: RgbAnimType) -> u8 {
+    match anim {
+        RgbAnimType::SolidColor(color) | RgbAnimType::PulseSolid(color) => color,
+        _ => 0,
+    }
+}
+
+/// RGB animation of effect id `effect`, see `rgb_effect`, with the color
+/// index `color`. `None` if either is invalid.
+pub fn rgb_anim(effect: u8, color: u8) -> Option<RgbAnimType> {
+    if color as usize >= NB_INDEXED_COLORS {
+        return None;
+    }
+    match effect {
+        0 => Some(RgbAnimType::Off),
+        1 => Some(RgbAnimType::SolidColor(color)),
+        2 => Some(RgbAnimType::Wheel),
+        3 => Some(RgbAnimType::Pulse),
+        4 => Some(RgbAnimType::PulseSolid(color)),
+        _ => None,
+    }
+}
+
+/// Maximum size of the keycodes of the keymap in a report, in bytes
+pub const KEYMAP_BUFFER_CHUNK: usize = REPORT_SIZE - 4;
+
+/// Layer, row and column of the key at `index` of the keymap buffer, in
+/// keycodes, for a keymap of `rows`x`cols` keys
+pub fn buffer_key(index: usize, rows: usize, cols: usize) -> (usize, usize, usize) {
+    let layer_size = rows * cols;
+    (index / layer_size, index % layer_size / cols, index % cols)
+}
+
+/// Size of the serialized `LinkStats`
+pub const LINK_STATS_SIZE: usize = 17;
+
+/// Statistics of the link between the halves
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkStats {
+    /// Whether the link is up
+    pub link_up: bool,
+    /// Number of times the link went down
+    pub errors: u32,
+    /// Number of events sent to the other half
+    pub events_sent: u32,
+    /// Number of events received from the other half
+    pub events_received: u32,
+    /// Drift of the clock of the master half, in ppm, as measured by the
+    /// slave one
+    pub drift_ppm: i32,
+}
+
+impl LinkStats {
+    /// Serialize the statistics, the counters as little endian
+    pub fn to_bytes(&self) -> [u8; LINK_STATS_SIZE] {
+        let mut bytes = [0u8; LINK_STATS_SIZE];
+        bytes[0] = self.link_up as u8;
+        bytes[1..5].copy_from_slice(&self.errors.to_le_bytes());
+        bytes[5..9].copy_from_slice(&self.events_sent.to_le_bytes());
+        bytes[9..13].copy_from_slice(&self.events_received.to_le_bytes());
+        bytes[13..17].copy_from_slice(&self.drift_ppm.to_le_bytes());
+        bytes
+    }
+
+    /// Deserialize the statistics, `None` if `bytes` is too short
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; LINK_STATS_SIZE] = bytes.get(..LINK_STATS_SIZE)?.try_into().ok()?;
+        let word = |i: usize| [bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]];
+        Some(Self {
+            link_up: bytes[0] != 0,
+            errors: u32::from_le_bytes(word(1)),
+            events_sent: u32::from_le_bytes(word(5)),
+            events_received: u32::from_le_bytes(word(9)),
+            drift_ppm: i32::from_le_bytes(word(13)),
+        })
+    }
+}
+
+/// Size of the serialized `SensorQuality`
+pub const SENSOR_QUALITY_SIZE: usize = 6;
+
+/// Tracking quality of the trackball sensor, over the last seconds
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SensorQuality {
+    /// Lowest surface quality, SQUAL: a quarter of the number of features
+    /// the sensor sees, 0 when it sees none
+    pub squal: u8,
+    /// Average raw data sum: the brightness of the surface, in units of
+    /// 1024
+    pub raw_data_sum: u8,
+    /// Highest shutter time, in clock cycles: it grows as the surface
+    /// reflects less light
+    pub shutter: u16,
+    /// Number of motion bursts measured
+    pub samples: u16,
+}
+
+impl SensorQuality {
+    /// Serialize the quality, the counters as little endian
+    pub fn to_bytes(&self) -> [u8; SENSOR_QUALITY_SIZE] {
+        let mut bytes = [0u8; SENSOR_QUALITY_SIZE];
+        bytes[0] = self.squal;
+        bytes[1] = self.raw_data_sum;
+        bytes[2..4].copy_from_slice(&self.shutter.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.samples.to_le_bytes());
+        bytes
+    }
+
+    /// Deserialize the quality, `None` if `bytes` is too short
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; SENSOR_QUALITY_SIZE] =
+            bytes.get(..SENSOR_QUALITY_SIZE)?.try_into().ok()?;
+        Some(Self {
+            squal: bytes[0],
+            raw_data_sum: bytes[1],
+            shutter: u16::from_le_bytes([bytes[2], bytes[3]]),
+            samples: u16::from_le_bytes([bytes[4], bytes[5]]),
+        })
+    }
+}
+
+/// Create a report for `cmd`, with `data` as arguments.
+/// `data` is truncated if it does not fit in the report.
+pub fn report(cmd: Command, data: &[u8]) -> Report {
+    let mut report = [0u8; REPORT_SIZE];
+    report[0] = cmd as u8;
+    let n = data.len().min(REPORT_SIZE - 1);
+    report[1..1 + n].copy_from_slice(&data[..n]);
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_ids() {
+        for cmd in [
+            Command::GetProtocolVersion,
+            Command::GetKeyboardValue,
+            Command::GetKeycode,
+            Command::SetKeycode,
+            Command::ResetKeymap,
+            Command::SetCustomValue,
+            Command::GetCustomValue,
+            Command::SaveCustomValues,
+            Command::BootloaderJump,
+            Command::GetMacroCount,
+            Command::GetMacroBufferSize,
+            Command::GetLayerCount,
+            Command::GetKeymapBuffer,
+            Command::SetKeymapBuffer,
+            Command::GetSettings,
+            Command::SetSettings,
+            Command::GetProfiles,
+            Command::SetProfile,
+            Command::SetProfileName,
+            Command::FactoryReset,
+            Command::GetHandedness,
+            Command::SetHandedness,
+            Command::GetDebounce,
+            Command::SetDebounce,
+            Command::GetKeyStats,
+            Command::ResetKeyStats,
+            Command::GetLinkStats,
+            Command::GetWpm,
+            Command::GetAngleTune,
+            Command::SetAngleTune,
+            Command::GetSensorRotation,
+            Command::SetSensorRotation,
+            Command::GetSensorQuality,
+            Command::GetLiftConfig,
+            Command::SetLiftConfig,
+            Command::GetIdleTimeout,
+            Command::SetIdleTimeout,
+            Command::GetLastPanic,
+            Command::Unhandled,
+        ] {
+            assert_eq!(Command::from_u8(cmd as u8), Some(cmd));
+        }
+        assert_eq!(Command::from_u8(0x7e), None);
+    }
+
+    #[test]
+    fn test_custom_values() {
+        for value in [
+            CustomValue::Cpi,
+            CustomValue::Layer,
+            CustomValue::RgbEffect,
+            CustomValue::RgbColor,
+        ] {
+            let (channel, id) = value.ids();
+            assert_eq!(CustomValue::from_ids(channel, id), Some(value));
+        }
+        assert_eq!(CustomValue::from_ids(0x03, RGB_LIGHT_EFFECT), None);
+        assert_eq!(KeyboardValue::from_u8(0x01), Some(KeyboardValue::Uptime));
+        assert_eq!(KeyboardValue::from_u8(0x03), None);
+    }
+
+    #[test]
+    fn test_rgb_anims() {
+        for anim in [
+            RgbAnimType::Off,
+            RgbAnimType::SolidColor(3),
+            RgbAnimType::Wheel,
+            RgbAnimType::Pulse,
+            RgbAnimType::PulseSolid(10),
+        ] {
+            assert_eq!(rgb_anim(rgb_effect(anim), rgb_color(anim)), Some(anim));
+        }
+        assert_eq!(rgb_anim(5, 0), None);
+        assert_eq!(rgb_anim(1, NB_INDEXED_COLORS as u8), None);
+    }
+
+    #[test]
+    fn test_buffer_key() {
+        assert_eq!(buffer_key(0, 4, 10), (0, 0, 0));
+        assert_eq!(buffer_key(13, 4, 10), (0, 1, 3));
+        assert_eq!(buffer_key(79, 4, 10), (1, 3, 9));
+    }
+
+    #[test]
+    fn test_report() {
+        let r = report(Command::GetProtocolVersion, &PROTOCOL_VERSION.to_be_bytes());
+        assert_eq!(r[0], 0x01);
+        assert_eq!(u16::from_be_bytes([r[1], r[2]]), PROTOCOL_VERSION);
+        let r = report(Command::GetLastPanic, &[0xaa; 64]);
+        assert_eq!(r[REPORT_SIZE - 1], 0xaa);
+    }
+
+    #[test]
+    fn test_link_stats() {
+        let stats = LinkStats {
+            link_up: true,
+            errors: 3,
+            events_sent: 123_456,
+            events_received: 7,
+            drift_ppm: -42,
+        };
+        let r = report(Command::GetLinkStats, &stats.to_bytes());
+        assert_eq!(LinkStats::from_bytes(&r[1..]), Some(stats));
+        assert_eq!(LinkStats::from_bytes(&r[1..LINK_STATS_SIZE]), None);
+    }
+
+    #[test]
+    fn test_sensor_quality() {
+        let quality = SensorQuality {
+            squal: 48,
+            raw_data_sum: 37,
+            shutter: 0x1234,
+            samples: 1000,
+        };
+        let r = report(Command::GetSensorQuality, &quality.to_bytes());
+        assert_eq!(SensorQuality::from_bytes(&r[1..]), Some(quality));
+        assert_eq!(SensorQuality::from_bytes(&r[1..SENSOR_QUALITY_SIZE]), None);
+    }
+}
@@ -0,0 +1,104 @@
+//! Resolution of the trackball sensors
+//!
+//! The sensors take their resolution as a number of steps minus one: the
+//! lowest resolution is 0. The PMW3320 sets a flag along it, in the upper
+//! bits of its register.
+
+/// Resolution range of a sensor, and its encoding in the registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resolution {
+    /// Lowest resolution, in CPI
+    pub min: u16,
+    /// Highest resolution, in CPI
+    pub max: u16,
+    /// Resolution step, in CPI
+    pub step: u16,
+    /// Bits set along the resolution in the registers
+    pub flags: u16,
+    /// Bits of the resolution in the registers
+    pub mask: u16,
+}
+
+/// PMW3360: 100 to 12000 CPI, in `Config1`
+pub const PMW3360: Resolution = Resolution {
+    min: 100,
+    max: 12000,
+    step: 100,
+    flags: 0,
+    mask: 0xff,
+};
+
+/// PMW3389: 50 to 16000 CPI, on two registers, `ResolutionL` and `Config1`
+pub const PMW3389: Resolution = Resolution {
+    min: 50,
+    max: 16000,
+    step: 50,
+    flags: 0,
+    mask: 0xffff,
+};
+
+/// PMW3320: 250 to 3500 CPI, in the lower bits of `Resolution`, with its
+/// bit 5 set for the resolution to be taken
+pub const PMW3320: Resolution = Resolution {
+    min: 250,
+    max: 3500,
+    step: 250,
+    flags: 0x20,
+    mask: 0x1f,
+};
+
+impl Resolution {
+    /// Value of the registers for `cpi`, clamped to the range of the
+    /// sensor and rounded down to its step
+    pub const fn config(&self, cpi: u16) -> u16 {
+        let cpi = if cpi < self.min {
+            self.min
+        } else if cpi > self.max {
+            self.max
+        } else {
+            cpi
+        };
+        self.flags | (cpi / self.step - 1)
+    }
+
+    /// CPI of the value `config` of the registers
+    pub const fn cpi(&self, config: u16) -> u16 {
+        ((config & self.mask) + 1) * self.step
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for resolution in [PMW3360, PMW3389, PMW3320] {
+            for cpi in (resolution.min..=resolution.max).step_by(resolution.step as usize) {
+                let config = resolution.config(cpi);
+                assert_eq!(config & !resolution.mask, resolution.flags);
+                assert_eq!(resolution.cpi(config), cpi);
+            }
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        assert_eq!(PMW3360.config(100), 0x00);
+        assert_eq!(PMW3360.config(800), 0x07);
+        assert_eq!(PMW3360.config(12000), 0x77);
+        assert_eq!(PMW3389.config(16000), 0x013f);
+        // QMK: 0x20 | (cpi / 250 - 1)
+        assert_eq!(PMW3320.config(250), 0x20);
+        assert_eq!(PMW3320.config(1000), 0x23);
+        assert_eq!(PMW3320.config(3500), 0x2d);
+    }
+
+    #[test]
+    fn test_clamp() {
+        assert_eq!(PMW3320.config(0), PMW3320.config(250));
+        assert_eq!(PMW3320.config(800), PMW3320.config(750));
+        assert_eq!(PMW3320.config(16000), PMW3320.config(3500));
+        assert_eq!(PMW3389.config(10), 0);
+    }
+}
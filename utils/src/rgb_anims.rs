@@ -1,22 +1,43 @@
 //! Compule LED Data to render RGB Animations
 
 use crate::log::*;
-use crate::prng::XorShift32;
+use crate::prng::Pcg32;
 use crate::serde::Error as SerdeError;
 
 /// Number of LEDs on each side
-#[cfg(not(feature = "dilemma"))]
+#[cfg(not(any(feature = "dilemma", feature = "scylla", feature = "tbkmini")))]
 pub const NUM_LEDS: usize = 18;
-#[cfg(feature = "dilemma")]
+#[cfg(all(feature = "dilemma", not(feature = "dilemma_max")))]
 pub const NUM_LEDS: usize = 36;
-/// Number of underglow LEDs
+#[cfg(feature = "dilemma_max")]
+pub const NUM_LEDS: usize = 56;
+#[cfg(feature = "scylla")]
+pub const NUM_LEDS: usize = 29;
+#[cfg(feature = "tbkmini")]
+pub const NUM_LEDS: usize = 21;
+/// Number of LEDs lit by the animations: the underglow ones, or one per key
+/// on the boards without underglow
+#[cfg(not(any(feature = "scylla", feature = "tbkmini")))]
 pub const UNDERGLOW_LEDS: usize = 18;
+#[cfg(any(feature = "scylla", feature = "tbkmini"))]
+pub const UNDERGLOW_LEDS: usize = NUM_LEDS;
 /// Keyboard matrix rows
+#[cfg(not(any(feature = "dilemma_max", feature = "scylla")))]
 pub const ROWS: usize = 4;
+#[cfg(any(feature = "dilemma_max", feature = "scylla"))]
+pub const ROWS: usize = 5;
 /// Keyboard matrix columns
+#[cfg(not(any(feature = "dilemma_max", feature = "scylla", feature = "tbkmini")))]
 pub const COLS: usize = 5;
+#[cfg(any(feature = "dilemma_max", feature = "scylla", feature = "tbkmini"))]
+pub const COLS: usize = 6;
 /// Maximum light level per color. Must be usable as a mask
 pub const MAX_LIGHT_LEVEL: u8 = 0xaf;
+/// Typing speed making the animations one frame per tick faster, in words
+/// per minute
+const WPM_PER_SPEED_STEP: u16 = 40;
+/// Maximum speed of the animations, in frames per tick
+const MAX_SPEED: u8 = 4;
 
 /// RGB Animation Type
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -90,40 +111,43 @@ impl RGB8 {
 
 /// No color
 const NO_COLOR: RGB8 = RGB8::default();
-/// Orange color, used for RAISE layer
+/// Orange color
 const ORANGE_COLOR: RGB8 = RGB8::new(0x40, 0x10, 0x00);
-/// Green color, used for LOWER layer
+/// Green color
 const GREEN_COLOR: RGB8 = RGB8::new(0x00, 0x40, 0x00);
-/// Purple color, used for MISC layer
+/// Purple color
 const PURPLE_COLOR: RGB8 = RGB8::new(0x40, 0, 0x10);
-/// Blue color, used for NUMBERS layer
+/// Blue color
 const BLUE_COLOR: RGB8 = RGB8::new(0x00, 0x00, 0x40);
-/// Red color, used for TMUX layer
+/// Red color
 const RED_COLOR: RGB8 = RGB8::new(0x07, 0, 0);
-/// Gray color, used for GAMING layer
+/// Gray color
 const GRAY_COLOR: RGB8 = RGB8::new(0x07, 0x07, 0x07);
-/// Beige color, used for CAPS layer
+/// Beige color
 const BEIGE_COLOR: RGB8 = RGB8::new(0x0f, 0x0f, 0x00);
-/// Yellow color, used for QWERTY layer
+/// Yellow color
 const YELLOW_COLOR: RGB8 = RGB8::new(0x40, 0x30, 0x00);
-/// Dark red color, used for MOUSE layer
+/// Dark red color
 const DARK_RED_COLOR: RGB8 = RGB8::new(0x10, 0, 0);
-/// White color, used for ERROR layer
+/// White color
 const WHITE_COLOR: RGB8 = RGB8::new(MAX_LIGHT_LEVEL, MAX_LIGHT_LEVEL, MAX_LIGHT_LEVEL);
 
+/// Number of indexed colors
+pub const NB_INDEXED_COLORS: usize = 11;
+
 /// Indexed colors
-const INDEXED_COLORS: [RGB8; 11] = [
+const INDEXED_COLORS: [RGB8; NB_INDEXED_COLORS] = [
     NO_COLOR,
-    ORANGE_COLOR,   // 1/ orange, RAISE
-    GREEN_COLOR,    // 2/ green, LOWER
-    PURPLE_COLOR,   // 3/ purple, MISC
-    BLUE_COLOR,     // 4/ blue, NUMBERS
-    RED_COLOR,      // 5/ red, TMUX
-    GRAY_COLOR,     // 6/ gray, GAMING
-    BEIGE_COLOR,    // 7/ beige, CAPS
-    YELLOW_COLOR,   // 8/ yellow, QWERTY
-    DARK_RED_COLOR, // 9/ dark red, MOUSE
-    WHITE_COLOR,    // 10/ white, ERROR
+    ORANGE_COLOR,   // 1/ orange
+    GREEN_COLOR,    // 2/ green
+    PURPLE_COLOR,   // 3/ purple
+    BLUE_COLOR,     // 4/ blue
+    RED_COLOR,      // 5/ red
+    GRAY_COLOR,     // 6/ gray
+    BEIGE_COLOR,    // 7/ beige
+    YELLOW_COLOR,   // 8/ yellow
+    DARK_RED_COLOR, // 9/ dark red
+    WHITE_COLOR,    // 10/ white
 ];
 /// Default color: dark red
 const DEFAULT_COLOR_INDEX: u8 = 9;
@@ -144,6 +168,8 @@ impl From<u32> for RGB8 {
 pub struct RgbAnim {
     /// The current animation frame
     frame: u8,
+    /// Frames the animation moves on by at each tick, with the typing speed
+    speed: u8,
     /// The current animation
     animation: RgbAnimType,
     /// Saved animation
@@ -151,12 +177,16 @@ pub struct RgbAnim {
 
     /// The LED data
     led_data: [RGB8; NUM_LEDS],
+    /// The LED data dimmed to the brightness, as sent to the LEDs
+    output: [RGB8; NUM_LEDS],
+    /// Brightness, in percent
+    brightness: u8,
 
     /// current color
     color: RGB8,
 
     /// PRNG
-    prng: XorShift32,
+    prng: Pcg32,
 }
 
 /// Input a value 0 to 255 to get a color value
@@ -186,17 +216,26 @@ const PULSE_TABLE: [u16; 128] = [
 
 impl RgbAnim {
     /// Create a new RGB Animation
-    pub fn new(seed: u32) -> Self {
+    pub fn new(seed: u64) -> Self {
         RgbAnim {
             frame: 0,
+            speed: 1,
             animation: RgbAnimType::SolidColor(0),
             saved_animation: None,
             led_data: [RGB8::default(); NUM_LEDS],
+            output: [RGB8::default(); NUM_LEDS],
+            brightness: 100,
             color: RGB8::indexed(DEFAULT_COLOR_INDEX),
-            prng: XorShift32::new(seed),
+            prng: Pcg32::new(seed),
         }
     }
 
+    /// Mix `entropy` into the PRNG, so that the random colors of both
+    /// halves differ and do not repeat on every boot
+    pub fn mix_entropy(&mut self, entropy: u64) {
+        self.prng.mix(entropy);
+    }
+
     /// Reset the leds
     fn reset(&mut self) {
         for led in self.led_data.iter_mut() {
@@ -246,15 +285,24 @@ impl RgbAnim {
             RgbAnimType::SolidColor(idx) => self.fill_color(RGB8::indexed(idx)),
             RgbAnimType::Wheel => self.tick_wheel(),
             RgbAnimType::Pulse => {
-                if self.frame.is_multiple_of(128) {
+                // New color when starting a pulse, whatever the speed
+                if self.frame & 127 < self.speed {
                     self.color = self.new_random_color();
                 }
                 self.tick_pulse()
             }
             RgbAnimType::PulseSolid(_) => self.tick_pulse(),
         }
-        self.frame = self.frame.wrapping_add(1);
-        &self.led_data
+        self.frame = self.frame.wrapping_add(self.speed);
+        let brightness = u16::from(self.brightness);
+        for (out, led) in self.output.iter_mut().zip(self.led_data.iter()) {
+            *out = RGB8 {
+                r: (u16::from(led.r) * brightness / 100) as u8,
+                g: (u16::from(led.g) * brightness / 100) as u8,
+                b: (u16::from(led.b) * brightness / 100) as u8,
+            };
+        }
+        &self.output
     }
 
     /// Cycle to the next animation
@@ -322,6 +370,17 @@ impl RgbAnim {
         self.fill_color(RGB8::indexed(color));
     }
 
+    /// Speed the animations up with the typing speed `wpm`, in words per
+    /// minute
+    pub fn set_wpm(&mut self, wpm: u16) {
+        self.speed = (1 + wpm / WPM_PER_SPEED_STEP).min(MAX_SPEED as u16) as u8;
+    }
+
+    /// Set the brightness of the LEDs, in percent
+    pub fn set_brightness(&mut self, percent: u8) {
+        self.brightness = percent.min(100);
+    }
+
     /// Restore the animation
     pub fn restore_animation(&mut self) {
         self.frame = 0;
@@ -353,4 +412,43 @@ mod tests {
             assert_eq!(*t, t2);
         }
     }
+
+    #[test]
+    fn test_speed() {
+        let mut anim = RgbAnim::new(0);
+        anim.set_animation(RgbAnimType::Pulse);
+        anim.set_wpm(90);
+        let mut colors = 0;
+        let mut color = RGB8::default();
+        // 3 frames per tick: a pulse every 43 ticks
+        for _ in 0..86 {
+            anim.tick();
+            if anim.color != color {
+                colors += 1;
+                color = anim.color;
+            }
+        }
+        assert_eq!(colors, 2);
+        anim.set_wpm(1000);
+        assert_eq!(anim.speed, MAX_SPEED);
+        anim.set_wpm(0);
+        assert_eq!(anim.speed, 1);
+    }
+
+    #[test]
+    fn test_brightness() {
+        let mut anim = RgbAnim::new(0);
+        anim.set_animation(RgbAnimType::SolidColor(9));
+        assert_eq!(anim.tick()[0], RGB8::indexed(9));
+        anim.set_brightness(50);
+        let color = RGB8::indexed(9);
+        assert_eq!(
+            anim.tick()[0],
+            RGB8::new(color.r / 2, color.g / 2, color.b / 2)
+        );
+        anim.set_brightness(0);
+        assert_eq!(anim.tick()[0], RGB8::default());
+        anim.set_brightness(200);
+        assert_eq!(anim.tick()[0], RGB8::indexed(9));
+    }
 }
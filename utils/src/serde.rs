@@ -11,13 +11,24 @@ pub enum Event {
     Ping,
     Retransmit(Sid),        // SidSize
     Ack(Sid),               // SidSize
-    Press(u8, u8),          // r: [0, 3], c: [0, 4]: 7 bits
-    Release(u8, u8),        // r: [0, 3], c: [0, 4]: 7 bits
+    Press(u8, u8),          // r: [0, 15], c: [0, 15]: 8 bits
+    Release(u8, u8),        // r: [0, 15], c: [0, 15]: 8 bits
     RgbAnim(RgbAnimType),   // 8 bits
     RgbAnimChangeLayer(u8), // 4 bits
     SeedRng(u8),            // 8 bits
+    SettingsStart,          // start of the settings mirrored to the other half
+    SettingsNibble(u8),     // 4 bits of the mirrored settings
+    DisplayWpm(u8),         // typing speed shown on the display: [0, 223]
+    DisplayLocks(u8),       // lock indicators shown on the display: 4 bits
+    HostSuspended(bool),    // whether the host of the other half is suspended
+    WakeHost,               // pointer activity, to resume the suspended host
+    LinkTest(u8),           // self test pattern sent to the other half: 4 bits
+    LinkTestEcho(u8),       // self test pattern echoed back by the other half: 4 bits
 }
 
+/// Highest typing speed that can be sent to the other half
+pub const MAX_DISPLAY_WPM: u8 = 0xdf;
+
 #[derive(Debug, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Error {
@@ -63,16 +74,36 @@ impl Event {
             Event::Ping => Ok((0b000, 0xcc)),
             Event::Retransmit(err) => Ok((0b001, err.as_u16())),
             Event::Ack(ack) => Ok((0b010, ack.as_u16())),
-            Event::Press(r, c) if *r <= 3 && *c <= 9 => {
+            // The acks and the typing speed share a tag: the acks only use
+            // the lower 5 bits
+            Event::DisplayWpm(wpm) if *wpm <= MAX_DISPLAY_WPM => Ok((0b010, 0x20 + *wpm as u16)),
+            Event::DisplayWpm(_) => Err(Error::Serialization),
+            // A nibble for the row and one for the column, up to the 5x12
+            // keys of the Dilemma Max
+            Event::Press(r, c) if *r <= 0xf && *c <= 0xf => {
                 Ok((0b011, ((*r as u16) << 4) | (*c as u16)))
             }
             Event::Press(_, _) => Err(Error::Serialization),
-            Event::Release(r, c) if *r <= 3 && *c <= 9 => {
+            Event::Release(r, c) if *r <= 0xf && *c <= 0xf => {
                 Ok((0b100, ((*r as u16) << 4) | (*c as u16)))
             }
             Event::Release(_, _) => Err(Error::Serialization),
             Event::RgbAnim(anim) => Ok((0b101, anim.to_u8()? as u16)),
-            Event::RgbAnimChangeLayer(layer) => Ok((0b110, *layer as u16)),
+            // The layer changes and the mirrored settings share a tag: the
+            // upper bit of the data tells them apart
+            Event::RgbAnimChangeLayer(layer) if *layer < 0x80 => Ok((0b110, *layer as u16)),
+            Event::RgbAnimChangeLayer(_) => Err(Error::Serialization),
+            Event::SettingsStart => Ok((0b110, 0xc0)),
+            Event::SettingsNibble(n) if *n <= 0xf => Ok((0b110, 0x80 | *n as u16)),
+            Event::SettingsNibble(_) => Err(Error::Serialization),
+            Event::DisplayLocks(locks) if *locks <= 0xf => Ok((0b110, 0x90 | *locks as u16)),
+            Event::DisplayLocks(_) => Err(Error::Serialization),
+            Event::HostSuspended(suspended) => Ok((0b110, 0xa0 | *suspended as u16)),
+            Event::WakeHost => Ok((0b110, 0xa2)),
+            Event::LinkTest(n) if *n <= 0xf => Ok((0b110, 0xd0 | *n as u16)),
+            Event::LinkTest(_) => Err(Error::Serialization),
+            Event::LinkTestEcho(n) if *n <= 0xf => Ok((0b110, 0xe0 | *n as u16)),
+            Event::LinkTestEcho(_) => Err(Error::Serialization),
             Event::SeedRng(seed) => Ok((0b111, *seed as u16)),
         }?;
         Ok(sid | (tag << 8) | data)
@@ -95,11 +126,19 @@ pub fn deserialize(bytes: Message) -> Result<(Event, Sid), Error> {
         0b000 if data == 0x33 => Ok((Event::Noop, sid)),
         0b000 if data == 0xcc => Ok((Event::Ping, sid)),
         0b001 => Ok((Event::Retransmit(Sid::from_u32_lsb(data)), sid)),
-        0b010 => Ok((Event::Ack(Sid::from_u32_lsb(data)), sid)),
+        0b010 if data < 0x20 => Ok((Event::Ack(Sid::from_u32_lsb(data)), sid)),
+        0b010 => Ok((Event::DisplayWpm((data - 0x20) as u8), sid)),
         0b011 => Ok((Event::Press((data >> 4) as u8, (data & 0xf) as u8), sid)),
         0b100 => Ok((Event::Release((data >> 4) as u8, (data & 0xf) as u8), sid)),
         0b101 => Ok((Event::RgbAnim(RgbAnimType::from_u8(data as u8)?), sid)),
-        0b110 => Ok((Event::RgbAnimChangeLayer(data as u8), sid)),
+        0b110 if data < 0x80 => Ok((Event::RgbAnimChangeLayer(data as u8), sid)),
+        0b110 if data == 0xc0 => Ok((Event::SettingsStart, sid)),
+        0b110 if data & 0xf0 == 0x80 => Ok((Event::SettingsNibble((data & 0xf) as u8), sid)),
+        0b110 if data & 0xf0 == 0x90 => Ok((Event::DisplayLocks((data & 0xf) as u8), sid)),
+        0b110 if data & 0xfe == 0xa0 => Ok((Event::HostSuspended(data & 1 != 0), sid)),
+        0b110 if data == 0xa2 => Ok((Event::WakeHost, sid)),
+        0b110 if data & 0xf0 == 0xd0 => Ok((Event::LinkTest((data & 0xf) as u8), sid)),
+        0b110 if data & 0xf0 == 0xe0 => Ok((Event::LinkTestEcho((data & 0xf) as u8), sid)),
         0b111 => Ok((Event::SeedRng(data as u8), sid)),
         _ => Err(Error::Deserialization),
     }
@@ -120,7 +159,7 @@ mod tests {
     use crate::rgb_anims::ERROR_COLOR_INDEX;
     use crate::sid::Sid;
 
-    const VALID_EVENTS: [(Event, Sid); 38] = [
+    const VALID_EVENTS: [(Event, Sid); 53] = [
         (Event::Noop, Sid::new(0x0)),
         (Event::Noop, Sid::new(0xa)),
         (Event::Noop, Sid::new(31)),
@@ -138,36 +177,48 @@ mod tests {
         (Event::Press(1, 9), Sid::new(24)),
         (Event::Release(1, 2), Sid::new(17)),
         (Event::Press(0, 4), Sid::new(12)),
-        (Event::Release(3, 9), Sid::new(03)),
+        (Event::Release(3, 9), Sid::new(3)),
+        (Event::Press(4, 0), Sid::new(5)),
+        (Event::Press(4, 11), Sid::new(18)),
+        (Event::Release(4, 10), Sid::new(22)),
+        (Event::Release(3, 11), Sid::new(28)),
         (Event::RgbAnim(RgbAnimType::Off), Sid::new(25)),
-        (Event::RgbAnim(RgbAnimType::SolidColor(0)), Sid::new(08)),
-        (Event::RgbAnim(RgbAnimType::SolidColor(1)), Sid::new(09)),
+        (Event::RgbAnim(RgbAnimType::SolidColor(0)), Sid::new(8)),
+        (Event::RgbAnim(RgbAnimType::SolidColor(1)), Sid::new(9)),
         (
             Event::RgbAnim(RgbAnimType::SolidColor(ERROR_COLOR_INDEX)),
             Sid::new(31),
         ),
-        (Event::RgbAnim(RgbAnimType::Wheel), Sid::new(07)),
+        (Event::RgbAnim(RgbAnimType::Wheel), Sid::new(7)),
         (Event::RgbAnim(RgbAnimType::Pulse), Sid::new(19)),
         (Event::RgbAnim(RgbAnimType::PulseSolid(0)), Sid::new(24)),
         (Event::RgbAnim(RgbAnimType::PulseSolid(1)), Sid::new(20)),
-        (Event::RgbAnim(RgbAnimType::PulseSolid(8)), Sid::new(02)),
+        (Event::RgbAnim(RgbAnimType::PulseSolid(8)), Sid::new(2)),
         (
             Event::RgbAnim(RgbAnimType::PulseSolid(ERROR_COLOR_INDEX)),
             Sid::new(0),
         ),
-        (Event::RgbAnim(RgbAnimType::Input), Sid::new(1)),
-        (Event::RgbAnim(RgbAnimType::InputSolid(0)), Sid::new(2)),
-        (Event::RgbAnim(RgbAnimType::InputSolid(1)), Sid::new(3)),
-        (Event::RgbAnim(RgbAnimType::InputSolid(8)), Sid::new(5)),
-        (
-            Event::RgbAnim(RgbAnimType::InputSolid(ERROR_COLOR_INDEX)),
-            Sid::new(7),
-        ),
         (Event::RgbAnimChangeLayer(0), Sid::new(11)),
         (Event::RgbAnimChangeLayer(8), Sid::new(13)),
         (Event::SeedRng(0), Sid::new(17)),
         (Event::SeedRng(8), Sid::new(19)),
         (Event::SeedRng(255), Sid::new(21)),
+        (Event::SettingsStart, Sid::new(23)),
+        (Event::SettingsNibble(0), Sid::new(27)),
+        (Event::SettingsNibble(7), Sid::new(29)),
+        (Event::SettingsNibble(0xf), Sid::new(30)),
+        (Event::DisplayWpm(0), Sid::new(4)),
+        (Event::DisplayWpm(85), Sid::new(6)),
+        (Event::DisplayWpm(MAX_DISPLAY_WPM), Sid::new(10)),
+        (Event::DisplayLocks(0), Sid::new(14)),
+        (Event::DisplayLocks(0b11), Sid::new(16)),
+        (Event::HostSuspended(false), Sid::new(18)),
+        (Event::HostSuspended(true), Sid::new(20)),
+        (Event::WakeHost, Sid::new(22)),
+        (Event::LinkTest(0), Sid::new(24)),
+        (Event::LinkTest(0xf), Sid::new(26)),
+        (Event::LinkTestEcho(0x5), Sid::new(28)),
+        (Event::LinkTestEcho(0xa), Sid::new(31)),
     ];
 
     #[test]
@@ -182,6 +233,42 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_ser_out_of_range() {
+        assert_eq!(
+            Err(Error::Serialization),
+            serialize(Event::RgbAnimChangeLayer(0x80), Sid::new(0))
+        );
+        assert_eq!(
+            Err(Error::Serialization),
+            serialize(Event::SettingsNibble(0x10), Sid::new(0))
+        );
+        assert_eq!(
+            Err(Error::Serialization),
+            serialize(Event::DisplayWpm(MAX_DISPLAY_WPM + 1), Sid::new(0))
+        );
+        assert_eq!(
+            Err(Error::Serialization),
+            serialize(Event::DisplayLocks(0x10), Sid::new(0))
+        );
+        assert_eq!(
+            Err(Error::Serialization),
+            serialize(Event::Press(0x10, 0), Sid::new(0))
+        );
+        assert_eq!(
+            Err(Error::Serialization),
+            serialize(Event::Release(0, 0x10), Sid::new(0))
+        );
+        assert_eq!(
+            Err(Error::Serialization),
+            serialize(Event::LinkTest(0x10), Sid::new(0))
+        );
+        assert_eq!(
+            Err(Error::Serialization),
+            serialize(Event::LinkTestEcho(0x10), Sid::new(0))
+        );
+    }
+
     #[test]
     fn test_bad_crc() {
         for (event, sid) in VALID_EVENTS.iter().copied() {
@@ -207,20 +207,10 @@ mod tests {
         assert_eq!(iter.next(), None);
 
         let sid = Sid::new(17);
-        let mut iter = sid.iter(Sid::new(17));
-        let mut count = 0;
-        while let Some(_) = iter.next() {
-            count += 1;
-        }
-        assert_eq!(count, SID_MAX_U8 as usize + 1);
+        assert_eq!(sid.iter(Sid::new(17)).count(), SID_MAX_U8 as usize + 1);
 
         let sid = Sid::new(17);
-        let mut iter = sid.iter(Sid::new(18));
-        let mut count = 0;
-        while let Some(_) = iter.next() {
-            count += 1;
-        }
-        assert_eq!(count, 1);
+        assert_eq!(sid.iter(Sid::new(18)).count(), 1);
     }
 
     #[test]
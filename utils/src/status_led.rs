@@ -0,0 +1,118 @@
+//! Status LED patterns
+//!
+//! The single status LED shows the most important condition of the system
+//! with a blink pattern, and stays off when everything is fine:
+//!
+//! - bootloader pending: fast blink
+//! - error on the previous run: 3 blinks, then a pause
+//! - link with the other half down: slow blink
+//! - host seen on USB, but not enumerated: 2 blinks, then a pause
+
+/// Period of the slow blink, in ms
+pub const SLOW_BLINK_PERIOD_MS: u64 = 1000;
+/// Period of the fast blink, in ms
+pub const FAST_BLINK_PERIOD_MS: u64 = 200;
+/// Period of each blink of a blink code, in ms
+pub const CODE_BLINK_PERIOD_MS: u64 = 400;
+/// Pause after the blinks of a blink code, in ms
+pub const CODE_PAUSE_MS: u64 = 1000;
+
+/// Blink pattern of the status LED
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Pattern {
+    /// Off
+    Off,
+    /// On
+    Solid,
+    /// Blinking every `SLOW_BLINK_PERIOD_MS`
+    SlowBlink,
+    /// Blinking every `FAST_BLINK_PERIOD_MS`
+    FastBlink,
+    /// Blinking that many times, then pausing
+    Code(u8),
+}
+
+impl Pattern {
+    /// Whether the LED is on `t_ms` after the start of the pattern
+    pub fn is_on(self, t_ms: u64) -> bool {
+        let blink = |period: u64, t: u64| t % period < period / 2;
+        match self {
+            Pattern::Off => false,
+            Pattern::Solid => true,
+            Pattern::SlowBlink => blink(SLOW_BLINK_PERIOD_MS, t_ms),
+            Pattern::FastBlink => blink(FAST_BLINK_PERIOD_MS, t_ms),
+            Pattern::Code(n) => {
+                let blinks = n as u64 * CODE_BLINK_PERIOD_MS;
+                let t = t_ms % (blinks + CODE_PAUSE_MS);
+                t < blinks && blink(CODE_BLINK_PERIOD_MS, t)
+            }
+        }
+    }
+}
+
+/// Conditions shown by the status LED
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Status {
+    /// A reset would enter the bootloader
+    pub bootloader_pending: bool,
+    /// The previous run ended with an error
+    pub error: bool,
+    /// The link with the other half is down
+    pub link_down: bool,
+    /// A host is seen on USB, but did not enumerate the keyboard
+    pub usb_not_enumerated: bool,
+}
+
+impl Status {
+    /// Pattern of the most important condition
+    pub fn pattern(&self) -> Pattern {
+        if self.bootloader_pending {
+            Pattern::FastBlink
+        } else if self.error {
+            Pattern::Code(3)
+        } else if self.link_down {
+            Pattern::SlowBlink
+        } else if self.usb_not_enumerated {
+            Pattern::Code(2)
+        } else {
+            Pattern::Off
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patterns() {
+        assert!(!Pattern::Off.is_on(0));
+        assert!(Pattern::Solid.is_on(1234));
+        assert!(Pattern::SlowBlink.is_on(0));
+        assert!(!Pattern::SlowBlink.is_on(500));
+        assert!(Pattern::SlowBlink.is_on(1499));
+        assert!(Pattern::FastBlink.is_on(250));
+        assert!(!Pattern::FastBlink.is_on(300));
+        // 2 blinks, then a pause
+        let on: [bool; 5] = core::array::from_fn(|i| Pattern::Code(2).is_on(i as u64 * 200));
+        assert_eq!(on, [true, false, true, false, false]);
+        assert!(!Pattern::Code(2).is_on(1700));
+        assert!(Pattern::Code(2).is_on(1800));
+    }
+
+    #[test]
+    fn test_priorities() {
+        let mut status = Status::default();
+        assert_eq!(status.pattern(), Pattern::Off);
+        status.usb_not_enumerated = true;
+        assert_eq!(status.pattern(), Pattern::Code(2));
+        status.link_down = true;
+        assert_eq!(status.pattern(), Pattern::SlowBlink);
+        status.error = true;
+        assert_eq!(status.pattern(), Pattern::Code(3));
+        status.bootloader_pending = true;
+        assert_eq!(status.pattern(), Pattern::FastBlink);
+    }
+}
@@ -0,0 +1,311 @@
+//! Steno mode: chords sent with the Plover HID protocol
+//!
+//! While the steno mode is active, the keys of the steno layout bypass the
+//! keymap: the steno keys they are bound to are accumulated into a chord,
+//! which is sent to Plover once all of them are released. Keys not bound
+//! in the steno layout keep going through the keymap. The chord is a
+//! bitmap of the 64 keys of the Plover HID protocol, the first key being
+//! the most significant bit.
+
+use crate::pipeline::KeyEvent;
+
+/// Report ID of the Plover HID reports
+pub const REPORT_ID: u8 = 0x50;
+/// Size of a Plover HID report: its ID and the bitmap of the keys
+pub const REPORT_SIZE: usize = 9;
+
+#[rustfmt::skip]
+/// Plover HID report descriptor: 64 buttons on the vendor defined usage
+/// Plover looks for
+pub const STENO_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x50, 0xFF,  // Usage Page (Vendor Defined 0xFF50)
+    0x0A, 0x56, 0x4C,  // Usage (0x4C56)
+    0xA1, 0x01,        // Collection (Application)
+    0x85, 0x50,        //   Report ID (0x50)
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x25, 0x01,        //   Logical Maximum (1)
+    0x75, 0x01,        //   Report Size (1)
+    0x95, 0x40,        //   Report Count (64)
+    0x05, 0x0A,        //   Usage Page (Ordinal)
+    0x19, 0x00,        //   Usage Minimum (0)
+    0x29, 0x3F,        //   Usage Maximum (63)
+    0x81, 0x02,        //   Input (Data,Var,Abs)
+    0xC0,              // End Collection
+// 27 bytes
+];
+
+/// Steno keys, in the order of the Plover HID protocol. The 26 extra keys
+/// following the number keys are not used.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum StenoKey {
+    /// S1-
+    S1 = 0,
+    /// S2-
+    S2,
+    /// T-
+    T,
+    /// K-
+    K,
+    /// P-
+    P,
+    /// W-
+    W,
+    /// H-
+    H,
+    /// R-
+    R,
+    /// A-
+    A,
+    /// O-
+    O,
+    /// *1
+    Star1,
+    /// *2
+    Star2,
+    /// *3
+    Star3,
+    /// *4
+    Star4,
+    /// -E
+    E,
+    /// -U
+    U,
+    /// -F
+    F,
+    /// -R
+    RightR,
+    /// -P
+    RightP,
+    /// -B
+    B,
+    /// -L
+    L,
+    /// -G
+    G,
+    /// -T
+    RightT,
+    /// -S
+    RightS,
+    /// -D
+    D,
+    /// -Z
+    Z,
+    /// #1
+    Num1,
+    /// #2
+    Num2,
+    /// #3
+    Num3,
+    /// #4
+    Num4,
+    /// #5
+    Num5,
+    /// #6
+    Num6,
+    /// #7
+    Num7,
+    /// #8
+    Num8,
+    /// #9
+    Num9,
+    /// #A
+    NumA,
+    /// #B
+    NumB,
+    /// #C
+    NumC,
+}
+
+impl StenoKey {
+    /// Bit of the key in a chord
+    pub const fn bit(self) -> u64 {
+        1 << (63 - self as u8)
+    }
+}
+
+/// What a key of the matrix does in steno mode
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StenoAction {
+    /// Not a steno key: processed by the keymap
+    None,
+    /// Steno key
+    Key(StenoKey),
+    /// Leave the steno mode
+    Exit,
+}
+
+/// What to do with a key event in steno mode
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Outcome {
+    /// Not a steno key: the event is for the keymap
+    Forward(KeyEvent),
+    /// Captured into the chord being typed
+    Captured,
+    /// All the keys of the chord are released: send it
+    Chord(u64),
+    /// The steno mode was left
+    Exit,
+}
+
+/// Plover HID report of the keys of `chord`
+pub fn report(chord: u64) -> [u8; REPORT_SIZE] {
+    let mut report = [0u8; REPORT_SIZE];
+    report[0] = REPORT_ID;
+    report[1..].copy_from_slice(&chord.to_be_bytes());
+    report
+}
+
+/// Chord capture of the steno mode, on a `ROWS`x`COLS` matrix of at most
+/// 64 keys
+#[derive(Debug)]
+pub struct StenoMode<const ROWS: usize, const COLS: usize> {
+    /// Steno layout of the matrix
+    layout: &'static [[StenoAction; COLS]; ROWS],
+    /// Whether the steno mode is active
+    active: bool,
+    /// Keys of the matrix captured and still held, one bit per key, row
+    /// by row
+    held: u64,
+    /// Chord being typed
+    chord: u64,
+}
+
+impl<const ROWS: usize, const COLS: usize> StenoMode<ROWS, COLS> {
+    /// Inactive steno mode with the steno layout `layout`
+    pub const fn new(layout: &'static [[StenoAction; COLS]; ROWS]) -> Self {
+        assert!(ROWS * COLS <= 64);
+        Self {
+            layout,
+            active: false,
+            held: 0,
+            chord: 0,
+        }
+    }
+
+    /// Whether the steno mode is active
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Enter the steno mode
+    pub fn enter(&mut self) {
+        self.active = true;
+        self.chord = 0;
+    }
+
+    /// Process a key event. The releases of the keys captured are captured
+    /// too, even once the steno mode is left, so that the keymap never
+    /// sees a release without its press.
+    pub fn on_key_event(&mut self, event: KeyEvent) -> Outcome {
+        let (KeyEvent::Press(i, j) | KeyEvent::Release(i, j)) = event;
+        let (i, j) = (i as usize, j as usize);
+        let Some(action) = self.layout.get(i).and_then(|r| r.get(j)) else {
+            return Outcome::Forward(event);
+        };
+        let bit = 1 << (i * COLS + j);
+        match event {
+            KeyEvent::Press(_, _) if self.active => match action {
+                StenoAction::None => Outcome::Forward(event),
+                StenoAction::Key(key) => {
+                    self.held |= bit;
+                    self.chord |= key.bit();
+                    Outcome::Captured
+                }
+                StenoAction::Exit => {
+                    self.held |= bit;
+                    self.active = false;
+                    self.chord = 0;
+                    Outcome::Exit
+                }
+            },
+            KeyEvent::Release(_, _) if self.held & bit != 0 => {
+                self.held &= !bit;
+                if self.held == 0 && self.chord != 0 {
+                    Outcome::Chord(core::mem::take(&mut self.chord))
+                } else {
+                    Outcome::Captured
+                }
+            }
+            _ => Outcome::Forward(event),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static LAYOUT: [[StenoAction; 3]; 2] = [
+        [
+            StenoAction::Key(StenoKey::S1),
+            StenoAction::Key(StenoKey::A),
+            StenoAction::None,
+        ],
+        [
+            StenoAction::Key(StenoKey::Z),
+            StenoAction::Exit,
+            StenoAction::Key(StenoKey::NumC),
+        ],
+    ];
+
+    #[test]
+    fn test_report() {
+        assert_eq!(STENO_REPORT_DESCRIPTOR.len(), 27);
+        let chord = StenoKey::S1.bit() | StenoKey::NumC.bit();
+        assert_eq!(report(chord), [REPORT_ID, 0x80, 0, 0, 0, 0x04, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_chords() {
+        let mut steno = StenoMode::new(&LAYOUT);
+        // Inactive: everything goes to the keymap
+        let press = KeyEvent::Press(0, 0);
+        assert_eq!(steno.on_key_event(press), Outcome::Forward(press));
+        steno.enter();
+        // Released after entering the steno mode
+        let release = KeyEvent::Release(0, 0);
+        assert_eq!(steno.on_key_event(release), Outcome::Forward(release));
+
+        assert_eq!(steno.on_key_event(press), Outcome::Captured);
+        assert_eq!(steno.on_key_event(KeyEvent::Press(1, 0)), Outcome::Captured);
+        // Not steno keys
+        let press = KeyEvent::Press(0, 2);
+        assert_eq!(steno.on_key_event(press), Outcome::Forward(press));
+        let press = KeyEvent::Press(3, 8);
+        assert_eq!(steno.on_key_event(press), Outcome::Forward(press));
+        assert_eq!(
+            steno.on_key_event(KeyEvent::Release(0, 0)),
+            Outcome::Captured
+        );
+        assert_eq!(steno.on_key_event(KeyEvent::Press(0, 1)), Outcome::Captured);
+        assert_eq!(
+            steno.on_key_event(KeyEvent::Release(1, 0)),
+            Outcome::Captured
+        );
+        assert_eq!(
+            steno.on_key_event(KeyEvent::Release(0, 1)),
+            Outcome::Chord(StenoKey::S1.bit() | StenoKey::A.bit() | StenoKey::Z.bit())
+        );
+
+        // Leaving the steno mode drops the chord being typed
+        assert_eq!(steno.on_key_event(KeyEvent::Press(1, 2)), Outcome::Captured);
+        assert_eq!(steno.on_key_event(KeyEvent::Press(1, 1)), Outcome::Exit);
+        assert!(!steno.is_active());
+        let press = KeyEvent::Press(0, 0);
+        assert_eq!(steno.on_key_event(press), Outcome::Forward(press));
+        assert_eq!(
+            steno.on_key_event(KeyEvent::Release(1, 2)),
+            Outcome::Captured
+        );
+        assert_eq!(
+            steno.on_key_event(KeyEvent::Release(1, 1)),
+            Outcome::Captured
+        );
+        let release = KeyEvent::Release(0, 0);
+        assert_eq!(steno.on_key_event(release), Outcome::Forward(release));
+    }
+}
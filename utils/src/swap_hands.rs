@@ -0,0 +1,110 @@
+//! Swap hands: the columns of the matrix mirrored left to right, for
+//! one-handed typing
+//!
+//! While active, a key of one half acts as the key at the same place on
+//! the other half. A key is released at the coordinates it was pressed
+//! at, even if the swap was toggled in between.
+
+use crate::pipeline::KeyEvent;
+
+/// Mirror of the key events of a matrix of `ROWS` rows
+#[derive(Debug)]
+pub struct SwapHands<const ROWS: usize> {
+    /// Number of columns of both halves, at most 32. Columns beyond, such
+    /// as virtual keys, are not mirrored.
+    cols: u8,
+    /// Whether the hands are swapped
+    active: bool,
+    /// Bitmap, by row, of the keys pressed while swapped
+    swapped: [u32; ROWS],
+}
+
+impl<const ROWS: usize> SwapHands<ROWS> {
+    /// Mirror of a matrix of `cols` columns over both halves
+    pub const fn new(cols: u8) -> Self {
+        Self {
+            cols,
+            active: false,
+            swapped: [0; ROWS],
+        }
+    }
+
+    /// Swap the hands, or not
+    pub fn set_active(&mut self, active: bool) {
+        self.active = active;
+    }
+
+    /// Mirror of the column `j`
+    fn mirror(&self, j: u8) -> u8 {
+        if j < self.cols {
+            self.cols - 1 - j
+        } else {
+            j
+        }
+    }
+
+    /// Event `event` with the hands swapped, if it needs to be
+    pub fn on_event(&mut self, event: KeyEvent) -> KeyEvent {
+        match event {
+            KeyEvent::Press(i, j) if (i as usize) < ROWS && j < self.cols.min(32) => {
+                let bit = 1 << j;
+                if self.active {
+                    self.swapped[i as usize] |= bit;
+                    KeyEvent::Press(i, self.mirror(j))
+                } else {
+                    self.swapped[i as usize] &= !bit;
+                    event
+                }
+            }
+            KeyEvent::Release(i, j) if (i as usize) < ROWS && j < self.cols.min(32) => {
+                let bit = 1 << j;
+                if self.swapped[i as usize] & bit != 0 {
+                    self.swapped[i as usize] &= !bit;
+                    KeyEvent::Release(i, self.mirror(j))
+                } else {
+                    event
+                }
+            }
+            _ => event,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_swap_hands() {
+        let mut swap = SwapHands::<4>::new(10);
+        assert_eq!(swap.on_event(KeyEvent::Press(1, 2)), KeyEvent::Press(1, 2));
+        // The swap key, released once swapped
+        swap.set_active(true);
+        assert_eq!(
+            swap.on_event(KeyEvent::Release(1, 2)),
+            KeyEvent::Release(1, 2)
+        );
+        assert_eq!(swap.on_event(KeyEvent::Press(0, 0)), KeyEvent::Press(0, 9));
+        assert_eq!(swap.on_event(KeyEvent::Press(3, 6)), KeyEvent::Press(3, 3));
+        // Not mirrored: virtual keys
+        assert_eq!(
+            swap.on_event(KeyEvent::Press(0, 10)),
+            KeyEvent::Press(0, 10)
+        );
+        // Released once back to normal
+        swap.set_active(false);
+        assert_eq!(
+            swap.on_event(KeyEvent::Release(0, 0)),
+            KeyEvent::Release(0, 9)
+        );
+        assert_eq!(
+            swap.on_event(KeyEvent::Release(0, 0)),
+            KeyEvent::Release(0, 0)
+        );
+        assert_eq!(swap.on_event(KeyEvent::Press(3, 6)), KeyEvent::Press(3, 6));
+        assert_eq!(
+            swap.on_event(KeyEvent::Release(3, 6)),
+            KeyEvent::Release(3, 6)
+        );
+    }
+}
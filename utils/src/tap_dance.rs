@@ -0,0 +1,251 @@
+//! Tap dance: keys doing different things when tapped, double tapped or
+//! held
+//!
+//! A tap dance key resolves, after its first press, into:
+//! - its hold key, when held for `TAPPING_TERM_MS`, pressed until released
+//! - its tap key, when released and not pressed again within
+//!   `TAPPING_TERM_MS`, tapped for `TAP_MS`
+//! - its double tap key, when pressed again within `TAPPING_TERM_MS`,
+//!   pressed until released
+//!
+//! Pressing another key resolves a pending tap dance right away: into its
+//! hold key if still held, into its tap key otherwise. A single tap dance
+//! is pending at a time.
+
+/// Time within which a tap dance key must be released to be a tap, and
+/// pressed again to be a double tap, in ms
+pub const TAPPING_TERM_MS: u16 = 200;
+/// Time a tap resolved from a tap dance stays pressed, for the host to see
+/// it, in ms
+pub const TAP_MS: u16 = 10;
+
+/// Keys of a tap dance key
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TapDance<K> {
+    /// Key tapped on a single tap
+    pub tap: K,
+    /// Key pressed on a double tap, until released
+    pub double_tap: K,
+    /// Key pressed when held, until released
+    pub hold: K,
+}
+
+/// State of the pending tap dance
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum State<K> {
+    /// Pressed a first time, `elapsed_ms` ago
+    Pressed { elapsed_ms: u16 },
+    /// Released after a first press, `elapsed_ms` ago
+    Released { elapsed_ms: u16 },
+    /// Resolved into `key`, pressed until the tap dance key is released
+    Held(K),
+    /// Resolved into `key`, tapped for `remaining_ms` more
+    Tapped { key: K, remaining_ms: u16 },
+}
+
+/// Tap dance engine, refreshed every ms
+#[derive(Debug)]
+pub struct TapDances<K: 'static> {
+    /// Keys of each tap dance, by index
+    table: &'static [TapDance<K>],
+    /// Index and state of the pending tap dance, if any
+    current: Option<(u8, State<K>)>,
+}
+
+impl<K: Copy> TapDances<K> {
+    /// Tap dance engine for the tap dances of `table`
+    pub const fn new(table: &'static [TapDance<K>]) -> Self {
+        Self {
+            table,
+            current: None,
+        }
+    }
+
+    /// Keys of the tap dance `index`, if it exists
+    fn keys(&self, index: u8) -> Option<&TapDance<K>> {
+        self.table.get(index as usize)
+    }
+
+    /// Press or release the tap dance key `index`
+    pub fn on_event(&mut self, index: u8, is_pressed: bool) {
+        let Some(keys) = self.keys(index).copied() else {
+            return;
+        };
+        match (self.current, is_pressed) {
+            (Some((i, State::Released { .. })), true) if i == index => {
+                self.current = Some((index, State::Held(keys.double_tap)));
+            }
+            (_, true) => {
+                self.interrupt();
+                self.current = Some((index, State::Pressed { elapsed_ms: 0 }));
+            }
+            (Some((i, State::Pressed { .. })), false) if i == index => {
+                self.current = Some((index, State::Released { elapsed_ms: 0 }));
+            }
+            (Some((i, State::Held(_))), false) if i == index => self.current = None,
+            _ => (),
+        }
+    }
+
+    /// Resolve the pending tap dance, as another key was pressed
+    pub fn interrupt(&mut self) {
+        let Some((index, state)) = self.current else {
+            return;
+        };
+        let Some(keys) = self.keys(index).copied() else {
+            return;
+        };
+        self.current = match state {
+            State::Pressed { .. } => Some((index, State::Held(keys.hold))),
+            State::Released { .. } => Some((
+                index,
+                State::Tapped {
+                    key: keys.tap,
+                    remaining_ms: TAP_MS,
+                },
+            )),
+            _ => return,
+        };
+    }
+
+    /// Advance by 1ms
+    pub fn tick(&mut self) {
+        let Some((index, state)) = self.current else {
+            return;
+        };
+        let Some(keys) = self.keys(index).copied() else {
+            return;
+        };
+        let state = match state {
+            State::Pressed { elapsed_ms } if elapsed_ms + 1 >= TAPPING_TERM_MS => {
+                State::Held(keys.hold)
+            }
+            State::Pressed { elapsed_ms } => State::Pressed {
+                elapsed_ms: elapsed_ms + 1,
+            },
+            State::Released { elapsed_ms } if elapsed_ms + 1 >= TAPPING_TERM_MS => State::Tapped {
+                key: keys.tap,
+                remaining_ms: TAP_MS,
+            },
+            State::Released { elapsed_ms } => State::Released {
+                elapsed_ms: elapsed_ms + 1,
+            },
+            State::Held(key) => State::Held(key),
+            State::Tapped { remaining_ms, .. } if remaining_ms <= 1 => {
+                self.current = None;
+                return;
+            }
+            State::Tapped { key, remaining_ms } => State::Tapped {
+                key,
+                remaining_ms: remaining_ms - 1,
+            },
+        };
+        self.current = Some((index, state));
+    }
+
+    /// Key currently pressed by the tap dances, if any
+    pub fn key(&self) -> Option<K> {
+        match self.current {
+            Some((_, State::Held(key) | State::Tapped { key, .. })) => Some(key),
+            _ => None,
+        }
+    }
+
+    /// Whether a tap dance is pending or pressing a key
+    pub fn is_active(&self) -> bool {
+        self.current.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static TABLE: [TapDance<u8>; 2] = [
+        TapDance {
+            tap: 1,
+            double_tap: 2,
+            hold: 3,
+        },
+        TapDance {
+            tap: 4,
+            double_tap: 5,
+            hold: 6,
+        },
+    ];
+
+    /// Keys pressed by `td` during the next `ms` ticks
+    fn run(td: &mut TapDances<u8>, ms: u16) -> Vec<Option<u8>> {
+        (0..ms)
+            .map(|_| {
+                td.tick();
+                td.key()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_tap() {
+        let mut td = TapDances::new(&TABLE);
+        td.on_event(0, true);
+        assert!(run(&mut td, 50).iter().all(Option::is_none));
+        td.on_event(0, false);
+        assert!(run(&mut td, TAPPING_TERM_MS - 1)
+            .iter()
+            .all(Option::is_none));
+        assert!(run(&mut td, TAP_MS).iter().all(|k| *k == Some(1)));
+        assert_eq!(run(&mut td, 1), [None]);
+        assert!(!td.is_active());
+    }
+
+    #[test]
+    fn test_hold() {
+        let mut td = TapDances::new(&TABLE);
+        td.on_event(0, true);
+        assert!(run(&mut td, TAPPING_TERM_MS - 1)
+            .iter()
+            .all(Option::is_none));
+        assert!(run(&mut td, 500).iter().all(|k| *k == Some(3)));
+        td.on_event(0, false);
+        assert_eq!(td.key(), None);
+        assert!(!td.is_active());
+    }
+
+    #[test]
+    fn test_double_tap() {
+        let mut td = TapDances::new(&TABLE);
+        td.on_event(0, true);
+        run(&mut td, 50);
+        td.on_event(0, false);
+        run(&mut td, 50);
+        td.on_event(0, true);
+        assert!(run(&mut td, 500).iter().all(|k| *k == Some(2)));
+        td.on_event(0, false);
+        assert_eq!(td.key(), None);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut td = TapDances::new(&TABLE);
+        // Held, then another key pressed
+        td.on_event(0, true);
+        run(&mut td, 50);
+        td.interrupt();
+        assert_eq!(td.key(), Some(3));
+        td.on_event(0, false);
+        assert_eq!(td.key(), None);
+
+        // Tapped, then another tap dance pressed
+        td.on_event(0, true);
+        td.on_event(0, false);
+        td.on_event(1, true);
+        assert_eq!(td.key(), None);
+        run(&mut td, TAPPING_TERM_MS);
+        assert_eq!(td.key(), Some(6));
+
+        // Unknown tap dance
+        td.on_event(0, false);
+        td.on_event(7, true);
+        assert_eq!(td.key(), Some(6));
+    }
+}
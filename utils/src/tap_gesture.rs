@@ -0,0 +1,268 @@
+//! Tap gestures of a trackpad
+//!
+//! A short touch, barely moving, clicks the left button. Touching again
+//! right after a tap keeps the button pressed until the finger is lifted,
+//! to drag; tapping again instead makes a double click. The button pressed
+//! by a tap is only released once no second touch can follow, so that the
+//! drag does not start with a release.
+
+/// Longest touch considered as a tap, in ms
+const TAP_MAX_MS: u64 = 200;
+/// Longest move during a touch considered as a tap, in counts
+const TAP_MAX_MOVE: u16 = 16;
+/// Longest time between a tap and a touch starting a drag, in ms
+const DOUBLE_TAP_MS: u64 = 250;
+/// Lowest pressure keeping the mouse mode without any move
+const PRESSURE_NO_MVMT: u8 = 27;
+/// Lowest pressure of a touch moving the pointer
+const MIN_PRESSURE_MVMT: u8 = 10;
+
+/// Left button changes made by the gestures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TapEvent {
+    /// Press the left button
+    Press,
+    /// Release the left button
+    Release,
+}
+
+/// State of the gestures
+#[derive(Debug, Clone, Copy)]
+enum State {
+    /// No touch, the button being released
+    Idle,
+    /// Touching since `start`, having moved by `moved` counts. When it
+    /// follows a tap, the button is `dragging`.
+    Touching {
+        start: u64,
+        moved: u16,
+        dragging: bool,
+    },
+    /// A tap pressed the button at `at`: it is released unless touched
+    /// again soon
+    Tapped { at: u64 },
+}
+
+/// Recognition of the tap gestures, from the touches
+#[derive(Debug)]
+pub struct TapGesture {
+    state: State,
+}
+
+impl Default for TapGesture {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TapGesture {
+    /// No gesture in progress
+    pub const fn new() -> Self {
+        Self { state: State::Idle }
+    }
+
+    /// A finger touches the trackpad, at `now_ms`
+    pub fn on_touch(&mut self, now_ms: u64) -> &'static [TapEvent] {
+        let (dragging, events): (bool, &'static [TapEvent]) = match self.state {
+            State::Tapped { at } if now_ms - at < DOUBLE_TAP_MS => (true, &[]),
+            State::Tapped { .. } => (false, &[TapEvent::Release]),
+            State::Touching { dragging, .. } => (dragging, &[]),
+            State::Idle => (false, &[]),
+        };
+        self.state = State::Touching {
+            start: now_ms,
+            moved: 0,
+            dragging,
+        };
+        events
+    }
+
+    /// The finger touching the trackpad moves by `(dx, dy)`
+    pub fn on_move(&mut self, dx: i16, dy: i16) {
+        if let State::Touching { moved, .. } = &mut self.state {
+            *moved = moved
+                .saturating_add(dx.unsigned_abs())
+                .saturating_add(dy.unsigned_abs());
+        }
+    }
+
+    /// The finger is lifted from the trackpad, at `now_ms`
+    pub fn on_lift(&mut self, now_ms: u64) -> &'static [TapEvent] {
+        let State::Touching {
+            start,
+            moved,
+            dragging,
+        } = self.state
+        else {
+            return &[];
+        };
+        let is_tap = now_ms - start < TAP_MAX_MS && moved <= TAP_MAX_MOVE;
+        self.state = if is_tap {
+            State::Tapped { at: now_ms }
+        } else {
+            State::Idle
+        };
+        match (is_tap, dragging) {
+            (true, false) => &[TapEvent::Press],
+            // A second tap: a double click
+            (true, true) => &[TapEvent::Release, TapEvent::Press],
+            (false, false) => &[],
+            // The end of a drag
+            (false, true) => &[TapEvent::Release],
+        }
+    }
+
+    /// Release the button pressed by a tap not followed by a touch, at
+    /// `now_ms`
+    pub fn tick(&mut self, now_ms: u64) -> Option<TapEvent> {
+        match self.state {
+            State::Tapped { at } if now_ms - at >= DOUBLE_TAP_MS => {
+                self.state = State::Idle;
+                Some(TapEvent::Release)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Filter of the mouse reports of a trackpad: the light touches are
+/// ignored, but not the scroll steps nor the button changes, which come
+/// once the finger is lifted for the taps
+#[derive(Debug, Default)]
+pub struct ReportFilter {
+    /// Buttons of the last report let through
+    buttons: u8,
+}
+
+impl ReportFilter {
+    /// No button pressed
+    pub const fn new() -> Self {
+        Self { buttons: 0 }
+    }
+
+    /// Whether a report of `buttons` is sent, at `pressure`, with scroll
+    /// steps if `scrolls`. `Some(true)` when the pressure is enough to
+    /// keep the mouse mode without moving.
+    pub fn filter(&mut self, pressure: u8, buttons: u8, scrolls: bool) -> Option<bool> {
+        let buttons_changed = buttons != self.buttons;
+        self.buttons = buttons;
+        match pressure {
+            p if p >= PRESSURE_NO_MVMT => Some(true),
+            p if p >= MIN_PRESSURE_MVMT => Some(false),
+            // Scroll steps of the gestures, the finger being lifted
+            0 if scrolls => Some(false),
+            // The taps, released or pressed whatever the pressure
+            _ if buttons_changed => Some(false),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tap() {
+        let mut gesture = TapGesture::new();
+        assert_eq!(gesture.on_touch(1000), &[]);
+        gesture.on_move(3, -4);
+        assert_eq!(gesture.on_lift(1100), &[TapEvent::Press]);
+        assert_eq!(gesture.tick(1100 + DOUBLE_TAP_MS - 1), None);
+        assert_eq!(gesture.tick(1100 + DOUBLE_TAP_MS), Some(TapEvent::Release));
+        assert_eq!(gesture.tick(2000), None);
+    }
+
+    #[test]
+    fn test_no_tap() {
+        let mut gesture = TapGesture::new();
+        // Too long
+        gesture.on_touch(1000);
+        assert_eq!(gesture.on_lift(1000 + TAP_MAX_MS), &[]);
+        // Moving too much
+        gesture.on_touch(2000);
+        gesture.on_move(10, 0);
+        gesture.on_move(0, -10);
+        assert_eq!(gesture.on_lift(2050), &[]);
+        assert_eq!(gesture.tick(3000), None);
+        // Lifted without a touch
+        assert_eq!(gesture.on_lift(4000), &[]);
+    }
+
+    #[test]
+    fn test_drag() {
+        let mut gesture = TapGesture::new();
+        gesture.on_touch(1000);
+        assert_eq!(gesture.on_lift(1050), &[TapEvent::Press]);
+        assert_eq!(gesture.on_touch(1100), &[]);
+        gesture.on_move(100, 100);
+        assert_eq!(gesture.tick(2000), None);
+        assert_eq!(gesture.on_lift(3000), &[TapEvent::Release]);
+        assert_eq!(gesture.tick(4000), None);
+    }
+
+    #[test]
+    fn test_double_tap() {
+        let mut gesture = TapGesture::new();
+        gesture.on_touch(1000);
+        assert_eq!(gesture.on_lift(1050), &[TapEvent::Press]);
+        gesture.on_touch(1100);
+        assert_eq!(gesture.on_lift(1150), &[TapEvent::Release, TapEvent::Press]);
+        assert_eq!(gesture.tick(1150 + DOUBLE_TAP_MS), Some(TapEvent::Release));
+    }
+
+    #[test]
+    fn test_late_touch() {
+        let mut gesture = TapGesture::new();
+        gesture.on_touch(1000);
+        assert_eq!(gesture.on_lift(1050), &[TapEvent::Press]);
+        // Touching after the release is due, before it is ticked
+        assert_eq!(gesture.on_touch(1050 + DOUBLE_TAP_MS), &[TapEvent::Release]);
+        assert_eq!(gesture.on_lift(2000), &[]);
+    }
+
+    #[test]
+    fn test_filter_tap() {
+        let mut gesture = TapGesture::new();
+        let mut filter = ReportFilter::new();
+        // Touching
+        assert_eq!(gesture.on_touch(1000), &[]);
+        assert_eq!(filter.filter(30, 0, false), Some(true));
+        // Lifting, the move of the lift being handled before the press
+        let events = gesture.on_lift(1100);
+        assert_eq!(events, &[TapEvent::Press]);
+        assert_eq!(filter.filter(0, 0, false), None);
+        assert_eq!(filter.filter(0, 1, false), Some(false));
+        // Released once no drag can follow
+        assert_eq!(gesture.tick(1100 + DOUBLE_TAP_MS), Some(TapEvent::Release));
+        assert_eq!(filter.filter(0, 0, false), Some(false));
+        assert_eq!(filter.filter(0, 0, false), None);
+    }
+
+    #[test]
+    fn test_filter_drag() {
+        let mut gesture = TapGesture::new();
+        let mut filter = ReportFilter::new();
+        gesture.on_touch(1000);
+        assert_eq!(gesture.on_lift(1050), &[TapEvent::Press]);
+        assert_eq!(filter.filter(0, 1, false), Some(false));
+        // Dragging, lightly
+        gesture.on_touch(1100);
+        gesture.on_move(100, 100);
+        assert_eq!(filter.filter(5, 1, false), None);
+        assert_eq!(filter.filter(12, 1, false), Some(false));
+        // The end of the drag
+        assert_eq!(gesture.on_lift(3000), &[TapEvent::Release]);
+        assert_eq!(filter.filter(0, 1, false), None);
+        assert_eq!(filter.filter(0, 0, false), Some(false));
+    }
+
+    #[test]
+    fn test_filter_scroll() {
+        let mut filter = ReportFilter::new();
+        assert_eq!(filter.filter(0, 0, true), Some(false));
+        assert_eq!(filter.filter(0, 0, false), None);
+        assert_eq!(filter.filter(5, 0, false), None);
+    }
+}
@@ -0,0 +1,132 @@
+//! Hold-tap keys resolved at runtime, following the settings
+//!
+//! A hold-tap key waiting to be resolved is held:
+//! - once held for the configured timeout
+//! - in permissive hold mode, as soon as another key is pressed and
+//!   released while it is held
+//! - in hold on other key press mode, as soon as another key is pressed
+//!
+//! It is tapped when released before. The keymap asks `resolve` every ms a
+//! hold-tap key is waiting, and `tick` is called once per ms after that.
+
+use crate::pipeline::KeyEvent;
+use crate::settings::{TapHold as Config, TapHoldMode};
+
+/// Resolution of the hold-tap keys
+#[derive(Debug, Default)]
+pub struct TapHolds {
+    /// Configuration
+    config: Config,
+    /// Time the waiting hold-tap key has been waited for, in ms
+    waited_ms: Option<u16>,
+    /// Whether a hold-tap key was waiting during the current ms
+    waiting: bool,
+}
+
+impl TapHolds {
+    /// Resolution of the hold-tap keys with the configuration `config`
+    pub const fn new(config: Config) -> Self {
+        Self {
+            config,
+            waited_ms: None,
+            waiting: false,
+        }
+    }
+
+    /// Apply a configuration changed at runtime
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    /// Whether the hold-tap key waiting for 1 more ms is held, given the
+    /// key events queued since it was pressed
+    pub fn resolve(&mut self, mut queued: impl Iterator<Item = KeyEvent> + Clone) -> bool {
+        self.waiting = true;
+        let waited_ms = self.waited_ms.map_or(1, |ms| ms.saturating_add(1));
+        self.waited_ms = Some(waited_ms);
+        if waited_ms >= self.config.timeout_ms {
+            return true;
+        }
+        match self.config.mode {
+            TapHoldMode::TapPreferred => false,
+            TapHoldMode::PermissiveHold => {
+                let mut after = queued.clone();
+                queued.any(|e| match e {
+                    KeyEvent::Press(i, j) => after.any(|e| e == KeyEvent::Release(i, j)),
+                    KeyEvent::Release(_, _) => false,
+                })
+            }
+            TapHoldMode::HoldOnOtherKeyPress => queued.any(|e| matches!(e, KeyEvent::Press(_, _))),
+        }
+    }
+
+    /// End the current ms, once the keymap got refreshed
+    pub fn tick(&mut self) {
+        if !self.waiting {
+            self.waited_ms = None;
+        }
+        self.waiting = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Wait for `ms`, with `queued` key events. Returns after how many ms
+    /// the key is held, if it is.
+    fn wait(holds: &mut TapHolds, ms: u16, queued: &[KeyEvent]) -> Option<u16> {
+        for i in 1..=ms {
+            if holds.resolve(queued.iter().copied()) {
+                holds.tick();
+                return Some(i);
+            }
+            holds.tick();
+        }
+        None
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut holds = TapHolds::new(Config::default());
+        let timeout = Config::default().timeout_ms;
+        // Some other key pressed is not enough
+        let queued = [KeyEvent::Press(0, 1), KeyEvent::Release(0, 1)];
+        assert_eq!(wait(&mut holds, 1000, &queued), Some(timeout));
+        // A new hold-tap key is waited for after a ms without any
+        holds.tick();
+        assert_eq!(wait(&mut holds, timeout - 1, &[]), None);
+        holds.tick();
+        holds.set_config(Config {
+            timeout_ms: 300,
+            ..Default::default()
+        });
+        assert_eq!(wait(&mut holds, 1000, &[]), Some(300));
+    }
+
+    #[test]
+    fn test_permissive_hold() {
+        let mut holds = TapHolds::new(Config {
+            mode: TapHoldMode::PermissiveHold,
+            ..Default::default()
+        });
+        assert_eq!(wait(&mut holds, 10, &[KeyEvent::Press(0, 1)]), None);
+        let queued = [
+            KeyEvent::Release(0, 2),
+            KeyEvent::Press(0, 1),
+            KeyEvent::Release(0, 1),
+        ];
+        assert_eq!(wait(&mut holds, 10, &queued), Some(1));
+    }
+
+    #[test]
+    fn test_hold_on_other_key_press() {
+        let mut holds = TapHolds::new(Config {
+            mode: TapHoldMode::HoldOnOtherKeyPress,
+            ..Default::default()
+        });
+        assert_eq!(wait(&mut holds, 10, &[KeyEvent::Release(0, 1)]), None);
+        holds.tick();
+        assert_eq!(wait(&mut holds, 10, &[KeyEvent::Press(0, 1)]), Some(1));
+    }
+}
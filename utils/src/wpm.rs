@@ -0,0 +1,176 @@
+//! Typing speed, in words per minute
+//!
+//! It is either averaged over a sliding window of seconds, or smoothed
+//! exponentially, each second weighing less and less as time passes.
+
+/// Number of seconds the typing speed is averaged over
+pub const WINDOW_S: usize = 10;
+/// Number of key presses per word
+const PRESSES_PER_WORD: u32 = 5;
+/// Weight of the last second in the smoothed typing speed: 1 out of
+/// `SMOOTHING`
+const SMOOTHING: u32 = 8;
+/// Scale of the smoothed typing speed, for its fractional part
+const SMOOTHED_SCALE: u32 = 256;
+
+/// Typing speed over a sliding window of `WINDOW_S` seconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Wpm {
+    /// Number of key presses of each second of the window
+    presses: [u16; WINDOW_S],
+    /// Index of the current second in `presses`
+    current: usize,
+}
+
+impl Default for Wpm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Wpm {
+    /// Create a typing speed counter, with no key press
+    pub const fn new() -> Self {
+        Self {
+            presses: [0; WINDOW_S],
+            current: 0,
+        }
+    }
+
+    /// Count `n` key presses in the current second
+    pub fn on_presses(&mut self, n: u16) {
+        self.presses[self.current] = self.presses[self.current].saturating_add(n);
+    }
+
+    /// Move on to the next second, forgetting the oldest one
+    pub fn next_second(&mut self) {
+        self.current = (self.current + 1) % WINDOW_S;
+        self.presses[self.current] = 0;
+    }
+
+    /// Typing speed, in words per minute
+    pub fn wpm(&self) -> u16 {
+        let presses: u32 = self.presses.iter().map(|&n| n as u32).sum();
+        let wpm = presses * 60 / (PRESSES_PER_WORD * WINDOW_S as u32);
+        wpm.min(u16::MAX as u32) as u16
+    }
+}
+
+/// Typing speed smoothed exponentially, second after second
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SmoothedWpm {
+    /// Typing speed, in `1 / SMOOTHED_SCALE` words per minute
+    wpm: u32,
+    /// Number of key presses in the current second
+    presses: u16,
+    /// Start of the current second, in ms
+    second_start_ms: u64,
+}
+
+impl Default for SmoothedWpm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SmoothedWpm {
+    /// Create a typing speed counter, with no key press
+    pub const fn new() -> Self {
+        Self {
+            wpm: 0,
+            presses: 0,
+            second_start_ms: 0,
+        }
+    }
+
+    /// Count a key press in the current second
+    pub fn on_press(&mut self) {
+        self.presses = self.presses.saturating_add(1);
+    }
+
+    /// Smooth the typing speed with each second ended by `now_ms`. Returns
+    /// whether the typing speed changed.
+    pub fn update(&mut self, now_ms: u64) -> bool {
+        let prev = self.wpm();
+        while now_ms >= self.second_start_ms + 1000 {
+            let wpm = self.presses as u32 * 60 / PRESSES_PER_WORD * SMOOTHED_SCALE;
+            // Rounded up, so that it goes down to 0 when idle
+            self.wpm -= self.wpm.div_ceil(SMOOTHING);
+            self.wpm += wpm / SMOOTHING;
+            self.presses = 0;
+            self.second_start_ms += 1000;
+            if self.wpm == 0 {
+                // Skip the idle seconds
+                self.second_start_ms = now_ms - (now_ms - self.second_start_ms) % 1000;
+            }
+        }
+        self.wpm() != prev
+    }
+
+    /// Typing speed, in words per minute
+    pub fn wpm(&self) -> u16 {
+        (self.wpm / SMOOTHED_SCALE).min(u16::MAX as u32) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wpm() {
+        let mut wpm = Wpm::new();
+        assert_eq!(wpm.wpm(), 0);
+        // 5 presses per second: 60 WPM
+        for _ in 0..WINDOW_S {
+            wpm.on_presses(5);
+            wpm.next_second();
+        }
+        assert_eq!(wpm.wpm(), 54);
+        wpm.on_presses(5);
+        assert_eq!(wpm.wpm(), 60);
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let mut wpm = Wpm::new();
+        wpm.on_presses(50);
+        assert_eq!(wpm.wpm(), 60);
+        for _ in 0..WINDOW_S - 1 {
+            wpm.next_second();
+        }
+        assert_eq!(wpm.wpm(), 60);
+        wpm.next_second();
+        assert_eq!(wpm.wpm(), 0);
+    }
+
+    #[test]
+    fn test_smoothed() {
+        let mut wpm = SmoothedWpm::new();
+        assert!(!wpm.update(5000));
+        // 5 presses per second: converges towards 60 WPM
+        let mut now = 5000;
+        for _ in 0..40 {
+            for _ in 0..5 {
+                wpm.on_press();
+            }
+            now += 1000;
+            wpm.update(now);
+        }
+        assert!((57..=60).contains(&wpm.wpm()));
+        // A burst only raises it a bit
+        for _ in 0..10 {
+            wpm.on_press();
+        }
+        assert!(wpm.update(now + 1000));
+        assert!((65..=75).contains(&wpm.wpm()));
+        // Back to 0 when idle, even for a long time
+        assert!(wpm.update(now + 3_600_000));
+        assert_eq!(wpm.wpm(), 0);
+        assert!(!wpm.update(now + 3_600_500));
+        wpm.on_press();
+        assert!(!wpm.update(now + 3_600_999));
+    }
+}